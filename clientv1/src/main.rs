@@ -1,51 +1,15 @@
+//! This binary stays on the 1.x driver, so it only shares the models with the other ones.
+
 use anyhow::Result;
 use mongodb::{
-    bson::{doc, from_document, to_document, Bson, Document},
-    options::{ClientOptions, DropCollectionOptions, StreamAddress, UpdateModifications},
-    Client, Collection, Database,
-};
-
-use serde::{
-    de::{DeserializeOwned, Error},
-    Deserialize, Deserializer, Serialize,
+    bson::{doc, from_document, to_document, Document},
+    options::{ClientOptions, UpdateModifications},
+    Client,
 };
+use practice_core::{s, Book, Config, User};
+use serde::Serialize;
 
-#[derive(Deserialize, Serialize, Debug)]
-struct User {
-    id: String,
-    name: String,
-    reviewed_book_ids: Vec<String>,
-}
-
-impl From<Document> for User {
-    fn from(d: Document) -> Self {
-        from_document(d).unwrap()
-    }
-}
-
-#[derive(Deserialize, Serialize, Debug)]
-struct Book {
-    id: String,
-    name: String,
-    reviews: Vec<Review>,
-}
-
-impl From<Document> for Book {
-    fn from(d: Document) -> Self {
-        from_document(d).unwrap()
-    }
-}
-
-#[derive(Deserialize, Serialize, Debug)]
-struct Review {
-    user_id: String,
-    text: String,
-}
-
-//just for convinience.
-fn s(s: &str) -> String {
-    s.to_string()
-}
+const DB_NAME: &str = "test_db";
 
 //just for convinience.
 fn to_doc<T>(v: T) -> Document
@@ -58,8 +22,8 @@ where
     }
 }
 
-async fn create_users(client: &Client) -> Result<()> {
-    let db = client.database("test_db");
+async fn create_users(client: &Client, db_name: &str) -> Result<()> {
+    let db = client.database(db_name);
     let user_coll = db.collection("users");
     user_coll
         .insert_many(
@@ -93,8 +57,8 @@ async fn create_users(client: &Client) -> Result<()> {
     Ok(())
 }
 
-async fn create_books(client: &Client) -> Result<()> {
-    let db = client.database("test_db");
+async fn create_books(client: &Client, db_name: &str) -> Result<()> {
+    let db = client.database(db_name);
     let book_coll = db.collection("books");
     book_coll
         .insert_one(
@@ -102,6 +66,9 @@ async fn create_books(client: &Client) -> Result<()> {
                 id: s("book_1"),
                 name: s("The Hitchhiker's Guide to Somewhere"),
                 reviews: vec![],
+                authors: vec![],
+                supervisors: vec![],
+                version: 0,
             })),
             None,
         )
@@ -110,18 +77,18 @@ async fn create_books(client: &Client) -> Result<()> {
     Ok(())
 }
 
-async fn find_users(client: &Client) -> Result<()> {
-    let db = client.database("test_db");
+async fn find_users(client: &Client, db_name: &str) -> Result<()> {
+    let db = client.database(db_name);
     let user_coll = db.collection("users");
     let found = user_coll.find_one(Some(doc! {"id":"user_1"}), None).await?;
-    let found = User::from(found.unwrap());
+    let found: User = from_document(found.unwrap())?;
     assert_eq!(s("user_1"), found.id);
     println!("\nfound user:{:?}", found);
     Ok(())
 }
 
-async fn add_reviews_in_session(client: &Client) -> Result<()> {
-    let db = client.database("test_db");
+async fn add_reviews_in_session(client: &Client, db_name: &str) -> Result<()> {
+    let db = client.database(db_name);
     let user_coll = db.collection("users");
     let book_coll = db.collection("books");
 
@@ -176,8 +143,8 @@ async fn add_reviews_in_session(client: &Client) -> Result<()> {
     Ok(())
 }
 
-async fn drop_colls(client: &Client) -> Result<()> {
-    let db = client.database("test_db");
+async fn drop_colls(client: &Client, db_name: &str) -> Result<()> {
+    let db = client.database(db_name);
     let user_coll = db.collection("users");
     user_coll.drop(None).await?;
 
@@ -188,28 +155,17 @@ async fn drop_colls(client: &Client) -> Result<()> {
 
 #[tokio::main]
 async fn main() {
-    let opts = ClientOptions::builder()
-        .hosts(vec![
-            StreamAddress {
-                hostname: "mongo1".to_string(),
-                port: Some(30001),
-            },
-            StreamAddress {
-                hostname: "mongo2".to_string(),
-                port: Some(30002),
-            },
-            StreamAddress {
-                hostname: "mongo3".to_string(),
-                port: Some(30003),
-            },
-        ])
-        .repl_set_name(Some("my-replica-set".to_string()))
-        .build();
+    let config = Config::load().unwrap();
+    let db_name = config.db_name_or(DB_NAME);
+    // the 1.x driver takes the settings as a connection string.
+    let opts = ClientOptions::parse(&config.connection_string())
+        .await
+        .unwrap();
 
     let client = Client::with_options(opts).unwrap();
-    create_users(&client).await.unwrap();
-    create_books(&client).await.unwrap();
-    find_users(&client).await.unwrap();
-    add_reviews_in_session(&client).await.unwrap();
-    drop_colls(&client).await.unwrap();
+    create_users(&client, db_name).await.unwrap();
+    create_books(&client, db_name).await.unwrap();
+    find_users(&client, db_name).await.unwrap();
+    add_reviews_in_session(&client, db_name).await.unwrap();
+    drop_colls(&client, db_name).await.unwrap();
 }
@@ -0,0 +1,707 @@
+//! Runs each scenario of `practice_core` on its own.
+//!
+//! ```sh
+//! mongo-practice seed
+//! mongo-practice load-fixtures --dir fixtures/seed
+//! mongo-practice generate --users 100000 --books 5000 --zipf 1.1
+//! mongo-practice dump-fixtures --dir snapshot --format jsonl --mode relaxed
+//! mongo-practice tx-demo --iterations 3
+//! mongo-practice --db-name my_db --books-coll my_books optimistic-lock
+//! mongo-practice sync-indexes --spec indexes.yaml --dry-run
+//! mongo-practice cleanup
+//! mongo-practice --local-nodes 3 write-conflict
+//! mongo-practice replset --nodes 3
+//! mongo-practice --local-nodes 3 isolation-matrix
+//! mongo-practice contention --workers 16 --books 2 --strategy pessimistic
+//! mongo-practice check-consistency --repair
+//! mongo-practice --record workload.jsonl tx-demo
+//! mongo-practice replay --file workloads/reviews.jsonl --speed 1 --concurrency 8
+//! mongo-practice --log-level debug --log-format json tx-demo
+//! mongo-practice --log-format otel --log-file trace.jsonl contention
+//! mongo-practice watch --coll books --filter '{"operationType": "update"}' --full-document
+//! mongo-practice project-book-stats
+//! mongo-practice rebuild-book-stats
+//! mongo-practice relay --sink http://127.0.0.1:8080/hooks/reviews
+//! mongo-practice --metrics-addr 127.0.0.1:9464 --local-nodes 3 write-conflict
+//! ```
+
+use std::{fs::File, io::Write, path::PathBuf, sync::Arc};
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use practice_core::{
+    book_stats::{self, BookStatsProjector},
+    connect_with_handler, connect_with_handlers, consistency,
+    fixtures::{self, ExtJsonMode, FixtureFormat},
+    generator::{self, GeneratorOptions},
+    indexes::{self, IndexSpecs, SyncOptions},
+    metrics::{self, MetricsHandler},
+    outbox::{Outbox, Relay, RelayOptions, SinkSpec},
+    replset::{LocalReplicaSet, ReplicaSetOptions},
+    review::ReviewService,
+    scenario::{
+        basic,
+        contention::{self, LoadOptions, Strategy},
+        isolation, optimistic_lock, pessimistic_lock, write_conflict, Target,
+    },
+    storage::MongoStorage,
+    telemetry::{self, Collector, CommandLogger, Level, LogFormat},
+    watch::{self, ChangeEvent, ResumeTokens, WatchOptions},
+    workload::{self, Recorder, ReplayOptions},
+    Book, BookRepository, CommandHandlers, Config, DbResult, User, UserRepository,
+    WithTransactionOptions,
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+#[derive(Parser, Debug)]
+#[clap(name = "mongo-practice", about = "Runs the mongodb practice scenarios")]
+struct Cli {
+    /// Database to use. Defaults to MONGO_DB_NAME, then to the database of each scenario.
+    #[clap(long, global = true)]
+    db_name: Option<String>,
+
+    #[clap(long, global = true, default_value = "users")]
+    users_coll: String,
+
+    #[clap(long, global = true, default_value = "books")]
+    books_coll: String,
+
+    #[clap(long, global = true, default_value = "index_test")]
+    index_test_coll: String,
+
+    /// Runs the command on a replica set of this many local mongod, started for it and
+    /// stopped after it, instead of the configured one.
+    #[clap(long, global = true)]
+    local_nodes: Option<usize>,
+
+    /// Records the operations the command sends into a workload file, for `replay`.
+    #[clap(long, global = true)]
+    record: Option<PathBuf>,
+
+    /// pretty, json (an object per line), or otel (OTLP/JSON spans and logs).
+    #[clap(long, global = true, default_value = "pretty")]
+    log_format: LogFormat,
+
+    /// error, warn, info, debug or trace. debug logs each command sent to the server.
+    #[clap(long, global = true, default_value = "info")]
+    log_level: Level,
+
+    /// Writes the logs into this file instead of stderr. Defaults to telemetry.jsonl with otel.
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Serves Prometheus metrics at http://<addr>/metrics while the command runs, and after
+    /// it until Ctrl-C.
+    #[clap(long, global = true)]
+    metrics_addr: Option<String>,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Recreates the users and the books.
+    Seed,
+    /// Replaces collections with the documents of the Extended JSON files of a directory,
+    /// named after the collections.
+    LoadFixtures {
+        #[clap(long, default_value = "fixtures/seed")]
+        dir: PathBuf,
+    },
+    /// Writes collections into a directory as Extended JSON files.
+    DumpFixtures {
+        #[clap(long)]
+        dir: PathBuf,
+        /// Defaults to the users and the books.
+        #[clap(long, multiple_values = true)]
+        collections: Vec<String>,
+        /// json (an array) or jsonl (a document per line).
+        #[clap(long, default_value = "json")]
+        format: FixtureFormat,
+        /// canonical (keeps the BSON types) or relaxed.
+        #[clap(long, default_value = "canonical")]
+        mode: ExtJsonMode,
+    },
+    /// Replaces the users and the books with generated ones. The same options give the same data.
+    Generate {
+        #[clap(long, default_value_t = 42)]
+        seed: u64,
+        #[clap(long, default_value_t = 1000)]
+        users: usize,
+        #[clap(long, default_value_t = 200)]
+        books: usize,
+        /// Each user reviews from 0 to this many books.
+        #[clap(long, default_value_t = 5)]
+        max_reviews: usize,
+        /// Each book has from 1 to this many authors.
+        #[clap(long, default_value_t = 3)]
+        max_authors: usize,
+        /// Each book has from 0 to this many supervisors.
+        #[clap(long, default_value_t = 2)]
+        max_supervisors: usize,
+        /// Number of distinct authors, and of distinct supervisors.
+        #[clap(long, default_value_t = 50)]
+        people: usize,
+        /// Exponent of the Zipf distribution of the reviewed books. 0 is uniform.
+        #[clap(long, default_value_t = 1.0)]
+        zipf: f64,
+        /// Documents per insert_many.
+        #[clap(long, default_value_t = 1000)]
+        batch_size: usize,
+    },
+    /// Creates unique indexes and checks that duplicated documents are rejected.
+    Indexes,
+    /// Adds a review to a book and the user in a transaction.
+    TxDemo {
+        #[clap(long, default_value_t = 1)]
+        iterations: usize,
+    },
+    /// Aborts a transaction and checks the insert in it is discarded.
+    AbortDemo {
+        #[clap(long, default_value_t = 1)]
+        iterations: usize,
+    },
+    /// Runs the queries and updates on arrays ($in, $pull, $addToSet).
+    Misc,
+    /// Adds a review of a book by a user, keeping both documents consistent.
+    Review {
+        #[clap(long)]
+        user_id: String,
+        #[clap(long)]
+        book_id: String,
+        #[clap(long)]
+        text: String,
+    },
+    /// Updates a user from two transactions and checks the second one fails with WriteConflict.
+    WriteConflict {
+        #[clap(long, default_value_t = 1)]
+        iterations: usize,
+    },
+    /// Updates a book with a stale version and checks it fails with a VersionConflict.
+    OptimisticLock {
+        #[clap(long, default_value_t = 1)]
+        iterations: usize,
+    },
+    /// Locks a user from two transactions and checks the second one waits for the first one.
+    PessimisticLock {
+        #[clap(long, default_value_t = 1)]
+        iterations: usize,
+    },
+    /// Runs each isolation anomaly under every read and write concern and prints which ones are allowed.
+    IsolationMatrix,
+    /// Runs concurrent updates of a few hot books with each locking strategy, and reports
+    /// throughput, latency, conflicts, retries and lost updates.
+    Contention {
+        /// Runs only this strategy: optimistic or pessimistic.
+        #[clap(long)]
+        strategy: Option<Strategy>,
+        #[clap(long, default_value_t = 8)]
+        workers: usize,
+        #[clap(long, default_value_t = 4)]
+        books: usize,
+        /// Updates run by each worker.
+        #[clap(long, default_value_t = 100)]
+        ops: usize,
+        /// Attempts of an update before it is counted as failed.
+        #[clap(long, default_value_t = 20)]
+        max_attempts: usize,
+    },
+    /// Reports the reviews the books and the users disagree on. Fails if there is any, unless
+    /// repaired.
+    CheckConsistency {
+        /// Fixes each inconsistency in a transaction.
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Runs the operations of a workload file, as recorded with --record.
+    Replay {
+        #[clap(long)]
+        file: PathBuf,
+        /// Runs at the recorded timing sped up this many times. As fast as possible if not given.
+        #[clap(long)]
+        speed: Option<f64>,
+        /// Sessions replayed at once.
+        #[clap(long, default_value_t = 4)]
+        concurrency: usize,
+    },
+    /// Prints the changes of the books or the users until Ctrl-C, continuing after the last
+    /// change the consumer printed.
+    Watch {
+        #[clap(long, default_value = "books", possible_values = &["books", "users"])]
+        coll: String,
+        /// Name the resume token is saved under. Defaults to watch-<coll>.
+        #[clap(long)]
+        consumer: Option<String>,
+        /// JSON filter on the change events, like '{"operationType": "update"}'.
+        #[clap(long)]
+        filter: Option<String>,
+        /// Prints the current document of the updates too.
+        #[clap(long)]
+        full_document: bool,
+    },
+    /// Keeps the book_stats collection up to date with the changes of the books and the users
+    /// until Ctrl-C, continuing after the last change the consumer handled.
+    ProjectBookStats {
+        /// Prefix of the names the resume tokens are saved under.
+        #[clap(long, default_value = "book-stats")]
+        consumer: String,
+    },
+    /// Recomputes the whole book_stats collection from the books.
+    RebuildBookStats,
+    /// Delivers the events of the outbox, like the reviews added, until Ctrl-C.
+    Relay {
+        /// stdout, file:<path> (a JSON line per event) or http://host:port/path (a POST per event).
+        #[clap(long, default_value = "stdout")]
+        sink: SinkSpec,
+        /// Delivers the events available now and exits.
+        #[clap(long)]
+        once: bool,
+        /// Deliveries of an event tried before giving up on it.
+        #[clap(long, default_value_t = 10)]
+        max_attempts: i32,
+    },
+    /// Makes the indexes match a spec, reporting extra and mismatched ones.
+    SyncIndexes {
+        /// YAML spec of the indexes by collection. Defaults to the indexes the scenarios use.
+        #[clap(long)]
+        spec: Option<PathBuf>,
+        /// Only prints what would change.
+        #[clap(long)]
+        dry_run: bool,
+        /// Drops the indexes not in the spec.
+        #[clap(long)]
+        drop_extra: bool,
+        /// Drops and creates again the indexes whose options differ from the spec.
+        #[clap(long)]
+        recreate: bool,
+    },
+    /// Drops the collections. Without --db-name, the ones of every scenario are dropped.
+    Cleanup,
+    /// Starts a replica set of local mongod and prints its connection string until Enter or Ctrl-C is pressed.
+    Replset {
+        #[clap(long, default_value_t = 3)]
+        nodes: usize,
+    },
+}
+
+impl Cli {
+    fn target(&self, config: &Config, default_db_name: &str) -> Target {
+        let db_name = self
+            .db_name
+            .as_deref()
+            .unwrap_or_else(|| config.db_name_or(default_db_name));
+        Target {
+            db_name: db_name.to_string(),
+            users: self.users_coll.clone(),
+            books: self.books_coll.clone(),
+            index_test: self.index_test_coll.clone(),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    init_logs(&cli)?;
+    let mut config = Config::load()?;
+    let local_replica_set = match cli.local_nodes {
+        Some(nodes) => Some(start_replica_set(nodes).await?),
+        None => None,
+    };
+    if let Some(replica_set) = &local_replica_set {
+        config = Config {
+            db_name: config.db_name,
+            ..replica_set.config()
+        };
+    }
+    let recorder = match &cli.record {
+        Some(path) => Some(Arc::new(Recorder::to_file(path)?)),
+        None => None,
+    };
+    let mut handlers = CommandHandlers(vec![Arc::new(CommandLogger)]);
+    if let Some(recorder) = &recorder {
+        handlers.0.push(recorder.clone());
+    }
+    let client = match &cli.metrics_addr {
+        Some(addr) => {
+            let addr = metrics::start_server(addr).await?;
+            eprintln!("serving metrics at http://{}/metrics", addr);
+            handlers.0.push(Arc::new(MetricsHandler));
+            connect_with_handlers(&config, Arc::new(handlers), Arc::new(MetricsHandler)).await?
+        }
+        None => connect_with_handler(&config, Arc::new(handlers)).await?,
+    };
+
+    match cli.command {
+        Command::Seed => {
+            let target = cli.target(&config, basic::DB_NAME);
+            basic::create_users(&client, &target).await?;
+            basic::create_books(&client, &target).await?;
+            basic::update_books(&client, &target).await?;
+        }
+        Command::LoadFixtures { ref dir } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let storage = MongoStorage::new(&client.database(&target.db_name));
+            let loaded = fixtures::read_dir(dir)?;
+            fixtures::load(&storage, &loaded).await?;
+            for fixture in loaded {
+                println!(
+                    "{}: {} documents",
+                    fixture.collection,
+                    fixture.documents.len()
+                );
+            }
+        }
+        Command::DumpFixtures {
+            ref dir,
+            ref collections,
+            format,
+            mode,
+        } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let storage = MongoStorage::new(&client.database(&target.db_name));
+            let collections: Vec<&str> = if collections.is_empty() {
+                vec![&target.users, &target.books]
+            } else {
+                collections.iter().map(String::as_str).collect()
+            };
+            let dumped = fixtures::dump(&storage, &collections).await?;
+            for path in fixtures::write_dir(dir, &dumped, format, mode)? {
+                println!("wrote {}", path.display());
+            }
+        }
+        Command::Generate {
+            seed,
+            users,
+            books,
+            max_reviews,
+            max_authors,
+            max_supervisors,
+            people,
+            zipf,
+            batch_size,
+        } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let options = GeneratorOptions {
+                seed,
+                users,
+                books,
+                reviews_per_user: 0..=max_reviews,
+                authors_per_book: 1.min(max_authors)..=max_authors,
+                supervisors_per_book: 0..=max_supervisors,
+                people,
+                zipf_exponent: zipf,
+            };
+            let dataset = generator::generate(&options);
+            let storage = MongoStorage::new(&client.database(&target.db_name));
+            generator::insert(&storage, &target, &dataset, batch_size, |p| {
+                println!("{}: {}/{}", p.collection, p.inserted, p.total)
+            })
+            .await?;
+        }
+        Command::Indexes => {
+            let target = cli.target(&config, basic::DB_NAME);
+            basic::indexes(&client, &target).await?;
+        }
+        Command::TxDemo { iterations } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            basic::find_users(&client, &target).await?;
+            for _ in 0..iterations {
+                basic::add_reviews_in_session(&client, &target).await?;
+            }
+        }
+        Command::AbortDemo { iterations } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            for _ in 0..iterations {
+                basic::abort_tx(&client, &target).await?;
+            }
+        }
+        Command::Misc => {
+            let target = cli.target(&config, basic::DB_NAME);
+            basic::misc(&client, &target).await?;
+        }
+        Command::Review {
+            ref user_id,
+            ref book_id,
+            ref text,
+        } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let db = client.database(&target.db_name);
+            let service = ReviewService::new(
+                &client,
+                UserRepository::new(&db, &target.users),
+                BookRepository::new(&db, &target.books),
+            );
+            let review = service.review_book(user_id, book_id, text).await?;
+            println!("reviewed: {:?}", review);
+        }
+        Command::WriteConflict { iterations } => {
+            let target = cli.target(&config, write_conflict::DB_NAME);
+            for _ in 0..iterations {
+                write_conflict::create_users(&client, &target).await?;
+                write_conflict::conflict_updating(&client, &target).await?;
+            }
+        }
+        Command::OptimisticLock { iterations } => {
+            let target = cli.target(&config, optimistic_lock::DB_NAME);
+            // the scenario expects the books at version 1, so they are recreated on each iteration.
+            for _ in 0..iterations {
+                optimistic_lock::create_books(&client, &target).await?;
+                optimistic_lock::conflict_updating(&client, &target).await?;
+            }
+        }
+        Command::PessimisticLock { iterations } => {
+            let target = cli.target(&config, pessimistic_lock::DB_NAME);
+            for _ in 0..iterations {
+                write_conflict::create_users(&client, &target).await?;
+                pessimistic_lock::locked_updating(&client, &target).await?;
+            }
+        }
+        Command::IsolationMatrix => {
+            let target = cli.target(&config, isolation::DB_NAME);
+            print!("{}", isolation::matrix(&client, &target).await?);
+        }
+        Command::Contention {
+            strategy,
+            workers,
+            books,
+            ops,
+            max_attempts,
+        } => {
+            let target = cli.target(&config, contention::DB_NAME);
+            let strategies = match strategy {
+                Some(strategy) => vec![strategy],
+                None => Strategy::ALL.to_vec(),
+            };
+            for strategy in strategies {
+                let mut options = LoadOptions {
+                    strategy,
+                    workers,
+                    books,
+                    ops_per_worker: ops,
+                    ..Default::default()
+                };
+                options.retry.max_attempts = max_attempts;
+                print!("{}", contention::run(&client, &target, &options).await?);
+            }
+        }
+        Command::CheckConsistency { repair } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let db = client.database(&target.db_name);
+            let users = UserRepository::new(&db, &target.users);
+            let books = BookRepository::new(&db, &target.books);
+            let report = if repair {
+                consistency::repair(&client, &users, &books, &WithTransactionOptions::default())
+                    .await?
+            } else {
+                consistency::check(&users, &books).await?
+            };
+            print!("{}", report);
+            if !repair && !report.is_consistent() {
+                anyhow::bail!(
+                    "{} inconsistencies found, --repair fixes them",
+                    report.inconsistencies.len()
+                );
+            }
+        }
+        Command::Replay {
+            ref file,
+            speed,
+            concurrency,
+        } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let entries = workload::read_file(file)?;
+            let options = ReplayOptions {
+                speed,
+                concurrency,
+                ..Default::default()
+            };
+            let storage = MongoStorage::new(&client.database(&target.db_name));
+            print!(
+                "{}",
+                workload::replay(&storage, &client, entries, &options).await?
+            );
+        }
+        Command::Watch {
+            ref coll,
+            ref consumer,
+            ref filter,
+            full_document,
+        } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let db = client.database(&target.db_name);
+            let tokens = ResumeTokens::new(MongoStorage::new(&db));
+            let consumer = consumer
+                .clone()
+                .unwrap_or_else(|| format!("watch-{}", coll));
+            let mut options = WatchOptions {
+                full_document_lookup: full_document,
+                ..Default::default()
+            };
+            if let Some(filter) = filter {
+                options
+                    .pipeline
+                    .push(watch::match_stage(filter).map_err(anyhow::Error::msg)?);
+            }
+            let consumed = async {
+                if coll == "users" {
+                    let users = db.collection(&target.users);
+                    watch::consume(&users, &tokens, &consumer, &options, print_event::<User>).await
+                } else {
+                    let books = db.collection(&target.books);
+                    watch::consume(&books, &tokens, &consumer, &options, print_event::<Book>).await
+                }
+            };
+            tokio::select! {
+                consumed = consumed => consumed?,
+                signal = tokio::signal::ctrl_c() => signal?,
+            }
+        }
+        Command::ProjectBookStats { ref consumer } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let db = client.database(&target.db_name);
+            let projector = BookStatsProjector::new(MongoStorage::new(&db), &target);
+            let tokens = ResumeTokens::new(MongoStorage::new(&db));
+            tokio::select! {
+                projected = book_stats::run(&db, &projector, &tokens, consumer) => projected?,
+                signal = tokio::signal::ctrl_c() => signal?,
+            }
+        }
+        Command::RebuildBookStats => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let db = client.database(&target.db_name);
+            let projector = BookStatsProjector::new(MongoStorage::new(&db), &target);
+            println!("rebuilt the stats of {} books", projector.rebuild().await?);
+        }
+        Command::Relay {
+            ref sink,
+            once,
+            max_attempts,
+        } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let outbox = Outbox::new(MongoStorage::new(&client.database(&target.db_name)));
+            let options = RelayOptions {
+                max_attempts,
+                ..Default::default()
+            };
+            let relay = Relay::new(outbox, sink.open()?, options);
+            if once {
+                eprint!("{}", relay.drain().await?);
+            } else {
+                tokio::select! {
+                    relayed = relay.run() => relayed?,
+                    signal = tokio::signal::ctrl_c() => signal?,
+                }
+            }
+        }
+        Command::SyncIndexes {
+            ref spec,
+            dry_run,
+            drop_extra,
+            recreate,
+        } => {
+            let target = cli.target(&config, basic::DB_NAME);
+            let specs = match spec {
+                Some(path) => IndexSpecs::from_file(path)?,
+                None => IndexSpecs::practice(&target),
+            };
+            let options = SyncOptions {
+                dry_run,
+                drop_extra,
+                recreate_mismatched: recreate,
+            };
+            let reports = indexes::sync(
+                &MongoStorage::new(&client.database(&target.db_name)),
+                &specs,
+                &options,
+            )
+            .await?;
+            for report in reports {
+                print!("{}", report);
+            }
+        }
+        Command::Cleanup => {
+            basic::drop_colls(&client, &cli.target(&config, basic::DB_NAME)).await?;
+            if cli.db_name.is_none() && config.db_name.is_none() {
+                write_conflict::drop_colls(&client, &cli.target(&config, write_conflict::DB_NAME))
+                    .await?;
+                optimistic_lock::drop_colls(
+                    &client,
+                    &cli.target(&config, optimistic_lock::DB_NAME),
+                )
+                .await?;
+                pessimistic_lock::drop_colls(
+                    &client,
+                    &cli.target(&config, pessimistic_lock::DB_NAME),
+                )
+                .await?;
+                isolation::drop_colls(&client, &cli.target(&config, isolation::DB_NAME)).await?;
+                contention::drop_colls(&client, &cli.target(&config, contention::DB_NAME)).await?;
+            }
+        }
+        Command::Replset { nodes } => {
+            let replica_set = start_replica_set(nodes).await?;
+            println!("{}", replica_set.connection_string());
+            println!("press Enter or Ctrl-C to stop");
+            let mut line = String::new();
+            let mut stdin = BufReader::new(tokio::io::stdin());
+            // either way the replica set is dropped, which stops the nodes.
+            tokio::select! {
+                read = stdin.read_line(&mut line) => { read?; }
+                signal = tokio::signal::ctrl_c() => signal?,
+            }
+        }
+    }
+
+    if let (Some(recorder), Some(path)) = (&recorder, &cli.record) {
+        let summary = recorder.summary();
+        eprintln!(
+            "recorded {} operations into {}",
+            summary.recorded,
+            path.display()
+        );
+        for (command, count) in &summary.skipped {
+            eprintln!("  skipped {} {} commands", count, command);
+        }
+        if let Some(e) = summary.write_error {
+            anyhow::bail!("failed to write {}: {}", path.display(), e);
+        }
+    }
+    if cli.metrics_addr.is_some() {
+        eprintln!("press Ctrl-C to stop serving the metrics");
+        tokio::signal::ctrl_c().await?;
+    }
+    Ok(())
+}
+
+async fn print_event<T: std::fmt::Debug>(event: ChangeEvent<T>) -> DbResult<()> {
+    let key = event.document_key.map(|key| key.to_string());
+    println!(
+        "{:?} {} {:?}",
+        event.operation_type,
+        key.as_deref().unwrap_or("-"),
+        event.full_document
+    );
+    Ok(())
+}
+
+fn init_logs(cli: &Cli) -> Result<()> {
+    let out: Box<dyn Write + Send> = match (&cli.log_file, cli.log_format) {
+        (Some(path), _) => Box::new(File::create(path)?),
+        (None, LogFormat::Otel) => Box::new(File::create("telemetry.jsonl")?),
+        (None, _) => Box::new(std::io::stderr()),
+    };
+    let collector =
+        Collector::new(cli.log_format, cli.log_level, out).service_name("mongo-practice");
+    telemetry::init(collector)?;
+    Ok(())
+}
+
+async fn start_replica_set(nodes: usize) -> Result<LocalReplicaSet> {
+    let options = ReplicaSetOptions {
+        nodes,
+        ..Default::default()
+    };
+    Ok(LocalReplicaSet::start(&options).await?)
+}
@@ -0,0 +1,376 @@
+//! The `book_stats` collection, a small document per book kept up to date from the change streams.
+//!
+//! Counting the reviews of a book means loading the book with its unbounded `reviews`, so
+//! [`BookStatsProjector`] keeps `{_id: <_id of the book>, book_id, review_count, reviewers,
+//! last_review_at}` instead, and [`run`] feeds it the changes of the books and the users.
+//!
+//! A change is not applied to the stats: the book is read again and its stats are written as
+//! computed from it. Handling an event twice, or an old event after a newer one, writes the same
+//! stats, so the replays of the at-least-once [`consume`] are harmless. The changes of a user
+//! refresh the books the user lists in `reviewed_book_ids`, since a review writes both documents
+//! and the two streams are read independently.
+//!
+//! ```ignore
+//! let projector = BookStatsProjector::new(MongoStorage::new(&db), &target);
+//! projector.rebuild().await?;
+//! book_stats::run(&db, &projector, &ResumeTokens::new(MongoStorage::new(&db)), "book-stats").await?;
+//! ```
+
+use std::collections::BTreeSet;
+
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::DbResult,
+    repository::{from_doc, to_doc},
+    scenario::Target,
+    storage::Storage,
+    watch::{consume, ChangeEvent, OperationType, ResumeTokens, WatchOptions},
+    Book, User,
+};
+
+pub const BOOK_STATS_COLL: &str = "book_stats";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookStats {
+    pub book_id: String,
+    pub review_count: i64,
+    /// Distinct users among the reviews.
+    pub reviewers: i64,
+    /// `None` if no review has a time.
+    pub last_review_at: Option<DateTime>,
+}
+
+impl BookStats {
+    pub fn of(book: &Book) -> Self {
+        let reviewers: BTreeSet<&str> = book.reviews.iter().map(|r| r.user_id.as_str()).collect();
+        BookStats {
+            book_id: book.id.clone(),
+            review_count: book.reviews.len() as i64,
+            reviewers: reviewers.len() as i64,
+            last_review_at: book.reviews.iter().filter_map(|r| r.reviewed_at).max(),
+        }
+    }
+}
+
+/// Writes the stats of the books of a [`Target`] into [`BOOK_STATS_COLL`].
+#[derive(Debug, Clone)]
+pub struct BookStatsProjector<S> {
+    storage: S,
+    books: String,
+    users: String,
+}
+
+impl<S: Storage> BookStatsProjector<S> {
+    pub fn new(storage: S, target: &Target) -> Self {
+        BookStatsProjector {
+            storage,
+            books: target.books.clone(),
+            users: target.users.clone(),
+        }
+    }
+
+    pub async fn stats(&self, book_id: &str) -> DbResult<Option<BookStats>> {
+        let found = self
+            .storage
+            .find_one(BOOK_STATS_COLL, doc! {"book_id": book_id}, None)
+            .await?;
+        found.map(from_doc).transpose()
+    }
+
+    /// Recomputes the stats of every book, dropping the stats of the books that are gone.
+    /// Returns the number of books.
+    pub async fn rebuild(&self) -> DbResult<usize> {
+        self.storage.drop_collection(BOOK_STATS_COLL).await?;
+        let books = self.storage.find(&self.books, doc! {}, None).await?;
+        let count = books.len();
+        for book in books {
+            self.project(book).await?;
+        }
+        Ok(count)
+    }
+
+    pub async fn apply_book_change(&self, event: &ChangeEvent<Book>) -> DbResult<()> {
+        match event.operation_type {
+            OperationType::Insert
+            | OperationType::Update
+            | OperationType::Replace
+            | OperationType::Delete => match event.document_key.as_ref().and_then(|k| k.get("_id"))
+            {
+                Some(key) => self.refresh(key).await,
+                None => Ok(()),
+            },
+            OperationType::Drop | OperationType::Rename | OperationType::DropDatabase => {
+                self.rebuild().await.map(|_| ())
+            }
+            OperationType::Invalidate | OperationType::Other => Ok(()),
+        }
+    }
+
+    /// Refreshes the books the user lists. Needs the user in the event, so the updates have to
+    /// be read with [`WatchOptions::full_document_lookup`].
+    pub async fn apply_user_change(&self, event: &ChangeEvent<User>) -> DbResult<()> {
+        let user = match &event.full_document {
+            Some(user) if !user.reviewed_book_ids.is_empty() => user,
+            _ => return Ok(()),
+        };
+        let filter = doc! {"id": {"$in": user.reviewed_book_ids.clone()}};
+        for book in self.storage.find(&self.books, filter, None).await? {
+            self.project(book).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the stats of the book whose `_id` is `key` as it is now, or deletes them if the
+    /// book is gone.
+    async fn refresh(&self, key: &Bson) -> DbResult<()> {
+        let filter = doc! {"_id": key.clone()};
+        match self
+            .storage
+            .find_one(&self.books, filter.clone(), None)
+            .await?
+        {
+            Some(book) => self.project(book).await,
+            None => {
+                self.storage
+                    .delete_one(BOOK_STATS_COLL, filter, None)
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn project(&self, book: Document) -> DbResult<()> {
+        let key = book.get("_id").cloned().unwrap_or(Bson::Null);
+        let mut stats = to_doc(&BookStats::of(&from_doc(book)?))?;
+        let filter = doc! {"_id": key.clone()};
+        let replaced = self
+            .storage
+            .find_one_and_replace(BOOK_STATS_COLL, filter, stats.clone(), None)
+            .await?;
+        if replaced.is_none() {
+            stats.insert("_id", key);
+            self.storage
+                .insert_one(BOOK_STATS_COLL, stats, None)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the stats up to date with the changes of the books and the users, as the consumers
+/// `<consumer>-books` and `<consumer>-users`, until a stream is invalidated or fails.
+pub async fn run<S: Storage>(
+    db: &Database,
+    projector: &BookStatsProjector<S>,
+    tokens: &ResumeTokens<S>,
+    consumer: &str,
+) -> DbResult<()> {
+    let books = db.collection::<Document>(&projector.books);
+    let users = db.collection::<Document>(&projector.users);
+    // the books are read again anyway.
+    let book_options = WatchOptions {
+        pipeline: vec![doc! {"$project": {"fullDocument": 0}}],
+        ..Default::default()
+    };
+    let user_options = WatchOptions {
+        pipeline: vec![
+            doc! {"$match": {"operationType": {"$in": ["insert", "update", "replace"]}}},
+        ],
+        full_document_lookup: true,
+        ..Default::default()
+    };
+    let book_consumer = format!("{}-books", consumer);
+    let user_consumer = format!("{}-users", consumer);
+    futures::try_join!(
+        consume(
+            &books,
+            tokens,
+            &book_consumer,
+            &book_options,
+            |event: ChangeEvent<Book>| async move { projector.apply_book_change(&event).await }
+        ),
+        consume(
+            &users,
+            tokens,
+            &user_consumer,
+            &user_options,
+            |event: ChangeEvent<User>| async move { projector.apply_user_change(&event).await }
+        ),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::MemoryStorage, Review};
+
+    const BOOKS: &str = "books";
+
+    fn review(user_id: &str, at_ms: Option<i64>) -> Review {
+        Review {
+            user_id: user_id.to_string(),
+            text: "good".to_string(),
+            reviewed_at: at_ms.map(DateTime::from_millis),
+        }
+    }
+
+    fn book(id: &str, reviews: Vec<Review>) -> Book {
+        Book {
+            id: id.to_string(),
+            name: id.to_string(),
+            reviews,
+            authors: vec![],
+            supervisors: vec![],
+            version: 0,
+        }
+    }
+
+    fn event<T: serde::de::DeserializeOwned>(
+        operation_type: &str,
+        key: i32,
+        full: Option<Document>,
+    ) -> ChangeEvent<T> {
+        let mut event = doc! {
+            "_id": {"_data": "01"},
+            "operationType": operation_type,
+            "documentKey": {"_id": key},
+        };
+        if let Some(full) = full {
+            event.insert("fullDocument", full);
+        }
+        from_doc(event).unwrap()
+    }
+
+    async fn insert_book(storage: &MemoryStorage, key: i32, book: &Book) {
+        let mut doc = doc! {"_id": key};
+        doc.extend(to_doc(book).unwrap());
+        storage.insert_one(BOOKS, doc, None).await.unwrap();
+    }
+
+    fn projector(storage: &MemoryStorage) -> BookStatsProjector<MemoryStorage> {
+        BookStatsProjector::new(storage.clone(), &Target::new("test_db"))
+    }
+
+    #[test]
+    fn counts_reviews() {
+        let stats = BookStats::of(&book(
+            "book_1",
+            vec![
+                review("user_1", Some(10)),
+                review("user_2", None),
+                review("user_1", Some(30)),
+            ],
+        ));
+        assert_eq!(
+            stats,
+            BookStats {
+                book_id: "book_1".to_string(),
+                review_count: 3,
+                reviewers: 2,
+                last_review_at: Some(DateTime::from_millis(30)),
+            }
+        );
+        assert_eq!(BookStats::of(&book("book_2", vec![])).last_review_at, None);
+    }
+
+    #[tokio::test]
+    async fn replayed_events_write_the_current_stats() {
+        let storage = MemoryStorage::new();
+        let projector = projector(&storage);
+        insert_book(
+            &storage,
+            1,
+            &book("book_1", vec![review("user_1", Some(10))]),
+        )
+        .await;
+        let inserted = event::<Book>("insert", 1, None);
+        projector.apply_book_change(&inserted).await.unwrap();
+        projector.apply_book_change(&inserted).await.unwrap();
+        assert_eq!(
+            storage
+                .find(BOOK_STATS_COLL, doc! {}, None)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        storage
+            .update_one(
+                BOOKS,
+                doc! {"_id": 1},
+                doc! {"$push": {"reviews": to_doc(&review("user_2", Some(20))).unwrap()}},
+                None,
+            )
+            .await
+            .unwrap();
+        // the insert replayed after the update doesn't bring the old count back.
+        projector.apply_book_change(&inserted).await.unwrap();
+        let stats = projector.stats("book_1").await.unwrap().unwrap();
+        assert_eq!((stats.review_count, stats.reviewers), (2, 2));
+
+        storage
+            .delete_one(BOOKS, doc! {"_id": 1}, None)
+            .await
+            .unwrap();
+        projector
+            .apply_book_change(&event::<Book>("delete", 1, None))
+            .await
+            .unwrap();
+        projector
+            .apply_book_change(&event::<Book>("delete", 1, None))
+            .await
+            .unwrap();
+        assert_eq!(projector.stats("book_1").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn user_changes_refresh_their_books() {
+        let storage = MemoryStorage::new();
+        let projector = projector(&storage);
+        insert_book(
+            &storage,
+            1,
+            &book("book_1", vec![review("user_1", Some(10))]),
+        )
+        .await;
+        insert_book(&storage, 2, &book("book_2", vec![])).await;
+
+        let user = doc! {"id": "user_1", "name": "john", "reviewed_book_ids": ["book_1"]};
+        projector
+            .apply_user_change(&event::<User>("update", 7, Some(user)))
+            .await
+            .unwrap();
+
+        let stats = projector.stats("book_1").await.unwrap().unwrap();
+        assert_eq!(stats.review_count, 1);
+        assert_eq!(projector.stats("book_2").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_every_book() {
+        let storage = MemoryStorage::new();
+        let projector = projector(&storage);
+        insert_book(&storage, 1, &book("book_1", vec![review("user_1", None)])).await;
+        insert_book(&storage, 2, &book("book_2", vec![])).await;
+        storage
+            .insert_one(BOOK_STATS_COLL, doc! {"_id": 3, "book_id": "book_3"}, None)
+            .await
+            .unwrap();
+
+        assert_eq!(projector.rebuild().await.unwrap(), 2);
+        assert_eq!(projector.rebuild().await.unwrap(), 2);
+
+        let all = storage.find(BOOK_STATS_COLL, doc! {}, None).await.unwrap();
+        let ids: Vec<_> = all.iter().map(|s| s.get_str("book_id").unwrap()).collect();
+        assert_eq!(ids, vec!["book_1", "book_2"]);
+        let stats = projector.stats("book_1").await.unwrap().unwrap();
+        assert_eq!((stats.review_count, stats.reviewers), (1, 1));
+    }
+}
@@ -0,0 +1,879 @@
+//! Connection settings of the practice binaries.
+//!
+//! Settings are read from the following sources, a later one overriding an earlier one:
+//!
+//! 1. defaults, which point to the replica set of `docker-compose.yml`
+//! 2. a TOML file whose path is given by `MONGO_CONFIG`
+//! 3. a connection string given by `MONGODB_URI`
+//! 4. the individual `MONGO_*` environment variables below
+//!
+//! The default replica set name only applies to the default hosts. Hosts given by any source
+//! without a replica set connect without one, e.g. to a standalone server.
+//!
+//! A connection string may use `mongodb://` or `mongodb+srv://`. The hosts, the database, the
+//! credentials and the `replicaSet`, `connectTimeoutMS`, `serverSelectionTimeoutMS` and
+//! `authSource` options are read into the settings so that the variables can override them.
+//! The other options, e.g. `w`, `retryWrites` or `tls`, are passed to the driver as they are.
+//!
+//! | variable                            | example                         |
+//! |-------------------------------------|---------------------------------|
+//! | `MONGO_HOSTS`                       | `localhost:30001,localhost:30002` |
+//! | `MONGO_REPLICA_SET`                 | `my-replica-set`                |
+//! | `MONGO_DB_NAME`                     | `test_db`                       |
+//! | `MONGO_CONNECT_TIMEOUT_MS`          | `3000`                          |
+//! | `MONGO_SERVER_SELECTION_TIMEOUT_MS` | `5000`                          |
+//! | `MONGO_USERNAME`                    | `root`                          |
+//! | `MONGO_PASSWORD`                    | `secret`                        |
+//! | `MONGO_AUTH_SOURCE`                 | `admin`                         |
+//!
+//! The TOML file uses the same names in snake case.
+//!
+//! ```toml
+//! hosts = ["mongo1:30001", "mongo2:30002", "mongo3:30003"]
+//! replica_set = "my-replica-set"
+//! db_name = "test_db"
+//! connect_timeout_ms = 3000
+//! server_selection_timeout_ms = 5000
+//!
+//! [credential]
+//! username = "root"
+//! password = "secret"
+//! auth_source = "admin"
+//! ```
+
+use std::{collections::HashMap, fmt, path::Path, time::Duration};
+
+use mongodb::options::ClientOptions;
+use serde::Deserialize;
+use thiserror::Error;
+
+pub const DEFAULT_PORT: u16 = 27017;
+
+pub const ENV_CONFIG_FILE: &str = "MONGO_CONFIG";
+pub const ENV_URI: &str = "MONGODB_URI";
+pub const ENV_HOSTS: &str = "MONGO_HOSTS";
+pub const ENV_REPLICA_SET: &str = "MONGO_REPLICA_SET";
+pub const ENV_DB_NAME: &str = "MONGO_DB_NAME";
+pub const ENV_CONNECT_TIMEOUT_MS: &str = "MONGO_CONNECT_TIMEOUT_MS";
+pub const ENV_SERVER_SELECTION_TIMEOUT_MS: &str = "MONGO_SERVER_SELECTION_TIMEOUT_MS";
+pub const ENV_USERNAME: &str = "MONGO_USERNAME";
+pub const ENV_PASSWORD: &str = "MONGO_PASSWORD";
+pub const ENV_AUTH_SOURCE: &str = "MONGO_AUTH_SOURCE";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read the config file {path}: {source}")]
+    ReadFile {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to parse the config file {path}: {source}")]
+    ParseFile {
+        path: String,
+        source: toml::de::Error,
+    },
+    /// `uri` is the connection string without its password.
+    #[error("invalid connection string {uri:?}: {reason}")]
+    InvalidUri { uri: String, reason: String },
+    #[error("invalid host {host:?}: {reason}")]
+    InvalidHost { host: String, reason: String },
+    #[error("{0} takes a single host name without a port")]
+    InvalidSrvHost(String),
+    #[error("invalid value of {name}: {value:?} is not a number of milliseconds")]
+    InvalidTimeout { name: String, value: String },
+    #[error("{0} must not be 0")]
+    ZeroTimeout(String),
+    #[error("no hosts are specified")]
+    NoHosts,
+    #[error("{0} must not be empty")]
+    Empty(&'static str),
+    #[error("a password is given without a username")]
+    PasswordWithoutUsername,
+}
+
+pub type ConfigResult<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Host {
+    pub fn parse(s: &str) -> ConfigResult<Self> {
+        let invalid = |reason: &str| ConfigError::InvalidHost {
+            host: s.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (host, port) = match split_host_port(s).map_err(invalid)? {
+            (host, Some(port)) => {
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| invalid("port must be a number between 1 and 65535"))?;
+                if port == 0 {
+                    return Err(invalid("port must be a number between 1 and 65535"));
+                }
+                (host, port)
+            }
+            (host, None) => (host, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(invalid("host name is empty"));
+        }
+        if host.contains(|c: char| c.is_whitespace() || c == '/' || c == '@') {
+            return Err(invalid("host name contains an invalid character"));
+        }
+
+        Ok(Host {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Splits `host[:port]` or `[ipv6][:port]`.
+fn split_host_port(s: &str) -> Result<(&str, Option<&str>), &'static str> {
+    if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or("\"[\" is not closed")?;
+        return match rest {
+            "" => Ok((host, None)),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => Ok((host, Some(port))),
+                None => Err("\"]\" must be followed by \":port\""),
+            },
+        };
+    }
+    match s.rsplit_once(':') {
+        Some((host, _)) if host.contains(':') => {
+            Err("IPv6 addresses must be enclosed in brackets, e.g. [::1]:27017")
+        }
+        Some((host, port)) => Ok((host, Some(port))),
+        None => Ok((s, None)),
+    }
+}
+
+impl Host {
+    /// The host name, in brackets if it is an IPv6 address.
+    fn name(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        }
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name(), self.port)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Option<String>,
+    pub auth_source: Option<String>,
+}
+
+/// Validated connection settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub hosts: Vec<Host>,
+    pub replica_set: Option<String>,
+    /// `None` lets each scenario use its own default database.
+    pub db_name: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    pub server_selection_timeout: Option<Duration>,
+    pub credentials: Option<Credentials>,
+    /// The host is looked up with DNS SRV records (`mongodb+srv://`).
+    pub srv: bool,
+    /// Options of the connection string that are passed to the driver as they are.
+    pub options: Vec<(String, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            hosts: vec![
+                Host {
+                    host: "mongo1".to_string(),
+                    port: 30001,
+                },
+                Host {
+                    host: "mongo2".to_string(),
+                    port: 30002,
+                },
+                Host {
+                    host: "mongo3".to_string(),
+                    port: 30003,
+                },
+            ],
+            replica_set: Some("my-replica-set".to_string()),
+            db_name: None,
+            connect_timeout: None,
+            server_selection_timeout: None,
+            credentials: None,
+            srv: false,
+            options: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Loads the settings from the TOML file, the connection string and the environment variables.
+    pub fn load() -> ConfigResult<Self> {
+        let vars: HashMap<String, String> = std::env::vars().collect();
+        Self::from_vars(&vars)
+    }
+
+    /// Same as [`Config::load`] but reads the variables from `vars` instead of the environment.
+    pub fn from_vars(vars: &HashMap<String, String>) -> ConfigResult<Self> {
+        let mut raw = RawConfig::default();
+        if let Some(path) = non_empty(vars, ENV_CONFIG_FILE) {
+            raw = raw.overridden_by(RawConfig::from_file(path)?);
+        }
+        if let Some(uri) = non_empty(vars, ENV_URI) {
+            raw = raw.overridden_by(RawConfig::from_uri(uri)?);
+        }
+        raw = raw.overridden_by(RawConfig::from_vars(vars)?);
+        raw.validate()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> ConfigResult<Self> {
+        RawConfig::from_file(path)?.validate()
+    }
+
+    pub fn from_uri(uri: &str) -> ConfigResult<Self> {
+        RawConfig::from_uri(uri)?.validate()
+    }
+
+    pub fn db_name_or<'a>(&'a self, default: &'a str) -> &'a str {
+        self.db_name.as_deref().unwrap_or(default)
+    }
+
+    /// Options of the driver, parsed from [`Config::connection_string`] so that the options
+    /// passed through are applied too.
+    pub async fn client_options(&self) -> mongodb::error::Result<ClientOptions> {
+        ClientOptions::parse(self.connection_string()).await
+    }
+
+    /// The settings as a connection string.
+    pub fn connection_string(&self) -> String {
+        let mut uri = if self.srv {
+            "mongodb+srv://"
+        } else {
+            "mongodb://"
+        }
+        .to_string();
+        if let Some(c) = &self.credentials {
+            uri.push_str(&percent_encode(&c.username));
+            if let Some(password) = &c.password {
+                uri.push(':');
+                uri.push_str(&percent_encode(password));
+            }
+            uri.push('@');
+        }
+        let hosts = self
+            .hosts
+            .iter()
+            .map(|h| if self.srv { h.name() } else { h.to_string() })
+            .collect::<Vec<_>>();
+        uri.push_str(&hosts.join(","));
+        uri.push('/');
+        if let Some(db_name) = &self.db_name {
+            uri.push_str(db_name);
+        }
+
+        let mut params = vec![];
+        if let Some(replica_set) = &self.replica_set {
+            params.push(format!("replicaSet={}", percent_encode(replica_set)));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            params.push(format!("connectTimeoutMS={}", timeout.as_millis()));
+        }
+        if let Some(timeout) = self.server_selection_timeout {
+            params.push(format!("serverSelectionTimeoutMS={}", timeout.as_millis()));
+        }
+        if let Some(auth_source) = self
+            .credentials
+            .as_ref()
+            .and_then(|c| c.auth_source.as_ref())
+        {
+            params.push(format!("authSource={}", percent_encode(auth_source)));
+        }
+        for (key, value) in &self.options {
+            params.push(format!("{}={}", key, percent_encode(value)));
+        }
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct RawCredentials {
+    username: Option<String>,
+    password: Option<String>,
+    auth_source: Option<String>,
+}
+
+/// Settings of a single source, before validation.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    hosts: Option<Vec<String>>,
+    replica_set: Option<String>,
+    db_name: Option<String>,
+    connect_timeout_ms: Option<u64>,
+    server_selection_timeout_ms: Option<u64>,
+    #[serde(default)]
+    credential: RawCredentials,
+    /// Only a connection string sets these two.
+    #[serde(skip)]
+    srv: bool,
+    #[serde(skip)]
+    options: Vec<(String, String)>,
+}
+
+impl RawConfig {
+    fn from_file<P: AsRef<Path>>(path: P) -> ConfigResult<Self> {
+        let path_str = path.as_ref().display().to_string();
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::ReadFile {
+            path: path_str.clone(),
+            source,
+        })?;
+        toml::from_str(&content).map_err(|source| ConfigError::ParseFile {
+            path: path_str,
+            source,
+        })
+    }
+
+    /// Parses `mongodb[+srv]://[username[:password]@]host1[:port1][,...]/[db_name][?options]`.
+    fn from_uri(uri: &str) -> ConfigResult<Self> {
+        let invalid = |reason: &str| ConfigError::InvalidUri {
+            uri: redact_password(uri),
+            reason: reason.to_string(),
+        };
+
+        let (srv, rest) = match uri.strip_prefix("mongodb+srv://") {
+            Some(rest) => (true, rest),
+            None => (
+                false,
+                uri.strip_prefix("mongodb://").ok_or_else(|| {
+                    invalid("must start with \"mongodb://\" or \"mongodb+srv://\"")
+                })?,
+            ),
+        };
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+        let (authority, db_name) = match rest.split_once('/') {
+            Some((authority, db_name)) => (authority, db_name),
+            None => (rest, ""),
+        };
+        let (userinfo, hosts) = match authority.rsplit_once('@') {
+            Some((userinfo, hosts)) => (Some(userinfo), hosts),
+            None => (None, authority),
+        };
+
+        if srv && (hosts.contains(',') || hosts.contains(':')) {
+            return Err(ConfigError::InvalidSrvHost("mongodb+srv://".to_string()));
+        }
+
+        let mut raw = RawConfig {
+            hosts: Some(hosts.split(',').map(|h| h.to_string()).collect()),
+            srv,
+            ..Default::default()
+        };
+        if !db_name.is_empty() {
+            raw.db_name = Some(
+                percent_decode(db_name).ok_or_else(|| invalid("bad escape in database name"))?,
+            );
+        }
+        if let Some(userinfo) = userinfo {
+            let (username, password) = match userinfo.split_once(':') {
+                Some((username, password)) => (username, Some(password)),
+                None => (userinfo, None),
+            };
+            raw.credential.username =
+                Some(percent_decode(username).ok_or_else(|| invalid("bad escape in username"))?);
+            raw.credential.password = password
+                .map(|p| percent_decode(p).ok_or_else(|| invalid("bad escape in password")))
+                .transpose()?;
+        }
+
+        for pair in query
+            .into_iter()
+            .flat_map(|q| q.split('&'))
+            .filter(|p| !p.is_empty())
+        {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| invalid(&format!("option {:?} has no value", pair)))?;
+            let value = percent_decode(value)
+                .ok_or_else(|| invalid(&format!("bad escape in option {}", key)))?;
+            // option names are case insensitive like in the driver.
+            match key.to_ascii_lowercase().as_str() {
+                "replicaset" => raw.replica_set = Some(value),
+                "connecttimeoutms" => raw.connect_timeout_ms = Some(parse_ms(key, &value)?),
+                "serverselectiontimeoutms" => {
+                    raw.server_selection_timeout_ms = Some(parse_ms(key, &value)?)
+                }
+                "authsource" => raw.credential.auth_source = Some(value),
+                _ => raw.options.push((key.to_string(), value)),
+            }
+        }
+
+        Ok(raw)
+    }
+
+    fn from_vars(vars: &HashMap<String, String>) -> ConfigResult<Self> {
+        let timeout = |name: &str| non_empty(vars, name).map(|v| parse_ms(name, v)).transpose();
+        Ok(RawConfig {
+            hosts: non_empty(vars, ENV_HOSTS)
+                .map(|v| v.split(',').map(|h| h.trim().to_string()).collect()),
+            replica_set: non_empty(vars, ENV_REPLICA_SET).map(|v| v.to_string()),
+            db_name: non_empty(vars, ENV_DB_NAME).map(|v| v.to_string()),
+            connect_timeout_ms: timeout(ENV_CONNECT_TIMEOUT_MS)?,
+            server_selection_timeout_ms: timeout(ENV_SERVER_SELECTION_TIMEOUT_MS)?,
+            credential: RawCredentials {
+                username: non_empty(vars, ENV_USERNAME).map(|v| v.to_string()),
+                password: non_empty(vars, ENV_PASSWORD).map(|v| v.to_string()),
+                auth_source: non_empty(vars, ENV_AUTH_SOURCE).map(|v| v.to_string()),
+            },
+            ..Default::default()
+        })
+    }
+
+    fn overridden_by(self, other: RawConfig) -> RawConfig {
+        // SRV only applies to the host of the connection string.
+        let srv = if other.hosts.is_some() {
+            other.srv
+        } else {
+            self.srv
+        };
+        let mut options = self.options;
+        options.extend(other.options);
+        RawConfig {
+            srv,
+            options,
+            hosts: other.hosts.or(self.hosts),
+            replica_set: other.replica_set.or(self.replica_set),
+            db_name: other.db_name.or(self.db_name),
+            connect_timeout_ms: other.connect_timeout_ms.or(self.connect_timeout_ms),
+            server_selection_timeout_ms: other
+                .server_selection_timeout_ms
+                .or(self.server_selection_timeout_ms),
+            credential: RawCredentials {
+                username: other.credential.username.or(self.credential.username),
+                password: other.credential.password.or(self.credential.password),
+                auth_source: other.credential.auth_source.or(self.credential.auth_source),
+            },
+        }
+    }
+
+    fn validate(self) -> ConfigResult<Config> {
+        let default = Config::default();
+
+        let (hosts, replica_set) = match self.hosts {
+            Some(hosts) => {
+                let hosts = hosts
+                    .iter()
+                    .map(|h| h.trim())
+                    .filter(|h| !h.is_empty())
+                    .map(Host::parse)
+                    .collect::<ConfigResult<Vec<_>>>()?;
+                if hosts.is_empty() {
+                    return Err(ConfigError::NoHosts);
+                }
+                (hosts, self.replica_set)
+            }
+            // the default replica set only makes sense with the default hosts.
+            None => (default.hosts, self.replica_set.or(default.replica_set)),
+        };
+        if replica_set.as_deref() == Some("") {
+            return Err(ConfigError::Empty("replica_set"));
+        }
+
+        if let Some(db_name) = &self.db_name {
+            if db_name.is_empty() {
+                return Err(ConfigError::Empty("db_name"));
+            }
+        }
+
+        let timeout = |name: &str, ms: Option<u64>| match ms {
+            Some(0) => Err(ConfigError::ZeroTimeout(name.to_string())),
+            Some(ms) => Ok(Some(Duration::from_millis(ms))),
+            None => Ok(None),
+        };
+
+        let credentials = match self.credential {
+            RawCredentials {
+                username: Some(username),
+                password,
+                auth_source,
+            } => {
+                if username.is_empty() {
+                    return Err(ConfigError::Empty("username"));
+                }
+                Some(Credentials {
+                    username,
+                    password,
+                    auth_source,
+                })
+            }
+            RawCredentials {
+                username: None,
+                password: Some(_),
+                ..
+            } => return Err(ConfigError::PasswordWithoutUsername),
+            _ => None,
+        };
+
+        Ok(Config {
+            hosts,
+            replica_set,
+            db_name: self.db_name,
+            connect_timeout: timeout("connect_timeout_ms", self.connect_timeout_ms)?,
+            server_selection_timeout: timeout(
+                "server_selection_timeout_ms",
+                self.server_selection_timeout_ms,
+            )?,
+            credentials,
+            srv: self.srv,
+            options: self.options,
+        })
+    }
+}
+
+fn non_empty<'a>(vars: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    vars.get(name).map(|v| v.as_str()).filter(|v| !v.is_empty())
+}
+
+fn parse_ms(name: &str, value: &str) -> ConfigResult<u64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| ConfigError::InvalidTimeout {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+}
+
+/// Replaces the password of a connection string so that it can be shown in errors.
+fn redact_password(uri: &str) -> String {
+    let start = uri.find("://").map_or(0, |i| i + 3);
+    let end = uri[start..]
+        .find(['/', '?'])
+        .map_or(uri.len(), |i| start + i);
+    match uri[start..end].rfind('@') {
+        Some(at) => match uri[start..start + at].find(':') {
+            Some(colon) => format!("{}***{}", &uri[..start + colon + 1], &uri[start + at..]),
+            None => uri.to_string(),
+        },
+        None => uri.to_string(),
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn config_file(content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn host(host: &str, port: u16) -> Host {
+        Host {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn defaults_to_docker_replica_set() {
+        let config = Config::from_vars(&vars(&[])).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let file = config_file(
+            r#"
+            hosts = ["file:1"]
+            db_name = "file_db"
+            connect_timeout_ms = 1
+            server_selection_timeout_ms = 1
+            "#,
+        );
+        let path = file.path().to_str().unwrap();
+        let config = Config::from_vars(&vars(&[
+            (ENV_CONFIG_FILE, path),
+            (ENV_URI, "mongodb://uri:2/uri_db?connectTimeoutMS=2"),
+            (ENV_DB_NAME, "env_db"),
+        ]))
+        .unwrap();
+
+        assert_eq!(config.hosts, vec![host("uri", 2)]);
+        assert_eq!(config.db_name.as_deref(), Some("env_db"));
+        assert_eq!(config.connect_timeout, Some(Duration::from_millis(2)));
+        assert_eq!(
+            config.server_selection_timeout,
+            Some(Duration::from_millis(1))
+        );
+    }
+
+    #[test]
+    fn custom_hosts_have_no_default_replica_set() {
+        let config = Config::from_vars(&vars(&[(ENV_HOSTS, "localhost:27017")])).unwrap();
+        assert_eq!(config.replica_set, None);
+        let config = Config::from_uri("mongodb://localhost").unwrap();
+        assert_eq!(config.replica_set, None);
+
+        let config = Config::from_vars(&vars(&[(ENV_REPLICA_SET, "rs0")])).unwrap();
+        assert_eq!(config.hosts, Config::default().hosts);
+        assert_eq!(config.replica_set.as_deref(), Some("rs0"));
+    }
+
+    #[test]
+    fn parses_uri() {
+        let config = Config::from_uri(
+            "mongodb://us%40er:p%3Ass@a:1,b/db?replicaSet=rs&authSource=admin&serverSelectionTimeoutMS=5",
+        )
+        .unwrap();
+        assert_eq!(config.hosts, vec![host("a", 1), host("b", DEFAULT_PORT)]);
+        assert_eq!(config.replica_set.as_deref(), Some("rs"));
+        assert_eq!(config.db_name.as_deref(), Some("db"));
+        assert_eq!(
+            config.server_selection_timeout,
+            Some(Duration::from_millis(5))
+        );
+        assert_eq!(
+            config.credentials,
+            Some(Credentials {
+                username: "us@er".to_string(),
+                password: Some("p:ss".to_string()),
+                auth_source: Some("admin".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn passes_through_other_uri_options() {
+        let config =
+            Config::from_uri("mongodb://a/?w=majority&retryWrites=false&directConnection=true")
+                .unwrap();
+        assert_eq!(
+            config.options,
+            vec![
+                ("w".to_string(), "majority".to_string()),
+                ("retryWrites".to_string(), "false".to_string()),
+                ("directConnection".to_string(), "true".to_string()),
+            ]
+        );
+        assert_eq!(
+            config.connection_string(),
+            "mongodb://a:27017/?w=majority&retryWrites=false&directConnection=true"
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_passed_through_options_to_driver() {
+        let config = Config::from_uri("mongodb://a/?w=majority&retryWrites=false").unwrap();
+        let options = config.client_options().await.unwrap();
+        assert_eq!(options.retry_writes, Some(false));
+        assert!(options.write_concern.and_then(|w| w.w).is_some());
+    }
+
+    #[test]
+    fn parses_srv_uri() {
+        let config = Config::from_uri("mongodb+srv://cluster.example.com/?tls=true").unwrap();
+        assert!(config.srv);
+        assert_eq!(
+            config.connection_string(),
+            "mongodb+srv://cluster.example.com/?tls=true"
+        );
+
+        for uri in ["mongodb+srv://a:1", "mongodb+srv://a,b"] {
+            assert!(matches!(
+                Config::from_uri(uri),
+                Err(ConfigError::InvalidSrvHost(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn hosts_of_env_replace_srv_host() {
+        let config = Config::from_vars(&vars(&[
+            (ENV_URI, "mongodb+srv://cluster.example.com"),
+            (ENV_HOSTS, "localhost"),
+        ]))
+        .unwrap();
+        assert!(!config.srv);
+    }
+
+    #[test]
+    fn rejects_invalid_uris() {
+        for uri in [
+            "localhost:27017",
+            "http://localhost",
+            "mongodb://a/?replicaSet",
+            "mongodb://us%zzer@a",
+        ] {
+            assert!(
+                matches!(Config::from_uri(uri), Err(ConfigError::InvalidUri { .. })),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn hides_password_of_invalid_uri() {
+        let err = Config::from_uri("mongodb://root:secret@a/?replicaSet").unwrap_err();
+        match &err {
+            ConfigError::InvalidUri { uri, .. } => {
+                assert_eq!(uri, "mongodb://root:***@a/?replicaSet")
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(!err.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn connection_string_round_trips() {
+        let config = Config {
+            hosts: vec![host("a", 1), host("::1", 2)],
+            replica_set: Some("rs 0".to_string()),
+            db_name: Some("db".to_string()),
+            connect_timeout: Some(Duration::from_millis(10)),
+            server_selection_timeout: Some(Duration::from_millis(20)),
+            credentials: Some(Credentials {
+                username: "user/name".to_string(),
+                password: Some("p@ss:w%rd".to_string()),
+                auth_source: Some("admin".to_string()),
+            }),
+            srv: false,
+            options: vec![("w".to_string(), "majority".to_string())],
+        };
+        assert_eq!(
+            Config::from_uri(&config.connection_string()).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        for s in ["plain", "a b/c?d@e:f%g", "é日本"] {
+            assert_eq!(percent_decode(&percent_encode(s)).as_deref(), Some(s));
+        }
+        assert_eq!(percent_encode("a b"), "a%20b");
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+
+    #[test]
+    fn parses_hosts() {
+        assert_eq!(Host::parse("a:1").unwrap(), host("a", 1));
+        assert_eq!(Host::parse("a").unwrap(), host("a", DEFAULT_PORT));
+        assert_eq!(Host::parse("[::1]:27018").unwrap(), host("::1", 27018));
+        assert_eq!(Host::parse("[::1]").unwrap(), host("::1", DEFAULT_PORT));
+        assert_eq!(
+            Host::parse("[::1]:27017").unwrap().to_string(),
+            "[::1]:27017"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_hosts() {
+        for s in [
+            "a:0", "a:65536", "a:x", ":1", "", "::1", "[::1", "[::1]x", "a b:1",
+        ] {
+            assert!(
+                matches!(Host::parse(s), Err(ConfigError::InvalidHost { .. })),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn reports_invalid_settings() {
+        let err = |pairs: &[(&str, &str)]| Config::from_vars(&vars(pairs)).unwrap_err();
+
+        assert!(matches!(
+            err(&[(ENV_CONFIG_FILE, "/no/such/config.toml")]),
+            ConfigError::ReadFile { .. }
+        ));
+        let file = config_file("unknown = 1");
+        assert!(matches!(
+            err(&[(ENV_CONFIG_FILE, file.path().to_str().unwrap())]),
+            ConfigError::ParseFile { .. }
+        ));
+        assert!(matches!(
+            err(&[(ENV_CONNECT_TIMEOUT_MS, "soon")]),
+            ConfigError::InvalidTimeout { .. }
+        ));
+        assert!(matches!(
+            err(&[(ENV_SERVER_SELECTION_TIMEOUT_MS, "0")]),
+            ConfigError::ZeroTimeout(_)
+        ));
+        assert!(matches!(err(&[(ENV_HOSTS, " , ")]), ConfigError::NoHosts));
+        assert!(matches!(
+            err(&[(ENV_PASSWORD, "secret")]),
+            ConfigError::PasswordWithoutUsername
+        ));
+        let file = config_file("replica_set = \"\"");
+        assert!(matches!(
+            err(&[(ENV_CONFIG_FILE, file.path().to_str().unwrap())]),
+            ConfigError::Empty("replica_set")
+        ));
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use mongodb::{
+    error::Result,
+    event::{
+        cmap::CmapEventHandler,
+        command::{
+            CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+        },
+    },
+    Client,
+};
+
+use crate::config::Config;
+
+pub async fn connect(config: &Config) -> Result<Client> {
+    Client::with_options(config.client_options().await?)
+}
+
+/// Connects with `handler` notified of every command the client sends.
+pub async fn connect_with_handler(
+    config: &Config,
+    handler: Arc<dyn CommandEventHandler>,
+) -> Result<Client> {
+    let mut options = config.client_options().await?;
+    options.command_event_handler = Some(handler);
+    Client::with_options(options)
+}
+
+/// Connects with `handler` notified of every command, and `pool_handler` of every event of the
+/// connection pools.
+pub async fn connect_with_handlers(
+    config: &Config,
+    handler: Arc<dyn CommandEventHandler>,
+    pool_handler: Arc<dyn CmapEventHandler>,
+) -> Result<Client> {
+    let mut options = config.client_options().await?;
+    options.command_event_handler = Some(handler);
+    options.cmap_event_handler = Some(pool_handler);
+    Client::with_options(options)
+}
+
+/// Passes the command events to each of the handlers, since a client takes only one.
+#[derive(Clone, Default)]
+pub struct CommandHandlers(pub Vec<Arc<dyn CommandEventHandler>>);
+
+impl CommandEventHandler for CommandHandlers {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        for handler in &self.0 {
+            handler.handle_command_started_event(event.clone());
+        }
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        for handler in &self.0 {
+            handler.handle_command_succeeded_event(event.clone());
+        }
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        for handler in &self.0 {
+            handler.handle_command_failed_event(event.clone());
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Checks that `Book.reviews` and `User.reviewed_book_ids` tell the same relation.
+//!
+//! Both are written in one transaction by [`ReviewService`](crate::review::ReviewService),
+//! but writes without a transaction, like the ones of `clientv1`, can leave them diverged.
+//! [`check`] scans both collections and reports each [`Inconsistency`]. [`repair`] also fixes
+//! them, each in a transaction that reads the documents again, so that an inconsistency fixed
+//! concurrently is left alone.
+//!
+//! The reviews are taken as the truth when the two sides disagree: a text can't be made up
+//! for a review only the user records.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    error::{DbError, DbResult},
+    storage::{SessionSource, Storage},
+    tx::{run_transaction, WithTransactionOptions},
+    Book, BookRepository, User, UserRepository,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inconsistency {
+    /// The book has a review by a user that doesn't exist. Repaired by removing the review.
+    OrphanedReview { book_id: String, user_id: String },
+    /// The user records a review of a book that doesn't exist. Repaired by removing the id.
+    DanglingReviewedBook { user_id: String, book_id: String },
+    /// The book has a review by the user, who doesn't record it. Repaired by adding the id.
+    MissingReviewedBookId { user_id: String, book_id: String },
+    /// The user records a review the book doesn't have. Repaired by removing the id.
+    MissingReview { user_id: String, book_id: String },
+    /// The user records the book `count` times. Repaired by keeping the first one.
+    DuplicateReviewedBookId {
+        user_id: String,
+        book_id: String,
+        count: usize,
+    },
+    /// The book has `count` reviews by the user. Repaired by keeping the first one.
+    DuplicateReview {
+        book_id: String,
+        user_id: String,
+        count: usize,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::OrphanedReview { book_id, user_id } => write!(
+                f,
+                "orphaned review     book {} has a review by unknown user {}",
+                book_id, user_id
+            ),
+            Inconsistency::DanglingReviewedBook { user_id, book_id } => write!(
+                f,
+                "dangling book id    user {} reviewed unknown book {}",
+                user_id, book_id
+            ),
+            Inconsistency::MissingReviewedBookId { user_id, book_id } => write!(
+                f,
+                "one-sided link      book {} has a review by user {}, who doesn't record it",
+                book_id, user_id
+            ),
+            Inconsistency::MissingReview { user_id, book_id } => write!(
+                f,
+                "one-sided link      user {} records a review book {} doesn't have",
+                user_id, book_id
+            ),
+            Inconsistency::DuplicateReviewedBookId {
+                user_id,
+                book_id,
+                count,
+            } => write!(
+                f,
+                "duplicate book id   user {} records book {} {} times",
+                user_id, book_id, count
+            ),
+            Inconsistency::DuplicateReview {
+                book_id,
+                user_id,
+                count,
+            } => write!(
+                f,
+                "duplicate review    book {} has {} reviews by user {}",
+                book_id, count, user_id
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsistencyReport {
+    pub users: usize,
+    pub books: usize,
+    pub inconsistencies: Vec<Inconsistency>,
+    /// Inconsistencies written by the repair. `None` unless repaired.
+    pub repaired: Option<usize>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+impl fmt::Display for ConsistencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "checked {} users and {} books: {} inconsistencies",
+            self.users,
+            self.books,
+            self.inconsistencies.len()
+        )?;
+        for inconsistency in &self.inconsistencies {
+            writeln!(f, "  {}", inconsistency)?;
+        }
+        if let Some(repaired) = self.repaired {
+            // the others were fixed by someone else since the check.
+            writeln!(
+                f,
+                "repaired {} of {} inconsistencies",
+                repaired,
+                self.inconsistencies.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Scans both collections and reports the inconsistencies, without writing anything.
+pub async fn check<S: Storage>(
+    users: &UserRepository<S>,
+    books: &BookRepository<S>,
+) -> DbResult<ConsistencyReport> {
+    let all_users = users.list_all().await?;
+    let all_books = books.list_all().await?;
+    Ok(ConsistencyReport {
+        users: all_users.len(),
+        books: all_books.len(),
+        inconsistencies: find_inconsistencies(&all_users, &all_books),
+        repaired: None,
+    })
+}
+
+/// Checks both collections, then repairs each inconsistency in a transaction.
+pub async fn repair<S, C>(
+    sessions: &C,
+    users: &UserRepository<S>,
+    books: &BookRepository<S>,
+    options: &WithTransactionOptions,
+) -> DbResult<ConsistencyReport>
+where
+    S: Storage + 'static,
+    C: SessionSource<S>,
+{
+    let mut report = check(users, books).await?;
+    let mut session = sessions.start_session().await?;
+    let mut repaired = 0;
+    for inconsistency in &report.inconsistencies {
+        let written = run_transaction(&mut session, options, |session| {
+            let users = users.clone();
+            let books = books.clone();
+            let inconsistency = inconsistency.clone();
+            Box::pin(async move { repair_one(&users, &books, &inconsistency, session).await })
+        })
+        .await?;
+        if written {
+            repaired += 1;
+        }
+    }
+    report.repaired = Some(repaired);
+    Ok(report)
+}
+
+/// Every inconsistency between the users and the books, by book then by user.
+///
+/// An id without a review is not reported as duplicated too, since its repair removes every
+/// copy of it. Neither is an orphaned review.
+pub fn find_inconsistencies(users: &[User], books: &[Book]) -> Vec<Inconsistency> {
+    let users_by_id: HashMap<&str, &User> = users.iter().map(|u| (u.id.as_str(), u)).collect();
+    let books_by_id: HashMap<&str, &Book> = books.iter().map(|b| (b.id.as_str(), b)).collect();
+    let mut found = vec![];
+
+    for book in books {
+        let reviewers = counted(book.reviews.iter().map(|r| r.user_id.as_str()));
+        for (user_id, count) in reviewers {
+            let (book_id, user_id) = (book.id.clone(), user_id.to_string());
+            match users_by_id.get(user_id.as_str()) {
+                None => found.push(Inconsistency::OrphanedReview { book_id, user_id }),
+                Some(user) => {
+                    if !user.reviewed_book_ids.contains(&book_id) {
+                        found.push(Inconsistency::MissingReviewedBookId {
+                            user_id: user_id.clone(),
+                            book_id: book_id.clone(),
+                        });
+                    }
+                    if count > 1 {
+                        found.push(Inconsistency::DuplicateReview {
+                            book_id,
+                            user_id,
+                            count,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    for user in users {
+        let book_ids = counted(user.reviewed_book_ids.iter().map(String::as_str));
+        for (book_id, count) in book_ids {
+            let (user_id, book_id) = (user.id.clone(), book_id.to_string());
+            match books_by_id.get(book_id.as_str()) {
+                None => found.push(Inconsistency::DanglingReviewedBook { user_id, book_id }),
+                Some(book) if !has_review_by(book, &user_id) => {
+                    found.push(Inconsistency::MissingReview { user_id, book_id })
+                }
+                Some(_) if count > 1 => found.push(Inconsistency::DuplicateReviewedBookId {
+                    user_id,
+                    book_id,
+                    count,
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+    found
+}
+
+/// Distinct values in the order they first appear, with the number of times they appear.
+fn counted<'a>(values: impl Iterator<Item = &'a str>) -> Vec<(&'a str, usize)> {
+    let mut counts: Vec<(&str, usize)> = vec![];
+    for value in values {
+        match counts.iter_mut().find(|(v, _)| *v == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    counts
+}
+
+fn has_review_by(book: &Book, user_id: &str) -> bool {
+    book.reviews.iter().any(|r| r.user_id == user_id)
+}
+
+/// Keeps the first of the values matching `is_dup`, and the other values.
+fn keep_first<T>(values: &[T], is_dup: impl Fn(&T) -> bool) -> Vec<T>
+where
+    T: Clone,
+{
+    let mut seen = false;
+    values
+        .iter()
+        .filter(|v| {
+            if !is_dup(v) {
+                return true;
+            }
+            !std::mem::replace(&mut seen, true)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Fixes `inconsistency` if the documents read in `session` still have it.
+/// Returns whether anything was written.
+async fn repair_one<S: Storage>(
+    users: &UserRepository<S>,
+    books: &BookRepository<S>,
+    inconsistency: &Inconsistency,
+    session: &mut S::Session,
+) -> Result<bool, DbError> {
+    match inconsistency {
+        Inconsistency::OrphanedReview { book_id, user_id } => {
+            if users
+                .find_by_id_with_session(user_id, session)
+                .await?
+                .is_some()
+            {
+                return Ok(false);
+            }
+            let book = match books.find_by_id_with_session(book_id, session).await? {
+                Some(book) => book,
+                None => return Ok(false),
+            };
+            let reviews: Vec<_> = book
+                .reviews
+                .iter()
+                .filter(|r| r.user_id != *user_id)
+                .cloned()
+                .collect();
+            if reviews.len() == book.reviews.len() {
+                return Ok(false);
+            }
+            books
+                .set_reviews_with_session(book_id, &reviews, session)
+                .await?;
+        }
+        Inconsistency::DanglingReviewedBook { user_id, book_id }
+        | Inconsistency::MissingReview { user_id, book_id } => {
+            let book = books.find_by_id_with_session(book_id, session).await?;
+            if book.is_some_and(|b| has_review_by(&b, user_id)) {
+                return Ok(false);
+            }
+            let user = match users.find_by_id_with_session(user_id, session).await? {
+                Some(user) => user,
+                None => return Ok(false),
+            };
+            let ids: Vec<_> = user
+                .reviewed_book_ids
+                .iter()
+                .filter(|id| *id != book_id)
+                .cloned()
+                .collect();
+            if ids.len() == user.reviewed_book_ids.len() {
+                return Ok(false);
+            }
+            users
+                .set_reviewed_book_ids_with_session(user_id, &ids, session)
+                .await?;
+        }
+        Inconsistency::MissingReviewedBookId { user_id, book_id } => {
+            let book = books.find_by_id_with_session(book_id, session).await?;
+            if !book.is_some_and(|b| has_review_by(&b, user_id)) {
+                return Ok(false);
+            }
+            let user = match users.find_by_id_with_session(user_id, session).await? {
+                Some(user) => user,
+                None => return Ok(false),
+            };
+            if user.reviewed_book_ids.contains(book_id) {
+                return Ok(false);
+            }
+            users
+                .add_reviewed_book_with_session(user_id, book_id, session)
+                .await?;
+        }
+        Inconsistency::DuplicateReviewedBookId {
+            user_id, book_id, ..
+        } => {
+            let user = match users.find_by_id_with_session(user_id, session).await? {
+                Some(user) => user,
+                None => return Ok(false),
+            };
+            let ids = keep_first(&user.reviewed_book_ids, |id| id == book_id);
+            if ids.len() == user.reviewed_book_ids.len() {
+                return Ok(false);
+            }
+            users
+                .set_reviewed_book_ids_with_session(user_id, &ids, session)
+                .await?;
+        }
+        Inconsistency::DuplicateReview {
+            book_id, user_id, ..
+        } => {
+            let book = match books.find_by_id_with_session(book_id, session).await? {
+                Some(book) => book,
+                None => return Ok(false),
+            };
+            let reviews = keep_first(&book.reviews, |r| r.user_id == *user_id);
+            if reviews.len() == book.reviews.len() {
+                return Ok(false);
+            }
+            books
+                .set_reviews_with_session(book_id, &reviews, session)
+                .await?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::MemoryStorage, Review};
+
+    fn user(id: &str, reviewed_book_ids: &[&str]) -> User {
+        User {
+            id: id.to_string(),
+            name: id.to_string(),
+            reviewed_book_ids: reviewed_book_ids.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    fn book(id: &str, reviewers: &[&str]) -> Book {
+        Book {
+            id: id.to_string(),
+            name: id.to_string(),
+            reviews: reviewers
+                .iter()
+                .map(|user_id| Review {
+                    user_id: user_id.to_string(),
+                    text: format!("review by {}", user_id),
+                    reviewed_at: None,
+                })
+                .collect(),
+            authors: vec![],
+            supervisors: vec![],
+            version: 0,
+        }
+    }
+
+    fn diverged() -> (Vec<User>, Vec<Book>) {
+        let users = vec![
+            user("user_1", &["book_1", "book_x"]),
+            user("user_2", &["book_2", "book_2"]),
+            user("user_3", &["book_1"]),
+        ];
+        let books = vec![
+            book("book_1", &["user_1", "user_x", "user_2"]),
+            book("book_2", &["user_2", "user_1", "user_1"]),
+        ];
+        (users, books)
+    }
+
+    #[test]
+    fn finds_every_kind_of_inconsistency() {
+        let (users, books) = diverged();
+        let s = |s: &str| s.to_string();
+        assert_eq!(
+            find_inconsistencies(&users, &books),
+            vec![
+                Inconsistency::OrphanedReview {
+                    book_id: s("book_1"),
+                    user_id: s("user_x")
+                },
+                Inconsistency::MissingReviewedBookId {
+                    user_id: s("user_2"),
+                    book_id: s("book_1")
+                },
+                Inconsistency::MissingReviewedBookId {
+                    user_id: s("user_1"),
+                    book_id: s("book_2")
+                },
+                Inconsistency::DuplicateReview {
+                    book_id: s("book_2"),
+                    user_id: s("user_1"),
+                    count: 2
+                },
+                Inconsistency::DanglingReviewedBook {
+                    user_id: s("user_1"),
+                    book_id: s("book_x")
+                },
+                Inconsistency::DuplicateReviewedBookId {
+                    user_id: s("user_2"),
+                    book_id: s("book_2"),
+                    count: 2
+                },
+                Inconsistency::MissingReview {
+                    user_id: s("user_3"),
+                    book_id: s("book_1")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_duplicate_review() {
+        let users = vec![user("user_1", &["book_1"])];
+        let books = vec![book("book_1", &["user_1", "user_1"])];
+        assert_eq!(
+            find_inconsistencies(&users, &books),
+            vec![Inconsistency::DuplicateReview {
+                book_id: "book_1".to_string(),
+                user_id: "user_1".to_string(),
+                count: 2
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn repair_makes_both_sides_agree() {
+        let storage = MemoryStorage::new();
+        let users = UserRepository::with_storage(storage.clone(), "users");
+        let books = BookRepository::with_storage(storage.clone(), "books");
+        let (all_users, mut all_books) = diverged();
+        all_books.push(book("book_3", &["user_3", "user_3"]));
+        for u in &all_users {
+            users.insert(u).await.unwrap();
+        }
+        for b in &all_books {
+            books.insert(b).await.unwrap();
+        }
+        users.add_reviewed_book("user_3", "book_3").await.unwrap();
+
+        let report = repair(&storage, &users, &books, &WithTransactionOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(report.inconsistencies.len(), 8);
+        assert_eq!(report.repaired, Some(8));
+
+        let after = check(&users, &books).await.unwrap();
+        assert!(after.is_consistent(), "{}", after);
+        let user_1 = users.find_by_id("user_1").await.unwrap().unwrap();
+        assert_eq!(user_1.reviewed_book_ids, vec!["book_1", "book_2"]);
+        let book_2 = books.find_by_id("book_2").await.unwrap().unwrap();
+        // the first review of user_1 is kept.
+        assert_eq!(book_2.reviews.len(), 2);
+
+        let again = repair(&storage, &users, &books, &WithTransactionOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(again.repaired, Some(0));
+    }
+
+    #[tokio::test]
+    async fn repair_leaves_fixed_inconsistency_alone() {
+        let storage = MemoryStorage::new();
+        let users = UserRepository::with_storage(storage.clone(), "users");
+        let books = BookRepository::with_storage(storage.clone(), "books");
+        users.insert(&user("user_1", &[])).await.unwrap();
+        books.insert(&book("book_1", &["user_1"])).await.unwrap();
+        let inconsistency = Inconsistency::MissingReviewedBookId {
+            user_id: "user_1".to_string(),
+            book_id: "book_1".to_string(),
+        };
+
+        // someone else records the review between the check and the repair.
+        users.add_reviewed_book("user_1", "book_1").await.unwrap();
+        let mut session = storage.start_session();
+        let written = repair_one(&users, &books, &inconsistency, &mut session)
+            .await
+            .unwrap();
+
+        assert!(!written);
+        let user_1 = users.find_by_id("user_1").await.unwrap().unwrap();
+        assert_eq!(user_1.reviewed_book_ids, vec!["book_1"]);
+    }
+}
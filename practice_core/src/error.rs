@@ -0,0 +1,296 @@
+//! Driver errors classified into the categories the scenarios care about.
+//!
+//! ```ignore
+//! match users.collection().insert_one(user, None).await.map_err(DbError::from) {
+//!     Err(DbError::DuplicateKey { index, .. }) => println!("duplicated in {:?}", index),
+//!     other => other?,
+//! }
+//! ```
+
+use mongodb::error::{
+    Error, ErrorKind, WriteFailure, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT,
+};
+use thiserror::Error;
+
+use crate::tx::ErrorLabels;
+
+const DUPLICATE_KEY_CODE: i32 = 11000;
+const WRITE_CONFLICT_CODE: i32 = 112;
+
+#[derive(Error, Debug)]
+pub enum DbError {
+    /// A unique index rejected the write. `index` and `key` are taken from the server message.
+    #[error("duplicate key {} in index {}", key.as_deref().unwrap_or("?"), index.as_deref().unwrap_or("?"))]
+    DuplicateKey {
+        index: Option<String>,
+        key: Option<String>,
+        #[source]
+        source: Error,
+    },
+    /// Another transaction wrote the same document.
+    #[error("write conflict: {0}")]
+    WriteConflict(#[source] Error),
+    #[error("unknown transaction commit result: {0}")]
+    UnknownCommitResult(#[source] Error),
+    /// The transaction can be retried from the start.
+    #[error("transient transaction error: {0}")]
+    TransientTransaction(#[source] Error),
+    #[error("server selection timeout: {0}")]
+    ServerSelectionTimeout(#[source] Error),
+    #[error("network timeout: {0}")]
+    NetworkTimeout(#[source] Error),
+    #[error("network error: {0}")]
+    Network(#[source] Error),
+    #[error("{0} is not found")]
+    NotFound(String),
+    #[error(transparent)]
+    Other(Error),
+}
+
+pub type DbResult<T> = std::result::Result<T, DbError>;
+
+impl DbError {
+    /// The driver error this error is classified from.
+    pub fn driver_error(&self) -> Option<&Error> {
+        match self {
+            DbError::DuplicateKey { source, .. } => Some(source),
+            DbError::WriteConflict(e)
+            | DbError::UnknownCommitResult(e)
+            | DbError::TransientTransaction(e)
+            | DbError::ServerSelectionTimeout(e)
+            | DbError::NetworkTimeout(e)
+            | DbError::Network(e)
+            | DbError::Other(e) => Some(e),
+            DbError::NotFound(_) => None,
+        }
+    }
+}
+
+impl From<Error> for DbError {
+    fn from(e: Error) -> Self {
+        let labels = e.labels().clone();
+        classify(e, |label| labels.contains(label))
+    }
+}
+
+/// Classifies `e`, whose labels are given by `has_label` since only the driver can label an error.
+fn classify(e: Error, has_label: impl Fn(&str) -> bool) -> DbError {
+    if let Some(message) = duplicate_key_message(&e) {
+        let (index, key) = parse_duplicate_key(message);
+        return DbError::DuplicateKey {
+            index,
+            key,
+            source: e,
+        };
+    }
+    if is_write_conflict(&e) {
+        return DbError::WriteConflict(e);
+    }
+    if has_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) {
+        return DbError::UnknownCommitResult(e);
+    }
+    if has_label(TRANSIENT_TRANSACTION_ERROR) {
+        return DbError::TransientTransaction(e);
+    }
+    match e.kind.as_ref() {
+        ErrorKind::ServerSelection { .. } => DbError::ServerSelectionTimeout(e),
+        ErrorKind::Io(io) if io.kind() == std::io::ErrorKind::TimedOut => {
+            DbError::NetworkTimeout(e)
+        }
+        ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } => DbError::Network(e),
+        _ => DbError::Other(e),
+    }
+}
+
+impl ErrorLabels for DbError {
+    /// A write conflict is always transient, as the server labels it in a transaction.
+    /// The errors of the in-memory storage can't carry labels.
+    fn has_label(&self, label: &str) -> bool {
+        if matches!(self, DbError::WriteConflict(_)) && label == TRANSIENT_TRANSACTION_ERROR {
+            return true;
+        }
+        self.driver_error()
+            .map(|e| e.contains_label(label))
+            .unwrap_or(false)
+    }
+}
+
+/// Turns a missing document into [`DbError::NotFound`].
+pub trait Required<T> {
+    fn required(self, what: impl Into<String>) -> DbResult<T>;
+}
+
+impl<T> Required<T> for DbResult<Option<T>> {
+    fn required(self, what: impl Into<String>) -> DbResult<T> {
+        self?.ok_or_else(|| DbError::NotFound(what.into()))
+    }
+}
+
+pub fn is_write_conflict(error: &Error) -> bool {
+    error_code(error) == Some(WRITE_CONFLICT_CODE)
+}
+
+pub fn is_duplicate_key(error: &Error) -> bool {
+    duplicate_key_message(error).is_some()
+}
+
+/// Code of a command error or of the first write error.
+fn error_code(error: &Error) -> Option<i32> {
+    match error.kind.as_ref() {
+        ErrorKind::Command(e) => Some(e.code),
+        ErrorKind::Write(WriteFailure::WriteError(e)) => Some(e.code),
+        ErrorKind::BulkWrite(failure) => failure
+            .write_errors
+            .as_ref()
+            .and_then(|errors| errors.first())
+            .map(|e| e.code),
+        _ => None,
+    }
+}
+
+fn duplicate_key_message(error: &Error) -> Option<&str> {
+    if error_code(error) != Some(DUPLICATE_KEY_CODE) {
+        return None;
+    }
+    match error.kind.as_ref() {
+        ErrorKind::Command(e) => Some(&e.message),
+        ErrorKind::Write(WriteFailure::WriteError(e)) => Some(&e.message),
+        ErrorKind::BulkWrite(failure) => failure
+            .write_errors
+            .as_ref()
+            .and_then(|errors| errors.first())
+            .map(|e| e.message.as_str()),
+        _ => None,
+    }
+}
+
+/// Parses `E11000 duplicate key error collection: db.coll index: name_1_opt_1 dup key: { name: "a", opt: "b" }`.
+fn parse_duplicate_key(message: &str) -> (Option<String>, Option<String>) {
+    let index = message.split(" index: ").nth(1).map(|rest| {
+        rest.split(" dup key:")
+            .next()
+            .unwrap_or(rest)
+            .trim()
+            .to_string()
+    });
+    let key = message
+        .split(" dup key: ")
+        .nth(1)
+        .map(|key| key.trim().to_string());
+    (index, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::{
+        bson::{doc, from_document},
+        error::{CommandError, WriteError},
+    };
+
+    fn command_error(code: i32, message: &str) -> Error {
+        let e: CommandError =
+            from_document(doc! {"code": code, "codeName": "", "errmsg": message}).unwrap();
+        Error::from(ErrorKind::Command(e))
+    }
+
+    fn write_error(code: i32, message: &str) -> Error {
+        let e: WriteError = from_document(doc! {"code": code, "errmsg": message}).unwrap();
+        Error::from(ErrorKind::Write(WriteFailure::WriteError(e)))
+    }
+
+    #[test]
+    fn duplicate_key_with_index_and_key() {
+        let e = write_error(
+            11000,
+            r#"E11000 duplicate key error collection: test_db.index_test index: name_1_opt_1 dup key: { name: "aaaa", opt: "sss" }"#,
+        );
+        match DbError::from(e) {
+            DbError::DuplicateKey { index, key, .. } => {
+                assert_eq!(index.as_deref(), Some("name_1_opt_1"));
+                assert_eq!(key.as_deref(), Some(r#"{ name: "aaaa", opt: "sss" }"#));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_key_without_details() {
+        let e = command_error(11000, "E11000 duplicate key error");
+        assert!(matches!(
+            DbError::from(e),
+            DbError::DuplicateKey {
+                index: None,
+                key: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn write_conflict() {
+        let e = command_error(112, "WriteConflict");
+        assert!(is_write_conflict(&e));
+        let e = DbError::from(e);
+        assert!(matches!(e, DbError::WriteConflict(_)));
+        assert!(e.has_label(TRANSIENT_TRANSACTION_ERROR));
+    }
+
+    #[test]
+    fn network_errors() {
+        let timeout = Error::from(std::io::ErrorKind::TimedOut);
+        assert!(matches!(DbError::from(timeout), DbError::NetworkTimeout(_)));
+
+        let reset = Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(matches!(DbError::from(reset), DbError::Network(_)));
+    }
+
+    #[test]
+    fn labeled_errors() {
+        let e = classify(command_error(50, "MaxTimeMSExpired"), |label| {
+            label == UNKNOWN_TRANSACTION_COMMIT_RESULT
+        });
+        assert!(matches!(e, DbError::UnknownCommitResult(_)));
+
+        let e = classify(Error::from(std::io::ErrorKind::ConnectionReset), |label| {
+            label == TRANSIENT_TRANSACTION_ERROR
+        });
+        assert!(matches!(e, DbError::TransientTransaction(_)));
+
+        // the code is more specific than the label the server adds to a write conflict.
+        let e = classify(command_error(112, "WriteConflict"), |label| {
+            label == TRANSIENT_TRANSACTION_ERROR
+        });
+        assert!(matches!(e, DbError::WriteConflict(_)));
+    }
+
+    #[tokio::test]
+    async fn server_selection_timeout() {
+        // nothing listens on port 1.
+        let client = mongodb::Client::with_uri_str(
+            "mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100&connectTimeoutMS=100",
+        )
+        .await
+        .unwrap();
+        let e = client.list_database_names(None, None).await.unwrap_err();
+        assert!(
+            matches!(DbError::from(e), DbError::ServerSelectionTimeout(_)),
+            "not a server selection error"
+        );
+    }
+
+    #[test]
+    fn other_errors() {
+        let e = command_error(2, "BadValue");
+        assert!(matches!(DbError::from(e), DbError::Other(_)));
+    }
+
+    #[test]
+    fn required() {
+        let found: DbResult<Option<i32>> = Ok(None);
+        assert!(matches!(
+            found.required("user user_1"),
+            Err(DbError::NotFound(what)) if what == "user user_1"
+        ));
+    }
+}
@@ -0,0 +1,385 @@
+//! Collections loaded from and dumped to MongoDB Extended JSON files.
+//!
+//! A fixture directory has a file per collection, named after the collection:
+//!
+//! ```text
+//! fixtures/seed/
+//!   users.json    [{"id": "user_1", ...}, ...]
+//!   books.jsonl   {"id": "book_1", ...}
+//!                 {"id": "book_2", ...}
+//! ```
+//!
+//! A `.json` file holds an array of documents, and a `.jsonl` file a document per line.
+//! Both canonical and relaxed Extended JSON are read, even mixed in a file. Dumps are written
+//! in either mode: canonical keeps every BSON type, so that loading the dump gives the same
+//! documents, while relaxed writes numbers as plain JSON numbers, which are read back as
+//! `Int32` when they fit.
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use mongodb::bson::{doc, Bson, Document};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{error::DbResult, storage::Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtJsonMode {
+    Canonical,
+    Relaxed,
+}
+
+impl FromStr for ExtJsonMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "canonical" => Ok(ExtJsonMode::Canonical),
+            "relaxed" => Ok(ExtJsonMode::Relaxed),
+            _ => Err(format!(
+                "unknown mode {:?}, expected canonical or relaxed",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureFormat {
+    /// An array of documents.
+    Json,
+    /// A document per line. Blank lines are skipped.
+    Jsonl,
+}
+
+impl FixtureFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(FixtureFormat::Json),
+            "jsonl" => Some(FixtureFormat::Jsonl),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FixtureFormat::Json => "json",
+            FixtureFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl FromStr for FixtureFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(FixtureFormat::Json),
+            "jsonl" => Ok(FixtureFormat::Jsonl),
+            _ => Err(format!("unknown format {:?}, expected json or jsonl", s)),
+        }
+    }
+}
+
+/// Where a fixture can't be parsed: the line of a JSONL file, or the index in a JSON array.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Position {
+    Line(usize),
+    Document(usize),
+    /// The JSON array itself.
+    Whole,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Position::Line(line) => write!(f, "line {}", line),
+            Position::Document(i) => write!(f, "document {}", i),
+            Position::Whole => write!(f, "the array"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FixtureError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to write {path}: {source}")]
+    Write {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{path}: {position}: {message}")]
+    Parse {
+        path: String,
+        position: Position,
+        message: String,
+    },
+    #[error("{0} is neither .json nor .jsonl")]
+    UnknownFormat(String),
+}
+
+/// The documents of a collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fixture {
+    pub collection: String,
+    pub documents: Vec<Document>,
+}
+
+/// Parses the documents of a fixture file. `path` only names the file in the errors.
+pub fn parse_documents(
+    text: &str,
+    format: FixtureFormat,
+    path: &str,
+) -> Result<Vec<Document>, FixtureError> {
+    let error = |position, message: String| FixtureError::Parse {
+        path: path.to_string(),
+        position,
+        message,
+    };
+    match format {
+        FixtureFormat::Json => {
+            let values: Vec<Value> =
+                serde_json::from_str(text).map_err(|e| error(Position::Whole, e.to_string()))?;
+            values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| to_document(value).map_err(|e| error(Position::Document(i), e)))
+                .collect()
+        }
+        FixtureFormat::Jsonl => text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                let value = serde_json::from_str(line)
+                    .map_err(|e| error(Position::Line(i + 1), e.to_string()))?;
+                to_document(value).map_err(|e| error(Position::Line(i + 1), e))
+            })
+            .collect(),
+    }
+}
+
+pub(crate) fn to_document(value: Value) -> Result<Document, String> {
+    match Bson::try_from(value).map_err(|e| e.to_string())? {
+        Bson::Document(doc) => Ok(doc),
+        other => Err(format!(
+            "expected a document, found {:?}",
+            other.element_type()
+        )),
+    }
+}
+
+/// Writes `documents` in `format`. A JSON array is pretty printed.
+pub fn format_documents(
+    documents: &[Document],
+    format: FixtureFormat,
+    mode: ExtJsonMode,
+) -> String {
+    let values = documents.iter().map(|doc| {
+        let bson = Bson::Document(doc.clone());
+        match mode {
+            ExtJsonMode::Canonical => bson.into_canonical_extjson(),
+            ExtJsonMode::Relaxed => bson.into_relaxed_extjson(),
+        }
+    });
+    match format {
+        FixtureFormat::Json => {
+            let values: Vec<Value> = values.collect();
+            // a Value is always serializable.
+            let mut text = serde_json::to_string_pretty(&values).unwrap();
+            text.push('\n');
+            text
+        }
+        FixtureFormat::Jsonl => values.map(|value| format!("{}\n", value)).collect(),
+    }
+}
+
+/// Reads a fixture file, named after its collection.
+pub fn read_file(path: &Path) -> Result<Fixture, FixtureError> {
+    let path_str = path.display().to_string();
+    let format = FixtureFormat::from_path(path)
+        .ok_or_else(|| FixtureError::UnknownFormat(path_str.clone()))?;
+    let collection = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| FixtureError::UnknownFormat(path_str.clone()))?
+        .to_string();
+    let text = std::fs::read_to_string(path).map_err(|source| FixtureError::Read {
+        path: path_str.clone(),
+        source,
+    })?;
+    Ok(Fixture {
+        collection,
+        documents: parse_documents(&text, format, &path_str)?,
+    })
+}
+
+/// Reads the `.json` and `.jsonl` files of `dir`, in the order of their names.
+/// Other files are skipped.
+pub fn read_dir(dir: &Path) -> Result<Vec<Fixture>, FixtureError> {
+    let read_error = |source| FixtureError::Read {
+        path: dir.display().to_string(),
+        source,
+    };
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        if path.is_file() && FixtureFormat::from_path(&path).is_some() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths.iter().map(|path| read_file(path)).collect()
+}
+
+/// Writes a file per fixture into `dir`, which is created if missing. Returns the files.
+pub fn write_dir(
+    dir: &Path,
+    fixtures: &[Fixture],
+    format: FixtureFormat,
+    mode: ExtJsonMode,
+) -> Result<Vec<PathBuf>, FixtureError> {
+    let write_error = |path: &Path, source| FixtureError::Write {
+        path: path.display().to_string(),
+        source,
+    };
+    std::fs::create_dir_all(dir).map_err(|e| write_error(dir, e))?;
+    let mut written = vec![];
+    for fixture in fixtures {
+        let path = dir.join(format!("{}.{}", fixture.collection, format.extension()));
+        let text = format_documents(&fixture.documents, format, mode);
+        std::fs::write(&path, text).map_err(|e| write_error(&path, e))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Replaces each collection with the documents of its fixture.
+pub async fn load<S: Storage>(storage: &S, fixtures: &[Fixture]) -> DbResult<()> {
+    for fixture in fixtures {
+        storage.drop_collection(&fixture.collection).await?;
+        for doc in &fixture.documents {
+            storage
+                .insert_one(&fixture.collection, doc.clone(), None)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads every document of `collections`, in the order the storage returns them.
+pub async fn dump<S: Storage>(storage: &S, collections: &[&str]) -> DbResult<Vec<Fixture>> {
+    let mut fixtures = vec![];
+    for coll in collections {
+        fixtures.push(Fixture {
+            collection: coll.to_string(),
+            documents: storage.find(coll, doc! {}, None).await?,
+        });
+    }
+    Ok(fixtures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::MemoryStorage, Book, BookRepository, User, UserRepository};
+    use mongodb::bson::{oid::ObjectId, DateTime};
+
+    fn typed() -> Document {
+        doc! {
+            "_id": ObjectId::parse_str("5f0c3b5e8a1b2c3d4e5f6a7b").unwrap(),
+            "id": "book_1",
+            "version": 3i64,
+            "count": 2,
+            "rate": 0.5,
+            "at": DateTime::from_millis(1_600_000_000_000),
+            "tags": ["a", "b"],
+        }
+    }
+
+    #[test]
+    fn canonical_round_trip_keeps_types() {
+        for format in [FixtureFormat::Json, FixtureFormat::Jsonl] {
+            let text = format_documents(&[typed(), typed()], format, ExtJsonMode::Canonical);
+            let parsed = parse_documents(&text, format, "books").unwrap();
+            assert_eq!(parsed, vec![typed(), typed()]);
+        }
+    }
+
+    #[test]
+    fn relaxed_numbers_are_read_as_int32() {
+        let text = format_documents(&[typed()], FixtureFormat::Jsonl, ExtJsonMode::Relaxed);
+        assert!(text.contains(r#""version":3"#), "{}", text);
+
+        let parsed = parse_documents(&text, FixtureFormat::Jsonl, "books").unwrap();
+        assert_eq!(parsed[0].get("version"), Some(&Bson::Int32(3)));
+        assert_eq!(parsed[0].get("at"), typed().get("at"));
+        assert_eq!(parsed[0].get("_id"), typed().get("_id"));
+    }
+
+    #[test]
+    fn reports_position_of_bad_document() {
+        let jsonl = "{\"id\": 1}\n\n[1, 2]\n";
+        let err = parse_documents(jsonl, FixtureFormat::Jsonl, "users.jsonl").unwrap_err();
+        assert!(matches!(
+            err,
+            FixtureError::Parse {
+                position: Position::Line(3),
+                ..
+            }
+        ));
+
+        let json = r#"[{"id": 1}, {"at": {"$date": "yesterday"}}]"#;
+        let err = parse_documents(json, FixtureFormat::Json, "users.json").unwrap_err();
+        assert!(
+            err.to_string().starts_with("users.json: document 1: "),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn dumps_what_is_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = MemoryStorage::new();
+        let fixtures = vec![Fixture {
+            collection: "books".to_string(),
+            documents: vec![typed()],
+        }];
+        load(&storage, &fixtures).await.unwrap();
+
+        let dumped = dump(&storage, &["books"]).await.unwrap();
+        let files = write_dir(
+            dir.path(),
+            &dumped,
+            FixtureFormat::Json,
+            ExtJsonMode::Canonical,
+        )
+        .unwrap();
+        assert_eq!(files, vec![dir.path().join("books.json")]);
+        assert_eq!(read_dir(dir.path()).unwrap(), fixtures);
+    }
+
+    #[tokio::test]
+    async fn seed_fixtures_are_valid() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../fixtures/seed");
+        let storage = MemoryStorage::new();
+        load(&storage, &read_dir(&dir).unwrap()).await.unwrap();
+
+        let users = UserRepository::with_storage(storage.clone(), "users");
+        let books = BookRepository::with_storage(storage, "books");
+        let user: User = users.find_by_id("user_3").await.unwrap().unwrap();
+        assert_eq!(user.name, "joseph");
+        let book: Book = books.find_by_id("book_1").await.unwrap().unwrap();
+        assert_eq!(book.version, 0);
+    }
+}
@@ -0,0 +1,327 @@
+//! Synthetic users, books and reviews, at a scale that exercises the indexes and the arrays.
+//!
+//! [`generate`] is reproducible: the same [`GeneratorOptions`] give the same data, the seed
+//! included. Users review books drawn from a Zipf distribution over `book_1, book_2, ...`, so
+//! that the first books are the hot ones. Each review is written to both the book and the user,
+//! the way [`ReviewService`](crate::review::ReviewService) does, so the generated data is
+//! consistent. Authors and supervisors are drawn uniformly from pools of `author_N` and
+//! `supervisor_N`, for the `$in` lookups on `authors`.
+
+use std::ops::RangeInclusive;
+
+use mongodb::bson::DateTime;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{
+    error::DbResult, repository::to_doc, scenario::Target, storage::Storage, Book, Review, User,
+};
+
+/// Reviews are dated in the year before this time, 2021-10-01, so the data stays reproducible.
+const REVIEWED_UNTIL_MS: i64 = 1_633_046_400_000;
+const YEAR_MS: i64 = 365 * 24 * 60 * 60 * 1000;
+
+const NAMES: &[&str] = &[
+    "john", "anna", "joseph", "maria", "ken", "yuki", "li", "omar", "sara", "ivan",
+];
+const ADJECTIVES: &[&str] = &[
+    "Silent", "Hidden", "Last", "Brave", "Endless", "Broken", "Golden", "Distant",
+];
+const NOUNS: &[&str] = &[
+    "Guide", "Garden", "River", "Empire", "Machine", "Voyage", "Library", "Winter",
+];
+const OPINIONS: &[&str] = &["great", "good", "fine", "boring", "too long", "a classic"];
+
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    pub seed: u64,
+    pub users: usize,
+    pub books: usize,
+    /// Distinct books each user reviews, at most `books`.
+    pub reviews_per_user: RangeInclusive<usize>,
+    pub authors_per_book: RangeInclusive<usize>,
+    pub supervisors_per_book: RangeInclusive<usize>,
+    /// Size of the pools the authors and the supervisors are drawn from.
+    pub people: usize,
+    /// Exponent of the Zipf distribution of the reviewed books. 0 spreads the reviews
+    /// uniformly, and around 1 a few books get most of them.
+    pub zipf_exponent: f64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        GeneratorOptions {
+            seed: 42,
+            users: 1000,
+            books: 200,
+            reviews_per_user: 0..=5,
+            authors_per_book: 1..=3,
+            supervisors_per_book: 0..=2,
+            people: 50,
+            zipf_exponent: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dataset {
+    pub users: Vec<User>,
+    pub books: Vec<Book>,
+}
+
+/// How far [`insert`] got, reported after each batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress<'a> {
+    pub collection: &'a str,
+    pub inserted: usize,
+    pub total: usize,
+}
+
+pub fn generate(options: &GeneratorOptions) -> Dataset {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut books: Vec<Book> = (1..=options.books)
+        .map(|i| Book {
+            id: format!("book_{}", i),
+            name: format!(
+                "The {} {}",
+                ADJECTIVES.choose(&mut rng).unwrap(),
+                NOUNS.choose(&mut rng).unwrap()
+            ),
+            reviews: vec![],
+            authors: draw_people(
+                &mut rng,
+                "author",
+                options.people,
+                &options.authors_per_book,
+            ),
+            supervisors: draw_people(
+                &mut rng,
+                "supervisor",
+                options.people,
+                &options.supervisors_per_book,
+            ),
+            version: 0,
+        })
+        .collect();
+
+    let zipf = Zipf::new(options.books, options.zipf_exponent);
+    let users = (1..=options.users)
+        .map(|i| {
+            let user_id = format!("user_{}", i);
+            let wanted = rng
+                .gen_range(options.reviews_per_user.clone())
+                .min(options.books);
+            let mut reviewed_book_ids = vec![];
+            // a few hot books take most draws, so distinct ones may take many of them.
+            let mut draws = 0;
+            while reviewed_book_ids.len() < wanted && draws < wanted * 100 {
+                draws += 1;
+                let book = &mut books[zipf.sample(&mut rng)];
+                if reviewed_book_ids.contains(&book.id) {
+                    continue;
+                }
+                reviewed_book_ids.push(book.id.clone());
+                book.reviews.push(Review {
+                    user_id: user_id.clone(),
+                    text: format!("{}, says {}", OPINIONS.choose(&mut rng).unwrap(), user_id),
+                    reviewed_at: Some(DateTime::from_millis(
+                        REVIEWED_UNTIL_MS - rng.gen_range(0..YEAR_MS),
+                    )),
+                });
+            }
+            User {
+                name: format!("{}_{}", NAMES.choose(&mut rng).unwrap(), i),
+                id: user_id,
+                reviewed_book_ids,
+            }
+        })
+        .collect();
+
+    Dataset { users, books }
+}
+
+/// Distinct people from `prefix_1` to `prefix_{pool}`.
+fn draw_people(
+    rng: &mut StdRng,
+    prefix: &str,
+    pool: usize,
+    count: &RangeInclusive<usize>,
+) -> Vec<String> {
+    let count = rng.gen_range(count.clone()).min(pool);
+    rand::seq::index::sample(rng, pool, count)
+        .into_iter()
+        .map(|i| format!("{}_{}", prefix, i + 1))
+        .collect()
+}
+
+/// Indexes `0..n` drawn with probabilities proportional to `1 / (index + 1)^exponent`.
+struct Zipf {
+    cumulative: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(n: usize, exponent: f64) -> Self {
+        let mut total = 0.0;
+        let cumulative = (1..=n)
+            .map(|rank| {
+                total += 1.0 / (rank as f64).powf(exponent);
+                total
+            })
+            .collect();
+        Zipf { cumulative }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> usize {
+        let total = self.cumulative.last().copied().unwrap_or(0.0);
+        let point = rng.gen_range(0.0..total);
+        self.cumulative
+            .partition_point(|c| *c <= point)
+            .min(self.cumulative.len() - 1)
+    }
+}
+
+/// Replaces the users and the books of `target` with `dataset`, in `insert_many` calls of
+/// `batch_size` documents, calling `progress` after each of them.
+pub async fn insert<S: Storage>(
+    storage: &S,
+    target: &Target,
+    dataset: &Dataset,
+    batch_size: usize,
+    mut progress: impl FnMut(Progress<'_>),
+) -> DbResult<()> {
+    insert_batches(
+        storage,
+        &target.users,
+        &dataset.users,
+        batch_size,
+        &mut progress,
+    )
+    .await?;
+    insert_batches(
+        storage,
+        &target.books,
+        &dataset.books,
+        batch_size,
+        &mut progress,
+    )
+    .await
+}
+
+async fn insert_batches<S: Storage, T: serde::Serialize>(
+    storage: &S,
+    coll: &str,
+    values: &[T],
+    batch_size: usize,
+    progress: &mut impl FnMut(Progress<'_>),
+) -> DbResult<()> {
+    storage.drop_collection(coll).await?;
+    let mut inserted = 0;
+    for batch in values.chunks(batch_size.max(1)) {
+        let docs = batch.iter().map(to_doc).collect::<DbResult<Vec<_>>>()?;
+        storage.insert_many(coll, docs, None).await?;
+        inserted += batch.len();
+        progress(Progress {
+            collection: coll,
+            inserted,
+            total: values.len(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consistency, storage::MemoryStorage, BookRepository};
+
+    fn small() -> GeneratorOptions {
+        GeneratorOptions {
+            users: 300,
+            books: 50,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_data() {
+        assert_eq!(generate(&small()), generate(&small()));
+        let other_seed = GeneratorOptions { seed: 7, ..small() };
+        assert_ne!(generate(&small()), generate(&other_seed));
+    }
+
+    #[test]
+    fn respects_the_ranges() {
+        let dataset = generate(&small());
+        assert_eq!((dataset.users.len(), dataset.books.len()), (300, 50));
+        for user in &dataset.users {
+            assert!(user.reviewed_book_ids.len() <= 5);
+        }
+        for book in &dataset.books {
+            assert!((1..=3).contains(&book.authors.len()));
+            assert!(book.supervisors.len() <= 2);
+        }
+        let all_reviews = |d: &Dataset| d.books.iter().map(|b| b.reviews.len()).sum::<usize>();
+        assert!(all_reviews(&dataset) > 0);
+    }
+
+    #[test]
+    fn reviews_are_consistent() {
+        let dataset = generate(&small());
+        assert_eq!(
+            consistency::find_inconsistencies(&dataset.users, &dataset.books),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn first_books_are_hot() {
+        let reviews = |exponent| {
+            let dataset = generate(&GeneratorOptions {
+                zipf_exponent: exponent,
+                ..small()
+            });
+            let first: usize = dataset.books[..5].iter().map(|b| b.reviews.len()).sum();
+            let last: usize = dataset.books[45..].iter().map(|b| b.reviews.len()).sum();
+            (first, last)
+        };
+        let (first, last) = reviews(1.2);
+        assert!(first > last * 3, "{} vs {}", first, last);
+        let (first, last) = reviews(0.0);
+        assert!(first < last * 3, "{} vs {}", first, last);
+    }
+
+    #[tokio::test]
+    async fn inserts_in_batches() {
+        let storage = MemoryStorage::new();
+        let target = Target::new("generated");
+        let dataset = generate(&small());
+        let mut reported = vec![];
+
+        insert(&storage, &target, &dataset, 128, |p| {
+            reported.push((p.collection.to_string(), p.inserted, p.total))
+        })
+        .await
+        .unwrap();
+
+        let s = |s: &str| s.to_string();
+        assert_eq!(
+            reported,
+            vec![
+                (s("users"), 128, 300),
+                (s("users"), 256, 300),
+                (s("users"), 300, 300),
+                (s("books"), 50, 50),
+            ]
+        );
+        let books = BookRepository::with_storage(storage, &target.books);
+        let by_authors = books
+            .list_by_any_author(&["author_1", "author_2"])
+            .await
+            .unwrap();
+        let expected = dataset
+            .books
+            .iter()
+            .filter(|b| b.authors.iter().any(|a| a == "author_1" || a == "author_2"))
+            .count();
+        assert_eq!(by_authors.len(), expected);
+    }
+}
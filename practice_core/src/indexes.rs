@@ -0,0 +1,596 @@
+//! Declarative index specs, and syncing them with the indexes the collections have.
+//!
+//! A spec can be written in code ([`IndexSpecs::practice`]) or in YAML:
+//!
+//! ```yaml
+//! index_test:
+//!   - keys: {id: 1}
+//!     unique: true
+//!   - keys: {name: 1, opt: 1}
+//!     unique: true
+//! sessions:
+//!   - keys: {created_at: 1}
+//!     expire_after_secs: 3600
+//! ```
+//!
+//! [`sync`] compares the spec with the indexes the [`Storage`] lists and creates the missing
+//! indexes. Indexes not in the spec and indexes whose options differ are only reported unless
+//! [`SyncOptions`] allows dropping or recreating them.
+
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::IndexOptions,
+    IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{error::DbResult, scenario::Target, storage::Storage};
+
+const ID_INDEX_NAME: &str = "_id_";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexSpec {
+    /// Keys in order, e.g. `{name: 1, opt: 1}`.
+    pub keys: Document,
+    /// Defaults to the name the server gives, e.g. `name_1_opt_1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub unique: bool,
+    #[serde(default)]
+    pub sparse: bool,
+    /// Makes it a TTL index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expire_after_secs: Option<u64>,
+}
+
+impl IndexSpec {
+    pub fn new(keys: Document) -> Self {
+        IndexSpec {
+            keys,
+            name: None,
+            unique: false,
+            sparse: false,
+            expire_after_secs: None,
+        }
+    }
+
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub fn sparse(mut self) -> Self {
+        self.sparse = true;
+        self
+    }
+
+    pub fn expire_after(mut self, secs: u64) -> Self {
+        self.expire_after_secs = Some(secs);
+        self
+    }
+
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// The given name, or the one the server generates from the keys.
+    pub fn index_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .keys
+                .iter()
+                .map(|(field, value)| match value {
+                    Bson::String(s) => format!("{}_{}", field, s),
+                    other => format!("{}_{}", field, other),
+                })
+                .collect::<Vec<_>>()
+                .join("_"),
+        }
+    }
+
+    fn same_keys(&self, other: &IndexSpec) -> bool {
+        self.keys.len() == other.keys.len()
+            && self
+                .keys
+                .iter()
+                .zip(other.keys.iter())
+                .all(|((f1, v1), (f2, v2))| f1 == f2 && same_key_value(v1, v2))
+    }
+
+    /// Names of the options that differ from `existing`. The name only counts when it is given.
+    fn differences(&self, existing: &IndexSpec) -> Vec<&'static str> {
+        let mut diffs = vec![];
+        if !self.same_keys(existing) {
+            diffs.push("keys");
+        }
+        if self.name.is_some() && self.index_name() != existing.index_name() {
+            diffs.push("name");
+        }
+        if self.unique != existing.unique {
+            diffs.push("unique");
+        }
+        if self.sparse != existing.sparse {
+            diffs.push("sparse");
+        }
+        if self.expire_after_secs != existing.expire_after_secs {
+            diffs.push("expire_after_secs");
+        }
+        diffs
+    }
+
+    pub(crate) fn to_model(&self) -> IndexModel {
+        let options = IndexOptions::builder()
+            .name(self.index_name())
+            .unique(Some(self.unique).filter(|u| *u))
+            .sparse(Some(self.sparse).filter(|s| *s))
+            .expire_after(self.expire_after_secs.map(Duration::from_secs))
+            .build();
+        IndexModel::builder()
+            .keys(self.keys.clone())
+            .options(options)
+            .build()
+    }
+
+    pub(crate) fn from_model(model: IndexModel) -> Self {
+        let options = model.options.unwrap_or_default();
+        IndexSpec {
+            keys: model.keys,
+            name: options.name,
+            unique: options.unique.unwrap_or(false),
+            sparse: options.sparse.unwrap_or(false),
+            expire_after_secs: options.expire_after.map(|d| d.as_secs()),
+        }
+    }
+}
+
+/// `1` from YAML is an Int64 and `1` from the server is an Int32, but they are the same direction.
+fn same_key_value(a: &Bson, b: &Bson) -> bool {
+    fn as_f64(v: &Bson) -> Option<f64> {
+        match v {
+            Bson::Int32(i) => Some(*i as f64),
+            Bson::Int64(i) => Some(*i as f64),
+            Bson::Double(f) => Some(*f),
+            _ => None,
+        }
+    }
+    match (as_f64(a), as_f64(b)) {
+        (Some(a), Some(b)) => (a - b).abs() < f64::EPSILON,
+        _ => a == b,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum IndexSpecError {
+    #[error("failed to read the index spec {path}: {source}")]
+    ReadFile {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to parse the index spec {path}: {source}")]
+    ParseFile {
+        path: String,
+        source: serde_yaml::Error,
+    },
+}
+
+/// Index specs by collection name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct IndexSpecs(pub BTreeMap<String, Vec<IndexSpec>>);
+
+impl IndexSpecs {
+    /// The indexes the scenarios expect.
+    pub fn practice(target: &Target) -> Self {
+        let mut specs = BTreeMap::new();
+        specs.insert(
+            target.users.clone(),
+            vec![IndexSpec::new(doc! {"id": 1}).unique()],
+        );
+        specs.insert(
+            target.books.clone(),
+            vec![IndexSpec::new(doc! {"id": 1}).unique()],
+        );
+        specs.insert(target.index_test.clone(), index_test_indexes());
+        IndexSpecs(specs)
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, IndexSpecError> {
+        let path_str = path.as_ref().display().to_string();
+        let content = std::fs::read_to_string(path).map_err(|source| IndexSpecError::ReadFile {
+            path: path_str.clone(),
+            source,
+        })?;
+        Self::from_yaml(&content).map_err(|source| IndexSpecError::ParseFile {
+            path: path_str,
+            source,
+        })
+    }
+}
+
+/// Unique `id` and unique `(name, opt)`, as created by the indexes scenario.
+pub fn index_test_indexes() -> Vec<IndexSpec> {
+    vec![
+        IndexSpec::new(doc! {"id": 1}).unique(),
+        IndexSpec::new(doc! {"name": 1, "opt": 1}).unique(),
+    ]
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Only reports the changes.
+    pub dry_run: bool,
+    /// Drops the indexes not in the spec instead of reporting them.
+    pub drop_extra: bool,
+    /// Drops and creates again the indexes whose options differ from the spec.
+    pub recreate_mismatched: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexChange {
+    Unchanged(IndexSpec),
+    Create(IndexSpec),
+    /// Not in the spec. Dropped with `drop_extra`.
+    Extra {
+        existing: IndexSpec,
+        dropped: bool,
+    },
+    /// Options differ from the spec. Recreated with `recreate_mismatched`.
+    Mismatch {
+        existing: IndexSpec,
+        wanted: IndexSpec,
+        differences: Vec<&'static str>,
+        recreated: bool,
+    },
+}
+
+impl std::fmt::Display for IndexChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexChange::Unchanged(spec) => write!(f, "  ok      {}", spec.index_name()),
+            IndexChange::Create(spec) => write!(f, "+ create  {} {}", spec.index_name(), spec.keys),
+            IndexChange::Extra {
+                existing,
+                dropped: true,
+            } => write!(f, "- drop    {} {}", existing.index_name(), existing.keys),
+            IndexChange::Extra {
+                existing,
+                dropped: false,
+            } => write!(f, "? extra   {} {}", existing.index_name(), existing.keys),
+            IndexChange::Mismatch {
+                existing,
+                differences,
+                recreated,
+                ..
+            } => write!(
+                f,
+                "{} {}  {} differs: {}",
+                if *recreated {
+                    "~ recreate"
+                } else {
+                    "! mismatch"
+                },
+                existing.index_name(),
+                existing.keys,
+                differences.join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncReport {
+    pub collection: String,
+    pub changes: Vec<IndexChange>,
+    /// False on a dry run.
+    pub applied: bool,
+}
+
+impl SyncReport {
+    /// Whether the collection matches the spec after the sync.
+    pub fn in_sync(&self) -> bool {
+        self.applied
+            && self.changes.iter().all(|c| match c {
+                IndexChange::Extra { dropped, .. } => *dropped,
+                IndexChange::Mismatch { recreated, .. } => *recreated,
+                _ => true,
+            })
+    }
+}
+
+impl std::fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{}{}",
+            self.collection,
+            if self.applied { "" } else { " (dry run)" }
+        )?;
+        for change in &self.changes {
+            writeln!(f, "  {}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the wanted indexes with the existing ones. The `_id_` index is left out.
+pub fn plan(
+    wanted: &[IndexSpec],
+    existing: &[IndexSpec],
+    options: &SyncOptions,
+) -> Vec<IndexChange> {
+    let existing: Vec<&IndexSpec> = existing
+        .iter()
+        .filter(|e| e.index_name() != ID_INDEX_NAME)
+        .collect();
+    let mut matched = vec![false; existing.len()];
+    let mut changes = vec![];
+
+    for spec in wanted {
+        let found = existing.iter().position(|e| spec.same_keys(e)).or_else(|| {
+            existing
+                .iter()
+                .position(|e| e.index_name() == spec.index_name())
+        });
+        match found {
+            None => changes.push(IndexChange::Create(spec.clone())),
+            Some(i) => {
+                matched[i] = true;
+                let differences = spec.differences(existing[i]);
+                if differences.is_empty() {
+                    changes.push(IndexChange::Unchanged(spec.clone()));
+                } else {
+                    changes.push(IndexChange::Mismatch {
+                        existing: existing[i].clone(),
+                        wanted: spec.clone(),
+                        differences,
+                        recreated: options.recreate_mismatched,
+                    });
+                }
+            }
+        }
+    }
+
+    for (e, _) in existing.iter().zip(matched).filter(|(_, m)| !m) {
+        changes.push(IndexChange::Extra {
+            existing: (*e).clone(),
+            dropped: options.drop_extra,
+        });
+    }
+    changes
+}
+
+/// Makes the indexes of `coll` match `wanted` as far as `options` allows.
+pub async fn sync_collection<S: Storage>(
+    storage: &S,
+    coll: &str,
+    wanted: &[IndexSpec],
+    options: &SyncOptions,
+) -> DbResult<SyncReport> {
+    let existing = storage.list_indexes(coll).await?;
+    let changes = plan(wanted, &existing, options);
+    let mut report = SyncReport {
+        collection: coll.to_string(),
+        changes,
+        applied: false,
+    };
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    // drops first, since an index can't be created while another one has its name or keys.
+    for change in &report.changes {
+        match change {
+            IndexChange::Extra {
+                existing,
+                dropped: true,
+            }
+            | IndexChange::Mismatch {
+                existing,
+                recreated: true,
+                ..
+            } => storage.drop_index(coll, &existing.index_name()).await?,
+            _ => {}
+        }
+    }
+    for change in &report.changes {
+        match change {
+            IndexChange::Create(wanted)
+            | IndexChange::Mismatch {
+                wanted,
+                recreated: true,
+                ..
+            } => storage.create_index(coll, wanted).await?,
+            _ => {}
+        }
+    }
+    report.applied = true;
+    Ok(report)
+}
+
+/// Syncs every collection of `specs`.
+pub async fn sync<S: Storage>(
+    storage: &S,
+    specs: &IndexSpecs,
+    options: &SyncOptions,
+) -> DbResult<Vec<SyncReport>> {
+    let mut reports = vec![];
+    for (coll, wanted) in &specs.0 {
+        reports.push(sync_collection(storage, coll, wanted, options).await?);
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn existing(keys: Document, name: &str) -> IndexSpec {
+        IndexSpec::new(keys).named(name)
+    }
+
+    #[test]
+    fn default_names() {
+        assert_eq!(
+            IndexSpec::new(doc! {"name": 1, "opt": -1}).index_name(),
+            "name_1_opt_-1"
+        );
+        assert_eq!(
+            IndexSpec::new(doc! {"text": "text"}).index_name(),
+            "text_text"
+        );
+    }
+
+    #[test]
+    fn creates_missing_and_keeps_matching() {
+        let wanted = index_test_indexes();
+        let existing = vec![
+            existing(doc! {"_id": 1i32}, "_id_"),
+            existing(doc! {"id": 1i32}, "id_1").unique(),
+        ];
+        let changes = plan(&wanted, &existing, &SyncOptions::default());
+        assert_eq!(
+            changes,
+            vec![
+                IndexChange::Unchanged(wanted[0].clone()),
+                IndexChange::Create(wanted[1].clone()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_option_mismatches() {
+        let wanted = vec![IndexSpec::new(doc! {"at": 1}).sparse().expire_after(60)];
+        let existing = vec![existing(doc! {"at": 1i32}, "at_1").unique()];
+        let changes = plan(&wanted, &existing, &SyncOptions::default());
+        match &changes[..] {
+            [IndexChange::Mismatch {
+                differences,
+                recreated: false,
+                ..
+            }] => assert_eq!(differences, &vec!["unique", "sparse", "expire_after_secs"]),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extra_indexes_are_dropped_only_when_asked() {
+        let existing = vec![existing(doc! {"old": 1i32}, "old_1")];
+        let flagged = plan(&[], &existing, &SyncOptions::default());
+        assert!(matches!(
+            &flagged[..],
+            [IndexChange::Extra { dropped: false, .. }]
+        ));
+
+        let options = SyncOptions {
+            drop_extra: true,
+            ..SyncOptions::default()
+        };
+        let dropped = plan(&[], &existing, &options);
+        assert!(matches!(
+            &dropped[..],
+            [IndexChange::Extra { dropped: true, .. }]
+        ));
+    }
+
+    async fn names(storage: &MemoryStorage, coll: &str) -> Vec<String> {
+        let indexes = storage.list_indexes(coll).await.unwrap();
+        indexes.iter().map(|i| i.index_name()).collect()
+    }
+
+    #[tokio::test]
+    async fn dry_run_writes_nothing() {
+        let storage = MemoryStorage::new();
+        storage
+            .create_index("coll", &IndexSpec::new(doc! {"old": 1}))
+            .await
+            .unwrap();
+        let options = SyncOptions {
+            dry_run: true,
+            drop_extra: true,
+            recreate_mismatched: true,
+        };
+
+        let report = sync_collection(&storage, "coll", &index_test_indexes(), &options)
+            .await
+            .unwrap();
+        assert!(!report.applied);
+        assert!(!report.in_sync());
+        assert_eq!(report.changes.len(), 3);
+        assert_eq!(names(&storage, "coll").await, vec!["_id_", "old_1"]);
+    }
+
+    #[tokio::test]
+    async fn drops_extra_indexes() {
+        let storage = MemoryStorage::new();
+        storage
+            .create_index("coll", &IndexSpec::new(doc! {"old": 1}))
+            .await
+            .unwrap();
+        let wanted = vec![IndexSpec::new(doc! {"id": 1}).unique()];
+
+        let kept = sync_collection(&storage, "coll", &wanted, &SyncOptions::default())
+            .await
+            .unwrap();
+        assert!(!kept.in_sync());
+        assert_eq!(names(&storage, "coll").await, vec!["_id_", "old_1", "id_1"]);
+
+        let options = SyncOptions {
+            drop_extra: true,
+            ..SyncOptions::default()
+        };
+        let dropped = sync_collection(&storage, "coll", &wanted, &options)
+            .await
+            .unwrap();
+        assert!(dropped.in_sync());
+        assert_eq!(names(&storage, "coll").await, vec!["_id_", "id_1"]);
+    }
+
+    #[tokio::test]
+    async fn recreates_mismatched_indexes() {
+        let storage = MemoryStorage::new();
+        storage
+            .create_index("coll", &IndexSpec::new(doc! {"at": 1}))
+            .await
+            .unwrap();
+        let wanted = vec![IndexSpec::new(doc! {"at": 1}).expire_after(60)];
+        let options = SyncOptions {
+            recreate_mismatched: true,
+            ..SyncOptions::default()
+        };
+
+        let report = sync_collection(&storage, "coll", &wanted, &options)
+            .await
+            .unwrap();
+        assert!(report.in_sync());
+        let indexes = storage.list_indexes("coll").await.unwrap();
+        assert_eq!(indexes[1].expire_after_secs, Some(60));
+
+        let again = sync_collection(&storage, "coll", &wanted, &options)
+            .await
+            .unwrap();
+        assert!(matches!(&again.changes[..], [IndexChange::Unchanged(_)]));
+    }
+
+    #[test]
+    fn parses_yaml() {
+        let specs = IndexSpecs::from_yaml(
+            "index_test:\n  - keys: {name: 1, opt: 1}\n    unique: true\nsessions:\n  - keys: {at: 1}\n    expire_after_secs: 3600\n",
+        )
+        .unwrap();
+        let index_test = &specs.0["index_test"][0];
+        assert_eq!(index_test.index_name(), "name_1_opt_1");
+        assert!(index_test.same_keys(&index_test_indexes()[1]));
+        assert_eq!(specs.0["sessions"][0].expire_after_secs, Some(3600));
+    }
+}
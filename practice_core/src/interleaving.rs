@@ -0,0 +1,320 @@
+//! Scripted interleavings of concurrent actors, in place of sleeping until the other one is done.
+//!
+//! A [`Script`] lists named checkpoints of the actors in the order they must run, e.g.
+//! "T1 updates, T2 reads, T2 updates, T1 commits". Each actor waits at its checkpoint until
+//! every earlier checkpoint of the script is done.
+//!
+//! ```no_run
+//! # async fn run() -> practice_core::interleaving::ScriptResult<()> {
+//! use practice_core::interleaving::Script;
+//!
+//! let script = Script::new(&["T1: update", "T2: read", "T2: update", "T1: commit"]);
+//! let (mut t1, mut t2) = (script.actor("T1"), script.actor("T2"));
+//! let first = async {
+//!     t1.at("update", async { /* update in session 1 */ }).await?;
+//!     t1.at("commit", async { /* commit session 1 */ }).await
+//! };
+//! let second = async {
+//!     t2.at("read", async { /* read in session 2 */ }).await?;
+//!     t2.at("update", async { /* update in session 2 */ }).await
+//! };
+//! futures::try_join!(first, second)?;
+//! assert!(script.is_complete());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A checkpoint is done when the future given to [`Actor::at`] completes, or when the [`Step`]
+//! of [`Actor::step`] is dropped. Dropping the step before an operation that blocks, e.g.
+//! waiting for a lock, lets the next checkpoint run while the actor waits.
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::sync::Notify;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    #[error(
+        "{checkpoint} is not the next checkpoint of {actor} in the script, expected {expected:?}"
+    )]
+    NotInScript {
+        actor: String,
+        checkpoint: String,
+        expected: Option<Checkpoint>,
+    },
+    #[error("{waiting} can't run because {actor} left before its checkpoints")]
+    Abandoned { actor: String, waiting: Checkpoint },
+    #[error("{waiting} waited for {next} more than {timeout:?}")]
+    Timeout {
+        waiting: Checkpoint,
+        next: Checkpoint,
+        timeout: Duration,
+    },
+}
+
+pub type ScriptResult<T> = std::result::Result<T, ScriptError>;
+
+/// Named point of an actor in a script, written as `actor: name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub actor: String,
+    pub name: String,
+}
+
+impl Checkpoint {
+    fn parse(s: &str) -> Self {
+        let (actor, name) = s
+            .split_once(':')
+            .unwrap_or_else(|| panic!("checkpoint {:?} is not written as \"actor: name\"", s));
+        Checkpoint {
+            actor: actor.trim().to_string(),
+            name: name.trim().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.actor, self.name)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    /// Index of the checkpoint allowed to run.
+    next: usize,
+    abandoned_by: Option<String>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    checkpoints: Vec<Checkpoint>,
+    timeout: Duration,
+    progress: Mutex<Progress>,
+    changed: Notify,
+}
+
+impl Shared {
+    fn update(&self, f: impl FnOnce(&mut Progress)) {
+        f(&mut self.progress.lock().unwrap_or_else(|e| e.into_inner()));
+        self.changed.notify_waiters();
+    }
+
+    async fn wait_for_turn(&self, index: usize) -> ScriptResult<()> {
+        let waiting = &self.checkpoints[index];
+        let wait = async {
+            loop {
+                // created before checking, so that no change after the check is missed.
+                let changed = self.changed.notified();
+                {
+                    let progress = self.progress.lock().unwrap_or_else(|e| e.into_inner());
+                    if progress.next == index {
+                        return Ok(());
+                    }
+                    if let Some(actor) = &progress.abandoned_by {
+                        return Err(ScriptError::Abandoned {
+                            actor: actor.clone(),
+                            waiting: waiting.clone(),
+                        });
+                    }
+                }
+                changed.await;
+            }
+        };
+        match tokio::time::timeout(self.timeout, wait).await {
+            Ok(result) => result,
+            Err(_) => {
+                let next = self.progress.lock().unwrap_or_else(|e| e.into_inner()).next;
+                Err(ScriptError::Timeout {
+                    waiting: waiting.clone(),
+                    next: self.checkpoints[next].clone(),
+                    timeout: self.timeout,
+                })
+            }
+        }
+    }
+}
+
+/// Order of the checkpoints of all the actors.
+#[derive(Debug, Clone)]
+pub struct Script {
+    shared: Arc<Shared>,
+}
+
+impl Script {
+    /// Panics if a checkpoint is not written as `actor: name`.
+    pub fn new<S: AsRef<str>>(checkpoints: &[S]) -> Self {
+        Self::with_timeout(checkpoints, DEFAULT_TIMEOUT)
+    }
+
+    /// `timeout` is how long an actor waits at a checkpoint before giving up.
+    pub fn with_timeout<S: AsRef<str>>(checkpoints: &[S], timeout: Duration) -> Self {
+        Script {
+            shared: Arc::new(Shared {
+                checkpoints: checkpoints
+                    .iter()
+                    .map(|c| Checkpoint::parse(c.as_ref()))
+                    .collect(),
+                timeout,
+                progress: Mutex::new(Progress::default()),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn actor(&self, name: &str) -> Actor {
+        let checkpoints = self
+            .shared
+            .checkpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.actor == name)
+            .map(|(i, _)| i)
+            .collect();
+        Actor {
+            shared: self.shared.clone(),
+            name: name.to_string(),
+            checkpoints,
+            passed: 0,
+        }
+    }
+
+    /// Whether every checkpoint is done.
+    pub fn is_complete(&self) -> bool {
+        let progress = self
+            .shared
+            .progress
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        progress.next == self.shared.checkpoints.len()
+    }
+}
+
+/// One of the actors of a script. Dropping it before passing all its checkpoints fails the
+/// actors waiting for them.
+#[derive(Debug)]
+pub struct Actor {
+    shared: Arc<Shared>,
+    name: String,
+    /// Indexes of the checkpoints of this actor in the script.
+    checkpoints: Vec<usize>,
+    passed: usize,
+}
+
+impl Actor {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waits until `checkpoint` is the next one of the script. It is done when the step is dropped.
+    pub async fn step(&mut self, checkpoint: &str) -> ScriptResult<Step> {
+        let index = self.checkpoints.get(self.passed).copied();
+        let expected = index.map(|i| self.shared.checkpoints[i].clone());
+        let index = match index {
+            Some(index) if self.shared.checkpoints[index].name == checkpoint => index,
+            _ => {
+                return Err(ScriptError::NotInScript {
+                    actor: self.name.clone(),
+                    checkpoint: checkpoint.to_string(),
+                    expected,
+                })
+            }
+        };
+        self.passed += 1;
+        self.shared.wait_for_turn(index).await?;
+        Ok(Step {
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Runs `f` at `checkpoint`, which is done when `f` completes.
+    pub async fn at<F: Future>(&mut self, checkpoint: &str, f: F) -> ScriptResult<F::Output> {
+        let _step = self.step(checkpoint).await?;
+        Ok(f.await)
+    }
+}
+
+impl Drop for Actor {
+    fn drop(&mut self) {
+        if self.passed < self.checkpoints.len() {
+            let name = self.name.clone();
+            self.shared.update(|p| p.abandoned_by = Some(name));
+        }
+    }
+}
+
+/// Checkpoint being run. Dropping it lets the next checkpoint of the script run.
+#[derive(Debug)]
+pub struct Step {
+    shared: Arc<Shared>,
+}
+
+impl Drop for Step {
+    fn drop(&mut self) {
+        self.shared.update(|p| p.next += 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_checkpoints_in_script_order() {
+        let script = Script::new(&["T1: a", "T2: b", "T2: c", "T1: d"]);
+        let (mut t1, mut t2) = (script.actor("T1"), script.actor("T2"));
+        let log = Mutex::new(vec![]);
+        let push = |s: &'static str| log.lock().unwrap().push(s);
+
+        let first = async {
+            t1.at("a", async { push("a") }).await?;
+            t1.at("d", async { push("d") }).await
+        };
+        let second = async {
+            // would run first without the script.
+            t2.at("b", async { push("b") }).await?;
+            t2.at("c", async { push("c") }).await
+        };
+        futures::try_join!(second, first).unwrap();
+
+        assert_eq!(*log.lock().unwrap(), vec!["a", "b", "c", "d"]);
+        assert!(script.is_complete());
+    }
+
+    #[tokio::test]
+    async fn rejects_checkpoint_out_of_script() {
+        let script = Script::new(&["T1: a", "T1: b"]);
+        let mut t1 = script.actor("T1");
+        let result = t1.step("b").await;
+        assert!(
+            matches!(result, Err(ScriptError::NotInScript { expected: Some(ref c), .. }) if c.name == "a")
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_waiters_of_abandoned_actor() {
+        let script = Script::new(&["T1: a", "T2: b"]);
+        let t1 = script.actor("T1");
+        let mut t2 = script.actor("T2");
+        drop(t1);
+        let result = t2.step("b").await;
+        assert!(matches!(result, Err(ScriptError::Abandoned { ref actor, .. }) if actor == "T1"));
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let script = Script::with_timeout(&["T1: a", "T2: b"], Duration::from_millis(10));
+        let _t1 = script.actor("T1");
+        let mut t2 = script.actor("T2");
+        let result = t2.step("b").await;
+        assert!(matches!(result, Err(ScriptError::Timeout { ref next, .. }) if next.name == "a"));
+    }
+}
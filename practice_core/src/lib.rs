@@ -0,0 +1,40 @@
+//! Shared code of the mongodb practice binaries.
+//!
+//! Domain models, connection setup and transaction helpers live here so that
+//! each binary only has to pick the scenario it wants to run.
+
+pub mod book_stats;
+pub mod config;
+pub mod connection;
+pub mod consistency;
+pub mod error;
+pub mod fixtures;
+pub mod generator;
+pub mod indexes;
+pub mod interleaving;
+pub mod lock;
+pub mod metrics;
+pub mod models;
+pub mod outbox;
+pub mod replset;
+pub mod repository;
+pub mod review;
+pub mod scenario;
+pub mod storage;
+pub mod telemetry;
+pub mod tx;
+pub mod versioned;
+pub mod watch;
+pub mod workload;
+
+pub use config::Config;
+pub use connection::{connect, connect_with_handler, connect_with_handlers, CommandHandlers};
+pub use error::{DbError, DbResult};
+pub use models::{Book, IndexTest, Review, User};
+pub use repository::{BookRepository, UserRepository};
+pub use tx::{commit_tx, majority_tx_options, with_transaction, WithTransactionOptions};
+
+//just for convinience.
+pub fn s(s: &str) -> String {
+    s.to_string()
+}
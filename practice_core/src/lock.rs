@@ -0,0 +1,286 @@
+//! Pessimistic locking of a document inside a transaction.
+//!
+//! MongoDB has no "SELECT ... FOR UPDATE", but a transaction that writes a document makes every
+//! other transaction writing the same document fail with WriteConflict until it ends.
+//! [`lock_for_update`] writes a fresh value into a lock field of the document, so that the
+//! document is held by the transaction from then on, even if the transaction only reads it.
+//! see https://www.mongodb.com/blog/post/how-to-select--for-update-inside-mongodb-transactions
+//!
+//! The server aborts a transaction that got a WriteConflict, so waiting for a lock means
+//! restarting the transaction. The lock has to be taken as the first operation of the transaction.
+//!
+//! The functions take the [`Storage`] and the name of the collection, like the repositories.
+
+use std::time::{Duration, Instant};
+
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    error::Error,
+    options::TransactionOptions,
+};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::{
+    error::DbError,
+    repository::from_doc,
+    storage::Storage,
+    tx::{majority_tx_options, TxSession},
+};
+
+pub const DEFAULT_LOCK_FIELD: &str = "_lock";
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("no document matches the filter")]
+    NotFound,
+    /// Another transaction holds the document.
+    #[error("the document is locked by another transaction")]
+    Busy,
+    #[error("the document is still locked after waiting {waited:?} and {attempts} attempts")]
+    Timeout { waited: Duration, attempts: usize },
+    #[error(transparent)]
+    Db(#[from] DbError),
+}
+
+impl From<Error> for LockError {
+    fn from(e: Error) -> Self {
+        LockError::Db(e.into())
+    }
+}
+
+pub type LockResult<T> = std::result::Result<T, LockError>;
+
+#[derive(Debug, Clone)]
+pub struct LockOptions {
+    /// Field written to take the lock. It is left in the document after the transaction.
+    pub lock_field: String,
+    /// Total time [`lock_for_update`] waits for the lock.
+    pub wait: Duration,
+    /// Wait before the first retry. Doubled on each retry up to `max_retry_interval`.
+    pub retry_interval: Duration,
+    pub max_retry_interval: Duration,
+    /// Gives up after this many attempts even if `wait` has not passed yet.
+    pub max_attempts: Option<usize>,
+    /// Options of the transactions restarted while waiting.
+    pub transaction_options: TransactionOptions,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        LockOptions {
+            lock_field: DEFAULT_LOCK_FIELD.to_string(),
+            wait: Duration::from_secs(5),
+            retry_interval: Duration::from_millis(20),
+            max_retry_interval: Duration::from_millis(500),
+            max_attempts: None,
+            transaction_options: majority_tx_options(),
+        }
+    }
+}
+
+/// Takes the lock of the document matching `filter` once, and returns the document.
+///
+/// Fails with [`LockError::Busy`] if another transaction holds it. The transaction of
+/// `session` is aborted by the server in that case.
+pub async fn try_lock_for_update<S, T>(
+    storage: &S,
+    session: &mut S::Session,
+    coll: &str,
+    filter: Document,
+    options: &LockOptions,
+) -> LockResult<T>
+where
+    S: Storage,
+    T: DeserializeOwned,
+{
+    let lock = doc! {
+        "$set": {
+            &options.lock_field: {
+                "id": ObjectId::new(),
+                "at": DateTime::now(),
+            }
+        }
+    };
+    let result = storage
+        .find_one_and_update(coll, filter, lock, Some(session))
+        .await;
+    match result {
+        Ok(Some(locked)) => Ok(from_doc(locked)?),
+        Ok(None) => Err(LockError::NotFound),
+        Err(DbError::WriteConflict(_)) => Err(LockError::Busy),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Takes the lock of the document matching `filter`, waiting for the transaction holding it.
+///
+/// The transaction of `session` must be started and must not have done anything else,
+/// since it is aborted and started again on each retry. On [`LockError::Timeout`] it is
+/// aborted, so a new transaction can be started on `session` right away.
+pub async fn lock_for_update<S, T>(
+    storage: &S,
+    session: &mut S::Session,
+    coll: &str,
+    filter: Document,
+    options: &LockOptions,
+) -> LockResult<T>
+where
+    S: Storage,
+    T: DeserializeOwned,
+{
+    let started = Instant::now();
+    let mut interval = options.retry_interval;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match try_lock_for_update(storage, session, coll, filter.clone(), options).await {
+            Err(LockError::Busy) => {}
+            result => return result,
+        }
+
+        // the transaction is already aborted on the server, so the result is not interesting.
+        let _ = session.abort_transaction().await;
+        let out_of_attempts = options.max_attempts.is_some_and(|max| attempts >= max);
+        if out_of_attempts || started.elapsed() + interval > options.wait {
+            return Err(LockError::Timeout {
+                waited: started.elapsed(),
+                attempts,
+            });
+        }
+
+        tokio::time::sleep(interval).await;
+        interval = std::cmp::min(interval * 2, options.max_retry_interval);
+        session
+            .start_transaction(options.transaction_options.clone())
+            .await
+            .map_err(DbError::from)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemorySession, MemoryStorage};
+
+    const USERS: &str = "users";
+
+    async fn storage_with_user() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        storage
+            .insert_one(USERS, doc! {"id": "user_1", "name": "john"}, None)
+            .await
+            .unwrap();
+        storage
+    }
+
+    async fn start_tx(storage: &MemoryStorage) -> MemorySession {
+        let mut session = storage.start_session();
+        session
+            .start_transaction(majority_tx_options())
+            .await
+            .unwrap();
+        session
+    }
+
+    fn user_1() -> Document {
+        doc! {"id": "user_1"}
+    }
+
+    fn quick(max_attempts: Option<usize>, wait: Duration) -> LockOptions {
+        LockOptions {
+            wait,
+            retry_interval: Duration::from_millis(1),
+            max_retry_interval: Duration::from_millis(5),
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn second_transaction_is_busy() {
+        let storage = storage_with_user().await;
+        let mut holder = start_tx(&storage).await;
+        let mut other = start_tx(&storage).await;
+        let options = LockOptions::default();
+
+        let locked: Document =
+            try_lock_for_update(&storage, &mut holder, USERS, user_1(), &options)
+                .await
+                .unwrap();
+        assert!(locked.contains_key(DEFAULT_LOCK_FIELD));
+
+        let result: LockResult<Document> =
+            try_lock_for_update(&storage, &mut other, USERS, user_1(), &options).await;
+        assert!(matches!(result, Err(LockError::Busy)), "{:?}", result);
+        let result: LockResult<Document> =
+            try_lock_for_update(&storage, &mut holder, USERS, doc! {"id": "x"}, &options).await;
+        assert!(matches!(result, Err(LockError::NotFound)), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn waits_until_holder_commits() {
+        let storage = storage_with_user().await;
+        let mut holder = start_tx(&storage).await;
+        let mut waiter = start_tx(&storage).await;
+        let options = quick(None, Duration::from_secs(5));
+        let _: Document = try_lock_for_update(&storage, &mut holder, USERS, user_1(), &options)
+            .await
+            .unwrap();
+
+        let commit = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            holder.commit_transaction().await.unwrap();
+        };
+        let lock = lock_for_update::<_, Document>(&storage, &mut waiter, USERS, user_1(), &options);
+        let ((), locked) = futures::join!(commit, lock);
+        assert!(locked.is_ok(), "{:?}", locked);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let storage = storage_with_user().await;
+        let mut holder = start_tx(&storage).await;
+        let mut waiter = start_tx(&storage).await;
+        let options = quick(Some(3), Duration::from_secs(5));
+        let _: Document = try_lock_for_update(&storage, &mut holder, USERS, user_1(), &options)
+            .await
+            .unwrap();
+
+        let result: LockResult<Document> =
+            lock_for_update(&storage, &mut waiter, USERS, user_1(), &options).await;
+        assert!(
+            matches!(result, Err(LockError::Timeout { attempts: 3, .. })),
+            "{:?}",
+            result
+        );
+        // the transaction is aborted, so a new one can start.
+        waiter
+            .start_transaction(majority_tx_options())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_wait() {
+        let storage = storage_with_user().await;
+        let mut holder = start_tx(&storage).await;
+        let mut waiter = start_tx(&storage).await;
+        let options = quick(None, Duration::from_millis(30));
+        let _: Document = try_lock_for_update(&storage, &mut holder, USERS, user_1(), &options)
+            .await
+            .unwrap();
+
+        let result: LockResult<Document> =
+            lock_for_update(&storage, &mut waiter, USERS, user_1(), &options).await;
+        match result {
+            Err(LockError::Timeout { waited, attempts }) => {
+                assert!(waited < Duration::from_secs(1), "{:?}", waited);
+                assert!(attempts > 1);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // aborted, so there is nothing to commit.
+        assert!(waiter.commit_transaction().await.is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! Prometheus metrics of the database calls.
+//!
+//! The storage, the transaction helpers and the versioned updates record into [`global`], and
+//! [`MetricsHandler`] adds the commands and the connection pools the driver reports. [`serve`]
+//! exposes them at `/metrics` in the Prometheus text format:
+//!
+//! - `practice_operation_duration_seconds{op, coll}`: latency of the storage operations
+//! - `mongodb_command_duration_seconds{command}`: latency of the commands sent to the server
+//! - `mongodb_command_failures_total{command, code_name}`: failed commands, write conflicts
+//!   included as `code_name="WriteConflict"`
+//! - `practice_transactions_total{event, label}`: transactions committed, aborted and retried,
+//!   by the label of the error that aborted or retried them
+//! - `practice_version_conflicts_total{coll}` and `practice_version_retries_total{coll}`: stale
+//!   versions of the optimistic locking, and the read-modify-writes retried on them
+//! - `mongodb_pool_connections{address}` and `mongodb_pool_checked_out_connections{address}`:
+//!   connections open and in use, and `mongodb_pool_checkout_failures_total{address, reason}`
+
+mod server;
+
+pub use server::{serve, start_server};
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Mutex, MutexGuard, OnceLock},
+    time::Duration,
+};
+
+use mongodb::{
+    error::{ErrorKind, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    event::{
+        cmap::{
+            CmapEventHandler, ConnectionCheckedInEvent, ConnectionCheckedOutEvent,
+            ConnectionCheckoutFailedEvent, ConnectionClosedEvent, ConnectionCreatedEvent,
+            PoolClearedEvent,
+        },
+        command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent},
+    },
+};
+
+use crate::tx::ErrorLabels;
+
+/// Upper bounds of the latency buckets, in seconds.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxEvent {
+    Commit,
+    /// The transaction ended without committing, after a failure of its body or its commit.
+    Abort,
+    /// The transaction, or only its commit, is run again.
+    Retry,
+}
+
+impl TxEvent {
+    fn name(self) -> &'static str {
+        match self {
+            TxEvent::Commit => "commit",
+            TxEvent::Abort => "abort",
+            TxEvent::Retry => "retry",
+        }
+    }
+}
+
+/// The transaction label of `e` the metrics are counted by, or `none`.
+pub fn error_label<E: ErrorLabels>(e: &E) -> &'static str {
+    [
+        TRANSIENT_TRANSACTION_ERROR,
+        UNKNOWN_TRANSACTION_COMMIT_RESULT,
+    ]
+    .iter()
+    .find(|label| e.has_label(label))
+    .copied()
+    .unwrap_or("none")
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+fn labels(pairs: &[(&'static str, &str)]) -> Labels {
+    pairs
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+/// Cumulative counts of the observations under each of [`LATENCY_BUCKETS`].
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        self.buckets.resize(LATENCY_BUCKETS.len(), 0);
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= *le {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Families {
+    operations: BTreeMap<Labels, Histogram>,
+    commands: BTreeMap<Labels, Histogram>,
+    command_failures: BTreeMap<Labels, u64>,
+    transactions: BTreeMap<Labels, u64>,
+    version_conflicts: BTreeMap<Labels, u64>,
+    version_retries: BTreeMap<Labels, u64>,
+    pool_connections: BTreeMap<Labels, i64>,
+    pool_checked_out: BTreeMap<Labels, i64>,
+    pool_checkout_failures: BTreeMap<Labels, u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: Mutex<Families>,
+}
+
+/// The metrics the crate records into.
+pub fn global() -> &'static Metrics {
+    static GLOBAL: OnceLock<Metrics> = OnceLock::new();
+    GLOBAL.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    fn lock(&self) -> MutexGuard<'_, Families> {
+        // a panic while recording leaves the metrics usable.
+        self.families.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn observe_operation(&self, op: &str, coll: &str, latency: Duration) {
+        self.lock()
+            .operations
+            .entry(labels(&[("op", op), ("coll", coll)]))
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn observe_command(&self, command: &str, latency: Duration) {
+        self.lock()
+            .commands
+            .entry(labels(&[("command", command)]))
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn command_failed(&self, command: &str, code_name: &str) {
+        *self
+            .lock()
+            .command_failures
+            .entry(labels(&[("command", command), ("code_name", code_name)]))
+            .or_default() += 1;
+    }
+
+    /// Counts a transaction event, by the label of the error that caused it.
+    pub fn transaction(&self, event: TxEvent, label: &str) {
+        *self
+            .lock()
+            .transactions
+            .entry(labels(&[("event", event.name()), ("label", label)]))
+            .or_default() += 1;
+    }
+
+    pub fn version_conflict(&self, coll: &str) {
+        *self
+            .lock()
+            .version_conflicts
+            .entry(labels(&[("coll", coll)]))
+            .or_default() += 1;
+    }
+
+    pub fn version_retry(&self, coll: &str) {
+        *self
+            .lock()
+            .version_retries
+            .entry(labels(&[("coll", coll)]))
+            .or_default() += 1;
+    }
+
+    fn pool_gauge(
+        &self,
+        gauge: fn(&mut Families) -> &mut BTreeMap<Labels, i64>,
+        address: &str,
+        delta: i64,
+    ) {
+        *gauge(&mut self.lock())
+            .entry(labels(&[("address", address)]))
+            .or_default() += delta;
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let families = self.lock();
+        let mut out = String::new();
+        histograms(
+            &mut out,
+            "practice_operation_duration_seconds",
+            "Latency of the storage operations.",
+            &families.operations,
+        );
+        histograms(
+            &mut out,
+            "mongodb_command_duration_seconds",
+            "Latency of the commands sent to the server.",
+            &families.commands,
+        );
+        samples(
+            &mut out,
+            "mongodb_command_failures_total",
+            "counter",
+            "Commands failed, by error code name.",
+            &families.command_failures,
+        );
+        samples(
+            &mut out,
+            "practice_transactions_total",
+            "counter",
+            "Transactions committed, aborted and retried, by error label.",
+            &families.transactions,
+        );
+        samples(
+            &mut out,
+            "practice_version_conflicts_total",
+            "counter",
+            "Versioned writes rejected on a stale version.",
+            &families.version_conflicts,
+        );
+        samples(
+            &mut out,
+            "practice_version_retries_total",
+            "counter",
+            "Read-modify-writes retried after a version conflict.",
+            &families.version_retries,
+        );
+        samples(
+            &mut out,
+            "mongodb_pool_connections",
+            "gauge",
+            "Connections open in the pool.",
+            &families.pool_connections,
+        );
+        samples(
+            &mut out,
+            "mongodb_pool_checked_out_connections",
+            "gauge",
+            "Connections of the pool in use.",
+            &families.pool_checked_out,
+        );
+        samples(
+            &mut out,
+            "mongodb_pool_checkout_failures_total",
+            "counter",
+            "Connections that couldn't be checked out of the pool, by reason.",
+            &families.pool_checkout_failures,
+        );
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn samples<V: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    family: &BTreeMap<Labels, V>,
+) {
+    header(out, name, kind, help);
+    for (labels, value) in family {
+        let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+    }
+}
+
+fn histograms(out: &mut String, name: &str, help: &str, family: &BTreeMap<Labels, Histogram>) {
+    header(out, name, "histogram", help);
+    for (labels, histogram) in family {
+        for (le, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            let le = le.to_string();
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                format_labels(labels, Some(&le)),
+                count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{} {}",
+            name,
+            format_labels(labels, Some("+Inf")),
+            histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{} {}",
+            name,
+            format_labels(labels, None),
+            histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{} {}",
+            name,
+            format_labels(labels, None),
+            histogram.count
+        );
+    }
+}
+
+/// `{name="value",...}`, with `le` last for the buckets.
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Records the commands and the connection pools the driver reports into [`global`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsHandler;
+
+impl CommandEventHandler for MetricsHandler {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        global().observe_command(&event.command_name, event.duration);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        global().observe_command(&event.command_name, event.duration);
+        let code_name = match event.failure.kind.as_ref() {
+            ErrorKind::Command(e) => e.code_name.clone(),
+            ErrorKind::Write(_) => "WriteError".to_string(),
+            _ => "none".to_string(),
+        };
+        global().command_failed(&event.command_name, &code_name);
+    }
+}
+
+impl CmapEventHandler for MetricsHandler {
+    fn handle_connection_created_event(&self, event: ConnectionCreatedEvent) {
+        global().pool_gauge(|f| &mut f.pool_connections, &event.address.to_string(), 1);
+    }
+
+    fn handle_connection_closed_event(&self, event: ConnectionClosedEvent) {
+        global().pool_gauge(|f| &mut f.pool_connections, &event.address.to_string(), -1);
+    }
+
+    fn handle_connection_checked_out_event(&self, event: ConnectionCheckedOutEvent) {
+        global().pool_gauge(|f| &mut f.pool_checked_out, &event.address.to_string(), 1);
+    }
+
+    fn handle_connection_checked_in_event(&self, event: ConnectionCheckedInEvent) {
+        global().pool_gauge(|f| &mut f.pool_checked_out, &event.address.to_string(), -1);
+    }
+
+    fn handle_connection_checkout_failed_event(&self, event: ConnectionCheckoutFailedEvent) {
+        let reason = format!("{:?}", event.reason);
+        *global()
+            .lock()
+            .pool_checkout_failures
+            .entry(labels(&[
+                ("address", &event.address.to_string()),
+                ("reason", &reason),
+            ]))
+            .or_default() += 1;
+    }
+
+    fn handle_pool_cleared_event(&self, _event: PoolClearedEvent) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repository::to_doc,
+        storage::{MemoryStorage, Storage},
+        versioned::{modify_with_retry, replace_with_version, RetryOptions, VersionError},
+        Book,
+    };
+
+    #[test]
+    fn renders_the_text_format() {
+        let metrics = Metrics::default();
+        metrics.observe_operation("find_one", "books", Duration::from_millis(3));
+        metrics.observe_operation("find_one", "books", Duration::from_secs(20));
+        metrics.transaction(TxEvent::Retry, TRANSIENT_TRANSACTION_ERROR);
+        metrics.command_failed("update", "Write\"Conflict\"");
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in &[
+            "# TYPE practice_operation_duration_seconds histogram",
+            r#"practice_operation_duration_seconds_bucket{op="find_one",coll="books",le="0.0025"} 0"#,
+            r#"practice_operation_duration_seconds_bucket{op="find_one",coll="books",le="0.005"} 1"#,
+            r#"practice_operation_duration_seconds_bucket{op="find_one",coll="books",le="10"} 1"#,
+            r#"practice_operation_duration_seconds_bucket{op="find_one",coll="books",le="+Inf"} 2"#,
+            r#"practice_operation_duration_seconds_count{op="find_one",coll="books"} 2"#,
+            r#"practice_transactions_total{event="retry",label="TransientTransactionError"} 1"#,
+            r#"mongodb_command_failures_total{command="update",code_name="Write\"Conflict\""} 1"#,
+            "# TYPE mongodb_pool_connections gauge",
+        ] {
+            assert!(lines.contains(expected), "{} not in\n{}", expected, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_version_conflicts() {
+        let storage = MemoryStorage::new();
+        let coll = "metrics_books";
+        let book = Book {
+            id: "book_1".to_string(),
+            name: "name".to_string(),
+            reviews: vec![],
+            authors: vec![],
+            supervisors: vec![],
+            version: 1,
+        };
+        storage
+            .insert_one(coll, to_doc(&book).unwrap(), None)
+            .await
+            .unwrap();
+        let stale = book.clone();
+        replace_with_version::<_, Book>(&storage, coll, &book)
+            .await
+            .unwrap();
+        let result = replace_with_version::<_, Book>(&storage, coll, &stale).await;
+        assert!(matches!(result, Err(VersionError::VersionConflict { .. })));
+        modify_with_retry::<_, Book, _>(&storage, coll, "book_1", &RetryOptions::default(), |b| {
+            b.name = "renamed".to_string()
+        })
+        .await
+        .unwrap();
+
+        let text = global().render();
+        assert!(
+            text.contains(r#"practice_version_conflicts_total{coll="metrics_books"} 1"#),
+            "{}",
+            text
+        );
+        assert!(
+            !text.contains(r#"practice_version_retries_total{coll="metrics_books"}"#),
+            "{}",
+            text
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! A minimal HTTP/1.1 server answering `GET /metrics`, enough for a Prometheus scrape.
+
+use std::{io, net::SocketAddr};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::debug;
+
+use super::global;
+
+/// Requests with a longer head are refused.
+const MAX_HEAD: usize = 8 * 1024;
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Binds `addr` and serves [`global`] from a background task. Returns the bound address.
+pub async fn start_server(addr: &str) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    tokio::spawn(serve(listener));
+    Ok(local)
+}
+
+/// Serves [`global`] on the connections of `listener`, one task per connection.
+pub async fn serve(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                debug!(%peer, error = %e, "metrics request failed");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    let head = match read_head(&mut stream).await? {
+        Some(head) => head,
+        None => return write_response(&mut stream, "400 Bad Request", "bad request\n").await,
+    };
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next());
+    // the query string is ignored, like most exporters do.
+    let path = path.map(|p| p.split('?').next().unwrap_or_default());
+    match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            write_response(&mut stream, "200 OK", &global().render()).await
+        }
+        (Some("GET"), _) => write_response(&mut stream, "404 Not Found", "not found\n").await,
+        _ => {
+            write_response(
+                &mut stream,
+                "405 Method Not Allowed",
+                "method not allowed\n",
+            )
+            .await
+        }
+    }
+}
+
+/// The request line and the headers, or `None` when the client closes early or sends too much.
+async fn read_head(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_HEAD {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8(head).ok())
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::TxEvent;
+
+    async fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        global().transaction(TxEvent::Abort, "server_test");
+        let addr = start_server("127.0.0.1:0").await.unwrap();
+
+        let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(
+            body.contains(r#"practice_transactions_total{event="abort",label="server_test"} 1"#)
+        );
+
+        let response = get(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
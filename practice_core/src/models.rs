@@ -0,0 +1,65 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct IndexTest {
+    pub id: String,
+    pub name: String,
+    pub opt: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+    pub reviewed_book_ids: Vec<String>,
+}
+
+/// Fields other than `id` and `name` default to empty so that documents
+/// written by any of the scenarios can be read back.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Book {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub reviews: Vec<Review>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub supervisors: Vec<String>,
+    /// used by the optimistic lock scenario.
+    #[serde(default)]
+    pub version: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Review {
+    pub user_id: String,
+    pub text: String,
+    /// Missing on the reviews written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reviewed_at: Option<DateTime>,
+}
+
+/// Field names of [`User`], so that queries don't spell them as literals.
+pub mod user_fields {
+    pub const ID: &str = "id";
+    pub const NAME: &str = "name";
+    pub const REVIEWED_BOOK_IDS: &str = "reviewed_book_ids";
+}
+
+/// Field names of [`Book`], so that queries don't spell them as literals.
+pub mod book_fields {
+    pub const ID: &str = "id";
+    pub const NAME: &str = "name";
+    pub const REVIEWS: &str = "reviews";
+    pub const AUTHORS: &str = "authors";
+    pub const SUPERVISORS: &str = "supervisors";
+    pub const VERSION: &str = "version";
+}
+
+/// Field names of [`Review`].
+pub mod review_fields {
+    pub const USER_ID: &str = "user_id";
+    pub const TEXT: &str = "text";
+}
@@ -0,0 +1,316 @@
+//! Transactional outbox of the domain events.
+//!
+//! A review is published by inserting a [`DomainEvent::ReviewAdded`] into [`OUTBOX_COLL`] in the
+//! transaction that writes the review, so the event exists exactly when the review is committed.
+//! A [`Relay`] then claims the events one at a time with `find_one_and_update`, passes them to a
+//! [`Sink`] and marks them done.
+//!
+//! Delivery is at least once: a claim is a lease, and the event of a relay that stops between
+//! the delivery and the mark is claimed again once the lease expires. Receivers deduplicate on
+//! the id of the event, which stays the same across deliveries.
+//!
+//! ```ignore
+//! let outbox = Outbox::new(MongoStorage::new(&db));
+//! let relay = Relay::new(outbox, SinkSpec::Stdout.open()?, RelayOptions::default());
+//! let report = relay.drain().await?;
+//! ```
+
+mod relay;
+mod sink;
+
+pub use relay::{Relay, RelayOptions, RelayReport};
+pub use sink::{FileSink, Sink, SinkError, SinkSpec, StdoutSink, WebhookSink};
+
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::DbResult,
+    repository::{from_doc, to_doc},
+    storage::Storage,
+};
+
+pub const OUTBOX_COLL: &str = "outbox";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DomainEvent {
+    ReviewAdded {
+        book_id: String,
+        user_id: String,
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboxStatus {
+    Pending,
+    /// Claimed by a relay until `lease_until`.
+    Processing,
+    Done,
+    /// Gave up after [`RelayOptions::max_attempts`].
+    Failed,
+}
+
+impl OutboxStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            OutboxStatus::Pending => "pending",
+            OutboxStatus::Processing => "processing",
+            OutboxStatus::Done => "done",
+            OutboxStatus::Failed => "failed",
+        }
+    }
+}
+
+/// A document of the outbox.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct OutboxEvent {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub event: DomainEvent,
+    pub status: OutboxStatus,
+    /// Deliveries started, the current one included.
+    pub attempts: i32,
+    pub created_at: DateTime,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+impl OutboxEvent {
+    /// The message the sinks deliver: the id and the time of the event, and the event.
+    pub fn to_json(&self) -> serde_json::Value {
+        let created_at = Utc.timestamp_millis(self.created_at.timestamp_millis());
+        json!({
+            "id": self.id.to_hex(),
+            "created_at": created_at.to_rfc3339(),
+            "attempt": self.attempts,
+            "event": self.event,
+        })
+    }
+}
+
+fn at(from: DateTime, after: Duration) -> DateTime {
+    DateTime::from_millis(from.timestamp_millis() + after.as_millis() as i64)
+}
+
+/// The events of [`OUTBOX_COLL`].
+#[derive(Debug, Clone)]
+pub struct Outbox<S> {
+    storage: S,
+}
+
+impl<S: Storage> Outbox<S> {
+    pub fn new(storage: S) -> Self {
+        Outbox { storage }
+    }
+
+    /// Adds `event`, in the transaction of `session` if any.
+    pub async fn enqueue(
+        &self,
+        event: &DomainEvent,
+        session: Option<&mut S::Session>,
+    ) -> DbResult<ObjectId> {
+        let id = ObjectId::new();
+        let now = DateTime::now();
+        let doc = doc! {
+            "_id": id,
+            "event": to_doc(event)?,
+            "status": OutboxStatus::Pending.as_str(),
+            "attempts": 0,
+            "created_at": now,
+            "available_at": now,
+        };
+        self.storage.insert_one(OUTBOX_COLL, doc, session).await?;
+        Ok(id)
+    }
+
+    /// Claims an event for `lease`: a pending one whose retry time has come, or one whose
+    /// claim expired. `None` if there is none.
+    pub async fn claim(&self, relay_id: &str, lease: Duration) -> DbResult<Option<OutboxEvent>> {
+        let now = DateTime::now();
+        let filter = doc! {"$or": [
+            {"status": OutboxStatus::Pending.as_str(), "available_at": {"$lte": now}},
+            {"status": OutboxStatus::Processing.as_str(), "lease_until": {"$lt": now}},
+        ]};
+        let update = doc! {
+            "$set": {
+                "status": OutboxStatus::Processing.as_str(),
+                "claimed_by": relay_id,
+                "lease_until": at(now, lease),
+            },
+            "$inc": {"attempts": 1},
+        };
+        let claimed = self
+            .storage
+            .find_one_and_update(OUTBOX_COLL, filter, update, None)
+            .await?;
+        claimed.map(from_doc).transpose()
+    }
+
+    pub async fn complete(&self, id: ObjectId) -> DbResult<()> {
+        let update = doc! {
+            "$set": {"status": OutboxStatus::Done.as_str(), "delivered_at": DateTime::now()},
+            "$unset": {"lease_until": "", "claimed_by": ""},
+        };
+        self.storage
+            .update_one(OUTBOX_COLL, doc! {"_id": id}, update, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Gives a failed delivery back, to be claimed again at `retry_at`, or marks the event
+    /// failed if `retry_at` is `None`.
+    pub async fn release(
+        &self,
+        id: ObjectId,
+        error: &str,
+        retry_at: Option<DateTime>,
+    ) -> DbResult<()> {
+        let set = match retry_at {
+            Some(retry_at) => doc! {
+                "status": OutboxStatus::Pending.as_str(),
+                "available_at": retry_at,
+                "last_error": error,
+            },
+            None => doc! {"status": OutboxStatus::Failed.as_str(), "last_error": error},
+        };
+        let update = doc! {"$set": set, "$unset": {"lease_until": "", "claimed_by": ""}};
+        self.storage
+            .update_one(OUTBOX_COLL, doc! {"_id": id}, update, None)
+            .await?;
+        Ok(())
+    }
+
+    /// The events in `status`, oldest first.
+    pub async fn events(&self, status: OutboxStatus) -> DbResult<Vec<OutboxEvent>> {
+        let found = self
+            .storage
+            .find(
+                OUTBOX_COLL,
+                doc! {"status": Bson::from(status.as_str())},
+                None,
+            )
+            .await?;
+        let mut events = found
+            .into_iter()
+            .map(from_doc)
+            .collect::<DbResult<Vec<OutboxEvent>>>()?;
+        events.sort_by_key(|e| e.id);
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::MemoryStorage, tx::majority_tx_options, tx::TxSession};
+
+    fn review_added(book_id: &str) -> DomainEvent {
+        DomainEvent::ReviewAdded {
+            book_id: book_id.to_string(),
+            user_id: "user_1".to_string(),
+            text: "good".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn enqueues_with_the_transaction() {
+        let storage = MemoryStorage::new();
+        let outbox = Outbox::new(storage.clone());
+        let mut session = storage.start_session();
+
+        session
+            .start_transaction(majority_tx_options())
+            .await
+            .unwrap();
+        outbox
+            .enqueue(&review_added("book_1"), Some(&mut session))
+            .await
+            .unwrap();
+        session.abort_transaction().await.unwrap();
+        assert!(outbox
+            .events(OutboxStatus::Pending)
+            .await
+            .unwrap()
+            .is_empty());
+
+        session
+            .start_transaction(majority_tx_options())
+            .await
+            .unwrap();
+        outbox
+            .enqueue(&review_added("book_2"), Some(&mut session))
+            .await
+            .unwrap();
+        session.commit_transaction().await.unwrap();
+        let pending = outbox.events(OutboxStatus::Pending).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].event, review_added("book_2"));
+    }
+
+    #[tokio::test]
+    async fn claims_each_event_once_until_its_lease_expires() {
+        let outbox = Outbox::new(MemoryStorage::new());
+        let id = outbox.enqueue(&review_added("book_1"), None).await.unwrap();
+
+        let claimed = outbox
+            .claim("relay_1", Duration::from_secs(60))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((claimed.id, claimed.attempts), (id, 1));
+        assert_eq!(claimed.status, OutboxStatus::Processing);
+        let again = outbox.claim("relay_2", Duration::from_secs(60)).await;
+        assert_eq!(again.unwrap(), None);
+
+        // an expired lease is taken over.
+        outbox
+            .release(id, "boom", Some(DateTime::now()))
+            .await
+            .unwrap();
+        let claimed = outbox
+            .claim("relay_1", Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.last_error.as_deref(), Some("boom"));
+        std::thread::sleep(Duration::from_millis(2));
+        let taken_over = outbox
+            .claim("relay_2", Duration::from_secs(60))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(taken_over.attempts, 3);
+
+        outbox.complete(id).await.unwrap();
+        assert_eq!(outbox.claim("relay_1", Duration::ZERO).await.unwrap(), None);
+        assert_eq!(outbox.events(OutboxStatus::Done).await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn formats_messages() {
+        let event = OutboxEvent {
+            id: ObjectId::parse_str("6155b2f3a1b2c3d4e5f60718").unwrap(),
+            event: review_added("book_1"),
+            status: OutboxStatus::Processing,
+            attempts: 1,
+            created_at: DateTime::from_millis(1_633_046_400_000),
+            last_error: None,
+        };
+        assert_eq!(
+            event.to_json(),
+            json!({
+                "id": "6155b2f3a1b2c3d4e5f60718",
+                "created_at": "2021-10-01T00:00:00+00:00",
+                "attempt": 1,
+                "event": {"type": "ReviewAdded", "book_id": "book_1", "user_id": "user_1", "text": "good"},
+            })
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! The worker moving the events of the outbox to a sink.
+
+use std::{cmp, fmt, time::Duration};
+
+use mongodb::bson::{oid::ObjectId, DateTime};
+use tracing::{debug, info, warn};
+
+use super::{at, Outbox, OutboxEvent, Sink};
+use crate::{error::DbResult, storage::Storage};
+
+#[derive(Debug, Clone)]
+pub struct RelayOptions {
+    /// Recorded in the claims, to tell which relay holds an event.
+    pub relay_id: String,
+    /// How long a claimed event is left to this relay. Longer than a delivery may take.
+    pub lease: Duration,
+    /// Wait of [`Relay::run`] when there is nothing to deliver.
+    pub poll_interval: Duration,
+    /// Wait before the first retry of a failed delivery. Doubled on each attempt up to
+    /// `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Deliveries tried before the event is marked failed.
+    pub max_attempts: i32,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        RelayOptions {
+            relay_id: format!("relay-{}", ObjectId::new()),
+            lease: Duration::from_secs(30),
+            poll_interval: Duration::from_secs(1),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_attempts: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayReport {
+    pub delivered: usize,
+    /// Deliveries failed and left to retry.
+    pub retried: usize,
+    /// Events given up on.
+    pub failed: usize,
+}
+
+impl fmt::Display for RelayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "delivered {}, retried {}, failed {}",
+            self.delivered, self.retried, self.failed
+        )
+    }
+}
+
+pub struct Relay<S, K> {
+    outbox: Outbox<S>,
+    sink: K,
+    options: RelayOptions,
+}
+
+impl<S: Storage, K: Sink> Relay<S, K> {
+    pub fn new(outbox: Outbox<S>, sink: K, options: RelayOptions) -> Self {
+        Relay {
+            outbox,
+            sink,
+            options,
+        }
+    }
+
+    /// Delivers the events until none can be claimed. Failed deliveries are released for a
+    /// later retry rather than retried here.
+    pub async fn drain(&self) -> DbResult<RelayReport> {
+        let mut report = RelayReport::default();
+        while let Some(event) = self
+            .outbox
+            .claim(&self.options.relay_id, self.options.lease)
+            .await?
+        {
+            self.deliver(event, &mut report).await?;
+        }
+        Ok(report)
+    }
+
+    /// Drains the outbox every `poll_interval`, until it fails on the database.
+    pub async fn run(&self) -> DbResult<()> {
+        loop {
+            let report = self.drain().await?;
+            if report != RelayReport::default() {
+                info!(
+                    delivered = report.delivered,
+                    retried = report.retried,
+                    failed = report.failed,
+                    "relayed the outbox"
+                );
+            }
+            tokio::time::sleep(self.options.poll_interval).await;
+        }
+    }
+
+    async fn deliver(&self, event: OutboxEvent, report: &mut RelayReport) -> DbResult<()> {
+        match self.sink.deliver(&event).await {
+            Ok(()) => {
+                debug!(id = %event.id, attempt = event.attempts, "delivered an event");
+                self.outbox.complete(event.id).await?;
+                report.delivered += 1;
+            }
+            Err(e) if event.attempts < self.options.max_attempts => {
+                let retry_at = at(DateTime::now(), self.backoff(event.attempts));
+                warn!(id = %event.id, attempt = event.attempts, error = %e, "delivery failed, retrying later");
+                self.outbox
+                    .release(event.id, &e.to_string(), Some(retry_at))
+                    .await?;
+                report.retried += 1;
+            }
+            Err(e) => {
+                warn!(id = %event.id, attempt = event.attempts, error = %e, "delivery failed, giving up");
+                self.outbox.release(event.id, &e.to_string(), None).await?;
+                report.failed += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait after the `attempt`th failed delivery.
+    fn backoff(&self, attempt: i32) -> Duration {
+        let doublings = cmp::min(attempt.saturating_sub(1), 16) as u32;
+        cmp::min(
+            self.options.initial_backoff * 2u32.pow(doublings),
+            self.options.max_backoff,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        outbox::{DomainEvent, OutboxStatus, SinkError},
+        storage::MemoryStorage,
+    };
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    /// Fails the first `failures` deliveries, then keeps the ids of the delivered events.
+    #[derive(Default)]
+    struct FlakySink {
+        failures: Mutex<usize>,
+        delivered: Mutex<Vec<ObjectId>>,
+    }
+
+    #[async_trait]
+    impl Sink for FlakySink {
+        async fn deliver(&self, event: &OutboxEvent) -> Result<(), SinkError> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(SinkError::Status("503 Service Unavailable".to_string()));
+            }
+            self.delivered.lock().unwrap().push(event.id);
+            Ok(())
+        }
+    }
+
+    fn options(max_attempts: i32) -> RelayOptions {
+        RelayOptions {
+            initial_backoff: Duration::ZERO,
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    async fn outbox_with(events: usize) -> Outbox<MemoryStorage> {
+        let outbox = Outbox::new(MemoryStorage::new());
+        for i in 0..events {
+            let event = DomainEvent::ReviewAdded {
+                book_id: format!("book_{}", i),
+                user_id: "user_1".to_string(),
+                text: "good".to_string(),
+            };
+            outbox.enqueue(&event, None).await.unwrap();
+        }
+        outbox
+    }
+
+    #[tokio::test]
+    async fn delivers_and_marks_done() {
+        let outbox = outbox_with(3).await;
+        let relay = Relay::new(outbox.clone(), FlakySink::default(), options(3));
+
+        let report = relay.drain().await.unwrap();
+        assert_eq!(report.delivered, 3);
+        let done = outbox.events(OutboxStatus::Done).await.unwrap();
+        let ids: Vec<_> = done.iter().map(|e| e.id).collect();
+        assert_eq!(*relay.sink.delivered.lock().unwrap(), ids);
+        assert_eq!(relay.drain().await.unwrap(), RelayReport::default());
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries_then_gives_up() {
+        let outbox = outbox_with(1).await;
+        let sink = FlakySink {
+            failures: Mutex::new(1),
+            ..Default::default()
+        };
+        let relay = Relay::new(outbox.clone(), sink, options(3));
+        let report = relay.drain().await.unwrap();
+        assert_eq!((report.retried, report.delivered), (1, 1));
+        let done = outbox.events(OutboxStatus::Done).await.unwrap();
+        assert_eq!(done[0].attempts, 2);
+
+        let outbox = outbox_with(1).await;
+        let sink = FlakySink {
+            failures: Mutex::new(5),
+            ..Default::default()
+        };
+        let relay = Relay::new(outbox.clone(), sink, options(2));
+        let report = relay.drain().await.unwrap();
+        assert_eq!((report.retried, report.failed), (1, 1));
+        let failed = outbox.events(OutboxStatus::Failed).await.unwrap();
+        assert_eq!(
+            failed[0].last_error.as_deref(),
+            Some("webhook answered 503 Service Unavailable")
+        );
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let relay = Relay::new(
+            Outbox::new(MemoryStorage::new()),
+            FlakySink::default(),
+            RelayOptions {
+                initial_backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(5),
+                ..Default::default()
+            },
+        );
+        let backoffs: Vec<_> = (1..=5).map(|a| relay.backoff(a).as_secs()).collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 5, 5]);
+    }
+}
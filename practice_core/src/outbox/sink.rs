@@ -0,0 +1,294 @@
+//! Where the relay delivers the events: a JSON message per event.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::OutboxEvent;
+
+#[derive(Error, Debug)]
+pub enum SinkError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("webhook answered {0}")]
+    Status(String),
+    #[error("webhook timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("invalid webhook url {0}, expected http://host:port/path")]
+    Url(String),
+}
+
+/// Receives the events. Returns once the event is delivered for good: the relay marks it done then.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    async fn deliver(&self, event: &OutboxEvent) -> Result<(), SinkError>;
+}
+
+#[async_trait]
+impl Sink for Box<dyn Sink> {
+    async fn deliver(&self, event: &OutboxEvent) -> Result<(), SinkError> {
+        (**self).deliver(event).await
+    }
+}
+
+/// Prints a line per event.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdoutSink;
+
+#[async_trait]
+impl Sink for StdoutSink {
+    async fn deliver(&self, event: &OutboxEvent) -> Result<(), SinkError> {
+        let mut stdout = io::stdout();
+        writeln!(stdout, "{}", event.to_json())?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// Appends a line per event to a file.
+#[derive(Debug)]
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    async fn deliver(&self, event: &OutboxEvent) -> Result<(), SinkError> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(file, "{}", event.to_json())?;
+        // the event is marked done after this, so it has to be on the disk first.
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// POSTs each event to a plain HTTP endpoint, like a webhook receiver on the local machine.
+///
+/// A 2xx answer delivers the event. The id of the event is sent as `Idempotency-Key`, for the
+/// receiver to drop the events delivered again.
+#[derive(Debug, Clone)]
+pub struct WebhookSink {
+    /// `host:port`
+    authority: String,
+    path: String,
+    timeout: Duration,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> Result<Self, SinkError> {
+        let invalid = || SinkError::Url(url.to_string());
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid());
+        }
+        let authority = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        Ok(WebhookSink {
+            authority,
+            path: path.to_string(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn post(&self, event: &OutboxEvent) -> Result<(), SinkError> {
+        let body = event.to_json().to_string();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nIdempotency-Key: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.authority,
+            body.len(),
+            event.id.to_hex(),
+            body
+        );
+        let mut stream = TcpStream::connect(&self.authority).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        let response = String::from_utf8_lossy(&response);
+        let status = response
+            .lines()
+            .next()
+            .and_then(|line| line.split_once(' '))
+            .map(|(_, status)| status)
+            .unwrap_or_default();
+        if status.starts_with('2') {
+            Ok(())
+        } else {
+            Err(SinkError::Status(status.to_string()))
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn deliver(&self, event: &OutboxEvent) -> Result<(), SinkError> {
+        tokio::time::timeout(self.timeout, self.post(event))
+            .await
+            .map_err(|_| SinkError::Timeout(self.timeout))?
+    }
+}
+
+/// A sink as given on the command line: `stdout`, `file:<path>` or `http://host:port/path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkSpec {
+    Stdout,
+    File(PathBuf),
+    Webhook(String),
+}
+
+impl SinkSpec {
+    pub fn open(&self) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(match self {
+            SinkSpec::Stdout => Box::new(StdoutSink),
+            SinkSpec::File(path) => Box::new(FileSink::open(path)?),
+            SinkSpec::Webhook(url) => Box::new(WebhookSink::new(url)?),
+        })
+    }
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stdout" {
+            Ok(SinkSpec::Stdout)
+        } else if let Some(path) = s.strip_prefix("file:") {
+            Ok(SinkSpec::File(PathBuf::from(path)))
+        } else if s.starts_with("http://") {
+            WebhookSink::new(s).map_err(|e| e.to_string())?;
+            Ok(SinkSpec::Webhook(s.to_string()))
+        } else {
+            Err(format!(
+                "unknown sink {:?}, expected stdout, file:<path> or http://host:port/path",
+                s
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::{DomainEvent, OutboxStatus};
+    use mongodb::bson::{oid::ObjectId, DateTime};
+    use tokio::net::TcpListener;
+
+    fn event() -> OutboxEvent {
+        OutboxEvent {
+            id: ObjectId::new(),
+            event: DomainEvent::ReviewAdded {
+                book_id: "book_1".to_string(),
+                user_id: "user_1".to_string(),
+                text: "good".to_string(),
+            },
+            status: OutboxStatus::Processing,
+            attempts: 1,
+            created_at: DateTime::now(),
+            last_error: None,
+        }
+    }
+
+    /// Answers one request with `status`, and returns the request.
+    async fn receiver(status: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/reviews", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let n = stream.read(&mut request).await.unwrap();
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..n]).into_owned()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn posts_to_webhooks() {
+        let event = event();
+        let (url, request) = receiver("204 No Content").await;
+        WebhookSink::new(&url)
+            .unwrap()
+            .deliver(&event)
+            .await
+            .unwrap();
+        let request = request.await.unwrap();
+        assert!(
+            request.starts_with("POST /hooks/reviews HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        assert!(request.contains(&format!("Idempotency-Key: {}\r\n", event.id.to_hex())));
+        assert!(request.ends_with(&event.to_json().to_string()));
+
+        let (url, _) = receiver("500 Internal Server Error").await;
+        let result = WebhookSink::new(&url).unwrap().deliver(&event).await;
+        assert!(
+            matches!(result, Err(SinkError::Status(ref s)) if s == "500 Internal Server Error")
+        );
+    }
+
+    #[tokio::test]
+    async fn appends_to_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let sink = FileSink::open(&path).unwrap();
+        sink.deliver(&event()).await.unwrap();
+        sink.deliver(&event()).await.unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+    }
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!("stdout".parse(), Ok(SinkSpec::Stdout));
+        assert_eq!(
+            "file:out/events.jsonl".parse(),
+            Ok(SinkSpec::File(PathBuf::from("out/events.jsonl")))
+        );
+        assert_eq!(
+            "http://localhost:8080/hook".parse(),
+            Ok(SinkSpec::Webhook("http://localhost:8080/hook".to_string()))
+        );
+        assert!("https://example.com".parse::<SinkSpec>().is_err());
+        assert!("http:///hook".parse::<SinkSpec>().is_err());
+        assert_eq!(
+            WebhookSink::new("http://localhost").unwrap().authority,
+            "localhost:80"
+        );
+    }
+}
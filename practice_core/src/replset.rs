@@ -0,0 +1,397 @@
+//! Replica set of local `mongod` processes, in place of `docker-compose.yml`.
+//!
+//! Only a `mongod` binary is needed, on `PATH` or at `MONGOD`. Each node listens on a free port
+//! of 127.0.0.1 with a temporary data directory, so no `/etc/hosts` entry is needed either.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use practice_core::{connect, replset::{LocalReplicaSet, ReplicaSetOptions}};
+//!
+//! let replica_set = LocalReplicaSet::start(&ReplicaSetOptions::default()).await?;
+//! let client = connect(&replica_set.config()).await?;
+//! // the processes are killed and the data directories removed on drop.
+//! drop(replica_set);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use mongodb::{
+    bson::{doc, Document},
+    error::ErrorKind,
+    Client,
+};
+use tempfile::TempDir;
+use thiserror::Error;
+
+use crate::config::{Config, Host};
+
+pub const ENV_MONGOD: &str = "MONGOD";
+
+const LOCALHOST: &str = "127.0.0.1";
+const ALREADY_INITIALIZED_CODE: i32 = 23;
+/// Bytes of the log shown when a node exits.
+const LOG_TAIL_LEN: u64 = 2000;
+/// Spawns of a node, each on a new port, when another process takes its port first.
+const SPAWN_ATTEMPTS: usize = 3;
+const ADDRESS_IN_USE: &str = "Address already in use";
+
+#[derive(Error, Debug)]
+pub enum LaunchError {
+    #[error("a replica set needs at least one node")]
+    NoNodes,
+    #[error("failed to create the data directory: {0}")]
+    DataDir(#[source] io::Error),
+    #[error("failed to start {mongod}: {source}")]
+    Spawn {
+        mongod: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("mongod on port {port} exited with {status}:\n{log}")]
+    Exited {
+        port: u16,
+        status: ExitStatus,
+        log: String,
+    },
+    #[error("mongod on port {port} didn't accept connections in {timeout:?}")]
+    NotListening { port: u16, timeout: Duration },
+    #[error("failed to initiate the replica set: {0}")]
+    Initiate(#[source] mongodb::error::Error),
+    #[error("no primary was elected in {0:?}")]
+    NoPrimary(Duration),
+}
+
+pub type LaunchResult<T> = std::result::Result<T, LaunchError>;
+
+#[derive(Debug, Clone)]
+pub struct ReplicaSetOptions {
+    pub nodes: usize,
+    pub name: String,
+    /// Defaults to `MONGOD`, then to `mongod` on `PATH`.
+    pub mongod: Option<PathBuf>,
+    /// How long to wait for each node to listen and for the primary to be elected.
+    pub startup_timeout: Duration,
+}
+
+impl Default for ReplicaSetOptions {
+    fn default() -> Self {
+        ReplicaSetOptions {
+            nodes: 3,
+            name: "my-replica-set".to_string(),
+            mongod: None,
+            startup_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReplicaSetOptions {
+    fn mongod(&self) -> PathBuf {
+        self.mongod
+            .clone()
+            .or_else(|| std::env::var_os(ENV_MONGOD).map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from("mongod"))
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    child: Child,
+    port: u16,
+    log_path: PathBuf,
+}
+
+impl Node {
+    fn addr(&self) -> String {
+        format!("{}:{}", LOCALHOST, self.port)
+    }
+
+    /// Fails if the process has exited.
+    fn check_running(&mut self) -> LaunchResult<()> {
+        match self.child.try_wait() {
+            Ok(Some(status)) => Err(LaunchError::Exited {
+                port: self.port,
+                status,
+                log: log_tail(&self.log_path),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Running replica set. Dropping it kills the processes and removes the data directories.
+#[derive(Debug)]
+pub struct LocalReplicaSet {
+    name: String,
+    nodes: Vec<Node>,
+    // removed after the nodes are killed.
+    data_dir: TempDir,
+}
+
+impl LocalReplicaSet {
+    /// Starts the nodes, initiates the replica set and waits until a primary is elected.
+    pub async fn start(options: &ReplicaSetOptions) -> LaunchResult<Self> {
+        if options.nodes == 0 {
+            return Err(LaunchError::NoNodes);
+        }
+        let data_dir = tempfile::Builder::new()
+            .prefix("mongo-practice-")
+            .tempdir()
+            .map_err(LaunchError::DataDir)?;
+        let mut replica_set = LocalReplicaSet {
+            name: options.name.clone(),
+            nodes: vec![],
+            data_dir,
+        };
+
+        for i in 0..options.nodes {
+            let node = replica_set.start_node(options, i).await?;
+            replica_set.nodes.push(node);
+        }
+        replica_set.initiate().await?;
+        replica_set
+            .wait_for_primary(options.startup_timeout)
+            .await?;
+        Ok(replica_set)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn hosts(&self) -> Vec<Host> {
+        self.nodes
+            .iter()
+            .map(|n| Host {
+                host: LOCALHOST.to_string(),
+                port: n.port,
+            })
+            .collect()
+    }
+
+    /// Settings to connect to the replica set, with the other settings left to the defaults.
+    pub fn config(&self) -> Config {
+        Config {
+            hosts: self.hosts(),
+            replica_set: Some(self.name.clone()),
+            ..Config::default()
+        }
+    }
+
+    pub fn connection_string(&self) -> String {
+        self.config().connection_string()
+    }
+
+    /// Spawns the node `i` and waits until it listens.
+    ///
+    /// The port from [`free_port`] can be taken by another process before `mongod` binds it,
+    /// so the node is spawned again on another port when it exits for that reason.
+    async fn start_node(&self, options: &ReplicaSetOptions, i: usize) -> LaunchResult<Node> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let mut node = self.spawn(options, i, attempt)?;
+            match wait_for_listening(&mut node, options.startup_timeout).await {
+                Ok(()) => return Ok(node),
+                Err(LaunchError::Exited { ref log, .. })
+                    if attempt < SPAWN_ATTEMPTS && log.contains(ADDRESS_IN_USE) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn spawn(&self, options: &ReplicaSetOptions, i: usize, attempt: usize) -> LaunchResult<Node> {
+        // a directory per attempt, so that nothing is left from the failed one.
+        let dir = self.data_dir.path().join(format!("node-{}-{}", i, attempt));
+        fs::create_dir(&dir).map_err(LaunchError::DataDir)?;
+        let log_path = dir.join("mongod.log");
+        let port = free_port().map_err(LaunchError::DataDir)?;
+
+        let mongod = options.mongod();
+        let child = Command::new(&mongod)
+            .args(["--replSet", &self.name, "--bind_ip", LOCALHOST])
+            .args(["--port", &port.to_string()])
+            .arg("--dbpath")
+            .arg(&dir)
+            .arg("--logpath")
+            .arg(&log_path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|source| LaunchError::Spawn {
+                mongod: mongod.display().to_string(),
+                source,
+            })?;
+        Ok(Node {
+            child,
+            port,
+            log_path,
+        })
+    }
+
+    /// Client of the first node only, which works before the replica set is initiated.
+    async fn direct_client(&self) -> mongodb::error::Result<Client> {
+        Client::with_uri_str(&format!(
+            "mongodb://{}/?directConnection=true",
+            self.nodes[0].addr()
+        ))
+        .await
+    }
+
+    async fn initiate(&self) -> LaunchResult<()> {
+        let members = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                // the first node is preferred so that it is usually the primary.
+                let priority = if i == 0 { 2 } else { 1 };
+                doc! {"_id": i as i32, "host": n.addr(), "priority": priority}
+            })
+            .collect::<Vec<_>>();
+        let config = doc! {"_id": &self.name, "members": members};
+
+        let client = self.direct_client().await.map_err(LaunchError::Initiate)?;
+        match client
+            .database("admin")
+            .run_command(doc! {"replSetInitiate": config}, None)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => match e.kind.as_ref() {
+                ErrorKind::Command(c) if c.code == ALREADY_INITIALIZED_CODE => Ok(()),
+                _ => Err(LaunchError::Initiate(e)),
+            },
+        }
+    }
+
+    async fn wait_for_primary(&mut self, timeout: Duration) -> LaunchResult<()> {
+        let client = self.direct_client().await.map_err(LaunchError::Initiate)?;
+        let admin = client.database("admin");
+        let started = Instant::now();
+        loop {
+            for node in &mut self.nodes {
+                node.check_running()?;
+            }
+            // errors are expected while the node is still loading its config.
+            let hello: Option<Document> = admin.run_command(doc! {"isMaster": 1}, None).await.ok();
+            if hello.is_some_and(|h| h.get_str("primary").is_ok()) {
+                return Ok(());
+            }
+            if started.elapsed() > timeout {
+                return Err(LaunchError::NoPrimary(timeout));
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+}
+
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind((LOCALHOST, 0))?.local_addr()?.port())
+}
+
+async fn wait_for_listening(node: &mut Node, timeout: Duration) -> LaunchResult<()> {
+    let started = Instant::now();
+    loop {
+        node.check_running()?;
+        let connect = tokio::net::TcpStream::connect((LOCALHOST, node.port));
+        if let Ok(Ok(_)) = tokio::time::timeout(Duration::from_millis(100), connect).await {
+            return Ok(());
+        }
+        if started.elapsed() > timeout {
+            return Err(LaunchError::NotListening {
+                port: node.port,
+                timeout,
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+fn log_tail(path: &Path) -> String {
+    let mut tail = vec![];
+    if let Ok(mut file) = fs::File::open(path) {
+        let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+        let _ = file.seek(SeekFrom::Start(len.saturating_sub(LOG_TAIL_LEN)));
+        let _ = file.read_to_end(&mut tail);
+    }
+    String::from_utf8_lossy(&tail).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn needs_nodes() {
+        let options = ReplicaSetOptions {
+            nodes: 0,
+            ..Default::default()
+        };
+        let result = LocalReplicaSet::start(&options).await;
+        assert!(matches!(result, Err(LaunchError::NoNodes)));
+    }
+
+    /// A `mongod` that exits at once like one whose port is taken, counting its spawns.
+    fn mongod_with_port_taken(dir: &Path) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("mongod");
+        let script = format!(
+            "#!/bin/sh\n\
+             echo spawned >> {spawns}\n\
+             while [ $# -gt 0 ]; do [ \"$1\" = --logpath ] && log=$2; shift; done\n\
+             echo 'Failed to set up listener: SocketException: {message}' > \"$log\"\n\
+             exit 48\n",
+            spawns = dir.join("spawns").display(),
+            message = ADDRESS_IN_USE,
+        );
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn spawns_again_when_port_is_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = ReplicaSetOptions {
+            nodes: 1,
+            mongod: Some(mongod_with_port_taken(dir.path())),
+            ..Default::default()
+        };
+        let result = LocalReplicaSet::start(&options).await;
+        assert!(
+            matches!(&result, Err(LaunchError::Exited { log, .. }) if log.contains(ADDRESS_IN_USE)),
+            "{:?}",
+            result
+        );
+        let spawns = fs::read_to_string(dir.path().join("spawns")).unwrap();
+        assert_eq!(spawns.lines().count(), SPAWN_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn fails_without_mongod() {
+        let options = ReplicaSetOptions {
+            mongod: Some(PathBuf::from("/no/such/mongod")),
+            ..Default::default()
+        };
+        let result = LocalReplicaSet::start(&options).await;
+        assert!(matches!(result, Err(LaunchError::Spawn { .. })));
+    }
+}
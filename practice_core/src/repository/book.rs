@@ -0,0 +1,259 @@
+use mongodb::{
+    bson::{doc, to_bson, Bson, Document},
+    error::Error,
+    Collection, Database,
+};
+
+use super::{from_doc, to_doc};
+use crate::{
+    error::{DbError, DbResult},
+    models::{book_fields as f, Book, Review},
+    storage::{MongoStorage, Storage, UpdateOutcome},
+};
+
+#[derive(Debug, Clone)]
+pub struct BookRepository<S: Storage = MongoStorage> {
+    storage: S,
+    coll: String,
+}
+
+impl BookRepository<MongoStorage> {
+    pub fn new(db: &Database, coll_name: &str) -> Self {
+        Self::with_storage(MongoStorage::new(db), coll_name)
+    }
+
+    pub fn collection(&self) -> Collection<Book> {
+        self.storage.collection(&self.coll)
+    }
+}
+
+impl<S: Storage> BookRepository<S> {
+    pub fn with_storage(storage: S, coll_name: &str) -> Self {
+        BookRepository {
+            storage,
+            coll: coll_name.to_string(),
+        }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub async fn insert(&self, book: &Book) -> DbResult<Bson> {
+        self.storage
+            .insert_one(&self.coll, to_doc(book)?, None)
+            .await
+    }
+
+    pub async fn insert_with_session(
+        &self,
+        book: &Book,
+        session: &mut S::Session,
+    ) -> DbResult<Bson> {
+        self.storage
+            .insert_one(&self.coll, to_doc(book)?, Some(session))
+            .await
+    }
+
+    pub async fn find_by_id(&self, id: &str) -> DbResult<Option<Book>> {
+        self.find_one(doc! {f::ID: id}, None).await
+    }
+
+    pub async fn find_by_id_with_session(
+        &self,
+        id: &str,
+        session: &mut S::Session,
+    ) -> DbResult<Option<Book>> {
+        self.find_one(doc! {f::ID: id}, Some(session)).await
+    }
+
+    pub async fn list_all(&self) -> DbResult<Vec<Book>> {
+        self.find(doc! {}, None).await
+    }
+
+    pub async fn list_all_with_session(&self, session: &mut S::Session) -> DbResult<Vec<Book>> {
+        self.find(doc! {}, Some(session)).await
+    }
+
+    /// Books written by `author`.
+    pub async fn list_by_author(&self, author: &str) -> DbResult<Vec<Book>> {
+        self.find(doc! {f::AUTHORS: author}, None).await
+    }
+
+    pub async fn list_by_author_with_session(
+        &self,
+        author: &str,
+        session: &mut S::Session,
+    ) -> DbResult<Vec<Book>> {
+        self.find(doc! {f::AUTHORS: author}, Some(session)).await
+    }
+
+    /// Books written by any of `authors`. Nothing matches when `authors` is empty.
+    pub async fn list_by_any_author(&self, authors: &[&str]) -> DbResult<Vec<Book>> {
+        self.find(doc! {f::AUTHORS: {"$in": authors}}, None).await
+    }
+
+    pub async fn list_by_any_author_with_session(
+        &self,
+        authors: &[&str],
+        session: &mut S::Session,
+    ) -> DbResult<Vec<Book>> {
+        self.find(doc! {f::AUTHORS: {"$in": authors}}, Some(session))
+            .await
+    }
+
+    pub async fn update_name(&self, id: &str, name: &str) -> DbResult<UpdateOutcome> {
+        self.update_one(id, doc! {"$set": {f::NAME: name}}, None)
+            .await
+    }
+
+    pub async fn update_name_with_session(
+        &self,
+        id: &str,
+        name: &str,
+        session: &mut S::Session,
+    ) -> DbResult<UpdateOutcome> {
+        self.update_one(id, doc! {"$set": {f::NAME: name}}, Some(session))
+            .await
+    }
+
+    pub async fn add_review(&self, id: &str, review: &Review) -> DbResult<UpdateOutcome> {
+        self.update_one(id, push_review(review)?, None).await
+    }
+
+    pub async fn add_review_with_session(
+        &self,
+        id: &str,
+        review: &Review,
+        session: &mut S::Session,
+    ) -> DbResult<UpdateOutcome> {
+        self.update_one(id, push_review(review)?, Some(session))
+            .await
+    }
+
+    /// Replaces all the reviews of the book.
+    pub async fn set_reviews(&self, id: &str, reviews: &[Review]) -> DbResult<UpdateOutcome> {
+        self.update_one(id, set_reviews(reviews)?, None).await
+    }
+
+    pub async fn set_reviews_with_session(
+        &self,
+        id: &str,
+        reviews: &[Review],
+        session: &mut S::Session,
+    ) -> DbResult<UpdateOutcome> {
+        self.update_one(id, set_reviews(reviews)?, Some(session))
+            .await
+    }
+
+    /// Adds `author` unless the book already has it, and returns the updated book.
+    pub async fn add_author(&self, id: &str, author: &str) -> DbResult<Option<Book>> {
+        self.find_one_and_update(id, doc! {"$addToSet": {f::AUTHORS: author}}, None)
+            .await
+    }
+
+    pub async fn add_author_with_session(
+        &self,
+        id: &str,
+        author: &str,
+        session: &mut S::Session,
+    ) -> DbResult<Option<Book>> {
+        self.find_one_and_update(id, doc! {"$addToSet": {f::AUTHORS: author}}, Some(session))
+            .await
+    }
+
+    /// Removes `author` if the book has it, and returns the updated book.
+    pub async fn remove_author(&self, id: &str, author: &str) -> DbResult<Option<Book>> {
+        self.find_one_and_update(id, doc! {"$pull": {f::AUTHORS: author}}, None)
+            .await
+    }
+
+    pub async fn remove_author_with_session(
+        &self,
+        id: &str,
+        author: &str,
+        session: &mut S::Session,
+    ) -> DbResult<Option<Book>> {
+        self.find_one_and_update(id, doc! {"$pull": {f::AUTHORS: author}}, Some(session))
+            .await
+    }
+
+    /// Removes `supervisor` if the book has it, and returns the updated book.
+    pub async fn remove_supervisor(&self, id: &str, supervisor: &str) -> DbResult<Option<Book>> {
+        self.find_one_and_update(id, doc! {"$pull": {f::SUPERVISORS: supervisor}}, None)
+            .await
+    }
+
+    pub async fn remove_supervisor_with_session(
+        &self,
+        id: &str,
+        supervisor: &str,
+        session: &mut S::Session,
+    ) -> DbResult<Option<Book>> {
+        self.find_one_and_update(
+            id,
+            doc! {"$pull": {f::SUPERVISORS: supervisor}},
+            Some(session),
+        )
+        .await
+    }
+
+    async fn find_one(
+        &self,
+        filter: Document,
+        session: Option<&mut S::Session>,
+    ) -> DbResult<Option<Book>> {
+        self.storage
+            .find_one(&self.coll, filter, session)
+            .await?
+            .map(from_doc)
+            .transpose()
+    }
+
+    async fn find(
+        &self,
+        filter: Document,
+        session: Option<&mut S::Session>,
+    ) -> DbResult<Vec<Book>> {
+        self.storage
+            .find(&self.coll, filter, session)
+            .await?
+            .into_iter()
+            .map(from_doc)
+            .collect()
+    }
+
+    async fn update_one(
+        &self,
+        id: &str,
+        update: Document,
+        session: Option<&mut S::Session>,
+    ) -> DbResult<UpdateOutcome> {
+        self.storage
+            .update_one(&self.coll, doc! {f::ID: id}, update, session)
+            .await
+    }
+
+    async fn find_one_and_update(
+        &self,
+        id: &str,
+        update: Document,
+        session: Option<&mut S::Session>,
+    ) -> DbResult<Option<Book>> {
+        self.storage
+            .find_one_and_update(&self.coll, doc! {f::ID: id}, update, session)
+            .await?
+            .map(from_doc)
+            .transpose()
+    }
+}
+
+fn push_review(review: &Review) -> DbResult<Document> {
+    let review = to_bson(review).map_err(|e| DbError::from(Error::from(e)))?;
+    Ok(doc! {"$push": {f::REVIEWS: review}})
+}
+
+fn set_reviews(reviews: &[Review]) -> DbResult<Document> {
+    let reviews = to_bson(reviews).map_err(|e| DbError::from(Error::from(e)))?;
+    Ok(doc! {"$set": {f::REVIEWS: reviews}})
+}
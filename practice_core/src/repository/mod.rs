@@ -0,0 +1,29 @@
+//! Typed access to the `users` and `books` collections.
+//!
+//! Every operation has a `_with_session` variant that runs it in the given session,
+//! the same way as the methods of [`mongodb::Collection`].
+//!
+//! The repositories go through a [`Storage`](crate::storage::Storage): the server by default,
+//! or [`MemoryStorage`](crate::storage::MemoryStorage) given to `with_storage`.
+
+mod book;
+mod user;
+
+pub use book::BookRepository;
+pub use user::UserRepository;
+
+use mongodb::{
+    bson::{from_document, to_document, Document},
+    error::Error,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{DbError, DbResult};
+
+pub(crate) fn from_doc<T: DeserializeOwned>(doc: Document) -> DbResult<T> {
+    from_document(doc).map_err(|e| DbError::from(Error::from(e)))
+}
+
+pub(crate) fn to_doc<T: Serialize>(value: &T) -> DbResult<Document> {
+    to_document(value).map_err(|e| DbError::from(Error::from(e)))
+}
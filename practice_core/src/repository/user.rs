@@ -0,0 +1,172 @@
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Collection, Database,
+};
+
+use super::{from_doc, to_doc};
+use crate::{
+    error::DbResult,
+    models::{user_fields as f, User},
+    storage::{MongoStorage, Storage, UpdateOutcome},
+};
+
+#[derive(Debug, Clone)]
+pub struct UserRepository<S: Storage = MongoStorage> {
+    storage: S,
+    coll: String,
+}
+
+impl UserRepository<MongoStorage> {
+    pub fn new(db: &Database, coll_name: &str) -> Self {
+        Self::with_storage(MongoStorage::new(db), coll_name)
+    }
+
+    pub fn collection(&self) -> Collection<User> {
+        self.storage.collection(&self.coll)
+    }
+}
+
+impl<S: Storage> UserRepository<S> {
+    pub fn with_storage(storage: S, coll_name: &str) -> Self {
+        UserRepository {
+            storage,
+            coll: coll_name.to_string(),
+        }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub async fn insert(&self, user: &User) -> DbResult<Bson> {
+        self.storage
+            .insert_one(&self.coll, to_doc(user)?, None)
+            .await
+    }
+
+    pub async fn insert_with_session(
+        &self,
+        user: &User,
+        session: &mut S::Session,
+    ) -> DbResult<Bson> {
+        self.storage
+            .insert_one(&self.coll, to_doc(user)?, Some(session))
+            .await
+    }
+
+    pub async fn find_by_id(&self, id: &str) -> DbResult<Option<User>> {
+        self.find_one(doc! {f::ID: id}, None).await
+    }
+
+    pub async fn find_by_id_with_session(
+        &self,
+        id: &str,
+        session: &mut S::Session,
+    ) -> DbResult<Option<User>> {
+        self.find_one(doc! {f::ID: id}, Some(session)).await
+    }
+
+    pub async fn list_all(&self) -> DbResult<Vec<User>> {
+        self.find(doc! {}, None).await
+    }
+
+    pub async fn list_all_with_session(&self, session: &mut S::Session) -> DbResult<Vec<User>> {
+        self.find(doc! {}, Some(session)).await
+    }
+
+    pub async fn update_name(&self, id: &str, name: &str) -> DbResult<UpdateOutcome> {
+        self.update_one(id, set_name(name), None).await
+    }
+
+    pub async fn update_name_with_session(
+        &self,
+        id: &str,
+        name: &str,
+        session: &mut S::Session,
+    ) -> DbResult<UpdateOutcome> {
+        self.update_one(id, set_name(name), Some(session)).await
+    }
+
+    /// Records that the user reviewed the book.
+    pub async fn add_reviewed_book(&self, id: &str, book_id: &str) -> DbResult<UpdateOutcome> {
+        self.update_one(id, push_reviewed_book(book_id), None).await
+    }
+
+    pub async fn add_reviewed_book_with_session(
+        &self,
+        id: &str,
+        book_id: &str,
+        session: &mut S::Session,
+    ) -> DbResult<UpdateOutcome> {
+        self.update_one(id, push_reviewed_book(book_id), Some(session))
+            .await
+    }
+
+    /// Replaces the ids of the books the user reviewed.
+    pub async fn set_reviewed_book_ids(
+        &self,
+        id: &str,
+        book_ids: &[String],
+    ) -> DbResult<UpdateOutcome> {
+        self.update_one(id, set_reviewed_book_ids(book_ids), None)
+            .await
+    }
+
+    pub async fn set_reviewed_book_ids_with_session(
+        &self,
+        id: &str,
+        book_ids: &[String],
+        session: &mut S::Session,
+    ) -> DbResult<UpdateOutcome> {
+        self.update_one(id, set_reviewed_book_ids(book_ids), Some(session))
+            .await
+    }
+
+    async fn find_one(
+        &self,
+        filter: Document,
+        session: Option<&mut S::Session>,
+    ) -> DbResult<Option<User>> {
+        self.storage
+            .find_one(&self.coll, filter, session)
+            .await?
+            .map(from_doc)
+            .transpose()
+    }
+
+    async fn find(
+        &self,
+        filter: Document,
+        session: Option<&mut S::Session>,
+    ) -> DbResult<Vec<User>> {
+        self.storage
+            .find(&self.coll, filter, session)
+            .await?
+            .into_iter()
+            .map(from_doc)
+            .collect()
+    }
+
+    async fn update_one(
+        &self,
+        id: &str,
+        update: Document,
+        session: Option<&mut S::Session>,
+    ) -> DbResult<UpdateOutcome> {
+        self.storage
+            .update_one(&self.coll, doc! {f::ID: id}, update, session)
+            .await
+    }
+}
+
+fn set_name(name: &str) -> Document {
+    doc! {"$set": {f::NAME: name}}
+}
+
+fn push_reviewed_book(book_id: &str) -> Document {
+    doc! {"$push": {f::REVIEWED_BOOK_IDS: book_id}}
+}
+
+fn set_reviewed_book_ids(book_ids: &[String]) -> Document {
+    doc! {"$set": {f::REVIEWED_BOOK_IDS: book_ids}}
+}
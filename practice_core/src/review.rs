@@ -0,0 +1,268 @@
+//! "A user reviews a book" as a single domain operation.
+//!
+//! The review is denormalized into both `Book.reviews` and `User.reviewed_book_ids`,
+//! so [`ReviewService::review_book`] checks and writes both documents in one transaction.
+//! The transaction also adds a `ReviewAdded` event to the [outbox](crate::outbox).
+
+use mongodb::{bson::DateTime, error::Error, Client};
+use thiserror::Error;
+
+use crate::{
+    error::DbError,
+    outbox::{DomainEvent, Outbox},
+    storage::{MongoStorage, SessionSource, Storage},
+    tx::{run_transaction, ErrorLabels, WithTransactionOptions},
+    BookRepository, Review, UserRepository,
+};
+
+#[derive(Error, Debug)]
+pub enum ReviewError {
+    #[error("user {0} is not found")]
+    UserNotFound(String),
+    #[error("book {0} is not found")]
+    BookNotFound(String),
+    #[error("user {user_id} already reviewed book {book_id}")]
+    AlreadyReviewed { user_id: String, book_id: String },
+    #[error(transparent)]
+    Db(#[from] DbError),
+}
+
+impl From<Error> for ReviewError {
+    fn from(e: Error) -> Self {
+        ReviewError::Db(e.into())
+    }
+}
+
+impl ErrorLabels for ReviewError {
+    fn has_label(&self, label: &str) -> bool {
+        match self {
+            ReviewError::Db(e) => e.has_label(label),
+            _ => false,
+        }
+    }
+}
+
+/// Runs the transactions on sessions of `C`, a [`Client`] by default.
+#[derive(Debug, Clone)]
+pub struct ReviewService<S: Storage = MongoStorage, C: SessionSource<S> = Client> {
+    sessions: C,
+    users: UserRepository<S>,
+    books: BookRepository<S>,
+    options: WithTransactionOptions,
+}
+
+impl ReviewService {
+    pub fn new(client: &Client, users: UserRepository, books: BookRepository) -> Self {
+        Self::with_sessions(client.clone(), users, books)
+    }
+}
+
+impl<S: Storage + 'static, C: SessionSource<S>> ReviewService<S, C> {
+    pub fn with_sessions(sessions: C, users: UserRepository<S>, books: BookRepository<S>) -> Self {
+        ReviewService {
+            sessions,
+            users,
+            books,
+            options: WithTransactionOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: WithTransactionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Adds a review of `book_id` by `user_id`.
+    ///
+    /// Fails without writing anything if either of them doesn't exist or the user already
+    /// reviewed the book. Two concurrent reviews of the same pair write the same documents,
+    /// so one of them gets a write conflict, is retried, and then fails with `AlreadyReviewed`.
+    pub async fn review_book(
+        &self,
+        user_id: &str,
+        book_id: &str,
+        text: &str,
+    ) -> Result<Review, ReviewError> {
+        let review = Review {
+            user_id: user_id.to_string(),
+            text: text.to_string(),
+            reviewed_at: Some(DateTime::now()),
+        };
+
+        let mut session = self.sessions.start_session().await?;
+        run_transaction(&mut session, &self.options, |session| {
+            let users = self.users.clone();
+            let books = self.books.clone();
+            let review = review.clone();
+            let book_id = book_id.to_string();
+            Box::pin(async move {
+                let user_id = review.user_id.clone();
+                let user = users
+                    .find_by_id_with_session(&user_id, session)
+                    .await?
+                    .ok_or_else(|| ReviewError::UserNotFound(user_id.clone()))?;
+                let book = books
+                    .find_by_id_with_session(&book_id, session)
+                    .await?
+                    .ok_or_else(|| ReviewError::BookNotFound(book_id.clone()))?;
+
+                let reviewed = user.reviewed_book_ids.contains(&book_id)
+                    || book.reviews.iter().any(|r| r.user_id == user_id);
+                if reviewed {
+                    return Err(ReviewError::AlreadyReviewed { user_id, book_id });
+                }
+
+                books
+                    .add_review_with_session(&book_id, &review, session)
+                    .await?;
+                users
+                    .add_reviewed_book_with_session(&user_id, &book_id, session)
+                    .await?;
+                let added = DomainEvent::ReviewAdded {
+                    book_id,
+                    user_id,
+                    text: review.text.clone(),
+                };
+                Outbox::new(books.storage().clone())
+                    .enqueue(&added, Some(session))
+                    .await?;
+
+                Ok(review)
+            })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{outbox::OutboxStatus, storage::MemoryStorage, Book, User};
+
+    const USER_ID: &str = "user_1";
+    const BOOK_ID: &str = "book_1";
+
+    struct Fixture {
+        service: ReviewService<MemoryStorage, MemoryStorage>,
+        users: UserRepository<MemoryStorage>,
+        books: BookRepository<MemoryStorage>,
+    }
+
+    async fn fixture() -> Fixture {
+        let storage = MemoryStorage::new();
+        let users = UserRepository::with_storage(storage.clone(), "users");
+        let books = BookRepository::with_storage(storage.clone(), "books");
+        users
+            .insert(&User {
+                id: USER_ID.to_string(),
+                name: "john".to_string(),
+                reviewed_book_ids: vec![],
+            })
+            .await
+            .unwrap();
+        books
+            .insert(&Book {
+                id: BOOK_ID.to_string(),
+                name: "rust".to_string(),
+                reviews: vec![],
+                authors: vec![],
+                supervisors: vec![],
+                version: 0,
+            })
+            .await
+            .unwrap();
+        Fixture {
+            service: ReviewService::with_sessions(storage, users.clone(), books.clone()),
+            users,
+            books,
+        }
+    }
+
+    impl Fixture {
+        async fn reviewed_book_ids(&self) -> Vec<String> {
+            let user = self.users.find_by_id(USER_ID).await.unwrap().unwrap();
+            user.reviewed_book_ids
+        }
+
+        async fn reviews(&self) -> Vec<Review> {
+            let book = self.books.find_by_id(BOOK_ID).await.unwrap().unwrap();
+            book.reviews
+        }
+
+        async fn published(&self) -> Vec<DomainEvent> {
+            let outbox = Outbox::new(self.books.storage().clone());
+            let pending = outbox.events(OutboxStatus::Pending).await.unwrap();
+            pending.into_iter().map(|e| e.event).collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_review_to_user_and_book() {
+        let f = fixture().await;
+        let review = f
+            .service
+            .review_book(USER_ID, BOOK_ID, "good")
+            .await
+            .unwrap();
+
+        assert_eq!(f.reviews().await, vec![review]);
+        assert_eq!(f.reviewed_book_ids().await, vec![BOOK_ID.to_string()]);
+        assert_eq!(
+            f.published().await,
+            vec![DomainEvent::ReviewAdded {
+                book_id: BOOK_ID.to_string(),
+                user_id: USER_ID.to_string(),
+                text: "good".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_user() {
+        let f = fixture().await;
+        let result = f.service.review_book("user_x", BOOK_ID, "good").await;
+
+        assert!(matches!(result, Err(ReviewError::UserNotFound(ref id)) if id == "user_x"));
+        assert!(f.reviews().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_book() {
+        let f = fixture().await;
+        let result = f.service.review_book(USER_ID, "book_x", "good").await;
+
+        assert!(matches!(result, Err(ReviewError::BookNotFound(ref id)) if id == "book_x"));
+        assert!(f.reviewed_book_ids().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_second_review_without_writing() {
+        let f = fixture().await;
+        f.service
+            .review_book(USER_ID, BOOK_ID, "good")
+            .await
+            .unwrap();
+        let result = f.service.review_book(USER_ID, BOOK_ID, "bad").await;
+
+        assert!(matches!(result, Err(ReviewError::AlreadyReviewed { .. })));
+        assert_eq!(f.reviews().await.len(), 1);
+        assert_eq!(f.reviewed_book_ids().await.len(), 1);
+        assert_eq!(f.published().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_review_recorded_only_on_book() {
+        let f = fixture().await;
+        let review = Review {
+            user_id: USER_ID.to_string(),
+            text: "good".to_string(),
+            reviewed_at: None,
+        };
+        f.books.add_review(BOOK_ID, &review).await.unwrap();
+        let result = f.service.review_book(USER_ID, BOOK_ID, "bad").await;
+
+        assert!(matches!(result, Err(ReviewError::AlreadyReviewed { .. })));
+        // the user is not written either, although it has no record of the review.
+        assert!(f.reviewed_book_ids().await.is_empty());
+    }
+}
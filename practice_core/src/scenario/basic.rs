@@ -0,0 +1,462 @@
+//! Scenarios originally written for the `clientv2` binary.
+
+use anyhow::Result;
+use mongodb::{
+    bson::{to_document, DateTime, Document},
+    Client,
+};
+use tracing::{debug, info, warn};
+
+use super::Target;
+use crate::{
+    error::Required,
+    indexes::{index_test_indexes, sync_collection, SyncOptions},
+    majority_tx_options,
+    outbox::{DomainEvent, Outbox, OUTBOX_COLL},
+    s,
+    storage::{MongoStorage, Storage},
+    tx::{with_transaction, WithTransactionOptions},
+    Book, BookRepository, DbError, IndexTest, Review, User, UserRepository,
+};
+
+/// default database of this scenario.
+pub const DB_NAME: &str = "test_db";
+
+pub async fn create_users(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let user_coll = db.collection::<User>(&target.users);
+    if let Err(e) = user_coll.drop(None).await {
+        warn!(error = %e, "failed to drop the users");
+    }
+    user_coll
+        .insert_many(
+            vec![
+                User {
+                    id: s("user_1"),
+                    name: s("john"),
+                    reviewed_book_ids: vec![],
+                },
+                User {
+                    id: s("user_2"),
+                    name: s("anna"),
+                    reviewed_book_ids: vec![],
+                },
+            ],
+            None,
+        )
+        .await?;
+
+    user_coll
+        .insert_one(
+            User {
+                id: s("user_3"),
+                name: s("joseph"),
+                reviewed_book_ids: vec![],
+            },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub async fn create_books(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let book_coll = db.collection::<Book>(&target.books);
+
+    if let Err(e) = book_coll.drop(None).await {
+        warn!(error = %e, "failed to drop the books");
+    }
+
+    book_coll
+        .insert_one(
+            Book {
+                id: s("book_1"),
+                name: s("The Hitchhiker's Guide to Somewhere"),
+                reviews: vec![],
+                authors: vec![],
+                supervisors: vec![],
+                version: 0,
+            },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub async fn update_books(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let books = BookRepository::new(&db, &target.books);
+
+    books
+        .update_name("book_1", "The Hitchhiker's Guide to Somewhere")
+        .await?;
+
+    // no error returns
+    books.update_name("****", "xxxxx").await?;
+
+    Ok(())
+}
+
+pub async fn indexes(client: &Client, target: &Target) -> Result<()> {
+    let storage = MongoStorage::new(&client.database(&target.db_name));
+    indexes_on(&storage, target).await
+}
+
+pub async fn indexes_on<S: Storage>(storage: &S, target: &Target) -> Result<()> {
+    let coll = target.index_test.as_str();
+    let index_test = |id: &str, name: &str, opt: &str| {
+        to_document(&IndexTest {
+            id: s(id),
+            name: s(name),
+            opt: s(opt),
+        })
+    };
+
+    storage.drop_collection(coll).await.unwrap();
+
+    let report = sync_collection(
+        storage,
+        coll,
+        &index_test_indexes(),
+        &SyncOptions::default(),
+    )
+    .await?;
+    info!(%report, "indexes synced");
+
+    //duplicated index
+    let indices = storage.list_indexes(coll).await?;
+    for each in indices {
+        debug!(index = ?each, "index");
+    }
+
+    storage
+        .insert_one(coll, index_test("test_11", "aaaa", "sss")?, None)
+        .await?;
+
+    let result = storage
+        .insert_one(coll, index_test("test_11", "aaaa", "bbb")?, None)
+        .await;
+    assert!(
+        matches!(&result, Err(DbError::DuplicateKey { index: Some(index), .. }) if index == "id_1"),
+        "{:?}",
+        result
+    );
+
+    let result = storage
+        .insert_one(coll, index_test("test_22", "aaaa", "sss")?, None)
+        .await;
+    assert!(
+        matches!(&result, Err(DbError::DuplicateKey { index: Some(index), .. }) if index == "name_1_opt_1"),
+        "{:?}",
+        result
+    );
+
+    let result = storage
+        .insert_one(coll, index_test("test_22", "aaaa", "bbbb")?, None)
+        .await;
+    assert!(result.is_ok());
+
+    Ok(())
+}
+
+pub async fn find_users(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let users = UserRepository::new(&db, &target.users);
+    let found = users.find_by_id("user_1").await.required("user user_1")?;
+    assert_eq!(found.id, s("user_1"));
+    info!(user = ?found, "found user");
+    Ok(())
+}
+
+pub async fn add_reviews_in_session(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let users = UserRepository::new(&db, &target.users);
+    let books = BookRepository::new(&db, &target.books);
+
+    let user_id = s("user_2");
+    let book_id = s("book_1");
+
+    // the scenario can be run repeatedly, so the counts are compared with the ones before it.
+    let reviews_before = books.find_by_id(&book_id).await?.unwrap().reviews.len();
+    let reviewed_before = users
+        .find_by_id(&user_id)
+        .await?
+        .unwrap()
+        .reviewed_book_ids
+        .len();
+
+    // TRANSIENT_TRANSACTION_ERROR implies entire transaction can be retried, which with_transaction does.
+    // see https://www.mongodb.com/blog/post/how-to-select--for-update-inside-mongodb-transactions for more detail
+    with_transaction(client, &WithTransactionOptions::default(), |session| {
+        let client = client.clone();
+        let users = users.clone();
+        let books = books.clone();
+        let user_id = user_id.clone();
+        let book_id = book_id.clone();
+        Box::pin(async move {
+            {
+                // TODO(tacogips) try to find a doc using indices.
+                let review = Review {
+                    user_id: user_id.clone(),
+                    text: s("Good reading"),
+                    reviewed_at: Some(DateTime::now()),
+                };
+                books
+                    .add_review_with_session(&book_id, &review, session)
+                    .await?;
+
+                users
+                    .add_reviewed_book_with_session(&user_id, &book_id, session)
+                    .await?;
+
+                // published only if the review commits.
+                let added = DomainEvent::ReviewAdded {
+                    book_id: book_id.clone(),
+                    user_id: user_id.clone(),
+                    text: review.text.clone(),
+                };
+                Outbox::new(books.storage().clone())
+                    .enqueue(&added, Some(session))
+                    .await?;
+            }
+
+            {
+                // read from other session before commit
+                let mut another_session = client.start_session(None).await?;
+                let found = books
+                    .find_by_id_with_session(&book_id, &mut another_session)
+                    .await?
+                    .unwrap();
+
+                assert_eq!(found.reviews.len(), reviews_before);
+                info!(book = ?found, "book read in another session");
+
+                let found = users
+                    .find_by_id_with_session(&user_id, &mut another_session)
+                    .await?
+                    .unwrap();
+
+                assert_eq!(found.reviewed_book_ids.len(), reviewed_before);
+                info!(user = ?found, "user read in another session");
+            }
+
+            Ok::<_, anyhow::Error>(())
+        })
+    })
+    .await?;
+
+    {
+        // read from other session after commit
+        let mut another_session = client.start_session(None).await?;
+        let found = books
+            .find_by_id_with_session(&book_id, &mut another_session)
+            .await?
+            .unwrap();
+
+        assert_eq!(found.reviews.len(), reviews_before + 1);
+        info!(book = ?found, "book read in another session");
+
+        let found = users
+            .find_by_id_with_session(&user_id, &mut another_session)
+            .await?
+            .unwrap();
+
+        assert_eq!(found.reviewed_book_ids.len(), reviewed_before + 1);
+        info!(user = ?found, "user read in another session");
+    }
+
+    Ok(())
+}
+
+pub async fn abort_tx(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let books = BookRepository::new(&db, &target.books);
+
+    let book_id = s("book_fake");
+    let mut session = client.start_session(None).await?;
+
+    session.start_transaction(majority_tx_options()).await?;
+
+    books
+        .collection()
+        .insert_one_with_session(
+            Book {
+                id: book_id.clone(),
+                name: s("ABC book"),
+                reviews: vec![],
+                authors: vec![],
+                supervisors: vec![],
+                version: 0,
+            },
+            None,
+            &mut session,
+        )
+        .await?;
+
+    {
+        let mut another_session = client.start_session(None).await?;
+        let found_before_tx = books
+            .find_by_id_with_session(&book_id, &mut another_session)
+            .await?;
+        assert!(found_before_tx.is_none());
+    }
+
+    session.abort_transaction().await?;
+
+    {
+        let mut another_session = client.start_session(None).await?;
+        let found_before_tx = books
+            .find_by_id_with_session(&book_id, &mut another_session)
+            .await?;
+        assert!(found_before_tx.is_none());
+    }
+
+    Ok(())
+}
+
+pub async fn drop_colls(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let user_coll = db.collection::<User>(&target.users);
+    user_coll.drop(None).await?;
+
+    let book_coll = db.collection::<Book>(&target.books);
+    book_coll.drop(None).await?;
+
+    let index_test_coll = db.collection::<IndexTest>(&target.index_test);
+    index_test_coll.drop(None).await?;
+
+    db.collection::<Document>(OUTBOX_COLL).drop(None).await?;
+    Ok(())
+}
+
+pub async fn misc(client: &Client, target: &Target) -> Result<()> {
+    let storage = MongoStorage::new(&client.database(&target.db_name));
+    misc_on(&storage, target).await
+}
+
+pub async fn misc_on<S: Storage>(storage: &S, target: &Target) -> Result<()> {
+    let books = BookRepository::with_storage(storage.clone(), &target.books);
+
+    if let Err(e) = storage.drop_collection(&target.books).await {
+        warn!(error = %e, "failed to drop the books");
+    }
+
+    let book_with_authors = |authors: &[&str]| Book {
+        id: s("book_with_authors"),
+        name: s("some book"),
+        reviews: vec![],
+        authors: authors.iter().map(|a| s(a)).collect(),
+        supervisors: vec![],
+        version: 0,
+    };
+
+    books
+        .insert(&book_with_authors(&["author_1", "author_2"]))
+        .await?;
+
+    // search by id
+    {
+        let found = books.find_by_id("book_with_authors").await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert_eq!(found, Some(book_with_authors(&["author_1", "author_2"])))
+    }
+
+    // search by $in
+    {
+        let found = books.list_by_any_author(&["author_1"]).await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert_eq!(found, vec![book_with_authors(&["author_1", "author_2"])])
+    }
+
+    // search by an element of the array
+    {
+        let found = books.list_by_author("author_2").await?;
+        assert_eq!(found, vec![book_with_authors(&["author_1", "author_2"])])
+    }
+
+    // search by $in not found
+    {
+        let found = books.list_by_any_author(&["imaginary_author_1"]).await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert!(found.is_empty())
+    }
+
+    // search by $in with empty vec
+    {
+        let found = books.list_by_any_author(&[]).await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert!(found.is_empty())
+    }
+
+    // update pull which not exists
+    {
+        let found = books
+            .remove_author("book_with_authors", "no_such_author")
+            .await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert_eq!(found, Some(book_with_authors(&["author_1", "author_2"])))
+    }
+
+    {
+        let found = books.add_author("book_with_authors", "author_3").await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert_eq!(
+            found,
+            Some(book_with_authors(&["author_1", "author_2", "author_3"]))
+        );
+
+        // add again
+        let found = books.add_author("book_with_authors", "author_3").await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert_eq!(
+            found,
+            Some(book_with_authors(&["author_1", "author_2", "author_3"]))
+        )
+    }
+
+    // update pull which exists
+    {
+        let found = books.remove_author("book_with_authors", "author_3").await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert_eq!(found, Some(book_with_authors(&["author_1", "author_2"])));
+
+        let found = books
+            .remove_supervisor("book_with_authors", "no_such_supervisor")
+            .await;
+        assert!(found.is_ok());
+        let found = found.unwrap();
+        assert_eq!(found, Some(book_with_authors(&["author_1", "author_2"])))
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn misc_on_memory() {
+        misc_on(&MemoryStorage::new(), &Target::new(DB_NAME))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn indexes_on_memory() {
+        indexes_on(&MemoryStorage::new(), &Target::new(DB_NAME))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,481 @@
+//! Load on a few hot books, to compare optimistic and pessimistic locking.
+//!
+//! `workers` concurrent workers each run `ops_per_worker` read-modify-writes of the `version`
+//! of a book, spread over `books` books. With [`Strategy::Optimistic`] the book is written back
+//! with [`modify_with_retry`], which fails on a version written by someone else in between.
+//! With [`Strategy::Pessimistic`] it is read with [`try_lock_for_update`] in a transaction,
+//! which fails while another transaction holds it. Both retry a failed attempt with the same
+//! backoff.
+//!
+//! Every successful operation adds 1 to a version, so the sum of the versions tells how many
+//! updates were lost.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use mongodb::{bson::doc, error::TRANSIENT_TRANSACTION_ERROR, options::TransactionOptions, Client};
+
+use super::Target;
+use crate::{
+    lock::{try_lock_for_update, LockError, LockOptions},
+    majority_tx_options,
+    models::book_fields,
+    repository::{from_doc, to_doc},
+    storage::{MongoStorage, SessionSource, Storage},
+    tx::{ErrorLabels, TxSession},
+    versioned::{modify_with_retry, RetryOptions, VersionError},
+    Book, DbError,
+};
+
+/// default database of this scenario.
+pub const DB_NAME: &str = "contention_db";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Optimistic,
+    Pessimistic,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 2] = [Strategy::Optimistic, Strategy::Pessimistic];
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::Optimistic => write!(f, "optimistic"),
+            Strategy::Pessimistic => write!(f, "pessimistic"),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "optimistic" => Ok(Strategy::Optimistic),
+            "pessimistic" => Ok(Strategy::Pessimistic),
+            _ => Err(format!(
+                "unknown strategy {:?}, expected optimistic or pessimistic",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub strategy: Strategy,
+    pub workers: usize,
+    pub books: usize,
+    pub ops_per_worker: usize,
+    /// Attempts of an operation and the backoff between them, for both strategies.
+    pub retry: RetryOptions,
+    /// Options of the transactions of [`Strategy::Pessimistic`].
+    pub transaction_options: TransactionOptions,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            strategy: Strategy::Optimistic,
+            workers: 8,
+            books: 4,
+            ops_per_worker: 100,
+            retry: RetryOptions {
+                max_attempts: 20,
+                ..Default::default()
+            },
+            transaction_options: majority_tx_options(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadReport {
+    pub strategy: Strategy,
+    pub workers: usize,
+    pub books: usize,
+    pub succeeded: usize,
+    /// Operations still conflicting after `retry.max_attempts` attempts.
+    pub failed: usize,
+    pub attempts: usize,
+    /// Attempts that failed on a version written by someone else, or on a held lock.
+    pub conflicts: usize,
+    /// Most retries of a single operation.
+    pub max_retries: usize,
+    pub elapsed: Duration,
+    /// Latencies of the operations, retries included.
+    pub p50: Duration,
+    pub p99: Duration,
+    /// Sum of the versions if no update was lost.
+    pub expected_versions: i64,
+    pub actual_versions: i64,
+}
+
+impl LoadReport {
+    pub fn throughput(&self) -> f64 {
+        self.succeeded as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn conflict_rate(&self) -> f64 {
+        if self.attempts == 0 {
+            return 0.0;
+        }
+        self.conflicts as f64 / self.attempts as f64
+    }
+
+    pub fn retries(&self) -> usize {
+        self.attempts - self.succeeded - self.failed
+    }
+
+    pub fn lost_updates(&self) -> i64 {
+        self.expected_versions - self.actual_versions
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} workers on {} books",
+            self.strategy, self.workers, self.books
+        )?;
+        writeln!(
+            f,
+            "  operations    {} succeeded, {} failed in {:.2?}",
+            self.succeeded, self.failed, self.elapsed
+        )?;
+        writeln!(f, "  throughput    {:.1} ops/s", self.throughput())?;
+        writeln!(
+            f,
+            "  latency       p50 {:.2?}, p99 {:.2?}",
+            self.p50, self.p99
+        )?;
+        writeln!(
+            f,
+            "  conflicts     {} of {} attempts ({:.1}%)",
+            self.conflicts,
+            self.attempts,
+            self.conflict_rate() * 100.0
+        )?;
+        writeln!(
+            f,
+            "  retries       {} in total, at most {} for an operation",
+            self.retries(),
+            self.max_retries
+        )?;
+        writeln!(f, "  lost updates  {}", self.lost_updates())
+    }
+}
+
+pub async fn run(client: &Client, target: &Target, options: &LoadOptions) -> Result<LoadReport> {
+    let storage = MongoStorage::new(&client.database(&target.db_name));
+    run_on(storage, client.clone(), &target.books, options).await
+}
+
+/// Writes the hot books to `coll` again, then runs the load on them.
+pub async fn run_on<S, C>(
+    storage: S,
+    sessions: C,
+    coll: &str,
+    options: &LoadOptions,
+) -> Result<LoadReport>
+where
+    S: Storage + 'static,
+    C: SessionSource<S> + 'static,
+{
+    storage.drop_collection(coll).await?;
+    for i in 0..options.books {
+        storage
+            .insert_one(coll, to_doc(&hot_book(i))?, None)
+            .await?;
+    }
+
+    let started = Instant::now();
+    let workers = (0..options.workers).map(|w| {
+        let worker = Worker {
+            storage: storage.clone(),
+            sessions: sessions.clone(),
+            coll: coll.to_string(),
+            options: options.clone(),
+        };
+        tokio::spawn(async move { worker.run(w).await })
+    });
+    let mut ops = vec![];
+    for worker in futures::future::join_all(workers).await {
+        ops.extend(worker??);
+    }
+    let elapsed = started.elapsed();
+
+    let mut actual_versions = 0;
+    for found in storage.find(coll, doc! {}, None).await? {
+        actual_versions += from_doc::<Book>(found)?.version;
+    }
+    Ok(report(options, ops, elapsed, actual_versions))
+}
+
+pub async fn drop_colls(client: &Client, target: &Target) -> Result<()> {
+    let storage = MongoStorage::new(&client.database(&target.db_name));
+    storage.drop_collection(&target.books).await?;
+    Ok(())
+}
+
+fn hot_book(i: usize) -> Book {
+    Book {
+        id: format!("hot_{}", i),
+        name: format!("hot book {}", i),
+        reviews: vec![],
+        authors: vec![],
+        supervisors: vec![],
+        version: 0,
+    }
+}
+
+fn report(options: &LoadOptions, mut ops: Vec<Op>, elapsed: Duration, actual: i64) -> LoadReport {
+    ops.sort_by_key(|op| op.latency);
+    let percentile = |p: f64| {
+        let rank = ((ops.len() as f64 * p).ceil() as usize).max(1);
+        ops.get(rank - 1).map(|op| op.latency).unwrap_or_default()
+    };
+    let succeeded = ops.iter().filter(|op| op.succeeded).count();
+    LoadReport {
+        strategy: options.strategy,
+        workers: options.workers,
+        books: options.books,
+        succeeded,
+        failed: ops.len() - succeeded,
+        attempts: ops.iter().map(|op| op.attempts).sum(),
+        conflicts: ops.iter().map(|op| op.conflicts).sum(),
+        max_retries: ops.iter().map(|op| op.attempts - 1).max().unwrap_or(0),
+        elapsed,
+        p50: percentile(0.5),
+        p99: percentile(0.99),
+        expected_versions: succeeded as i64,
+        actual_versions: actual,
+    }
+}
+
+/// One read-modify-write.
+struct Op {
+    latency: Duration,
+    attempts: usize,
+    conflicts: usize,
+    succeeded: bool,
+}
+
+struct Worker<S, C> {
+    storage: S,
+    sessions: C,
+    coll: String,
+    options: LoadOptions,
+}
+
+impl<S: Storage, C: SessionSource<S>> Worker<S, C> {
+    /// Worker `w` starts on book `w` and moves to the next one on each operation.
+    async fn run(self, w: usize) -> Result<Vec<Op>> {
+        let mut session = match self.options.strategy {
+            Strategy::Optimistic => None,
+            Strategy::Pessimistic => Some(self.sessions.start_session().await?),
+        };
+        let mut ops = vec![];
+        for i in 0..self.options.ops_per_worker {
+            let id = hot_book((w + i) % self.options.books).id;
+            let started = Instant::now();
+            let mut op = match &mut session {
+                None => self.optimistic(&id).await?,
+                Some(session) => self.pessimistic(session, &id).await?,
+            };
+            op.latency = started.elapsed();
+            ops.push(op);
+        }
+        Ok(ops)
+    }
+
+    async fn optimistic(&self, id: &str) -> Result<Op> {
+        let mut attempts = 0;
+        let result = modify_with_retry::<_, Book, _>(
+            &self.storage,
+            &self.coll,
+            id,
+            &self.options.retry,
+            |_| attempts += 1,
+        )
+        .await;
+        let succeeded = match result {
+            Ok(_) => true,
+            Err(VersionError::VersionConflict { .. }) => false,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Op {
+            latency: Duration::default(),
+            attempts,
+            conflicts: if succeeded { attempts - 1 } else { attempts },
+            succeeded,
+        })
+    }
+
+    async fn pessimistic(&self, session: &mut S::Session, id: &str) -> Result<Op> {
+        let retry = &self.options.retry;
+        let mut backoff = retry.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            session
+                .start_transaction(self.options.transaction_options.clone())
+                .await
+                .map_err(DbError::from)?;
+            match self.lock_and_increment(session, id).await {
+                Ok(()) => {
+                    return Ok(Op {
+                        latency: Duration::default(),
+                        attempts,
+                        conflicts: attempts - 1,
+                        succeeded: true,
+                    })
+                }
+                Err(e) if is_conflict(&e) => {
+                    // the transaction is already aborted on the server, so the result is not interesting.
+                    let _ = session.abort_transaction().await;
+                }
+                Err(e) => {
+                    let _ = session.abort_transaction().await;
+                    return Err(e.into());
+                }
+            }
+            if attempts >= retry.max_attempts {
+                return Ok(Op {
+                    latency: Duration::default(),
+                    attempts,
+                    conflicts: attempts,
+                    succeeded: false,
+                });
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, retry.max_backoff);
+        }
+    }
+
+    /// Writes the version read under the lock plus 1, which would lose updates without the lock.
+    async fn lock_and_increment(
+        &self,
+        session: &mut S::Session,
+        id: &str,
+    ) -> Result<(), LockError> {
+        let filter = doc! {book_fields::ID: id};
+        let book: Book = try_lock_for_update(
+            &self.storage,
+            session,
+            &self.coll,
+            filter.clone(),
+            &LockOptions::default(),
+        )
+        .await?;
+        self.storage
+            .update_one(
+                &self.coll,
+                filter,
+                doc! {"$set": {book_fields::VERSION: book.version + 1}},
+                Some(session),
+            )
+            .await?;
+        session.commit_transaction().await.map_err(DbError::from)?;
+        Ok(())
+    }
+}
+
+fn is_conflict(e: &LockError) -> bool {
+    match e {
+        LockError::Busy => true,
+        LockError::Db(e) => e.has_label(TRANSIENT_TRANSACTION_ERROR),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const BOOKS: &str = "books";
+
+    fn options(strategy: Strategy) -> LoadOptions {
+        LoadOptions {
+            strategy,
+            workers: 4,
+            books: 2,
+            ops_per_worker: 25,
+            retry: RetryOptions {
+                max_attempts: 1000,
+                initial_backoff: Duration::from_micros(100),
+                max_backoff: Duration::from_millis(1),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn no_update_is_lost() {
+        for strategy in &Strategy::ALL {
+            let storage = MemoryStorage::new();
+            let report = run_on(storage.clone(), storage, BOOKS, &options(*strategy))
+                .await
+                .unwrap();
+            assert_eq!((report.succeeded, report.failed), (100, 0), "{}", report);
+            assert_eq!(report.attempts, report.succeeded + report.conflicts);
+            assert_eq!(report.lost_updates(), 0, "{}", report);
+            assert!(report.p50 <= report.p99);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_failed_operations() {
+        let storage = MemoryStorage::new();
+        storage
+            .insert_one(BOOKS, to_doc(&hot_book(0)).unwrap(), None)
+            .await
+            .unwrap();
+        let mut session = storage.start_session();
+        session
+            .start_transaction(majority_tx_options())
+            .await
+            .unwrap();
+
+        // the book is held by the transaction of the test during the whole load.
+        let mut options = options(Strategy::Pessimistic);
+        options.retry.max_attempts = 2;
+        options.books = 1;
+        let worker = Worker {
+            storage: storage.clone(),
+            sessions: storage.clone(),
+            coll: BOOKS.to_string(),
+            options,
+        };
+        let _: Book = try_lock_for_update(
+            &storage,
+            &mut session,
+            BOOKS,
+            doc! {"id": "hot_0"},
+            &LockOptions::default(),
+        )
+        .await
+        .unwrap();
+        let ops = worker.run(0).await.unwrap();
+
+        assert_eq!(ops.len(), 25);
+        assert!(ops
+            .iter()
+            .all(|op| !op.succeeded && op.attempts == 2 && op.conflicts == 2));
+    }
+
+    #[test]
+    fn strategy_from_str() {
+        assert_eq!("pessimistic".parse(), Ok(Strategy::Pessimistic));
+        assert!("other".parse::<Strategy>().is_err());
+    }
+}
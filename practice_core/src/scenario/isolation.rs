@@ -0,0 +1,692 @@
+//! Which isolation anomalies transactions allow, under each read and write concern.
+//!
+//! Every anomaly is provoked by two transactions whose operations are ordered by a
+//! [`Script`], on documents of the form `{id, group, value}` that are written again before
+//! each run. The result is a [`Matrix`] of the outcomes, printed as a table:
+//!
+//! ```text
+//! isolation       dirty read  non-repeatable read  phantom    lost update  write skew  read skew
+//! local/w:1       prevented   prevented            prevented  prevented    allowed     prevented
+//! ```
+
+use std::fmt;
+
+use anyhow::Result;
+use mongodb::{
+    bson::{doc, Document},
+    error::TRANSIENT_TRANSACTION_ERROR,
+    options::{Acknowledgment, ReadConcern, TransactionOptions, WriteConcern},
+    Client,
+};
+
+use super::Target;
+use crate::{
+    interleaving::Script,
+    storage::{MongoStorage, SessionSource, Storage},
+    tx::{ErrorLabels, TxSession},
+};
+
+/// default database of this scenario.
+pub const DB_NAME: &str = "isolation_db";
+/// Collection the anomalies are provoked on.
+pub const ANOMALY_COLL: &str = "anomalies";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anomaly {
+    /// A transaction reads a write another one hasn't committed.
+    DirtyRead,
+    /// Reading a document twice gives different values.
+    NonRepeatableRead,
+    /// Running a query twice gives different documents.
+    Phantom,
+    /// Two read-modify-writes of a document both commit, so one of the writes is lost.
+    LostUpdate,
+    /// Two transactions check a constraint on the same documents, then write different ones,
+    /// breaking the constraint together.
+    WriteSkew,
+    /// Two documents are read from different states of the database.
+    ReadSkew,
+}
+
+impl Anomaly {
+    pub const ALL: [Anomaly; 6] = [
+        Anomaly::DirtyRead,
+        Anomaly::NonRepeatableRead,
+        Anomaly::Phantom,
+        Anomaly::LostUpdate,
+        Anomaly::WriteSkew,
+        Anomaly::ReadSkew,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Anomaly::DirtyRead => "dirty read",
+            Anomaly::NonRepeatableRead => "non-repeatable read",
+            Anomaly::Phantom => "phantom",
+            Anomaly::LostUpdate => "lost update",
+            Anomaly::WriteSkew => "write skew",
+            Anomaly::ReadSkew => "read skew",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadLevel {
+    Local,
+    Majority,
+    Snapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteLevel {
+    W1,
+    Majority,
+}
+
+/// Read and write concern of both transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Isolation {
+    pub read: ReadLevel,
+    pub write: WriteLevel,
+}
+
+impl Isolation {
+    /// Every combination of the levels.
+    pub fn all() -> Vec<Isolation> {
+        let mut all = vec![];
+        for read in [ReadLevel::Local, ReadLevel::Majority, ReadLevel::Snapshot] {
+            for write in [WriteLevel::W1, WriteLevel::Majority] {
+                all.push(Isolation { read, write });
+            }
+        }
+        all
+    }
+
+    pub fn transaction_options(&self) -> TransactionOptions {
+        let read_concern = match self.read {
+            ReadLevel::Local => ReadConcern::local(),
+            ReadLevel::Majority => ReadConcern::majority(),
+            ReadLevel::Snapshot => ReadConcern::snapshot(),
+        };
+        let w = match self.write {
+            WriteLevel::W1 => Acknowledgment::Nodes(1),
+            WriteLevel::Majority => Acknowledgment::Majority,
+        };
+        TransactionOptions::builder()
+            .read_concern(read_concern)
+            .write_concern(WriteConcern::builder().w(w).build())
+            .build()
+    }
+}
+
+impl fmt::Display for Isolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let read = match self.read {
+            ReadLevel::Local => "local",
+            ReadLevel::Majority => "majority",
+            ReadLevel::Snapshot => "snapshot",
+        };
+        let write = match self.write {
+            WriteLevel::W1 => "w:1",
+            WriteLevel::Majority => "w:majority",
+        };
+        write!(f, "{}/{}", read, write)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The anomaly was observed.
+    Allowed,
+    /// Tells what prevented it: the snapshot of the transaction or a write conflict.
+    Prevented(&'static str),
+    /// The run failed for another reason.
+    Error(String),
+}
+
+impl Outcome {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Outcome::Allowed)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Allowed => write!(f, "allowed"),
+            Outcome::Prevented(_) => write!(f, "prevented"),
+            Outcome::Error(_) => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixRow {
+    pub isolation: Isolation,
+    /// In the order of [`Anomaly::ALL`].
+    pub outcomes: Vec<(Anomaly, Outcome)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix {
+    pub rows: Vec<MatrixRow>,
+}
+
+impl Matrix {
+    pub fn outcome(&self, isolation: Isolation, anomaly: Anomaly) -> Option<&Outcome> {
+        self.rows
+            .iter()
+            .find(|r| r.isolation == isolation)?
+            .outcomes
+            .iter()
+            .find(|(a, _)| *a == anomaly)
+            .map(|(_, outcome)| outcome)
+    }
+}
+
+/// The table, followed by what prevented each anomaly and the errors.
+impl fmt::Display for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let first_width = self
+            .rows
+            .iter()
+            .map(|r| r.isolation.to_string().len())
+            .chain(Some("isolation".len()))
+            .max()
+            .unwrap_or(0);
+        write!(f, "{:width$}", "isolation", width = first_width)?;
+        for anomaly in &Anomaly::ALL {
+            write!(
+                f,
+                "  {:width$}",
+                anomaly.name(),
+                width = anomaly.name().len().max(9)
+            )?;
+        }
+        writeln!(f)?;
+        for row in &self.rows {
+            write!(
+                f,
+                "{:width$}",
+                row.isolation.to_string(),
+                width = first_width
+            )?;
+            for (anomaly, outcome) in &row.outcomes {
+                let width = anomaly.name().len().max(9);
+                write!(f, "  {:width$}", outcome.to_string(), width = width)?;
+            }
+            writeln!(f)?;
+        }
+
+        for row in &self.rows {
+            for (anomaly, outcome) in &row.outcomes {
+                match outcome {
+                    Outcome::Prevented(by) => writeln!(
+                        f,
+                        "{} {}: prevented by {}",
+                        row.isolation,
+                        anomaly.name(),
+                        by
+                    )?,
+                    Outcome::Error(e) => {
+                        writeln!(f, "{} {}: {}", row.isolation, anomaly.name(), e)?
+                    }
+                    Outcome::Allowed => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+const SNAPSHOT: &str = "the snapshot of the transaction";
+const WRITE_CONFLICT: &str = "a write conflict";
+
+pub async fn matrix(client: &Client, target: &Target) -> Result<Matrix> {
+    let storage = MongoStorage::new(&client.database(&target.db_name));
+    Ok(matrix_on(&storage, client, ANOMALY_COLL, &Isolation::all()).await)
+}
+
+/// Runs every anomaly under each of `levels`, on `coll` of `storage`.
+pub async fn matrix_on<S: Storage, C: SessionSource<S>>(
+    storage: &S,
+    sessions: &C,
+    coll: &str,
+    levels: &[Isolation],
+) -> Matrix {
+    let mut rows = vec![];
+    for isolation in levels {
+        let run = Run {
+            storage,
+            sessions,
+            coll,
+            options: isolation.transaction_options(),
+        };
+        let mut outcomes = vec![];
+        for anomaly in &Anomaly::ALL {
+            let outcome = run
+                .anomaly(*anomaly)
+                .await
+                .unwrap_or_else(|e| Outcome::Error(format!("{:#}", e)));
+            outcomes.push((*anomaly, outcome));
+        }
+        rows.push(MatrixRow {
+            isolation: *isolation,
+            outcomes,
+        });
+    }
+    Matrix { rows }
+}
+
+pub async fn drop_colls(client: &Client, target: &Target) -> Result<()> {
+    let storage = MongoStorage::new(&client.database(&target.db_name));
+    storage.drop_collection(ANOMALY_COLL).await?;
+    Ok(())
+}
+
+/// Whether the error is the write conflict that prevents an anomaly.
+fn is_conflict(e: &anyhow::Error) -> bool {
+    e.has_label(TRANSIENT_TRANSACTION_ERROR)
+}
+
+fn value_doc(id: &str, group: &str, value: i32) -> Document {
+    doc! {"id": id, "group": group, "value": value}
+}
+
+/// One isolation level of the matrix.
+struct Run<'a, S, C> {
+    storage: &'a S,
+    sessions: &'a C,
+    coll: &'a str,
+    options: TransactionOptions,
+}
+
+impl<S: Storage, C: SessionSource<S>> Run<'_, S, C> {
+    async fn anomaly(&self, anomaly: Anomaly) -> Result<Outcome> {
+        match anomaly {
+            Anomaly::DirtyRead => self.dirty_read().await,
+            Anomaly::NonRepeatableRead => self.non_repeatable_read().await,
+            Anomaly::Phantom => self.phantom().await,
+            Anomaly::LostUpdate => self.lost_update().await,
+            Anomaly::WriteSkew => self.write_skew().await,
+            Anomaly::ReadSkew => self.read_skew().await,
+        }
+    }
+
+    /// Writes the documents again, outside transactions.
+    async fn reset(&self, docs: Vec<Document>) -> Result<()> {
+        self.storage.drop_collection(self.coll).await?;
+        for doc in docs {
+            self.storage.insert_one(self.coll, doc, None).await?;
+        }
+        Ok(())
+    }
+
+    async fn start(&self) -> Result<S::Session> {
+        let mut session = self.sessions.start_session().await?;
+        session
+            .start_transaction(self.options.clone())
+            .await
+            .map_err(crate::DbError::from)?;
+        Ok(session)
+    }
+
+    async fn read(&self, id: &str, session: Option<&mut S::Session>) -> Result<i32> {
+        let found = self
+            .storage
+            .find_one(self.coll, doc! {"id": id}, session)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("{} is not found", id))?;
+        Ok(found.get_i32("value")?)
+    }
+
+    async fn write(&self, id: &str, value: i32, session: &mut S::Session) -> Result<()> {
+        self.storage
+            .update_one(
+                self.coll,
+                doc! {"id": id},
+                doc! {"$set": {"value": value}},
+                Some(session),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn count(&self, group: &str, session: &mut S::Session) -> Result<usize> {
+        let found = self
+            .storage
+            .find(self.coll, doc! {"group": group}, Some(session))
+            .await?;
+        Ok(found.len())
+    }
+
+    async fn commit(session: &mut S::Session) -> Result<()> {
+        session
+            .commit_transaction()
+            .await
+            .map_err(crate::DbError::from)?;
+        Ok(())
+    }
+
+    /// T2 reads the value T1 wrote before T1 aborts.
+    async fn dirty_read(&self) -> Result<Outcome> {
+        self.reset(vec![value_doc("x", "a", 1)]).await?;
+        let script = Script::new(&["T1: write", "T2: read", "T1: abort"]);
+        let (t1, t2) = (script.actor("T1"), script.actor("T2"));
+
+        let first = async {
+            let mut t1 = t1;
+            let mut session = self.start().await?;
+            t1.at("write", self.write("x", 2, &mut session)).await??;
+            t1.at("abort", session.abort_transaction()).await?.ok();
+            Ok::<_, anyhow::Error>(())
+        };
+        let second = async {
+            let mut t2 = t2;
+            let mut session = self.start().await?;
+            let read = t2.at("read", self.read("x", Some(&mut session))).await??;
+            Self::commit(&mut session).await?;
+            Ok::<_, anyhow::Error>(read)
+        };
+        let ((), read) = futures::try_join!(first, second)?;
+
+        Ok(if read == 2 {
+            Outcome::Allowed
+        } else {
+            Outcome::Prevented(SNAPSHOT)
+        })
+    }
+
+    /// T1 reads a value before and after T2 commits a new one.
+    async fn non_repeatable_read(&self) -> Result<Outcome> {
+        self.reset(vec![value_doc("x", "a", 1)]).await?;
+        let script = Script::new(&["T1: read", "T2: write", "T2: commit", "T1: read again"]);
+        let (t1, t2) = (script.actor("T1"), script.actor("T2"));
+
+        let first = async {
+            let mut t1 = t1;
+            let mut session = self.start().await?;
+            let before = t1.at("read", self.read("x", Some(&mut session))).await??;
+            let after = t1
+                .at("read again", self.read("x", Some(&mut session)))
+                .await??;
+            Self::commit(&mut session).await?;
+            Ok::<_, anyhow::Error>((before, after))
+        };
+        let second = async {
+            let mut t2 = t2;
+            let mut session = self.start().await?;
+            t2.at("write", self.write("x", 2, &mut session)).await??;
+            t2.at("commit", Self::commit(&mut session)).await??;
+            Ok::<_, anyhow::Error>(())
+        };
+        let ((before, after), ()) = futures::try_join!(first, second)?;
+
+        Ok(if before != after {
+            Outcome::Allowed
+        } else {
+            Outcome::Prevented(SNAPSHOT)
+        })
+    }
+
+    /// T1 runs a query before and after T2 commits a document matching it.
+    async fn phantom(&self) -> Result<Outcome> {
+        self.reset(vec![value_doc("x", "a", 1)]).await?;
+        let script = Script::new(&["T1: count", "T2: insert", "T2: commit", "T1: count again"]);
+        let (t1, t2) = (script.actor("T1"), script.actor("T2"));
+
+        let first = async {
+            let mut t1 = t1;
+            let mut session = self.start().await?;
+            let before = t1.at("count", self.count("a", &mut session)).await??;
+            let after = t1
+                .at("count again", self.count("a", &mut session))
+                .await??;
+            Self::commit(&mut session).await?;
+            Ok::<_, anyhow::Error>((before, after))
+        };
+        let second = async {
+            let mut t2 = t2;
+            let mut session = self.start().await?;
+            t2.at(
+                "insert",
+                self.storage
+                    .insert_one(self.coll, value_doc("y", "a", 1), Some(&mut session)),
+            )
+            .await??;
+            t2.at("commit", Self::commit(&mut session)).await??;
+            Ok::<_, anyhow::Error>(())
+        };
+        let ((before, after), ()) = futures::try_join!(first, second)?;
+
+        Ok(if before != after {
+            Outcome::Allowed
+        } else {
+            Outcome::Prevented(SNAPSHOT)
+        })
+    }
+
+    /// T1 and T2 both increment the value they read. One increment is lost if both commit.
+    async fn lost_update(&self) -> Result<Outcome> {
+        self.reset(vec![value_doc("x", "a", 10)]).await?;
+        let script = Script::new(&[
+            "T1: read",
+            "T2: read",
+            "T1: write",
+            "T1: commit",
+            "T2: write",
+            "T2: commit",
+        ]);
+        let (t1, t2) = (script.actor("T1"), script.actor("T2"));
+
+        let first = async {
+            let mut t1 = t1;
+            let mut session = self.start().await?;
+            let read = t1.at("read", self.read("x", Some(&mut session))).await??;
+            t1.at("write", self.write("x", read + 1, &mut session))
+                .await??;
+            t1.at("commit", Self::commit(&mut session)).await??;
+            Ok::<_, anyhow::Error>(())
+        };
+        let second = async {
+            let mut t2 = t2;
+            let mut session = self.start().await?;
+            let read = t2.at("read", self.read("x", Some(&mut session))).await??;
+            let written = t2
+                .at("write", self.write("x", read + 1, &mut session))
+                .await?;
+            let committed = t2
+                .at("commit", async {
+                    match written {
+                        Ok(()) => Self::commit(&mut session).await,
+                        Err(e) => {
+                            let _ = session.abort_transaction().await;
+                            Err(e)
+                        }
+                    }
+                })
+                .await?;
+            Ok::<_, anyhow::Error>(committed)
+        };
+        let ((), committed) = futures::try_join!(first, second)?;
+
+        match committed {
+            Err(e) if is_conflict(&e) => Ok(Outcome::Prevented(WRITE_CONFLICT)),
+            Err(e) => Err(e),
+            Ok(()) if self.read("x", None).await? == 11 => Ok(Outcome::Allowed),
+            Ok(()) => Ok(Outcome::Prevented(SNAPSHOT)),
+        }
+    }
+
+    /// At least one of `a` and `b` must stay 1. T1 and T2 both see two of them at 1,
+    /// and each sets a different one to 0.
+    async fn write_skew(&self) -> Result<Outcome> {
+        self.reset(vec![
+            value_doc("a", "on_call", 1),
+            value_doc("b", "on_call", 1),
+        ])
+        .await?;
+        let script = Script::new(&[
+            "T1: read",
+            "T2: read",
+            "T1: write",
+            "T2: write",
+            "T1: commit",
+            "T2: commit",
+        ]);
+        let (t1, t2) = (script.actor("T1"), script.actor("T2"));
+
+        let leave = |actor, id: &'static str| async move {
+            let mut actor: crate::interleaving::Actor = actor;
+            let mut session = self.start().await?;
+            let on_call = actor
+                .at("read", async {
+                    Ok::<_, anyhow::Error>(
+                        self.read("a", Some(&mut session)).await?
+                            + self.read("b", Some(&mut session)).await?,
+                    )
+                })
+                .await??;
+            let written = actor
+                .at("write", async {
+                    if on_call >= 2 {
+                        self.write(id, 0, &mut session).await
+                    } else {
+                        Ok(())
+                    }
+                })
+                .await?;
+            let committed = actor
+                .at("commit", async {
+                    match written {
+                        Ok(()) => Self::commit(&mut session).await,
+                        Err(e) => {
+                            let _ = session.abort_transaction().await;
+                            Err(e)
+                        }
+                    }
+                })
+                .await?;
+            Ok::<_, anyhow::Error>(committed)
+        };
+        let (first, second) = futures::try_join!(leave(t1, "a"), leave(t2, "b"))?;
+
+        for committed in [first, second] {
+            match committed {
+                Err(e) if is_conflict(&e) => return Ok(Outcome::Prevented(WRITE_CONFLICT)),
+                Err(e) => return Err(e),
+                Ok(()) => {}
+            }
+        }
+        let on_call = self.read("a", None).await? + self.read("b", None).await?;
+        Ok(if on_call == 0 {
+            Outcome::Allowed
+        } else {
+            Outcome::Prevented(SNAPSHOT)
+        })
+    }
+
+    /// `x + y` is always 100. T1 reads `x` before and `y` after T2 moves 25 from `x` to `y`.
+    async fn read_skew(&self) -> Result<Outcome> {
+        self.reset(vec![value_doc("x", "a", 50), value_doc("y", "a", 50)])
+            .await?;
+        let script = Script::new(&["T1: read x", "T2: write", "T2: commit", "T1: read y"]);
+        let (t1, t2) = (script.actor("T1"), script.actor("T2"));
+
+        let first = async {
+            let mut t1 = t1;
+            let mut session = self.start().await?;
+            let x = t1
+                .at("read x", self.read("x", Some(&mut session)))
+                .await??;
+            let y = t1
+                .at("read y", self.read("y", Some(&mut session)))
+                .await??;
+            Self::commit(&mut session).await?;
+            Ok::<_, anyhow::Error>(x + y)
+        };
+        let second = async {
+            let mut t2 = t2;
+            let mut session = self.start().await?;
+            t2.at("write", async {
+                self.write("x", 25, &mut session).await?;
+                self.write("y", 75, &mut session).await
+            })
+            .await??;
+            t2.at("commit", Self::commit(&mut session)).await??;
+            Ok::<_, anyhow::Error>(())
+        };
+        let (sum, ()) = futures::try_join!(first, second)?;
+
+        Ok(if sum != 100 {
+            Outcome::Allowed
+        } else {
+            Outcome::Prevented(SNAPSHOT)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn memory_storage_gives_snapshot_isolation() {
+        let storage = MemoryStorage::new();
+        let levels = Isolation::all();
+        let matrix = matrix_on(&storage, &storage, ANOMALY_COLL, &levels).await;
+
+        for isolation in levels.iter().copied() {
+            for anomaly in &Anomaly::ALL {
+                let outcome = matrix.outcome(isolation, *anomaly).unwrap();
+                assert!(
+                    !matches!(outcome, Outcome::Error(_)),
+                    "{} {}: {:?}",
+                    isolation,
+                    anomaly.name(),
+                    outcome
+                );
+                // only write skew gets through a snapshot.
+                assert_eq!(
+                    outcome.is_allowed(),
+                    *anomaly == Anomaly::WriteSkew,
+                    "{} {}",
+                    isolation,
+                    anomaly.name()
+                );
+            }
+        }
+        assert_eq!(
+            matrix.outcome(levels[0], Anomaly::LostUpdate),
+            Some(&Outcome::Prevented(WRITE_CONFLICT))
+        );
+    }
+
+    #[test]
+    fn prints_table() {
+        let isolation = Isolation {
+            read: ReadLevel::Snapshot,
+            write: WriteLevel::Majority,
+        };
+        let matrix = Matrix {
+            rows: vec![MatrixRow {
+                isolation,
+                outcomes: Anomaly::ALL
+                    .iter()
+                    .map(|a| (*a, Outcome::Prevented(SNAPSHOT)))
+                    .collect(),
+            }],
+        };
+        let table = matrix.to_string();
+        let mut lines = table.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("isolation            dirty read"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("snapshot/w:majority  prevented "));
+    }
+}
@@ -0,0 +1,27 @@
+pub mod basic;
+pub mod contention;
+pub mod isolation;
+pub mod optimistic_lock;
+pub mod pessimistic_lock;
+pub mod write_conflict;
+
+/// Database and collection names a scenario works on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub db_name: String,
+    pub users: String,
+    pub books: String,
+    pub index_test: String,
+}
+
+impl Target {
+    /// Target with the default collection names.
+    pub fn new(db_name: &str) -> Self {
+        Target {
+            db_name: db_name.to_string(),
+            users: "users".to_string(),
+            books: "books".to_string(),
+            index_test: "index_test".to_string(),
+        }
+    }
+}
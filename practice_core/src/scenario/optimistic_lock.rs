@@ -0,0 +1,218 @@
+//! Scenario originally written for the `optimistic_lock` binary.
+//!
+//! Updates are conditioned on the `version` of the book, so that a writer
+//! holding a stale version gets a `VersionConflict`.
+
+use anyhow::Result;
+use mongodb::{bson::doc, Client, ClientSession, Collection};
+use tracing::{info, warn};
+
+use super::Target;
+use crate::{
+    commit_tx,
+    interleaving::Script,
+    majority_tx_options,
+    models::book_fields,
+    s,
+    storage::MongoStorage,
+    versioned::{update_with_version_with_session, VersionError, VersionResult},
+    Book, DbError,
+};
+
+/// default database of this scenario.
+pub const DB_NAME: &str = "tx_test_db_1";
+
+pub async fn conflict_updating(client: &Client, target: &Target) -> Result<()> {
+    let script = Script::new(&[
+        "T1: update",
+        "T2: read",
+        "T2: update",
+        "T1: commit",
+        "T3: update",
+    ]);
+    let (t1, t2, t3) = (script.actor("T1"), script.actor("T2"), script.actor("T3"));
+    let db = client.database(&target.db_name);
+    let book_coll = db.collection::<Book>(&target.books);
+    let storage = MongoStorage::new(&db);
+
+    let session_1 = async {
+        // owned by the session, so that leaving early fails the others at once.
+        let mut t1 = t1;
+        let mut session = client.start_session(None).await?;
+        session.start_transaction(majority_tx_options()).await?;
+
+        let result = t1
+            .at(
+                "update",
+                update_users_name(
+                    &storage,
+                    &target.books,
+                    &mut session,
+                    "book_1",
+                    "update_in_session1",
+                    1,
+                ),
+            )
+            .await?;
+        info!(?result, "updated in session 1");
+        result?;
+
+        t1.at("commit", commit_tx(&mut session)).await??;
+
+        let found = find_book(&book_coll, &mut session).await?;
+        info!(book = ?found, "found in session 1");
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let session_2 = async {
+        let mut t2 = t2;
+        let mut session = client.start_session(None).await?;
+        session.start_transaction(majority_tx_options()).await?;
+
+        let found = t2.at("read", find_book(&book_coll, &mut session)).await??;
+        // session 1 hasn't committed yet.
+        assert_eq!(found.as_ref().map(|b| b.version), Some(1));
+        info!(book = ?found, "found in session 2 before the update");
+
+        let result = t2
+            .at(
+                "update",
+                update_users_name(
+                    &storage,
+                    &target.books,
+                    &mut session,
+                    "book_1",
+                    "update_in_session2",
+                    1,
+                ),
+            )
+            .await?;
+        assert!(
+            matches!(result, Err(VersionError::Db(DbError::WriteConflict(_)))),
+            "{:?}",
+            result
+        );
+        info!(error = ?result.as_ref().err(), "session 2 failed as expected");
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let session_3 = async {
+        let mut t3 = t3;
+        let mut session = client.start_session(None).await?;
+        session.start_transaction(majority_tx_options()).await?;
+
+        let result = t3
+            .at(
+                "update",
+                update_users_name(
+                    &storage,
+                    &target.books,
+                    &mut session,
+                    "book_1",
+                    "update_in_session3",
+                    1,
+                ),
+            )
+            .await?;
+        // session 1 has already committed version 2.
+        assert!(
+            matches!(
+                result,
+                Err(VersionError::VersionConflict {
+                    expected: 1,
+                    actual: 2
+                })
+            ),
+            "{:?}",
+            result
+        );
+        Ok::<_, anyhow::Error>(())
+    };
+
+    futures::try_join!(session_1, session_2, session_3)?;
+
+    Ok(())
+}
+
+async fn find_book(
+    book_coll: &Collection<Book>,
+    session: &mut ClientSession,
+) -> mongodb::error::Result<Option<Book>> {
+    book_coll
+        .find_one_with_session(Some(doc! {book_fields::ID: "book_1"}), None, session)
+        .await
+}
+
+pub async fn update_users_name(
+    storage: &MongoStorage,
+    books: &str,
+    session: &mut ClientSession,
+    book_id: &str,
+    name: &str,
+    version: i64,
+) -> VersionResult<Book> {
+    update_with_version_with_session(
+        storage,
+        books,
+        book_id,
+        version,
+        doc! {"$set": {book_fields::NAME: name}},
+        session,
+    )
+    .await
+}
+
+pub async fn drop_colls(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let book_coll = db.collection::<Book>(&target.books);
+    book_coll.drop(None).await?;
+
+    Ok(())
+}
+
+pub async fn create_books(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let book_coll = db.collection::<Book>(&target.books);
+    if let Err(e) = book_coll.drop(None).await {
+        warn!(error = %e, "failed to drop the books");
+    }
+    book_coll
+        .insert_many(
+            vec![
+                Book {
+                    id: s("book_1"),
+                    name: s("john"),
+                    reviews: vec![],
+                    authors: vec![],
+                    supervisors: vec![],
+                    version: 1,
+                },
+                Book {
+                    id: s("book_2"),
+                    name: s("anna"),
+                    reviews: vec![],
+                    authors: vec![],
+                    supervisors: vec![],
+                    version: 1,
+                },
+            ],
+            None,
+        )
+        .await?;
+
+    book_coll
+        .insert_one(
+            Book {
+                id: s("book_3"),
+                name: s("joseph"),
+                reviews: vec![],
+                authors: vec![],
+                supervisors: vec![],
+                version: 1,
+            },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
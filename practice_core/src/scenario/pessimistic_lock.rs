@@ -0,0 +1,117 @@
+//! Serializes two transactions on a user deliberately with `lock_for_update`.
+//!
+//! Unlike the write conflict scenario, the second transaction doesn't fail but waits
+//! until the first one commits, then sees its update.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use mongodb::{bson::doc, Client};
+use tracing::info;
+
+use super::Target;
+use crate::{
+    commit_tx,
+    interleaving::Script,
+    lock::{lock_for_update, try_lock_for_update, LockError, LockOptions},
+    majority_tx_options,
+    models::user_fields,
+    User, UserRepository,
+};
+
+/// default database of this scenario.
+pub const DB_NAME: &str = "tx_test_db_2";
+
+pub async fn locked_updating(client: &Client, target: &Target) -> Result<()> {
+    let script = Script::new(&["T1: lock", "T2: try lock", "T2: lock", "T1: commit"]);
+    let (t1, t2) = (script.actor("T1"), script.actor("T2"));
+    let db = client.database(&target.db_name);
+    let users = UserRepository::new(&db, &target.users);
+    let user_filter = || doc! {user_fields::ID: "user_1"};
+
+    let session_1 = async {
+        let mut t1 = t1;
+        let mut session = client.start_session(None).await?;
+        session.start_transaction(majority_tx_options()).await?;
+
+        {
+            let _step = t1.step("lock").await?;
+            let locked: User = lock_for_update(
+                users.storage(),
+                &mut session,
+                &target.users,
+                user_filter(),
+                &LockOptions::default(),
+            )
+            .await?;
+            info!(user = ?locked, "locked in session 1");
+
+            users
+                .update_name_with_session("user_1", "update_in_session1", &mut session)
+                .await?;
+        }
+
+        t1.at("commit", commit_tx(&mut session)).await??;
+        info!("session 1 committed");
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let session_2 = async {
+        let mut t2 = t2;
+        let mut session = client.start_session(None).await?;
+        session.start_transaction(majority_tx_options()).await?;
+
+        let result: Result<User, LockError> = t2
+            .at(
+                "try lock",
+                try_lock_for_update(
+                    users.storage(),
+                    &mut session,
+                    &target.users,
+                    user_filter(),
+                    &LockOptions::default(),
+                ),
+            )
+            .await?;
+        assert!(matches!(result, Err(LockError::Busy)), "{:?}", result);
+        info!(error = ?result.as_ref().err(), "session 2 failed to lock as expected");
+
+        let _ = session.abort_transaction().await;
+        session.start_transaction(majority_tx_options()).await?;
+        // session 1 commits while this one waits for the lock.
+        drop(t2.step("lock").await?);
+        let locked: User = lock_for_update(
+            users.storage(),
+            &mut session,
+            &target.users,
+            user_filter(),
+            &LockOptions {
+                wait: Duration::from_secs(10),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // the lock is taken after session 1 committed, so its update is visible.
+        assert_eq!(locked.name, "update_in_session1");
+        info!(user = ?locked, "locked in session 2");
+
+        users
+            .update_name_with_session("user_1", "update_in_session2", &mut session)
+            .await?;
+        commit_tx(&mut session).await?;
+        Ok::<_, anyhow::Error>(())
+    };
+
+    futures::try_join!(session_1, session_2)?;
+
+    Ok(())
+}
+
+pub async fn drop_colls(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let user_coll = db.collection::<User>(&target.users);
+    user_coll.drop(None).await?;
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Scenario originally written for the `clientv2_lock` binary.
+//!
+//! A second transaction updating a document that is already updated by another
+//! uncommitted transaction fails with a WriteConflict error.
+
+use anyhow::Result;
+use mongodb::{Client, ClientSession};
+use tracing::{info, warn};
+
+use super::Target;
+use crate::{
+    commit_tx, error::Required, interleaving::Script, majority_tx_options, s, DbError, User,
+    UserRepository,
+};
+
+/// default database of this scenario.
+pub const DB_NAME: &str = "tx_test_db";
+
+pub async fn conflict_updating(client: &Client, target: &Target) -> Result<()> {
+    let script = Script::new(&["T1: update", "T2: update", "T1: commit"]);
+    let (t1, t2) = (script.actor("T1"), script.actor("T2"));
+    let db = client.database(&target.db_name);
+    let users = UserRepository::new(&db, &target.users);
+
+    let session_1 = async {
+        let mut t1 = t1;
+        let mut session = client.start_session(None).await?;
+        session.start_transaction(majority_tx_options()).await?;
+
+        let result = t1
+            .at(
+                "update",
+                update_users_name(&users, &mut session, "user_1", "update_in_session1"),
+            )
+            .await?;
+        info!(?result, "updated in session 1");
+        result?;
+
+        t1.at("commit", commit_tx(&mut session)).await??;
+
+        let found = users
+            .find_by_id_with_session("user_1", &mut session)
+            .await
+            .required("user user_1")?;
+        assert_eq!(found.name, "update_in_session1");
+        info!(user = ?found, "found in session 1");
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let session_2 = async {
+        let mut t2 = t2;
+        let mut session = client.start_session(None).await?;
+        session.start_transaction(majority_tx_options()).await?;
+
+        let result = t2
+            .at(
+                "update",
+                update_users_name(&users, &mut session, "user_1", "update_in_session2"),
+            )
+            .await?;
+
+        let conflict = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<DbError>());
+        assert!(
+            matches!(conflict, Some(DbError::WriteConflict(_))),
+            "{:?}",
+            result
+        );
+        info!(error = ?result.as_ref().err(), "session 2 failed as expected");
+        Ok::<_, anyhow::Error>(())
+    };
+
+    futures::try_join!(session_1, session_2)?;
+
+    Ok(())
+}
+
+pub async fn update_users_name(
+    users: &UserRepository,
+    session: &mut ClientSession,
+    user_id: &str,
+    name: &str,
+) -> Result<()> {
+    {
+        let result = users.update_name_with_session(user_id, name, session).await;
+
+        match &result {
+            Err(DbError::WriteConflict(err)) => warn!(error = %err, "write conflict"),
+            Err(err) => warn!(error = %err, "update failed"),
+            Ok(_) => {}
+        }
+        result?;
+    }
+
+    Ok(())
+}
+
+pub async fn drop_colls(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let user_coll = db.collection::<User>(&target.users);
+    user_coll.drop(None).await?;
+
+    Ok(())
+}
+
+pub async fn create_users(client: &Client, target: &Target) -> Result<()> {
+    let db = client.database(&target.db_name);
+    let user_coll = db.collection::<User>(&target.users);
+    if let Err(e) = user_coll.drop(None).await {
+        warn!(error = %e, "failed to drop the users");
+    }
+    user_coll
+        .insert_many(
+            vec![
+                User {
+                    id: s("user_1"),
+                    name: s("john"),
+                    reviewed_book_ids: vec![],
+                },
+                User {
+                    id: s("user_2"),
+                    name: s("anna"),
+                    reviewed_book_ids: vec![],
+                },
+            ],
+            None,
+        )
+        .await?;
+
+    user_coll
+        .insert_one(
+            User {
+                id: s("user_3"),
+                name: s("joseph"),
+                reviewed_book_ids: vec![],
+            },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
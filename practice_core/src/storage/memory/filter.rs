@@ -0,0 +1,210 @@
+//! Query filters of the in-memory storage.
+//!
+//! Supports equality (matching an element of an array too), `$eq`, `$ne`, `$gt`, `$gte`,
+//! `$lt`, `$lte`, `$in`, `$nin`, `$exists`, `$and`, `$or` and `$nor` on dotted paths.
+
+use std::cmp::Ordering;
+
+use mongodb::{
+    bson::{Bson, Document},
+    error::Result,
+};
+
+use super::bad_value;
+
+/// The value at a dotted `path`. Paths through arrays like `reviews.user_id` are not supported.
+pub(super) fn lookup<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut current = doc.get(parts.next()?)?;
+    for part in parts {
+        current = match current {
+            Bson::Document(d) => d.get(part)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+fn as_f64(v: &Bson) -> Option<f64> {
+    match v {
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        Bson::Double(f) => Some(*f),
+        _ => None,
+    }
+}
+
+/// Equality where numbers of different types are equal if their values are.
+pub(super) fn values_equal(a: &Bson, b: &Bson) -> bool {
+    match (as_f64(a), as_f64(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => match (a, b) {
+            (Bson::Array(a), Bson::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| values_equal(a, b))
+            }
+            (Bson::Document(a), Bson::Document(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b)
+                        .all(|((ka, va), (kb, vb))| ka == kb && values_equal(va, vb))
+            }
+            _ => a == b,
+        },
+    }
+}
+
+/// Ordering of values of the same kind. Values of different kinds are not comparable.
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (as_f64(a), as_f64(b)) {
+        return a.partial_cmp(&b);
+    }
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        _ => None,
+    }
+}
+
+/// `value` is the field of the document, `None` when it is missing.
+fn equals(value: Option<&Bson>, expected: &Bson) -> bool {
+    match value {
+        None => *expected == Bson::Null,
+        Some(value) if values_equal(value, expected) => true,
+        Some(Bson::Array(elements)) => elements.iter().any(|e| values_equal(e, expected)),
+        Some(_) => false,
+    }
+}
+
+fn compares(value: Option<&Bson>, expected: &Bson, accept: fn(Ordering) -> bool) -> bool {
+    let matches = |v: &Bson| compare(v, expected).map(accept).unwrap_or(false);
+    match value {
+        None => false,
+        Some(Bson::Array(elements)) => elements.iter().any(matches),
+        Some(value) => matches(value),
+    }
+}
+
+fn array_operand<'a>(op: &str, operand: &'a Bson) -> Result<&'a Vec<Bson>> {
+    match operand {
+        Bson::Array(values) => Ok(values),
+        _ => Err(bad_value(format!("{} needs an array", op))),
+    }
+}
+
+pub(super) fn is_operator_doc(cond: &Bson) -> bool {
+    match cond {
+        Bson::Document(d) => d.keys().next().is_some_and(|k| k.starts_with('$')),
+        _ => false,
+    }
+}
+
+/// Whether `value` satisfies `cond`, which is either a value to be equal to or operators.
+pub(super) fn value_matches(value: Option<&Bson>, cond: &Bson) -> Result<bool> {
+    let ops = match cond {
+        Bson::Document(ops) if is_operator_doc(cond) => ops,
+        _ => return Ok(equals(value, cond)),
+    };
+    for (op, operand) in ops {
+        let matched = match op.as_str() {
+            "$eq" => equals(value, operand),
+            "$ne" => !equals(value, operand),
+            "$gt" => compares(value, operand, |o| o == Ordering::Greater),
+            "$gte" => compares(value, operand, |o| o != Ordering::Less),
+            "$lt" => compares(value, operand, |o| o == Ordering::Less),
+            "$lte" => compares(value, operand, |o| o != Ordering::Greater),
+            "$in" => array_operand(op, operand)?
+                .iter()
+                .any(|candidate| equals(value, candidate)),
+            "$nin" => !array_operand(op, operand)?
+                .iter()
+                .any(|candidate| equals(value, candidate)),
+            "$exists" => value.is_some() == matches!(operand, Bson::Boolean(true)),
+            other => return Err(bad_value(format!("unknown operator: {}", other))),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn each_filter<'a>(op: &str, operand: &'a Bson) -> Result<Vec<&'a Document>> {
+    array_operand(op, operand)?
+        .iter()
+        .map(|f| match f {
+            Bson::Document(f) => Ok(f),
+            _ => Err(bad_value(format!("{} needs an array of documents", op))),
+        })
+        .collect()
+}
+
+pub(super) fn matches(doc: &Document, filter: &Document) -> Result<bool> {
+    for (key, cond) in filter {
+        let matched = match key.as_str() {
+            "$and" => {
+                let mut all = true;
+                for f in each_filter(key, cond)? {
+                    all = all && matches(doc, f)?;
+                }
+                all
+            }
+            "$or" | "$nor" => {
+                let mut any = false;
+                for f in each_filter(key, cond)? {
+                    any = any || matches(doc, f)?;
+                }
+                any == (key == "$or")
+            }
+            op if op.starts_with('$') => {
+                return Err(bad_value(format!("unknown top level operator: {}", op)))
+            }
+            path => value_matches(lookup(doc, path), cond)?,
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn book() -> Document {
+        doc! {"id": "book_1", "version": 2i64, "authors": ["author_1", "author_2"], "meta": {"pages": 120}}
+    }
+
+    #[test]
+    fn equality_and_array_elements() {
+        assert!(matches(&book(), &doc! {"id": "book_1"}).unwrap());
+        assert!(matches(&book(), &doc! {"authors": "author_2"}).unwrap());
+        assert!(matches(&book(), &doc! {"version": 2}).unwrap());
+        assert!(matches(&book(), &doc! {"meta.pages": 120}).unwrap());
+        assert!(!matches(&book(), &doc! {"id": "book_2"}).unwrap());
+        assert!(matches(&book(), &doc! {"missing": null}).unwrap());
+    }
+
+    #[test]
+    fn in_and_logical_operators() {
+        let authors: Vec<&str> = vec![];
+        assert!(!matches(&book(), &doc! {"authors": {"$in": authors}}).unwrap());
+        assert!(matches(&book(), &doc! {"authors": {"$in": ["x", "author_1"]}}).unwrap());
+        assert!(matches(
+            &book(),
+            &doc! {"$and": [{"id": "book_1"}, {"version": {"$gte": 2, "$lt": 3}}]}
+        )
+        .unwrap());
+        assert!(!matches(&book(), &doc! {"$and": [{"id": "book_1"}, {"version": 1}]}).unwrap());
+        assert!(matches(&book(), &doc! {"$or": [{"id": "x"}, {"version": 2}]}).unwrap());
+        assert!(matches(&book(), &doc! {"missing": {"$exists": false}}).unwrap());
+    }
+
+    #[test]
+    fn unknown_operator() {
+        assert!(matches(&book(), &doc! {"id": {"$regex": "book"}}).is_err());
+    }
+}
@@ -0,0 +1,899 @@
+//! Documents kept in memory, for running the scenarios without a server.
+//!
+//! Transactions get snapshot isolation like the ones on the server: a transaction reads the
+//! documents as they were when it started, plus its own writes. Writing a document that another
+//! running transaction wrote, or that was written after the snapshot, fails with WriteConflict.
+//! Writes outside transactions fail the same way instead of waiting for the transaction.
+
+mod filter;
+mod update;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, from_document, oid::ObjectId, Bson, Document},
+    error::{CommandError, Error, ErrorKind, Result, WriteError, WriteFailure},
+    options::TransactionOptions,
+};
+
+use super::{Storage, UpdateOutcome};
+use crate::{error::DbResult, indexes::IndexSpec, tx::TxSession};
+
+const DB_NAME: &str = "memory";
+const ID_INDEX_NAME: &str = "_id_";
+
+fn command_error(code: i32, code_name: &str, message: String) -> Error {
+    let e: CommandError =
+        from_document(doc! {"code": code, "codeName": code_name, "errmsg": message})
+            .expect("a command error is deserialized from its fields");
+    Error::from(ErrorKind::Command(e))
+}
+
+pub(super) fn bad_value(message: String) -> Error {
+    command_error(2, "BadValue", message)
+}
+
+fn write_conflict() -> Error {
+    command_error(
+        112,
+        "WriteConflict",
+        "WriteConflict error: this operation conflicted with another operation.".to_string(),
+    )
+}
+
+fn no_such_transaction() -> Error {
+    command_error(
+        251,
+        "NoSuchTransaction",
+        "no transaction started".to_string(),
+    )
+}
+
+fn duplicate_key(coll: &str, index: &IndexSpec, key: &[(String, Bson)]) -> Error {
+    let key = key
+        .iter()
+        .map(|(field, value)| format!("{}: {}", field, value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let message = format!(
+        "E11000 duplicate key error collection: {}.{} index: {} dup key: {{ {} }}",
+        DB_NAME,
+        coll,
+        index.index_name(),
+        key
+    );
+    let e: WriteError = from_document(doc! {"code": 11000, "errmsg": message})
+        .expect("a write error is deserialized from its fields");
+    Error::from(ErrorKind::Write(WriteFailure::WriteError(e)))
+}
+
+/// A document is identified by its collection and `_id`.
+type DocKey = (String, String);
+
+fn doc_key(coll: &str, doc: &Document) -> DocKey {
+    let id = doc.get("_id").map(|id| id.to_string()).unwrap_or_default();
+    (coll.to_string(), id)
+}
+
+#[derive(Debug, Clone)]
+struct StoredDoc {
+    doc: Document,
+    /// Commit sequence of the last write.
+    seq: u64,
+}
+
+#[derive(Debug, Clone)]
+struct MemoryColl {
+    docs: Vec<StoredDoc>,
+    indexes: Vec<IndexSpec>,
+}
+
+impl Default for MemoryColl {
+    fn default() -> Self {
+        MemoryColl {
+            docs: vec![],
+            indexes: vec![IndexSpec::new(doc! {"_id": 1})
+                .named(ID_INDEX_NAME)
+                .unique()],
+        }
+    }
+}
+
+impl MemoryColl {
+    fn position(&self, filter: &Document) -> Result<Option<usize>> {
+        for (i, stored) in self.docs.iter().enumerate() {
+            if filter::matches(&stored.doc, filter)? {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    fn position_by_key(&self, coll: &str, key: &DocKey) -> Option<usize> {
+        self.docs
+            .iter()
+            .position(|stored| &doc_key(coll, &stored.doc) == key)
+    }
+
+    /// Fails if `doc` has the same key as another document in a unique index.
+    fn check_unique(&self, coll: &str, doc: &Document, skip: Option<usize>) -> Result<()> {
+        for index in self.indexes.iter().filter(|i| i.unique) {
+            let key = match index_key(index, doc) {
+                Some(key) => key,
+                None => continue,
+            };
+            let duplicated = self
+                .docs
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| Some(*i) != skip)
+                .filter_map(|(_, other)| index_key(index, &other.doc))
+                .any(|other| {
+                    other
+                        .iter()
+                        .zip(&key)
+                        .all(|((_, a), (_, b))| filter::values_equal(a, b))
+                });
+            if duplicated {
+                return Err(duplicate_key(coll, index, &key));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The values of the keys of `index` in `doc`. `None` if a sparse index doesn't have the document.
+fn index_key(index: &IndexSpec, doc: &Document) -> Option<Vec<(String, Bson)>> {
+    let key: Vec<(String, Option<&Bson>)> = index
+        .keys
+        .keys()
+        .map(|field| (field.clone(), filter::lookup(doc, field)))
+        .collect();
+    if index.sparse && key.iter().all(|(_, v)| v.is_none()) {
+        return None;
+    }
+    Some(
+        key.into_iter()
+            .map(|(field, v)| (field, v.cloned().unwrap_or(Bson::Null)))
+            .collect(),
+    )
+}
+
+type Colls = BTreeMap<String, MemoryColl>;
+
+#[derive(Debug, Default)]
+struct MemoryDb {
+    colls: Colls,
+    /// Incremented by each commit and each write outside transactions.
+    seq: u64,
+    /// Documents written by running transactions, with the id of the transaction.
+    locks: BTreeMap<DocKey, u64>,
+    next_tx_id: u64,
+}
+
+#[derive(Debug)]
+struct MemoryTx {
+    id: u64,
+    /// `seq` of the database when the transaction started.
+    snapshot_seq: u64,
+    /// The snapshot with the writes of the transaction.
+    colls: Colls,
+    written: BTreeSet<DocKey>,
+}
+
+impl MemoryDb {
+    fn start_tx(&mut self) -> MemoryTx {
+        self.next_tx_id += 1;
+        MemoryTx {
+            id: self.next_tx_id,
+            snapshot_seq: self.seq,
+            colls: self.colls.clone(),
+            written: BTreeSet::new(),
+        }
+    }
+
+    /// Fails with WriteConflict if `tx` can't write the document `key`.
+    fn check_tx_writable(&self, key: &DocKey, tx: &MemoryTx) -> Result<()> {
+        let locked_by_other = self.locks.get(key).is_some_and(|holder| *holder != tx.id);
+        let committed_after_snapshot = self
+            .colls
+            .get(&key.0)
+            .and_then(|coll| coll.position_by_key(&key.0, key))
+            .is_some_and(|i| self.colls[&key.0].docs[i].seq > tx.snapshot_seq);
+        if locked_by_other || committed_after_snapshot {
+            return Err(write_conflict());
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, tx: MemoryTx) -> Result<()> {
+        self.seq += 1;
+        let seq = self.seq;
+        let result = self.apply_writes(&tx, seq);
+        self.locks.retain(|_, holder| *holder != tx.id);
+        result
+    }
+
+    /// Copies the documents the transaction wrote. Nothing is copied if one of them has
+    /// the key of a unique index another transaction committed since the snapshot.
+    fn apply_writes(&mut self, tx: &MemoryTx, seq: u64) -> Result<()> {
+        let mut colls = self.colls.clone();
+        for key in &tx.written {
+            let written = tx.colls.get(&key.0).and_then(|coll| {
+                coll.position_by_key(&key.0, key)
+                    .map(|i| coll.docs[i].doc.clone())
+            });
+            let coll = colls.entry(key.0.clone()).or_default();
+            let current = coll.position_by_key(&key.0, key);
+            match (written, current) {
+                (Some(doc), Some(i)) => {
+                    coll.check_unique(&key.0, &doc, Some(i))?;
+                    coll.docs[i] = StoredDoc { doc, seq };
+                }
+                (Some(doc), None) => {
+                    coll.check_unique(&key.0, &doc, None)?;
+                    coll.docs.push(StoredDoc { doc, seq });
+                }
+                (None, Some(i)) => {
+                    coll.docs.remove(i);
+                }
+                (None, None) => {}
+            }
+        }
+        self.colls = colls;
+        Ok(())
+    }
+}
+
+/// In-memory database. Clones share the documents.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    db: Arc<Mutex<MemoryDb>>,
+}
+
+/// A session of [`MemoryStorage`], holding the snapshot of its transaction.
+#[derive(Debug)]
+pub struct MemorySession {
+    db: Arc<Mutex<MemoryDb>>,
+    tx: Option<MemoryTx>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start_session(&self) -> MemorySession {
+        MemorySession {
+            db: self.db.clone(),
+            tx: None,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryDb> {
+        lock_db(&self.db)
+    }
+
+    /// Runs `read` on the snapshot of the transaction of `session`, or on the committed documents.
+    fn read<T>(&self, session: Option<&mut MemorySession>, read: impl FnOnce(&Colls) -> T) -> T {
+        match session.and_then(|s| s.tx.as_ref()) {
+            Some(tx) => read(&tx.colls),
+            None => read(&self.lock().colls),
+        }
+    }
+
+    /// Finds the first document matching `filter`, applies `change` to it and returns
+    /// the updated document, and whether it is modified.
+    fn write_one(
+        &self,
+        coll: &str,
+        filter: &Document,
+        change: Change<'_>,
+        session: Option<&mut MemorySession>,
+    ) -> Result<Option<(Document, bool)>> {
+        let mut db = self.lock();
+        match session.and_then(|s| s.tx.as_mut()) {
+            Some(tx) => {
+                let (i, new) = match updated(
+                    tx.colls.entry(coll.to_string()).or_default(),
+                    filter,
+                    change,
+                )? {
+                    Some(found) => found,
+                    None => return Ok(None),
+                };
+                let target = &tx.colls[coll];
+                if target.docs[i].doc == new {
+                    return Ok(Some((new, false)));
+                }
+                let key = doc_key(coll, &new);
+                db.check_tx_writable(&key, tx)?;
+                target.check_unique(coll, &new, Some(i))?;
+
+                let seq = tx.snapshot_seq;
+                if let Some(target) = tx.colls.get_mut(coll) {
+                    target.docs[i] = StoredDoc {
+                        doc: new.clone(),
+                        seq,
+                    };
+                }
+                db.locks.insert(key.clone(), tx.id);
+                tx.written.insert(key);
+                Ok(Some((new, true)))
+            }
+            None => {
+                let MemoryDb {
+                    colls, locks, seq, ..
+                } = &mut *db;
+                let target = colls.entry(coll.to_string()).or_default();
+                let (i, new) = match updated(target, filter, change)? {
+                    Some(found) => found,
+                    None => return Ok(None),
+                };
+                if target.docs[i].doc == new {
+                    return Ok(Some((new, false)));
+                }
+                if locks.contains_key(&doc_key(coll, &new)) {
+                    return Err(write_conflict());
+                }
+                target.check_unique(coll, &new, Some(i))?;
+
+                *seq += 1;
+                target.docs[i] = StoredDoc {
+                    doc: new.clone(),
+                    seq: *seq,
+                };
+                Ok(Some((new, true)))
+            }
+        }
+    }
+
+    fn insert(
+        &self,
+        coll: &str,
+        mut doc: Document,
+        session: Option<&mut MemorySession>,
+    ) -> Result<Bson> {
+        if !doc.contains_key("_id") {
+            let mut with_id = doc! {"_id": ObjectId::new()};
+            with_id.extend(doc);
+            doc = with_id;
+        }
+        let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+        let key = doc_key(coll, &doc);
+
+        let mut db = self.lock();
+        match session.and_then(|s| s.tx.as_mut()) {
+            Some(tx) => {
+                db.check_tx_writable(&key, tx)?;
+                let target = tx.colls.entry(coll.to_string()).or_default();
+                target.check_unique(coll, &doc, None)?;
+                target.docs.push(StoredDoc {
+                    doc,
+                    seq: tx.snapshot_seq,
+                });
+                db.locks.insert(key.clone(), tx.id);
+                tx.written.insert(key);
+            }
+            None => {
+                let MemoryDb {
+                    colls, locks, seq, ..
+                } = &mut *db;
+                if locks.contains_key(&key) {
+                    return Err(write_conflict());
+                }
+                let target = colls.entry(coll.to_string()).or_default();
+                target.check_unique(coll, &doc, None)?;
+                *seq += 1;
+                target.docs.push(StoredDoc { doc, seq: *seq });
+            }
+        }
+        Ok(id)
+    }
+
+    fn delete(
+        &self,
+        coll: &str,
+        filter: &Document,
+        session: Option<&mut MemorySession>,
+    ) -> Result<bool> {
+        let mut db = self.lock();
+        match session.and_then(|s| s.tx.as_mut()) {
+            Some(tx) => {
+                let target = tx.colls.entry(coll.to_string()).or_default();
+                let i = match target.position(filter)? {
+                    Some(i) => i,
+                    None => return Ok(false),
+                };
+                let key = doc_key(coll, &target.docs[i].doc);
+                db.check_tx_writable(&key, tx)?;
+                if let Some(target) = tx.colls.get_mut(coll) {
+                    target.docs.remove(i);
+                }
+                db.locks.insert(key.clone(), tx.id);
+                tx.written.insert(key);
+            }
+            None => {
+                let MemoryDb {
+                    colls, locks, seq, ..
+                } = &mut *db;
+                let target = colls.entry(coll.to_string()).or_default();
+                let i = match target.position(filter)? {
+                    Some(i) => i,
+                    None => return Ok(false),
+                };
+                if locks.contains_key(&doc_key(coll, &target.docs[i].doc)) {
+                    return Err(write_conflict());
+                }
+                *seq += 1;
+                target.docs.remove(i);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// How a write changes the document it matched.
+#[derive(Debug, Clone, Copy)]
+enum Change<'a> {
+    /// A document of update operators.
+    Update(&'a Document),
+    Replace(&'a Document),
+}
+
+/// The position of the first document matching `filter` and the document with `change` applied.
+fn updated(
+    coll: &MemoryColl,
+    filter: &Document,
+    change: Change<'_>,
+) -> Result<Option<(usize, Document)>> {
+    let i = match coll.position(filter)? {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let mut new = coll.docs[i].doc.clone();
+    match change {
+        Change::Update(update) => update::apply(&mut new, update)?,
+        Change::Replace(replacement) => update::replace(&mut new, replacement)?,
+    }
+    Ok(Some((i, new)))
+}
+
+#[async_trait]
+impl TxSession for MemorySession {
+    type Error = Error;
+
+    async fn start_transaction(&mut self, _options: TransactionOptions) -> Result<()> {
+        if self.tx.is_some() {
+            return Err(command_error(
+                256,
+                "TransactionInProgress",
+                "transaction already in progress".to_string(),
+            ));
+        }
+        let tx = lock_db(&self.db).start_tx();
+        self.tx = Some(tx);
+        Ok(())
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        let tx = self.tx.take().ok_or_else(no_such_transaction)?;
+        lock_db(&self.db).commit(tx)
+    }
+
+    async fn abort_transaction(&mut self) -> Result<()> {
+        let tx = self.tx.take().ok_or_else(no_such_transaction)?;
+        lock_db(&self.db).locks.retain(|_, holder| *holder != tx.id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    type Session = MemorySession;
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut MemorySession>,
+    ) -> DbResult<Option<Document>> {
+        let found = self.read(session, |colls| -> Result<_> {
+            let coll = match colls.get(coll) {
+                Some(coll) => coll,
+                None => return Ok(None),
+            };
+            Ok(coll.position(&filter)?.map(|i| coll.docs[i].doc.clone()))
+        })?;
+        Ok(found)
+    }
+
+    async fn find(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut MemorySession>,
+    ) -> DbResult<Vec<Document>> {
+        let found = self.read(session, |colls| -> Result<_> {
+            let mut found = vec![];
+            for stored in colls.get(coll).map(|c| &c.docs[..]).unwrap_or(&[]) {
+                if filter::matches(&stored.doc, &filter)? {
+                    found.push(stored.doc.clone());
+                }
+            }
+            Ok(found)
+        })?;
+        Ok(found)
+    }
+
+    async fn insert_one(
+        &self,
+        coll: &str,
+        doc: Document,
+        session: Option<&mut MemorySession>,
+    ) -> DbResult<Bson> {
+        Ok(self.insert(coll, doc, session)?)
+    }
+
+    async fn insert_many(
+        &self,
+        coll: &str,
+        docs: Vec<Document>,
+        mut session: Option<&mut MemorySession>,
+    ) -> DbResult<Vec<Bson>> {
+        let mut ids = vec![];
+        for doc in docs {
+            ids.push(self.insert(coll, doc, session.as_deref_mut())?);
+        }
+        Ok(ids)
+    }
+
+    async fn update_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+        session: Option<&mut MemorySession>,
+    ) -> DbResult<UpdateOutcome> {
+        let written = self.write_one(coll, &filter, Change::Update(&update), session)?;
+        Ok(UpdateOutcome {
+            matched_count: written.is_some() as u64,
+            modified_count: written.is_some_and(|(_, modified)| modified) as u64,
+        })
+    }
+
+    async fn find_one_and_update(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+        session: Option<&mut MemorySession>,
+    ) -> DbResult<Option<Document>> {
+        let written = self.write_one(coll, &filter, Change::Update(&update), session)?;
+        Ok(written.map(|(doc, _)| doc))
+    }
+
+    async fn find_one_and_replace(
+        &self,
+        coll: &str,
+        filter: Document,
+        replacement: Document,
+        session: Option<&mut MemorySession>,
+    ) -> DbResult<Option<Document>> {
+        let written = self.write_one(coll, &filter, Change::Replace(&replacement), session)?;
+        Ok(written.map(|(doc, _)| doc))
+    }
+
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut MemorySession>,
+    ) -> DbResult<bool> {
+        Ok(self.delete(coll, &filter, session)?)
+    }
+
+    async fn drop_collection(&self, coll: &str) -> DbResult<()> {
+        self.lock().colls.remove(coll);
+        Ok(())
+    }
+
+    async fn list_indexes(&self, coll: &str) -> DbResult<Vec<IndexSpec>> {
+        Ok(self
+            .lock()
+            .colls
+            .get(coll)
+            .map(|c| c.indexes.clone())
+            .unwrap_or_default())
+    }
+
+    async fn create_index(&self, coll: &str, index: &IndexSpec) -> DbResult<()> {
+        let mut db = self.lock();
+        let target = db.colls.entry(coll.to_string()).or_default();
+        let index = index.clone().named(&index.index_name());
+        if let Some(existing) = target
+            .indexes
+            .iter()
+            .find(|i| i.index_name() == index.index_name())
+        {
+            if *existing == index {
+                return Ok(());
+            }
+            return Err(command_error(
+                85,
+                "IndexOptionsConflict",
+                format!(
+                    "index {} already exists with different options",
+                    index.index_name()
+                ),
+            )
+            .into());
+        }
+
+        let mut with_index = target.clone();
+        with_index.indexes = vec![index.clone()];
+        for (i, stored) in target.docs.iter().enumerate() {
+            with_index.check_unique(coll, &stored.doc, Some(i))?;
+        }
+        target.indexes.push(index);
+        Ok(())
+    }
+
+    async fn drop_index(&self, coll: &str, name: &str) -> DbResult<()> {
+        if name == ID_INDEX_NAME {
+            return Err(bad_value("cannot drop _id index".to_string()).into());
+        }
+        let mut db = self.lock();
+        let indexes = &mut db.colls.entry(coll.to_string()).or_default().indexes;
+        match indexes.iter().position(|i| i.index_name() == name) {
+            Some(i) => {
+                indexes.remove(i);
+                Ok(())
+            }
+            None => Err(command_error(
+                27,
+                "IndexNotFound",
+                format!("index not found with name [{}]", name),
+            )
+            .into()),
+        }
+    }
+}
+
+fn lock_db(db: &Mutex<MemoryDb>) -> MutexGuard<'_, MemoryDb> {
+    // a panic in another test holding the lock doesn't leave the documents half written.
+    db.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::DbError, tx::majority_tx_options};
+
+    const USERS: &str = "users";
+
+    async fn storage_with_user() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        storage
+            .insert_one(USERS, doc! {"id": "user_1", "name": "john"}, None)
+            .await
+            .unwrap();
+        storage
+    }
+
+    async fn start_tx(storage: &MemoryStorage) -> MemorySession {
+        let mut session = storage.start_session();
+        session
+            .start_transaction(majority_tx_options())
+            .await
+            .unwrap();
+        session
+    }
+
+    async fn name(storage: &MemoryStorage, session: Option<&mut MemorySession>) -> String {
+        let user = storage
+            .find_one(USERS, doc! {"id": "user_1"}, session)
+            .await
+            .unwrap()
+            .unwrap();
+        user.get_str("name").unwrap().to_string()
+    }
+
+    fn rename(name: &str) -> Document {
+        doc! {"$set": {"name": name}}
+    }
+
+    #[tokio::test]
+    async fn transaction_reads_its_snapshot() {
+        let storage = storage_with_user().await;
+        let mut tx = start_tx(&storage).await;
+
+        storage
+            .update_one(USERS, doc! {"id": "user_1"}, rename("anna"), None)
+            .await
+            .unwrap();
+
+        assert_eq!(name(&storage, Some(&mut tx)).await, "john");
+        assert_eq!(name(&storage, None).await, "anna");
+    }
+
+    #[tokio::test]
+    async fn writes_are_visible_after_commit() {
+        let storage = storage_with_user().await;
+        let mut tx = start_tx(&storage).await;
+        storage
+            .update_one(USERS, doc! {"id": "user_1"}, rename("in_tx"), Some(&mut tx))
+            .await
+            .unwrap();
+
+        assert_eq!(name(&storage, Some(&mut tx)).await, "in_tx");
+        assert_eq!(name(&storage, None).await, "john");
+
+        tx.commit_transaction().await.unwrap();
+        assert_eq!(name(&storage, None).await, "in_tx");
+    }
+
+    #[tokio::test]
+    async fn second_writer_gets_write_conflict() {
+        let storage = storage_with_user().await;
+        let mut tx1 = start_tx(&storage).await;
+        let mut tx2 = start_tx(&storage).await;
+
+        storage
+            .update_one(USERS, doc! {"id": "user_1"}, rename("tx1"), Some(&mut tx1))
+            .await
+            .unwrap();
+        let result = storage
+            .update_one(USERS, doc! {"id": "user_1"}, rename("tx2"), Some(&mut tx2))
+            .await;
+        assert!(
+            matches!(result, Err(DbError::WriteConflict(_))),
+            "{:?}",
+            result
+        );
+
+        let result = storage
+            .update_one(USERS, doc! {"id": "user_1"}, rename("direct"), None)
+            .await;
+        assert!(
+            matches!(result, Err(DbError::WriteConflict(_))),
+            "{:?}",
+            result
+        );
+
+        // the document is committed after the snapshot of tx2.
+        tx1.commit_transaction().await.unwrap();
+        let result = storage
+            .update_one(USERS, doc! {"id": "user_1"}, rename("tx2"), Some(&mut tx2))
+            .await;
+        assert!(
+            matches!(result, Err(DbError::WriteConflict(_))),
+            "{:?}",
+            result
+        );
+        tx2.abort_transaction().await.unwrap();
+
+        let mut tx3 = start_tx(&storage).await;
+        storage
+            .update_one(USERS, doc! {"id": "user_1"}, rename("tx3"), Some(&mut tx3))
+            .await
+            .unwrap();
+        tx3.commit_transaction().await.unwrap();
+        assert_eq!(name(&storage, None).await, "tx3");
+    }
+
+    #[tokio::test]
+    async fn abort_discards_writes_and_releases_documents() {
+        let storage = storage_with_user().await;
+        let mut tx = start_tx(&storage).await;
+        storage
+            .update_one(
+                USERS,
+                doc! {"id": "user_1"},
+                rename("aborted"),
+                Some(&mut tx),
+            )
+            .await
+            .unwrap();
+        tx.abort_transaction().await.unwrap();
+
+        assert_eq!(name(&storage, None).await, "john");
+        storage
+            .update_one(USERS, doc! {"id": "user_1"}, rename("anna"), None)
+            .await
+            .unwrap();
+        assert_eq!(name(&storage, None).await, "anna");
+    }
+
+    #[tokio::test]
+    async fn delete_is_seen_after_commit() {
+        let storage = storage_with_user().await;
+        let filter = || doc! {"id": "user_1"};
+        let mut tx = start_tx(&storage).await;
+        assert!(storage
+            .delete_one(USERS, filter(), Some(&mut tx))
+            .await
+            .unwrap());
+
+        assert!(storage
+            .find_one(USERS, filter(), None)
+            .await
+            .unwrap()
+            .is_some());
+        let result = storage.delete_one(USERS, filter(), None).await;
+        assert!(matches!(result, Err(DbError::WriteConflict(_))));
+        tx.commit_transaction().await.unwrap();
+        assert!(storage
+            .find_one(USERS, filter(), None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!storage.delete_one(USERS, filter(), None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unique_index_is_checked_on_commit() {
+        let storage = MemoryStorage::new();
+        storage
+            .create_index(USERS, &IndexSpec::new(doc! {"id": 1}).unique())
+            .await
+            .unwrap();
+        let mut tx1 = start_tx(&storage).await;
+        let mut tx2 = start_tx(&storage).await;
+        for tx in [&mut tx1, &mut tx2] {
+            storage
+                .insert_one(USERS, doc! {"id": "user_1"}, Some(tx))
+                .await
+                .unwrap();
+        }
+
+        tx1.commit_transaction().await.unwrap();
+        let result = tx2.commit_transaction().await.map_err(DbError::from);
+        assert!(
+            matches!(&result, Err(DbError::DuplicateKey { index: Some(index), .. }) if index == "id_1"),
+            "{:?}",
+            result
+        );
+        let users = storage.find(USERS, doc! {}, None).await.unwrap();
+        assert_eq!(users.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn insert_many_stops_at_duplicate() {
+        let storage = storage_with_user().await;
+        storage
+            .create_index(USERS, &IndexSpec::new(doc! {"id": 1}).unique())
+            .await
+            .unwrap();
+        let docs = vec![
+            doc! {"_id": 2, "id": "user_2"},
+            doc! {"_id": 3, "id": "user_1"},
+            doc! {"_id": 4, "id": "user_4"},
+        ];
+
+        let result = storage.insert_many(USERS, docs, None).await;
+        assert!(
+            matches!(result, Err(DbError::DuplicateKey { .. })),
+            "{:?}",
+            result
+        );
+        let ids: Vec<_> = storage
+            .find(USERS, doc! {}, None)
+            .await
+            .unwrap()
+            .iter()
+            .map(|u| u.get_str("id").unwrap().to_string())
+            .collect();
+        assert_eq!(ids, vec!["user_1", "user_2"]);
+
+        let inserted = storage
+            .insert_many(USERS, vec![doc! {"_id": 4, "id": "user_4"}], None)
+            .await
+            .unwrap();
+        assert_eq!(inserted, vec![Bson::Int32(4)]);
+    }
+}
@@ -0,0 +1,320 @@
+//! Update operators of the in-memory storage: `$set`, `$unset`, `$inc`, `$push`, `$pull`
+//! and `$addToSet`. `$push` and `$addToSet` take `$each` too.
+
+use mongodb::{
+    bson::{Bson, Document},
+    error::Result,
+};
+
+use super::{
+    bad_value,
+    filter::{is_operator_doc, matches, value_matches, values_equal},
+};
+
+/// Parent document of the last part of `path`, created if `create` is set.
+fn parent_mut<'a, 'p>(
+    doc: &'a mut Document,
+    path: &'p str,
+    create: bool,
+) -> Result<Option<(&'a mut Document, &'p str)>> {
+    let mut parts: Vec<&str> = path.split('.').collect();
+    let last = parts.pop().unwrap_or(path);
+    let mut current = doc;
+    for part in parts {
+        if !current.contains_key(part) {
+            if !create {
+                return Ok(None);
+            }
+            current.insert(part, Document::new());
+        }
+        current = match current.get_mut(part) {
+            Some(Bson::Document(d)) => d,
+            _ => return Err(bad_value(format!("cannot create field in {}", path))),
+        };
+    }
+    Ok(Some((current, last)))
+}
+
+fn set(doc: &mut Document, path: &str, value: Bson) -> Result<()> {
+    if let Some((parent, field)) = parent_mut(doc, path, true)? {
+        parent.insert(field, value);
+    }
+    Ok(())
+}
+
+fn unset(doc: &mut Document, path: &str) -> Result<()> {
+    if let Some((parent, field)) = parent_mut(doc, path, false)? {
+        parent.remove(field);
+    }
+    Ok(())
+}
+
+fn inc(doc: &mut Document, path: &str, by: &Bson) -> Result<()> {
+    let (parent, field) = match parent_mut(doc, path, true)? {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    let overflow = || bad_value(format!("$inc of {} overflows a 64-bit integer", path));
+    let sum = match (parent.get(field), by) {
+        (None, by) => by.clone(),
+        (Some(Bson::Int32(a)), Bson::Int32(b)) => match a.checked_add(*b) {
+            Some(sum) => Bson::Int32(sum),
+            None => Bson::Int64(*a as i64 + *b as i64),
+        },
+        (Some(Bson::Int32(a)), Bson::Int64(b)) => {
+            Bson::Int64((*a as i64).checked_add(*b).ok_or_else(overflow)?)
+        }
+        (Some(Bson::Int64(a)), Bson::Int32(b)) => {
+            Bson::Int64(a.checked_add(*b as i64).ok_or_else(overflow)?)
+        }
+        (Some(Bson::Int64(a)), Bson::Int64(b)) => {
+            Bson::Int64(a.checked_add(*b).ok_or_else(overflow)?)
+        }
+        (Some(Bson::Double(a)), Bson::Double(b)) => Bson::Double(a + b),
+        (Some(Bson::Double(a)), Bson::Int32(b)) => Bson::Double(a + *b as f64),
+        (Some(Bson::Double(a)), Bson::Int64(b)) => Bson::Double(a + *b as f64),
+        (Some(Bson::Int32(a)), Bson::Double(b)) => Bson::Double(*a as f64 + b),
+        (Some(Bson::Int64(a)), Bson::Double(b)) => Bson::Double(*a as f64 + b),
+        _ => return Err(bad_value(format!("cannot $inc non-numeric field {}", path))),
+    };
+    parent.insert(field, sum);
+    Ok(())
+}
+
+/// The values of `$push` and `$addToSet`: `{$each: [...]}` or a single value.
+fn each(value: &Bson) -> Vec<Bson> {
+    match value {
+        Bson::Document(d) if d.contains_key("$each") => match d.get("$each") {
+            Some(Bson::Array(values)) => values.clone(),
+            Some(value) => vec![value.clone()],
+            None => vec![],
+        },
+        value => vec![value.clone()],
+    }
+}
+
+fn array_mut<'a>(
+    doc: &'a mut Document,
+    path: &str,
+    create: bool,
+) -> Result<Option<&'a mut Vec<Bson>>> {
+    let (parent, field) = match parent_mut(doc, path, create)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    if !parent.contains_key(field) {
+        if !create {
+            return Ok(None);
+        }
+        parent.insert(field, Bson::Array(vec![]));
+    }
+    match parent.get_mut(field) {
+        Some(Bson::Array(values)) => Ok(Some(values)),
+        _ => Err(bad_value(format!("{} is not an array", path))),
+    }
+}
+
+fn push(doc: &mut Document, path: &str, value: &Bson, unique: bool) -> Result<()> {
+    if let Some(values) = array_mut(doc, path, true)? {
+        for v in each(value) {
+            if !unique || !values.iter().any(|e| values_equal(e, &v)) {
+                values.push(v);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the elements equal to `cond`, or matching it when it has operators like `$in`.
+/// A document without operators is a query on the fields of document elements, e.g.
+/// `{reviews: {user_id: "user_1"}}` removes every review of `user_1`.
+fn pull(doc: &mut Document, path: &str, cond: &Bson) -> Result<()> {
+    if let Some(values) = array_mut(doc, path, false)? {
+        let mut kept = vec![];
+        for v in values.drain(..) {
+            let pulled = match (&v, cond) {
+                (Bson::Document(element), Bson::Document(query)) if !is_operator_doc(cond) => {
+                    matches(element, query)?
+                }
+                (_, Bson::Document(_)) if !is_operator_doc(cond) => false,
+                _ => value_matches(Some(&v), cond)?,
+            };
+            if !pulled {
+                kept.push(v);
+            }
+        }
+        *values = kept;
+    }
+    Ok(())
+}
+
+/// Applies an update document of operators to `doc`.
+pub(super) fn apply(doc: &mut Document, update: &Document) -> Result<()> {
+    for (op, fields) in update {
+        let fields = match fields {
+            Bson::Document(fields) => fields,
+            _ => return Err(bad_value(format!("{} needs a document", op))),
+        };
+        for (path, value) in fields {
+            if path == "_id" {
+                return Err(bad_value("_id is immutable".to_string()));
+            }
+            match op.as_str() {
+                "$set" => set(doc, path, value.clone())?,
+                "$unset" => unset(doc, path)?,
+                "$inc" => inc(doc, path, value)?,
+                "$push" => push(doc, path, value, false)?,
+                "$addToSet" => push(doc, path, value, true)?,
+                "$pull" => pull(doc, path, value)?,
+                other => return Err(bad_value(format!("unknown update operator: {}", other))),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the fields of `doc` with the ones of `replacement`, keeping `_id`.
+pub(super) fn replace(doc: &mut Document, replacement: &Document) -> Result<()> {
+    if let Some(op) = replacement.keys().find(|k| k.starts_with('$')) {
+        return Err(bad_value(format!(
+            "the replacement document must not contain operators: {}",
+            op
+        )));
+    }
+    let id = doc.get("_id").cloned();
+    if let (Some(id), Some(new_id)) = (&id, replacement.get("_id")) {
+        if !values_equal(id, new_id) {
+            return Err(bad_value("_id is immutable".to_string()));
+        }
+    }
+    doc.clear();
+    if let Some(id) = id {
+        doc.insert("_id", id);
+    }
+    for (key, value) in replacement {
+        if key != "_id" {
+            doc.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn updated(update: Document) -> Document {
+        let mut book = doc! {"id": "book_1", "version": 1i64, "authors": ["author_1", "author_2"]};
+        apply(&mut book, &update).unwrap();
+        book
+    }
+
+    #[test]
+    fn set_and_inc() {
+        let book = updated(
+            doc! {"$set": {"name": "new", "meta.pages": 10}, "$inc": {"version": 1i64, "count": 2}},
+        );
+        assert_eq!(book.get_str("name").unwrap(), "new");
+        assert_eq!(book.get_document("meta").unwrap(), &doc! {"pages": 10});
+        assert_eq!(book.get_i64("version").unwrap(), 2);
+        assert_eq!(book.get_i32("count").unwrap(), 2);
+    }
+
+    #[test]
+    fn array_operators() {
+        let book = updated(doc! {"$push": {"authors": "author_1"}});
+        assert_eq!(
+            book.get_array("authors").unwrap(),
+            &vec![
+                Bson::from("author_1"),
+                Bson::from("author_2"),
+                Bson::from("author_1")
+            ]
+        );
+
+        let book = updated(doc! {"$addToSet": {"authors": {"$each": ["author_2", "author_3"]}}});
+        assert_eq!(
+            book.get_array("authors").unwrap(),
+            &vec![
+                Bson::from("author_1"),
+                Bson::from("author_2"),
+                Bson::from("author_3")
+            ]
+        );
+
+        let book = updated(doc! {"$pull": {"authors": "author_1", "supervisors": "x"}});
+        assert_eq!(
+            book.get_array("authors").unwrap(),
+            &vec![Bson::from("author_2")]
+        );
+        assert!(!book.contains_key("supervisors"));
+
+        let book = updated(doc! {"$pull": {"authors": {"$in": ["author_1", "author_2"]}}});
+        assert!(book.get_array("authors").unwrap().is_empty());
+    }
+
+    #[test]
+    fn pull_by_sub_document_query() {
+        let mut book = doc! {"reviews": [
+            {"user_id": "user_1", "text": "a"},
+            {"user_id": "user_2", "text": "b"},
+            {"user_id": "user_1", "text": "c"},
+            "not a review",
+        ]};
+        apply(
+            &mut book,
+            &doc! {"$pull": {"reviews": {"user_id": "user_1"}}},
+        )
+        .unwrap();
+        assert_eq!(
+            book.get_array("reviews").unwrap(),
+            &vec![
+                Bson::from(doc! {"user_id": "user_2", "text": "b"}),
+                Bson::from("not a review")
+            ]
+        );
+
+        apply(
+            &mut book,
+            &doc! {"$pull": {"reviews": {"user_id": {"$in": ["user_2"]}}}},
+        )
+        .unwrap();
+        assert_eq!(
+            book.get_array("reviews").unwrap(),
+            &vec![Bson::from("not a review")]
+        );
+    }
+
+    #[test]
+    fn inc_overflow_is_rejected() {
+        let mut doc = doc! {"n": i64::MAX, "m": 1i32};
+        assert!(apply(&mut doc, &doc! {"$inc": {"n": 1i64}}).is_err());
+        assert!(apply(&mut doc, &doc! {"$inc": {"n": 1i32}}).is_err());
+        assert!(apply(&mut doc, &doc! {"$inc": {"m": i64::MAX}}).is_err());
+        assert_eq!(doc.get_i64("n").unwrap(), i64::MAX);
+
+        // an Int32 overflow is widened like on the server.
+        let mut doc = doc! {"m": i32::MAX};
+        apply(&mut doc, &doc! {"$inc": {"m": 1i32}}).unwrap();
+        assert_eq!(doc.get_i64("m").unwrap(), i32::MAX as i64 + 1);
+    }
+
+    #[test]
+    fn rejects_bad_updates() {
+        let mut book = doc! {"id": "book_1", "name": "x"};
+        assert!(apply(&mut book, &doc! {"$inc": {"name": 1}}).is_err());
+        assert!(apply(&mut book, &doc! {"$push": {"name": "y"}}).is_err());
+        assert!(apply(&mut book, &doc! {"$rename": {"name": "title"}}).is_err());
+    }
+
+    #[test]
+    fn replace_keeps_id() {
+        let mut book = doc! {"_id": 1, "id": "book_1", "name": "x"};
+        replace(&mut book, &doc! {"id": "book_1", "version": 2i64}).unwrap();
+        assert_eq!(book, doc! {"_id": 1, "id": "book_1", "version": 2i64});
+
+        assert!(replace(&mut book, &doc! {"_id": 2}).is_err());
+        assert!(replace(&mut book, &doc! {"$set": {"name": "y"}}).is_err());
+    }
+}
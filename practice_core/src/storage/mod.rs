@@ -0,0 +1,130 @@
+//! Storage behind the repositories.
+//!
+//! [`MongoStorage`] goes to the server through the driver. [`MemoryStorage`] keeps the
+//! documents in memory, so that the scenarios written against [`Storage`] run as plain
+//! `cargo test` without the replica set. Transactions start on a [`SessionSource`].
+
+mod memory;
+mod mongo;
+
+pub use memory::{MemorySession, MemoryStorage};
+pub use mongo::MongoStorage;
+
+use async_trait::async_trait;
+use mongodb::{
+    bson::{Bson, Document},
+    error::Error,
+    Client,
+};
+
+use crate::{error::DbResult, indexes::IndexSpec, tx::TxSession};
+
+/// Result of an update. Unlike the driver's `UpdateResult`, it can be built outside the driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateOutcome {
+    pub matched_count: u64,
+    pub modified_count: u64,
+}
+
+/// Operations on the collections of one database.
+///
+/// Every operation taking a session runs in the transaction of the session if it has one.
+/// `find_one_and_update` and `find_one_and_replace` return the document after the write.
+#[async_trait]
+pub trait Storage: Clone + Send + Sync + std::fmt::Debug {
+    type Session: TxSession<Error = Error> + Send;
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut Self::Session>,
+    ) -> DbResult<Option<Document>>;
+
+    async fn find(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut Self::Session>,
+    ) -> DbResult<Vec<Document>>;
+
+    /// Returns the `_id` of the inserted document.
+    async fn insert_one(
+        &self,
+        coll: &str,
+        doc: Document,
+        session: Option<&mut Self::Session>,
+    ) -> DbResult<Bson>;
+
+    /// Inserts the documents in order and returns their `_id`s. Stops at the first failure,
+    /// leaving the documents before it inserted unless in a transaction.
+    async fn insert_many(
+        &self,
+        coll: &str,
+        docs: Vec<Document>,
+        session: Option<&mut Self::Session>,
+    ) -> DbResult<Vec<Bson>>;
+
+    async fn update_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+        session: Option<&mut Self::Session>,
+    ) -> DbResult<UpdateOutcome>;
+
+    async fn find_one_and_update(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+        session: Option<&mut Self::Session>,
+    ) -> DbResult<Option<Document>>;
+
+    /// Replaces the whole document except its `_id`.
+    async fn find_one_and_replace(
+        &self,
+        coll: &str,
+        filter: Document,
+        replacement: Document,
+        session: Option<&mut Self::Session>,
+    ) -> DbResult<Option<Document>>;
+
+    /// Deletes the first document matching `filter`. Returns whether there was one.
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut Self::Session>,
+    ) -> DbResult<bool>;
+
+    async fn drop_collection(&self, coll: &str) -> DbResult<()>;
+
+    /// Indexes of the collection including `_id_`. A collection that doesn't exist has none.
+    async fn list_indexes(&self, coll: &str) -> DbResult<Vec<IndexSpec>>;
+
+    async fn create_index(&self, coll: &str, index: &IndexSpec) -> DbResult<()>;
+
+    async fn drop_index(&self, coll: &str, name: &str) -> DbResult<()>;
+}
+
+/// Starts the sessions of the storage `S`: a [`Client`] for [`MongoStorage`], and
+/// [`MemoryStorage`] itself.
+#[async_trait]
+pub trait SessionSource<S: Storage>: Clone + Send + Sync + std::fmt::Debug {
+    async fn start_session(&self) -> DbResult<S::Session>;
+}
+
+#[async_trait]
+impl SessionSource<MongoStorage> for Client {
+    async fn start_session(&self) -> DbResult<mongodb::ClientSession> {
+        Ok(Client::start_session(self, None).await?)
+    }
+}
+
+#[async_trait]
+impl SessionSource<MemoryStorage> for MemoryStorage {
+    async fn start_session(&self) -> DbResult<MemorySession> {
+        Ok(MemoryStorage::start_session(self))
+    }
+}
@@ -0,0 +1,283 @@
+use std::{future::Future, time::Instant};
+
+use async_trait::async_trait;
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{Bson, Document},
+    error::{Error, ErrorKind},
+    options::{FindOneAndReplaceOptions, FindOneAndUpdateOptions, ReturnDocument},
+    ClientSession, Collection, Database, IndexModel,
+};
+use tracing::Instrument;
+
+use super::{Storage, UpdateOutcome};
+use crate::{error::DbResult, indexes::IndexSpec, metrics, telemetry::db_span};
+
+const NAMESPACE_NOT_FOUND_CODE: i32 = 26;
+
+/// A database on the server.
+#[derive(Debug, Clone)]
+pub struct MongoStorage {
+    db: Database,
+}
+
+impl MongoStorage {
+    pub fn new(db: &Database) -> Self {
+        MongoStorage { db: db.clone() }
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    pub fn collection<T>(&self, coll: &str) -> Collection<T> {
+        self.db.collection(coll)
+    }
+}
+
+fn return_after() -> FindOneAndUpdateOptions {
+    FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build()
+}
+
+fn return_after_replace() -> FindOneAndReplaceOptions {
+    FindOneAndReplaceOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build()
+}
+
+/// Runs `op` on `coll` in its span, and records its latency.
+async fn traced<T>(
+    op: &'static str,
+    coll: &str,
+    f: impl Future<Output = DbResult<T>>,
+) -> DbResult<T> {
+    let started = Instant::now();
+    let result = f.instrument(db_span(op, coll)).await;
+    metrics::global().observe_operation(op, coll, started.elapsed());
+    result
+}
+
+fn is_namespace_not_found(e: &Error) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == NAMESPACE_NOT_FOUND_CODE)
+}
+
+#[async_trait]
+impl Storage for MongoStorage {
+    type Session = ClientSession;
+
+    async fn find_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut ClientSession>,
+    ) -> DbResult<Option<Document>> {
+        traced("find_one", coll, async move {
+            let coll = self.collection::<Document>(coll);
+            let found = match session {
+                Some(session) => coll.find_one_with_session(filter, None, session).await?,
+                None => coll.find_one(filter, None).await?,
+            };
+            Ok(found)
+        })
+        .await
+    }
+
+    async fn find(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut ClientSession>,
+    ) -> DbResult<Vec<Document>> {
+        traced("find", coll, async move {
+            let coll = self.collection::<Document>(coll);
+            let found = match session {
+                Some(session) => {
+                    coll.find_with_session(filter, None, session)
+                        .await?
+                        .stream(session)
+                        .try_collect()
+                        .await?
+                }
+                None => coll.find(filter, None).await?.try_collect().await?,
+            };
+            Ok(found)
+        })
+        .await
+    }
+
+    async fn insert_one(
+        &self,
+        coll: &str,
+        doc: Document,
+        session: Option<&mut ClientSession>,
+    ) -> DbResult<Bson> {
+        traced("insert_one", coll, async move {
+            let coll = self.collection::<Document>(coll);
+            let result = match session {
+                Some(session) => coll.insert_one_with_session(doc, None, session).await?,
+                None => coll.insert_one(doc, None).await?,
+            };
+            Ok(result.inserted_id)
+        })
+        .await
+    }
+
+    async fn insert_many(
+        &self,
+        coll: &str,
+        docs: Vec<Document>,
+        session: Option<&mut ClientSession>,
+    ) -> DbResult<Vec<Bson>> {
+        traced("insert_many", coll, async move {
+            // the server rejects an empty batch.
+            if docs.is_empty() {
+                return Ok(vec![]);
+            }
+            let coll = self.collection::<Document>(coll);
+            let result = match session {
+                Some(session) => coll.insert_many_with_session(docs, None, session).await?,
+                None => coll.insert_many(docs, None).await?,
+            };
+            let mut ids: Vec<_> = result.inserted_ids.into_iter().collect();
+            ids.sort_by_key(|(i, _)| *i);
+            Ok(ids.into_iter().map(|(_, id)| id).collect())
+        })
+        .await
+    }
+
+    async fn update_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+        session: Option<&mut ClientSession>,
+    ) -> DbResult<UpdateOutcome> {
+        traced("update_one", coll, async move {
+            let coll = self.collection::<Document>(coll);
+            let result = match session {
+                Some(session) => {
+                    coll.update_one_with_session(filter, update, None, session)
+                        .await?
+                }
+                None => coll.update_one(filter, update, None).await?,
+            };
+            Ok(UpdateOutcome {
+                matched_count: result.matched_count,
+                modified_count: result.modified_count,
+            })
+        })
+        .await
+    }
+
+    async fn find_one_and_update(
+        &self,
+        coll: &str,
+        filter: Document,
+        update: Document,
+        session: Option<&mut ClientSession>,
+    ) -> DbResult<Option<Document>> {
+        traced("find_one_and_update", coll, async move {
+            let coll = self.collection::<Document>(coll);
+            let found = match session {
+                Some(session) => {
+                    coll.find_one_and_update_with_session(filter, update, return_after(), session)
+                        .await?
+                }
+                None => {
+                    coll.find_one_and_update(filter, update, return_after())
+                        .await?
+                }
+            };
+            Ok(found)
+        })
+        .await
+    }
+
+    async fn find_one_and_replace(
+        &self,
+        coll: &str,
+        filter: Document,
+        replacement: Document,
+        session: Option<&mut ClientSession>,
+    ) -> DbResult<Option<Document>> {
+        traced("find_one_and_replace", coll, async move {
+            let coll = self.collection::<Document>(coll);
+            let found = match session {
+                Some(session) => {
+                    coll.find_one_and_replace_with_session(
+                        filter,
+                        replacement,
+                        return_after_replace(),
+                        session,
+                    )
+                    .await?
+                }
+                None => {
+                    coll.find_one_and_replace(filter, replacement, return_after_replace())
+                        .await?
+                }
+            };
+            Ok(found)
+        })
+        .await
+    }
+
+    async fn delete_one(
+        &self,
+        coll: &str,
+        filter: Document,
+        session: Option<&mut ClientSession>,
+    ) -> DbResult<bool> {
+        traced("delete_one", coll, async move {
+            let coll = self.collection::<Document>(coll);
+            let deleted = match session {
+                Some(session) => coll.delete_one_with_session(filter, None, session).await?,
+                None => coll.delete_one(filter, None).await?,
+            };
+            Ok(deleted.deleted_count > 0)
+        })
+        .await
+    }
+
+    async fn drop_collection(&self, coll: &str) -> DbResult<()> {
+        traced("drop_collection", coll, async move {
+            Ok(self.collection::<Document>(coll).drop(None).await?)
+        })
+        .await
+    }
+
+    async fn list_indexes(&self, coll: &str) -> DbResult<Vec<IndexSpec>> {
+        traced("list_indexes", coll, async move {
+            let models: Vec<IndexModel> =
+                match self.collection::<Document>(coll).list_indexes(None).await {
+                    Ok(cursor) => cursor.try_collect().await?,
+                    Err(e) if is_namespace_not_found(&e) => vec![],
+                    Err(e) => return Err(e.into()),
+                };
+            Ok(models.into_iter().map(IndexSpec::from_model).collect())
+        })
+        .await
+    }
+
+    async fn create_index(&self, coll: &str, index: &IndexSpec) -> DbResult<()> {
+        traced("create_index", coll, async move {
+            self.collection::<Document>(coll)
+                .create_index(index.to_model(), None)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn drop_index(&self, coll: &str, name: &str) -> DbResult<()> {
+        traced("drop_index", coll, async move {
+            Ok(self
+                .collection::<Document>(coll)
+                .drop_index(name, None)
+                .await?)
+        })
+        .await
+    }
+}
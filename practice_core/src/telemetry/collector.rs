@@ -0,0 +1,596 @@
+//! A [`Subscriber`] writing the events, and in [`LogFormat::Otel`] the spans, to a writer.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Write as _},
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span::{Attributes, Id, Record},
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_core::span::Current;
+
+use super::LogFormat;
+
+thread_local! {
+    /// Spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FieldValue {
+    Str(String),
+    I64(i64),
+    U64(u64),
+    Bool(bool),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Str(s) => write!(f, "{}", s),
+            FieldValue::I64(n) => write!(f, "{}", n),
+            FieldValue::U64(n) => write!(f, "{}", n),
+            FieldValue::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl FieldValue {
+    fn to_json(&self) -> Value {
+        match self {
+            FieldValue::Str(s) => json!(s),
+            FieldValue::I64(n) => json!(n),
+            FieldValue::U64(n) => json!(n),
+            FieldValue::Bool(b) => json!(b),
+        }
+    }
+
+    /// An OTLP `AnyValue`, which has the 64 bits integers as strings.
+    fn to_otel(&self) -> Value {
+        match self {
+            FieldValue::Str(s) => json!({ "stringValue": s }),
+            FieldValue::I64(n) => json!({ "intValue": n.to_string() }),
+            FieldValue::U64(n) => json!({ "intValue": n.to_string() }),
+            FieldValue::Bool(b) => json!({ "boolValue": b }),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Fields(Vec<(&'static str, FieldValue)>);
+
+impl Fields {
+    fn set(&mut self, field: &Field, value: FieldValue) {
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+
+    /// Removes the `message` of an event.
+    fn take_message(&mut self) -> String {
+        match self.0.iter().position(|(name, _)| *name == "message") {
+            Some(i) => self.0.remove(i).1.to_string(),
+            None => String::new(),
+        }
+    }
+
+    fn to_json(&self) -> Map<String, Value> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_json()))
+            .collect()
+    }
+
+    fn to_otel(&self) -> Vec<Value> {
+        self.0
+            .iter()
+            .map(|(name, value)| json!({ "key": name, "value": value.to_otel() }))
+            .collect()
+    }
+}
+
+impl fmt::Display for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(' ')?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Visit for Fields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, FieldValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, FieldValue::U64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, FieldValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, FieldValue::Str(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, FieldValue::Str(format!("{:?}", value)));
+    }
+}
+
+#[derive(Debug)]
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    fields: Fields,
+    parent: Option<u64>,
+    /// Handles of the span still alive.
+    refs: usize,
+    trace_id: u128,
+    span_id: u64,
+    /// Kept since the parent may close first.
+    parent_span_id: Option<u64>,
+    started: SystemTime,
+}
+
+/// Writes a line per event in the [`LogFormat`], and in [`LogFormat::Otel`] a line per span when
+/// it closes. The events and spans more verbose than `max_level` are left out.
+pub struct Collector {
+    format: LogFormat,
+    max_level: Level,
+    service_name: String,
+    out: Mutex<Box<dyn Write + Send>>,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+impl fmt::Debug for Collector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Collector")
+            .field("format", &self.format)
+            .field("max_level", &self.max_level)
+            .field("service_name", &self.service_name)
+            .finish()
+    }
+}
+
+impl Collector {
+    pub fn new(format: LogFormat, max_level: Level, out: impl Write + Send + 'static) -> Self {
+        Collector {
+            format,
+            max_level,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+            out: Mutex::new(Box::new(out)),
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the `service.name` of the OTLP resource, the crate name by default.
+    pub fn service_name(mut self, name: &str) -> Self {
+        self.service_name = name.to_string();
+        self
+    }
+
+    fn lock_spans(&self) -> MutexGuard<'_, HashMap<u64, SpanData>> {
+        // a panic while writing leaves the spans usable.
+        self.spans.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // nowhere to report a failure to write the log to.
+        let _ = writeln!(out, "{}", line).and_then(|()| out.flush());
+    }
+
+    fn current(&self) -> Option<u64> {
+        ENTERED.with(|entered| entered.borrow().last().copied())
+    }
+
+    fn resource(&self) -> Value {
+        json!({
+            "attributes": [
+                { "key": "service.name", "value": { "stringValue": self.service_name } }
+            ]
+        })
+    }
+
+    fn pretty(&self, event: &Event<'_>, message: &str, fields: &Fields, scope: &[&SpanData]) {
+        let metadata = event.metadata();
+        let mut line = format!("{} {:>5} ", timestamp(SystemTime::now()), metadata.level());
+        for span in scope.iter().rev() {
+            let _ = write!(line, "{}{{{}}}:", span.metadata.name(), span.fields);
+        }
+        if !scope.is_empty() {
+            line.push(' ');
+        }
+        let _ = write!(line, "{}: {}", metadata.target(), message);
+        if !fields.0.is_empty() {
+            let _ = write!(line, " {}", fields);
+        }
+        self.write_line(&line);
+    }
+
+    fn json(&self, event: &Event<'_>, message: &str, fields: &Fields, scope: &[&SpanData]) {
+        let metadata = event.metadata();
+        let spans: Vec<Value> = scope
+            .iter()
+            .rev()
+            .map(|span| {
+                let mut object = span.fields.to_json();
+                object.insert("name".to_string(), json!(span.metadata.name()));
+                Value::Object(object)
+            })
+            .collect();
+        let line = json!({
+            "timestamp": timestamp(SystemTime::now()),
+            "level": metadata.level().to_string(),
+            "target": metadata.target(),
+            "message": message,
+            "fields": fields.to_json(),
+            "spans": spans,
+        });
+        self.write_line(&line.to_string());
+    }
+
+    fn otel_log(&self, event: &Event<'_>, message: &str, fields: &Fields, span: Option<&SpanData>) {
+        let metadata = event.metadata();
+        let mut record = json!({
+            "timeUnixNano": unix_nanos(SystemTime::now()),
+            "severityNumber": severity_number(metadata.level()),
+            "severityText": metadata.level().to_string(),
+            "body": { "stringValue": message },
+            "attributes": fields.to_otel(),
+        });
+        if let Some(span) = span {
+            record["traceId"] = json!(format!("{:032x}", span.trace_id));
+            record["spanId"] = json!(format!("{:016x}", span.span_id));
+        }
+        let line = json!({
+            "resourceLogs": [{
+                "resource": self.resource(),
+                "scopeLogs": [{
+                    "scope": { "name": metadata.target() },
+                    "logRecords": [record],
+                }],
+            }],
+        });
+        self.write_line(&line.to_string());
+    }
+
+    fn otel_span(&self, span: &SpanData) {
+        let line = json!({
+            "resourceSpans": [{
+                "resource": self.resource(),
+                "scopeSpans": [{
+                    "scope": { "name": span.metadata.target() },
+                    "spans": [{
+                        "traceId": format!("{:032x}", span.trace_id),
+                        "spanId": format!("{:016x}", span.span_id),
+                        "parentSpanId": span.parent_span_id.map(|id| format!("{:016x}", id)).unwrap_or_default(),
+                        "name": span.metadata.name(),
+                        // SPAN_KIND_INTERNAL
+                        "kind": 1,
+                        "startTimeUnixNano": unix_nanos(span.started),
+                        "endTimeUnixNano": unix_nanos(SystemTime::now()),
+                        "attributes": span.fields.to_otel(),
+                    }],
+                }],
+            }],
+        });
+        self.write_line(&line.to_string());
+    }
+}
+
+impl Subscriber for Collector {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= &self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.max_level))
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        let parent = if attributes.is_root() {
+            None
+        } else {
+            attributes
+                .parent()
+                .map(Id::into_u64)
+                .or_else(|| self.current())
+        };
+        let mut spans = self.lock_spans();
+        let parent_data = parent.and_then(|parent| spans.get(&parent));
+        let trace_id = parent_data
+            .map(|parent| parent.trace_id)
+            .unwrap_or_else(rand::random);
+        let parent_span_id = parent_data.map(|parent| parent.span_id);
+        spans.insert(
+            id,
+            SpanData {
+                metadata: attributes.metadata(),
+                fields,
+                parent,
+                refs: 1,
+                trace_id,
+                span_id: rand::random(),
+                parent_span_id,
+                started: SystemTime::now(),
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.lock_spans().get_mut(&span.into_u64()) {
+            values.record(&mut span.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let message = fields.take_message();
+        let parent = if event.is_root() {
+            None
+        } else {
+            event
+                .parent()
+                .map(|id| id.into_u64())
+                .or_else(|| self.current())
+        };
+
+        let spans = self.lock_spans();
+        let mut scope = vec![];
+        let mut next = parent.and_then(|id| spans.get(&id));
+        while let Some(span) = next {
+            scope.push(span);
+            next = span.parent.and_then(|id| spans.get(&id));
+        }
+        match self.format {
+            LogFormat::Pretty => self.pretty(event, &message, &fields, &scope),
+            LogFormat::Json => self.json(event, &message, &fields, &scope),
+            LogFormat::Otel => self.otel_log(event, &message, &fields, scope.first().copied()),
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(span) = self.lock_spans().get_mut(&span.into_u64()) {
+            span.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.lock_spans();
+        let id = span.into_u64();
+        match spans.get_mut(&id) {
+            Some(data) if data.refs > 1 => {
+                data.refs -= 1;
+                false
+            }
+            Some(_) => {
+                let data = spans.remove(&id).unwrap();
+                if self.format == LogFormat::Otel {
+                    self.otel_span(&data);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn current_span(&self) -> Current {
+        match self.current() {
+            Some(id) => match self.lock_spans().get(&id) {
+                Some(span) => Current::new(Id::from_u64(id), span.metadata),
+                None => Current::none(),
+            },
+            None => Current::none(),
+        }
+    }
+}
+
+fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Nanoseconds since the Unix epoch, as a string like in OTLP/JSON.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// The OTLP `SeverityNumber` of the first severity of each level.
+fn severity_number(level: &Level) -> u8 {
+    match *level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        _ => 17,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing::{info, info_span, warn};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn lines(&self) -> Vec<String> {
+            let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            text.lines().map(str::to_string).collect()
+        }
+
+        fn json_lines(&self) -> Vec<Value> {
+            self.lines()
+                .iter()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    /// Runs `f` with a collector writing into the returned buffer.
+    fn collect(format: LogFormat, level: Level, f: impl FnOnce()) -> Shared {
+        let out = Shared::default();
+        tracing::subscriber::with_default(Collector::new(format, level, out.clone()), f);
+        out
+    }
+
+    fn workload() {
+        let span = info_span!(
+            "db",
+            op = "find_one",
+            coll = "books",
+            txn_number = tracing::field::Empty
+        );
+        let _entered = span.enter();
+        tracing::Span::current().record("txn_number", &3i64);
+        info!(found = true, "looked up");
+        tracing::debug!("not shown");
+    }
+
+    #[test]
+    fn writes_pretty_lines() {
+        let out = collect(LogFormat::Pretty, Level::INFO, || {
+            workload();
+            warn!(attempt = 2u64, "retrying");
+        });
+        let lines = out.lines();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(
+            lines[0].ends_with(
+                " INFO db{op=find_one coll=books txn_number=3}: \
+                 practice_core::telemetry::collector::tests: looked up found=true"
+            ),
+            "{}",
+            lines[0]
+        );
+        assert!(
+            lines[1]
+                .ends_with(" WARN practice_core::telemetry::collector::tests: retrying attempt=2"),
+            "{}",
+            lines[1]
+        );
+    }
+
+    #[test]
+    fn writes_json_events_with_their_spans() {
+        let out = collect(LogFormat::Json, Level::INFO, workload);
+        let lines = out.json_lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["message"], "looked up");
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["fields"], json!({"found": true}));
+        assert_eq!(
+            lines[0]["spans"],
+            json!([{"name": "db", "op": "find_one", "coll": "books", "txn_number": 3}])
+        );
+    }
+
+    #[test]
+    fn writes_otel_logs_and_spans() {
+        let out = collect(LogFormat::Otel, Level::INFO, || {
+            let outer = info_span!("transaction");
+            let _entered = outer.enter();
+            workload();
+        });
+        let lines = out.json_lines();
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+
+        let log = &lines[0]["resourceLogs"][0]["scopeLogs"][0]["logRecords"][0];
+        assert_eq!(log["body"]["stringValue"], "looked up");
+        assert_eq!(log["severityNumber"], 9);
+        // the inner span closes first.
+        let span = |i: usize| lines[i]["resourceSpans"][0]["scopeSpans"][0]["spans"][0].clone();
+        let (inner, outer) = (span(1), span(2));
+        assert_eq!(inner["name"], "db");
+        assert_eq!(outer["name"], "transaction");
+        assert_eq!(log["spanId"], inner["spanId"]);
+        assert_eq!(inner["traceId"], outer["traceId"]);
+        assert_eq!(inner["parentSpanId"], outer["spanId"]);
+        assert_eq!(outer["parentSpanId"], "");
+        assert!(inner["attributes"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({"key": "txn_number", "value": {"intValue": "3"}})));
+        assert_eq!(
+            lines[0]["resourceLogs"][0]["resource"]["attributes"][0]["value"]["stringValue"],
+            "practice_core"
+        );
+    }
+
+    #[test]
+    fn spans_transactions() {
+        let storage = crate::storage::MemoryStorage::new();
+        let out = collect(LogFormat::Otel, Level::INFO, || {
+            let mut session = storage.start_session();
+            let options = crate::WithTransactionOptions::default();
+            futures::executor::block_on(crate::tx::run_transaction(&mut session, &options, |_| {
+                Box::pin(async { Ok::<_, crate::DbError>(()) })
+            }))
+            .unwrap();
+        });
+        let lines = out.json_lines();
+        let span = &lines[0]["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "transaction");
+        assert_eq!(
+            span["attributes"],
+            json!([{"key": "attempts", "value": {"intValue": "1"}}])
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Tracing of the database calls.
+//!
+//! The operations of [`MongoStorage`](crate::storage::MongoStorage), so every repository call,
+//! run in a `db` span with the operation and the collection, and the transaction helpers in a
+//! `transaction` or `commit` span. [`CommandLogger`] logs the commands the driver sends with
+//! their durations, and fills the `session_id` and `txn_number` of the span they are sent in.
+//! [`init`] installs a [`Collector`] writing it all in one of the [`LogFormat`]s.
+//!
+//! ```ignore
+//! telemetry::init(Collector::new(LogFormat::Otel, Level::DEBUG, File::create("trace.jsonl")?))?;
+//! let client = connect_with_handler(&config, Arc::new(CommandLogger)).await?;
+//! ```
+
+mod collector;
+
+pub use collector::Collector;
+pub use tracing::Level;
+
+use std::{fmt, str::FromStr};
+
+use mongodb::{
+    bson::{spec::BinarySubtype, Bson, Document},
+    event::command::{
+        CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+    },
+};
+use tracing::{debug, dispatcher::SetGlobalDefaultError, field::Empty, warn, Span};
+
+/// Target of the events of [`CommandLogger`].
+pub const COMMAND_TARGET: &str = "mongodb::command";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A human readable line per event.
+    Pretty,
+    /// A JSON object per event, with its spans.
+    Json,
+    /// OTLP/JSON: the spans when they close and the events as log records, a request per line.
+    Otel,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Pretty => write!(f, "pretty"),
+            LogFormat::Json => write!(f, "json"),
+            LogFormat::Otel => write!(f, "otel"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            "otel" => Ok(LogFormat::Otel),
+            _ => Err(format!(
+                "unknown log format {:?}, expected pretty, json or otel",
+                s
+            )),
+        }
+    }
+}
+
+/// Installs `collector` for the whole process. Fails if a subscriber is already installed.
+pub fn init(collector: Collector) -> Result<(), SetGlobalDefaultError> {
+    tracing::subscriber::set_global_default(collector)
+}
+
+/// Span of an operation of the storage on `coll`.
+pub(crate) fn db_span(op: &'static str, coll: &str) -> Span {
+    tracing::info_span!("db", op, coll, session_id = Empty, txn_number = Empty)
+}
+
+/// Span of a transaction run with retries. `attempts` is recorded when it ends.
+pub(crate) fn transaction_span() -> Span {
+    tracing::info_span!(
+        "transaction",
+        attempts = Empty,
+        session_id = Empty,
+        txn_number = Empty
+    )
+}
+
+/// Span of a commit run with retries.
+pub(crate) fn commit_span() -> Span {
+    tracing::info_span!("commit", session_id = Empty, txn_number = Empty)
+}
+
+/// Logs the commands the driver sends at debug level, and the failed ones at warn level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CommandLogger;
+
+impl CommandEventHandler for CommandLogger {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        record_session(&Span::current(), &event.command);
+        debug!(
+            target: COMMAND_TARGET,
+            command_name = %event.command_name,
+            db = %event.db,
+            request_id = event.request_id,
+            server = %event.connection.address,
+            "command started"
+        );
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        debug!(
+            target: COMMAND_TARGET,
+            command_name = %event.command_name,
+            request_id = event.request_id,
+            server = %event.connection.address,
+            duration_us = event.duration.as_micros() as u64,
+            "command succeeded"
+        );
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        warn!(
+            target: COMMAND_TARGET,
+            command_name = %event.command_name,
+            request_id = event.request_id,
+            server = %event.connection.address,
+            duration_us = event.duration.as_micros() as u64,
+            error = %event.failure,
+            "command failed"
+        );
+    }
+}
+
+/// Records the session and the transaction number of `command` into the `session_id` and
+/// `txn_number` fields of `span`, if it has them.
+pub(crate) fn record_session(span: &Span, command: &Document) {
+    if let Some(id) = command.get_document("lsid").ok().and_then(session_id) {
+        span.record("session_id", &id.as_str());
+    }
+    if let Ok(txn_number) = command.get_i64("txnNumber") {
+        span.record("txn_number", &txn_number);
+    }
+}
+
+/// The UUID of a session id, `{"id": UUID(...)}`.
+fn session_id(lsid: &Document) -> Option<String> {
+    let bytes = match lsid.get("id")? {
+        Bson::Binary(binary) if binary.subtype == BinarySubtype::Uuid => &binary.bytes,
+        _ => return None,
+    };
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    if hex.len() != 32 {
+        return Some(hex);
+    }
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::{doc, Binary};
+
+    #[test]
+    fn formats_session_ids() {
+        let lsid = doc! {"id": Binary {subtype: BinarySubtype::Uuid, bytes: (0..16).collect()}};
+        assert_eq!(
+            session_id(&lsid).as_deref(),
+            Some("00010203-0405-0607-0809-0a0b0c0d0e0f")
+        );
+        assert_eq!(session_id(&doc! {"id": 1}), None);
+    }
+}
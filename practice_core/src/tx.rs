@@ -0,0 +1,535 @@
+//! Transaction helpers.
+//!
+//! [`with_transaction`] runs a closure in a transaction and retries it in the way the
+//! driver specification describes for the "convenient transaction API":
+//!
+//! - the whole transaction is retried when the body or the commit fails with `TransientTransactionError`
+//! - only the commit is retried when it fails with `UnknownTransactionCommitResult`
+//! - no retry is started after the deadline, and every retry waits with an exponential backoff
+//! - the last error is returned as it is when the transaction gives up
+
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use mongodb::{
+    error::{
+        Error, Result as TxResult, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT,
+    },
+    options::{Acknowledgment, ReadConcern, TransactionOptions, WriteConcern},
+    Client, ClientSession,
+};
+use tracing::{debug, Instrument};
+
+use crate::{
+    metrics::{self, error_label, TxEvent},
+    telemetry::{commit_span, transaction_span},
+};
+
+/// Transaction options with majority read and write concern, used by every scenario.
+pub fn majority_tx_options() -> TransactionOptions {
+    TransactionOptions::builder()
+        .read_concern(ReadConcern::majority())
+        .write_concern(WriteConcern::builder().w(Acknowledgment::Majority).build())
+        .build()
+}
+
+/// Errors that can carry the labels the server attaches to transaction errors.
+pub trait ErrorLabels {
+    fn has_label(&self, label: &str) -> bool;
+}
+
+impl ErrorLabels for Error {
+    fn has_label(&self, label: &str) -> bool {
+        self.contains_label(label)
+    }
+}
+
+impl ErrorLabels for anyhow::Error {
+    fn has_label(&self, label: &str) -> bool {
+        if let Some(e) = self.downcast_ref::<crate::error::DbError>() {
+            return e.has_label(label);
+        }
+        self.downcast_ref::<Error>()
+            .map(|e| e.contains_label(label))
+            .unwrap_or(false)
+    }
+}
+
+/// The operations of a session [`run_transaction`] needs.
+///
+/// Implemented by [`ClientSession`], and by a scripted session in the tests.
+#[async_trait]
+pub trait TxSession: Send {
+    type Error: ErrorLabels + Send;
+
+    async fn start_transaction(&mut self, options: TransactionOptions) -> Result<(), Self::Error>;
+    async fn commit_transaction(&mut self) -> Result<(), Self::Error>;
+    async fn abort_transaction(&mut self) -> Result<(), Self::Error>;
+}
+
+#[async_trait]
+impl TxSession for ClientSession {
+    type Error = Error;
+
+    async fn start_transaction(&mut self, options: TransactionOptions) -> TxResult<()> {
+        ClientSession::start_transaction(self, options).await
+    }
+
+    async fn commit_transaction(&mut self) -> TxResult<()> {
+        ClientSession::commit_transaction(self).await
+    }
+
+    async fn abort_transaction(&mut self) -> TxResult<()> {
+        ClientSession::abort_transaction(self).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WithTransactionOptions {
+    pub transaction_options: TransactionOptions,
+    /// No retry is started once this much time has passed since the first attempt.
+    pub timeout: Duration,
+    /// Wait before the first retry. Doubled on each retry up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for WithTransactionOptions {
+    fn default() -> Self {
+        WithTransactionOptions {
+            transaction_options: majority_tx_options(),
+            // same as the limit of the convenient transaction API of the other drivers.
+            timeout: Duration::from_secs(120),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+struct Backoff {
+    deadline: Instant,
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(options: &WithTransactionOptions) -> Self {
+        Backoff {
+            deadline: Instant::now() + options.timeout,
+            next: options.initial_backoff,
+            max: options.max_backoff,
+        }
+    }
+
+    /// Waits before the next retry, or returns false if the retry would end after the deadline.
+    async fn wait(&mut self) -> bool {
+        if Instant::now() + self.next >= self.deadline {
+            return false;
+        }
+        if !self.next.is_zero() {
+            tokio::time::sleep(self.next).await;
+        }
+        self.next = std::cmp::min(self.next * 2, self.max);
+        true
+    }
+}
+
+/// Starts a session on `client` and runs `body` in a transaction of it.
+///
+/// ```ignore
+/// let book = with_transaction(&client, &WithTransactionOptions::default(), |session| {
+///     let book_coll = book_coll.clone();
+///     Box::pin(async move {
+///         book_coll
+///             .find_one_with_session(doc! {"id": "book_1"}, None, session)
+///             .await
+///     })
+/// })
+/// .await?;
+/// ```
+pub async fn with_transaction<T, E, F>(
+    client: &Client,
+    options: &WithTransactionOptions,
+    body: F,
+) -> Result<T, E>
+where
+    E: From<Error> + ErrorLabels + Send,
+    F: for<'a> FnMut(&'a mut ClientSession) -> BoxFuture<'a, Result<T, E>>,
+{
+    let mut session = client.start_session(None).await?;
+    run_transaction(&mut session, options, body).await
+}
+
+/// Runs `body` in a transaction of `session`, retrying it as described in the module document.
+pub async fn run_transaction<S, T, E, F>(
+    session: &mut S,
+    options: &WithTransactionOptions,
+    mut body: F,
+) -> Result<T, E>
+where
+    S: TxSession,
+    E: From<S::Error> + ErrorLabels + Send,
+    F: for<'a> FnMut(&'a mut S) -> BoxFuture<'a, Result<T, E>>,
+{
+    let span = transaction_span();
+    let mut attempts = 0;
+    let result = run_attempts(session, options, &mut body, &mut attempts)
+        .instrument(span.clone())
+        .await;
+    span.record("attempts", &attempts);
+    result
+}
+
+/// The attempts of [`run_transaction`], counted in `attempts`.
+async fn run_attempts<S, T, E, F>(
+    session: &mut S,
+    options: &WithTransactionOptions,
+    body: &mut F,
+    attempts: &mut u64,
+) -> Result<T, E>
+where
+    S: TxSession,
+    E: From<S::Error> + ErrorLabels + Send,
+    F: for<'a> FnMut(&'a mut S) -> BoxFuture<'a, Result<T, E>>,
+{
+    let mut backoff = Backoff::new(options);
+
+    'transaction: loop {
+        *attempts += 1;
+        session
+            .start_transaction(options.transaction_options.clone())
+            .await?;
+
+        let value = match body(session).await {
+            Ok(value) => value,
+            Err(e) => {
+                // the transaction may be already aborted by the server, so the result is not interesting.
+                let _ = session.abort_transaction().await;
+                metrics::global().transaction(TxEvent::Abort, error_label(&e));
+                if e.has_label(TRANSIENT_TRANSACTION_ERROR) && backoff.wait().await {
+                    metrics::global().transaction(TxEvent::Retry, TRANSIENT_TRANSACTION_ERROR);
+                    debug!(
+                        label = TRANSIENT_TRANSACTION_ERROR,
+                        "retrying the transaction"
+                    );
+                    continue 'transaction;
+                }
+                return Err(e);
+            }
+        };
+
+        loop {
+            let e = match session.commit_transaction().await {
+                Ok(()) => {
+                    metrics::global().transaction(TxEvent::Commit, "none");
+                    return Ok(value);
+                }
+                Err(e) => E::from(e),
+            };
+            if e.has_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && backoff.wait().await {
+                metrics::global().transaction(TxEvent::Retry, UNKNOWN_TRANSACTION_COMMIT_RESULT);
+                debug!(
+                    label = UNKNOWN_TRANSACTION_COMMIT_RESULT,
+                    "retrying the commit"
+                );
+                continue;
+            }
+            // the server aborted the transaction the commit failed for.
+            metrics::global().transaction(TxEvent::Abort, error_label(&e));
+            if e.has_label(TRANSIENT_TRANSACTION_ERROR) && backoff.wait().await {
+                metrics::global().transaction(TxEvent::Retry, TRANSIENT_TRANSACTION_ERROR);
+                debug!(
+                    label = TRANSIENT_TRANSACTION_ERROR,
+                    "retrying the transaction"
+                );
+                continue 'transaction;
+            }
+            return Err(e);
+        }
+    }
+}
+
+/// Commits the transaction of `session`, retrying it while the result is unknown.
+pub async fn commit_tx(session: &mut ClientSession) -> TxResult<()> {
+    commit_with_retry(session, &WithTransactionOptions::default()).await
+}
+
+pub async fn commit_with_retry<S: TxSession>(
+    session: &mut S,
+    options: &WithTransactionOptions,
+) -> Result<(), S::Error> {
+    let mut backoff = Backoff::new(options);
+    async {
+        loop {
+            match session.commit_transaction().await {
+                Err(e)
+                    if e.has_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && backoff.wait().await =>
+                {
+                    metrics::global()
+                        .transaction(TxEvent::Retry, UNKNOWN_TRANSACTION_COMMIT_RESULT);
+                    debug!(
+                        label = UNKNOWN_TRANSACTION_COMMIT_RESULT,
+                        "retrying the commit"
+                    );
+                    continue;
+                }
+                Ok(()) => {
+                    metrics::global().transaction(TxEvent::Commit, "none");
+                    return Ok(());
+                }
+                Err(e) => {
+                    metrics::global().transaction(TxEvent::Abort, error_label(&e));
+                    return Err(e);
+                }
+            }
+        }
+    }
+    .instrument(commit_span())
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct FakeError {
+        name: &'static str,
+        labels: Vec<&'static str>,
+    }
+
+    impl FakeError {
+        fn new(name: &'static str, labels: &[&'static str]) -> Self {
+            FakeError {
+                name,
+                labels: labels.to_vec(),
+            }
+        }
+
+        fn transient(name: &'static str) -> Self {
+            Self::new(name, &[TRANSIENT_TRANSACTION_ERROR])
+        }
+
+        fn unknown_commit(name: &'static str) -> Self {
+            Self::new(name, &[UNKNOWN_TRANSACTION_COMMIT_RESULT])
+        }
+    }
+
+    impl ErrorLabels for FakeError {
+        fn has_label(&self, label: &str) -> bool {
+            self.labels.contains(&label)
+        }
+    }
+
+    /// A session whose commits fail with the scripted errors, and which records the calls.
+    #[derive(Default)]
+    struct FakeSession {
+        commit_results: VecDeque<Result<(), FakeError>>,
+        calls: Vec<&'static str>,
+    }
+
+    impl FakeSession {
+        fn with_commits(commit_results: Vec<Result<(), FakeError>>) -> Self {
+            FakeSession {
+                commit_results: commit_results.into(),
+                calls: vec![],
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TxSession for FakeSession {
+        type Error = FakeError;
+
+        async fn start_transaction(&mut self, _: TransactionOptions) -> Result<(), FakeError> {
+            self.calls.push("start");
+            Ok(())
+        }
+
+        async fn commit_transaction(&mut self) -> Result<(), FakeError> {
+            self.calls.push("commit");
+            self.commit_results.pop_front().unwrap_or(Ok(()))
+        }
+
+        async fn abort_transaction(&mut self) -> Result<(), FakeError> {
+            self.calls.push("abort");
+            Ok(())
+        }
+    }
+
+    fn no_wait() -> WithTransactionOptions {
+        WithTransactionOptions {
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    /// Runs a body that returns the scripted results in order.
+    async fn run(
+        session: &mut FakeSession,
+        options: &WithTransactionOptions,
+        body_results: Vec<Result<u32, FakeError>>,
+    ) -> (Result<u32, FakeError>, usize) {
+        let mut body_results: VecDeque<_> = body_results.into();
+        let mut body_calls = 0;
+        let result = run_transaction(session, options, |session| {
+            body_calls += 1;
+            session.calls.push("body");
+            let result = body_results.pop_front().unwrap();
+            Box::pin(async move { result })
+        })
+        .await;
+        (result, body_calls)
+    }
+
+    #[tokio::test]
+    async fn commits_once_on_success() {
+        let mut session = FakeSession::default();
+        let (result, body_calls) = run(&mut session, &no_wait(), vec![Ok(1)]).await;
+
+        assert_eq!(result, Ok(1));
+        assert_eq!(body_calls, 1);
+        assert_eq!(session.calls, vec!["start", "body", "commit"]);
+    }
+
+    #[tokio::test]
+    async fn retries_whole_transaction_on_transient_body_error() {
+        let mut session = FakeSession::default();
+        let (result, body_calls) = run(
+            &mut session,
+            &no_wait(),
+            vec![Err(FakeError::transient("write conflict")), Ok(2)],
+        )
+        .await;
+
+        assert_eq!(result, Ok(2));
+        assert_eq!(body_calls, 2);
+        assert_eq!(
+            session.calls,
+            vec!["start", "body", "abort", "start", "body", "commit"]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_whole_transaction_on_transient_commit_error() {
+        let mut session =
+            FakeSession::with_commits(vec![Err(FakeError::transient("primary stepped down"))]);
+        let (result, body_calls) = run(&mut session, &no_wait(), vec![Ok(1), Ok(2)]).await;
+
+        assert_eq!(result, Ok(2));
+        assert_eq!(body_calls, 2);
+        assert_eq!(
+            session.calls,
+            vec!["start", "body", "commit", "start", "body", "commit"]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_only_commit_on_unknown_commit_result() {
+        let mut session = FakeSession::with_commits(vec![
+            Err(FakeError::unknown_commit("timeout")),
+            Err(FakeError::unknown_commit("timeout")),
+        ]);
+        let (result, body_calls) = run(&mut session, &no_wait(), vec![Ok(1)]).await;
+
+        assert_eq!(result, Ok(1));
+        assert_eq!(body_calls, 1);
+        assert_eq!(
+            session.calls,
+            vec!["start", "body", "commit", "commit", "commit"]
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_non_retryable_body_error_after_abort() {
+        let mut session = FakeSession::default();
+        let error = FakeError::new("duplicate key", &[]);
+        let (result, body_calls) = run(&mut session, &no_wait(), vec![Err(error.clone())]).await;
+
+        assert_eq!(result, Err(error));
+        assert_eq!(body_calls, 1);
+        assert_eq!(session.calls, vec!["start", "body", "abort"]);
+    }
+
+    #[tokio::test]
+    async fn returns_non_retryable_commit_error() {
+        let error = FakeError::new("unauthorized", &[]);
+        let mut session = FakeSession::with_commits(vec![Err(error.clone())]);
+        let (result, _) = run(&mut session, &no_wait(), vec![Ok(1)]).await;
+
+        assert_eq!(result, Err(error));
+        assert_eq!(session.calls, vec!["start", "body", "commit"]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_deadline_with_last_error() {
+        let mut session = FakeSession::with_commits(
+            (0..100)
+                .map(|_| Err(FakeError::unknown_commit("timeout")))
+                .collect(),
+        );
+        let options = WithTransactionOptions {
+            timeout: Duration::from_millis(50),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let (result, _) = run(&mut session, &options, vec![Ok(1)]).await;
+
+        assert_eq!(result, Err(FakeError::unknown_commit("timeout")));
+        let commits = session.calls.iter().filter(|c| **c == "commit").count();
+        assert!(commits > 1 && commits <= 5, "commits: {}", commits);
+    }
+
+    #[tokio::test]
+    async fn gives_up_transient_retries_after_deadline() {
+        let mut session = FakeSession::default();
+        let options = WithTransactionOptions {
+            timeout: Duration::ZERO,
+            ..no_wait()
+        };
+        let error = FakeError::transient("write conflict");
+        let (result, body_calls) = run(&mut session, &options, vec![Err(error.clone())]).await;
+
+        assert_eq!(result, Err(error));
+        assert_eq!(body_calls, 1);
+    }
+
+    #[tokio::test]
+    async fn backoff_doubles_up_to_max() {
+        let options = WithTransactionOptions {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            ..Default::default()
+        };
+        let mut backoff = Backoff::new(&options);
+        let mut waits = vec![];
+        for _ in 0..4 {
+            waits.push(backoff.next);
+            assert!(backoff.wait().await);
+        }
+        assert_eq!(
+            waits,
+            vec![1, 2, 4, 4]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn commit_with_retry_returns_error_instead_of_ok() {
+        let error = FakeError::new("write concern error", &[]);
+        let mut session = FakeSession::with_commits(vec![
+            Err(FakeError::unknown_commit("timeout")),
+            Err(error.clone()),
+        ]);
+
+        let result = commit_with_retry(&mut session, &no_wait()).await;
+        assert_eq!(result, Err(error));
+        assert_eq!(session.calls, vec!["commit", "commit"]);
+    }
+}
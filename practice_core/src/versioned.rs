@@ -0,0 +1,505 @@
+//! Optimistic locking on a `version` field, generalized from the optimistic lock scenario.
+//!
+//! An update only matches the document when its version is still the expected one, and
+//! increments the version. A writer holding a stale version gets [`VersionError::VersionConflict`]
+//! instead of an `UpdateResult` whose `matched_count` it has to check.
+//!
+//! The functions take the [`Storage`] and the name of the collection, like the repositories.
+
+use std::{cmp, time::Duration};
+
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::Error,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::{
+    error::DbError,
+    metrics,
+    models::book_fields,
+    repository::{from_doc, to_doc},
+    storage::Storage,
+    Book,
+};
+
+/// Documents guarded by a version number.
+pub trait Versioned: Serialize + DeserializeOwned + Clone + Unpin + Send + Sync {
+    /// Field that identifies the document.
+    const ID_FIELD: &'static str = "id";
+    const VERSION_FIELD: &'static str = "version";
+
+    fn id(&self) -> &str;
+    fn version(&self) -> i64;
+    fn set_version(&mut self, version: i64);
+}
+
+impl Versioned for Book {
+    const ID_FIELD: &'static str = book_fields::ID;
+    const VERSION_FIELD: &'static str = book_fields::VERSION;
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn version(&self) -> i64 {
+        self.version
+    }
+
+    fn set_version(&mut self, version: i64) {
+        self.version = version
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum VersionError {
+    /// The document was updated by someone else since it was read.
+    #[error("version conflict: expected {expected}, actual {actual}")]
+    VersionConflict { expected: i64, actual: i64 },
+    #[error("document {0} is not found")]
+    NotFound(String),
+    #[error(transparent)]
+    Db(#[from] DbError),
+}
+
+impl From<Error> for VersionError {
+    fn from(e: Error) -> Self {
+        VersionError::Db(e.into())
+    }
+}
+
+pub type VersionResult<T> = std::result::Result<T, VersionError>;
+
+/// How [`modify_with_retry`] retries after a version conflict.
+#[derive(Debug, Clone)]
+pub struct RetryOptions {
+    /// Number of read-modify-writes including the first one.
+    pub max_attempts: usize,
+    /// Wait before the first retry. Doubled on each retry up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        RetryOptions {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryOptions {
+    pub fn max_attempts(max_attempts: usize) -> Self {
+        RetryOptions {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+}
+
+fn version_filter<T: Versioned>(id: &str, version: i64) -> Document {
+    doc! {T::ID_FIELD: id, T::VERSION_FIELD: version}
+}
+
+/// Adds the version increment to `update`, keeping the other fields of its `$inc` if any.
+fn with_version_inc<T: Versioned>(mut update: Document) -> Document {
+    let mut inc = match update.remove("$inc") {
+        Some(Bson::Document(inc)) => inc,
+        _ => Document::new(),
+    };
+    inc.insert(T::VERSION_FIELD, 1i64);
+    update.insert("$inc", inc);
+    update
+}
+
+async fn find<S: Storage, T: Versioned>(
+    storage: &S,
+    coll: &str,
+    id: &str,
+    session: Option<&mut S::Session>,
+) -> VersionResult<Option<T>> {
+    let found = storage
+        .find_one(coll, doc! {T::ID_FIELD: id}, session)
+        .await?;
+    Ok(found.map(from_doc).transpose()?)
+}
+
+/// Tells why nothing matched the versioned filter.
+async fn mismatch<S: Storage, T: Versioned>(
+    storage: &S,
+    coll: &str,
+    id: &str,
+    expected: i64,
+    session: Option<&mut S::Session>,
+) -> VersionError {
+    match find::<S, T>(storage, coll, id, session).await {
+        Ok(Some(current)) => {
+            metrics::global().version_conflict(coll);
+            VersionError::VersionConflict {
+                expected,
+                actual: current.version(),
+            }
+        }
+        Ok(None) => VersionError::NotFound(id.to_string()),
+        Err(e) => e,
+    }
+}
+
+/// Applies `update` to the document `id` if its version is `expected`, and returns the updated document.
+///
+/// ```ignore
+/// let book: Book = update_with_version(&storage, "books", "book_1", 1, doc! {"$set": {"name": "new name"}}).await?;
+/// assert_eq!(book.version, 2);
+/// ```
+pub async fn update_with_version<S: Storage, T: Versioned>(
+    storage: &S,
+    coll: &str,
+    id: &str,
+    expected: i64,
+    update: Document,
+) -> VersionResult<T> {
+    update_in::<S, T>(storage, coll, id, expected, update, None).await
+}
+
+pub async fn update_with_version_with_session<S: Storage, T: Versioned>(
+    storage: &S,
+    coll: &str,
+    id: &str,
+    expected: i64,
+    update: Document,
+    session: &mut S::Session,
+) -> VersionResult<T> {
+    update_in::<S, T>(storage, coll, id, expected, update, Some(session)).await
+}
+
+async fn update_in<S: Storage, T: Versioned>(
+    storage: &S,
+    coll: &str,
+    id: &str,
+    expected: i64,
+    update: Document,
+    mut session: Option<&mut S::Session>,
+) -> VersionResult<T> {
+    let updated = storage
+        .find_one_and_update(
+            coll,
+            version_filter::<T>(id, expected),
+            with_version_inc::<T>(update),
+            session.as_deref_mut(),
+        )
+        .await?;
+    match updated {
+        Some(updated) => Ok(from_doc(updated)?),
+        None => Err(mismatch::<S, T>(storage, coll, id, expected, session).await),
+    }
+}
+
+/// Replaces the document with `doc` if the stored version is still `doc.version()`.
+///
+/// The stored document gets the next version, and is returned.
+pub async fn replace_with_version<S: Storage, T: Versioned>(
+    storage: &S,
+    coll: &str,
+    doc: &T,
+) -> VersionResult<T> {
+    replace_in(storage, coll, doc, None).await
+}
+
+pub async fn replace_with_version_with_session<S: Storage, T: Versioned>(
+    storage: &S,
+    coll: &str,
+    doc: &T,
+    session: &mut S::Session,
+) -> VersionResult<T> {
+    replace_in(storage, coll, doc, Some(session)).await
+}
+
+async fn replace_in<S: Storage, T: Versioned>(
+    storage: &S,
+    coll: &str,
+    doc: &T,
+    mut session: Option<&mut S::Session>,
+) -> VersionResult<T> {
+    let expected = doc.version();
+    let replaced = storage
+        .find_one_and_replace(
+            coll,
+            version_filter::<T>(doc.id(), expected),
+            to_doc(&next_version(doc))?,
+            session.as_deref_mut(),
+        )
+        .await?;
+    match replaced {
+        Some(replaced) => Ok(from_doc(replaced)?),
+        None => Err(mismatch::<S, T>(storage, coll, doc.id(), expected, session).await),
+    }
+}
+
+fn next_version<T: Versioned>(doc: &T) -> T {
+    let mut next = doc.clone();
+    next.set_version(doc.version() + 1);
+    next
+}
+
+/// Reads the document `id`, applies `modify` to it and writes it back with [`replace_with_version`].
+///
+/// When someone else updates the document in between, the whole read-modify-write is retried
+/// after a backoff, up to `options.max_attempts` times in total, then the last `VersionConflict`
+/// is returned.
+pub async fn modify_with_retry<S, T, F>(
+    storage: &S,
+    coll: &str,
+    id: &str,
+    options: &RetryOptions,
+    modify: F,
+) -> VersionResult<T>
+where
+    S: Storage,
+    T: Versioned,
+    F: FnMut(&mut T),
+{
+    modify_in(storage, coll, id, options, modify, None).await
+}
+
+/// Same as [`modify_with_retry`] in `session`.
+///
+/// In a transaction, the document written by someone else since the transaction started fails
+/// with a write conflict instead, which is retried by [`run_transaction`](crate::tx::run_transaction)
+/// rather than here.
+pub async fn modify_with_retry_with_session<S, T, F>(
+    storage: &S,
+    coll: &str,
+    id: &str,
+    options: &RetryOptions,
+    modify: F,
+    session: &mut S::Session,
+) -> VersionResult<T>
+where
+    S: Storage,
+    T: Versioned,
+    F: FnMut(&mut T),
+{
+    modify_in(storage, coll, id, options, modify, Some(session)).await
+}
+
+async fn modify_in<S, T, F>(
+    storage: &S,
+    coll: &str,
+    id: &str,
+    options: &RetryOptions,
+    mut modify: F,
+    mut session: Option<&mut S::Session>,
+) -> VersionResult<T>
+where
+    S: Storage,
+    T: Versioned,
+    F: FnMut(&mut T),
+{
+    let mut backoff = options.initial_backoff;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let mut doc: T = find::<S, T>(storage, coll, id, session.as_deref_mut())
+            .await?
+            .ok_or_else(|| VersionError::NotFound(id.to_string()))?;
+        let read_version = doc.version();
+        modify(&mut doc);
+        doc.set_version(read_version);
+        match replace_in(storage, coll, &doc, session.as_deref_mut()).await {
+            Err(VersionError::VersionConflict { .. }) if attempt < options.max_attempts => {
+                metrics::global().version_retry(coll);
+                tokio::time::sleep(backoff).await;
+                backoff = cmp::min(backoff * 2, options.max_backoff);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::MemoryStorage,
+        tx::{majority_tx_options, TxSession},
+    };
+
+    const BOOKS: &str = "books";
+
+    async fn storage_with_book() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        let book = Book {
+            id: "book_1".to_string(),
+            name: "john".to_string(),
+            reviews: vec![],
+            authors: vec![],
+            supervisors: vec![],
+            version: 1,
+        };
+        storage
+            .insert_one(BOOKS, to_doc(&book).unwrap(), None)
+            .await
+            .unwrap();
+        storage
+    }
+
+    /// Updates the book behind the back of the reader, from inside a `modify` closure.
+    fn update_concurrently(storage: &MemoryStorage) {
+        futures::executor::block_on(storage.update_one(
+            BOOKS,
+            doc! {"id": "book_1"},
+            doc! {"$inc": {"version": 1i64}},
+            None,
+        ))
+        .unwrap();
+    }
+
+    fn retry(max_attempts: usize) -> RetryOptions {
+        RetryOptions {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        }
+    }
+
+    fn rename(book: &mut Book) {
+        book.name = "anna".to_string();
+    }
+
+    #[tokio::test]
+    async fn update_checks_version() {
+        let storage = storage_with_book().await;
+        let set_name = || doc! {"$set": {"name": "anna"}};
+
+        let book: Book = update_with_version(&storage, BOOKS, "book_1", 1, set_name())
+            .await
+            .unwrap();
+        assert_eq!((book.name.as_str(), book.version), ("anna", 2));
+
+        let result = update_with_version::<_, Book>(&storage, BOOKS, "book_1", 1, set_name()).await;
+        assert!(matches!(
+            result,
+            Err(VersionError::VersionConflict {
+                expected: 1,
+                actual: 2
+            })
+        ));
+        let result = update_with_version::<_, Book>(&storage, BOOKS, "book_x", 1, set_name()).await;
+        assert!(matches!(result, Err(VersionError::NotFound(ref id)) if id == "book_x"));
+    }
+
+    #[tokio::test]
+    async fn replace_checks_version() {
+        let storage = storage_with_book().await;
+        let mut book: Book = find(&storage, BOOKS, "book_1", None)
+            .await
+            .unwrap()
+            .unwrap();
+        rename(&mut book);
+
+        let replaced = replace_with_version(&storage, BOOKS, &book).await.unwrap();
+        assert_eq!((replaced.name.as_str(), replaced.version), ("anna", 2));
+
+        let result = replace_with_version(&storage, BOOKS, &book).await;
+        assert!(matches!(
+            result,
+            Err(VersionError::VersionConflict {
+                expected: 1,
+                actual: 2
+            })
+        ));
+        book.id = "book_x".to_string();
+        let result = replace_with_version(&storage, BOOKS, &book).await;
+        assert!(matches!(result, Err(VersionError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn modify_retries_after_conflict() {
+        let storage = storage_with_book().await;
+        let mut calls = 0;
+
+        let book: Book = modify_with_retry(&storage, BOOKS, "book_1", &retry(3), |book| {
+            calls += 1;
+            if calls == 1 {
+                update_concurrently(&storage);
+            }
+            rename(book);
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(calls, 2);
+        // 1 read, 2 by the concurrent update, 3 by the retry.
+        assert_eq!((book.name.as_str(), book.version), ("anna", 3));
+    }
+
+    #[tokio::test]
+    async fn modify_gives_up_after_max_attempts() {
+        let storage = storage_with_book().await;
+        let mut calls = 0;
+
+        let result = modify_with_retry(&storage, BOOKS, "book_1", &retry(3), |book: &mut Book| {
+            calls += 1;
+            update_concurrently(&storage);
+            rename(book);
+        })
+        .await;
+
+        assert_eq!(calls, 3);
+        assert!(
+            matches!(
+                result,
+                Err(VersionError::VersionConflict {
+                    expected: 3,
+                    actual: 4
+                })
+            ),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn modify_fails_on_missing_document() {
+        let storage = storage_with_book().await;
+        let result = modify_with_retry(&storage, BOOKS, "book_x", &retry(3), rename).await;
+        assert!(matches!(result, Err(VersionError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn modify_in_transaction() {
+        let storage = storage_with_book().await;
+        let mut session = storage.start_session();
+        session
+            .start_transaction(majority_tx_options())
+            .await
+            .unwrap();
+
+        let book = modify_with_retry_with_session(
+            &storage,
+            BOOKS,
+            "book_1",
+            &retry(3),
+            rename,
+            &mut session,
+        )
+        .await
+        .unwrap();
+        assert_eq!(book.version, 2);
+        let outside: Book = find(&storage, BOOKS, "book_1", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(outside.version, 1);
+
+        session.commit_transaction().await.unwrap();
+        let committed: Book = find(&storage, BOOKS, "book_1", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(committed.name, "anna");
+    }
+}
@@ -0,0 +1,256 @@
+//! Change streams on the collections, as typed [`ChangeEvent`]s.
+//!
+//! The driver has no `watch` yet, so a stream is an aggregation starting with `$changeStream`,
+//! whose cursor the server keeps open. [`consume`] runs a named consumer: it starts after the
+//! last event the consumer handled, whose resume token is kept in [`RESUME_TOKENS_COLL`], so a
+//! restarted consumer continues where it left off.
+//!
+//! ```ignore
+//! let tokens = ResumeTokens::new(MongoStorage::new(&db));
+//! let options = WatchOptions {
+//!     pipeline: vec![doc! {"$match": {"operationType": "update"}}],
+//!     full_document_lookup: true,
+//!     ..Default::default()
+//! };
+//! consume(&db.collection("books"), &tokens, "reviews-feed", &options, |event: ChangeEvent<Book>| async move {
+//!     println!("{:?}", event.full_document);
+//!     Ok(())
+//! })
+//! .await?;
+//! ```
+
+mod tokens;
+
+pub use tokens::{ResumeTokens, RESUME_TOKENS_COLL};
+
+use std::{future::Future, marker::PhantomData, time::Duration};
+
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, Document, Timestamp},
+    options::AggregateOptions,
+    Collection, Cursor,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    error::{DbError, DbResult},
+    repository::from_doc,
+    storage::Storage,
+};
+
+/// The `_id` of a change event, to start a stream after it.
+pub type ResumeToken = Document;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OperationType {
+    Insert,
+    Update,
+    Replace,
+    Delete,
+    Drop,
+    Rename,
+    DropDatabase,
+    /// The collection is gone. The stream ends after it.
+    Invalidate,
+    /// A type added by a later server.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Namespace {
+    pub db: String,
+    /// Missing for `dropDatabase`.
+    pub coll: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateDescription {
+    pub updated_fields: Document,
+    pub removed_fields: Vec<String>,
+}
+
+/// A change of a document of `T`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEvent<T> {
+    #[serde(rename = "_id")]
+    pub token: ResumeToken,
+    pub operation_type: OperationType,
+    pub ns: Option<Namespace>,
+    /// `_id` of the changed document, and the shard key if any.
+    pub document_key: Option<Document>,
+    /// The inserted or replacing document. For updates, the document as it is when the event is
+    /// read, only with [`WatchOptions::full_document_lookup`], and `None` if it is deleted since.
+    pub full_document: Option<T>,
+    pub update_description: Option<UpdateDescription>,
+    pub cluster_time: Option<Timestamp>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Stages run on the events after `$changeStream`, like a `$match` on `operationType` or
+    /// `fullDocument`. They must keep the `_id` of the events, which is their resume token.
+    pub pipeline: Vec<Document>,
+    /// Looks the current document up for the updates.
+    pub full_document_lookup: bool,
+    pub batch_size: Option<u32>,
+    /// How long the server waits for new events before answering an empty batch.
+    pub max_await_time: Option<Duration>,
+}
+
+/// The aggregation pipeline of a change stream starting after `start_after`, or now.
+pub fn pipeline(options: &WatchOptions, start_after: Option<ResumeToken>) -> Vec<Document> {
+    let mut change_stream = Document::new();
+    if options.full_document_lookup {
+        change_stream.insert("fullDocument", "updateLookup");
+    }
+    if let Some(token) = start_after {
+        // unlike resumeAfter, startAfter also starts after an invalidate event.
+        change_stream.insert("startAfter", token);
+    }
+    let mut pipeline = vec![doc! {"$changeStream": change_stream}];
+    pipeline.extend(options.pipeline.iter().cloned());
+    pipeline
+}
+
+/// Events of a collection, in the order of the oplog.
+pub struct ChangeStream<T> {
+    cursor: Cursor<Document>,
+    _event: PhantomData<fn() -> T>,
+}
+
+impl<T> std::fmt::Debug for ChangeStream<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChangeStream").finish()
+    }
+}
+
+impl<T: DeserializeOwned> ChangeStream<T> {
+    /// Waits for the next event. `None` once the stream is invalidated.
+    pub async fn next(&mut self) -> Option<DbResult<ChangeEvent<T>>> {
+        let event = self.cursor.next().await?;
+        Some(event.map_err(DbError::from).and_then(from_doc))
+    }
+}
+
+/// Opens a change stream on `coll`, starting after `start_after` or now.
+pub async fn watch<T: DeserializeOwned>(
+    coll: &Collection<Document>,
+    options: &WatchOptions,
+    start_after: Option<ResumeToken>,
+) -> DbResult<ChangeStream<T>> {
+    let aggregate = AggregateOptions::builder()
+        .batch_size(options.batch_size)
+        .max_await_time(options.max_await_time)
+        .build();
+    let cursor = coll
+        .aggregate(pipeline(options, start_after), aggregate)
+        .await?;
+    Ok(ChangeStream {
+        cursor,
+        _event: PhantomData,
+    })
+}
+
+/// Passes the events of `coll` to `handle` as the consumer `consumer`, until the stream is
+/// invalidated or `handle` fails.
+///
+/// The token of an event is saved once `handle` returns, so an event is handled at least once:
+/// the event being handled when the consumer stops is handled again by the next run.
+pub async fn consume<T, S, F, Fut>(
+    coll: &Collection<Document>,
+    tokens: &ResumeTokens<S>,
+    consumer: &str,
+    options: &WatchOptions,
+    mut handle: F,
+) -> DbResult<()>
+where
+    T: DeserializeOwned,
+    S: Storage,
+    F: FnMut(ChangeEvent<T>) -> Fut,
+    Fut: Future<Output = DbResult<()>>,
+{
+    let start_after = tokens.load(consumer).await?;
+    let mut stream = watch::<T>(coll, options, start_after).await?;
+    while let Some(event) = stream.next().await {
+        let event = event?;
+        let token = event.token.clone();
+        handle(event).await?;
+        tokens.save(consumer, &token).await?;
+    }
+    Ok(())
+}
+
+/// A `$match` stage of the JSON of a filter on the events, like `{"operationType": "insert"}`.
+pub fn match_stage(filter: &str) -> Result<Document, String> {
+    let value: serde_json::Value = serde_json::from_str(filter).map_err(|e| e.to_string())?;
+    Ok(doc! {"$match": crate::fixtures::to_document(value)?})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Book;
+
+    #[test]
+    fn builds_the_pipeline() {
+        let options = WatchOptions {
+            pipeline: vec![match_stage(r#"{"operationType": "update"}"#).unwrap()],
+            full_document_lookup: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pipeline(&options, Some(doc! {"_data": "82"})),
+            vec![
+                doc! {"$changeStream": {"fullDocument": "updateLookup", "startAfter": {"_data": "82"}}},
+                doc! {"$match": {"operationType": "update"}},
+            ]
+        );
+        assert_eq!(
+            pipeline(&WatchOptions::default(), None),
+            vec![doc! {"$changeStream": {}}]
+        );
+        assert!(match_stage("[1]").is_err());
+    }
+
+    #[test]
+    fn reads_events() {
+        let update = doc! {
+            "_id": {"_data": "8261"},
+            "operationType": "update",
+            "clusterTime": Timestamp {time: 1, increment: 2},
+            "ns": {"db": "test_db", "coll": "books"},
+            "documentKey": {"_id": 1},
+            "updateDescription": {"updatedFields": {"name": "new"}, "removedFields": ["authors"]},
+            "fullDocument": {"id": "book_1", "name": "new"},
+        };
+        let event: ChangeEvent<Book> = from_doc(update).unwrap();
+        assert_eq!(event.token, doc! {"_data": "8261"});
+        assert_eq!(event.operation_type, OperationType::Update);
+        assert_eq!(event.ns.unwrap().coll.as_deref(), Some("books"));
+        assert_eq!(event.full_document.unwrap().name, "new");
+        let description = event.update_description.unwrap();
+        assert_eq!(description.updated_fields, doc! {"name": "new"});
+        assert_eq!(description.removed_fields, vec!["authors".to_string()]);
+
+        let delete = doc! {
+            "_id": {"_data": "8262"},
+            "operationType": "delete",
+            "documentKey": {"_id": 1},
+            "fullDocument": null,
+        };
+        let event: ChangeEvent<Book> = from_doc(delete).unwrap();
+        assert_eq!(
+            (event.operation_type, event.full_document),
+            (OperationType::Delete, None)
+        );
+
+        let later: ChangeEvent<Book> =
+            from_doc(doc! {"_id": {"_data": "8263"}, "operationType": "shardCollection"}).unwrap();
+        assert_eq!(later.operation_type, OperationType::Other);
+    }
+}
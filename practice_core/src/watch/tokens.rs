@@ -0,0 +1,73 @@
+//! Resume tokens of the consumers, a document per consumer.
+
+use mongodb::bson::{doc, DateTime};
+
+use super::ResumeToken;
+use crate::{error::DbResult, storage::Storage};
+
+/// Collection of the tokens, `{_id: <consumer>, token: <resume token>, updated_at: <date>}`.
+pub const RESUME_TOKENS_COLL: &str = "_resume_tokens";
+
+#[derive(Debug, Clone)]
+pub struct ResumeTokens<S> {
+    storage: S,
+}
+
+impl<S: Storage> ResumeTokens<S> {
+    pub fn new(storage: S) -> Self {
+        ResumeTokens { storage }
+    }
+
+    /// The token of the last event `consumer` handled.
+    pub async fn load(&self, consumer: &str) -> DbResult<Option<ResumeToken>> {
+        let found = self
+            .storage
+            .find_one(RESUME_TOKENS_COLL, doc! {"_id": consumer}, None)
+            .await?;
+        Ok(found.and_then(|doc| doc.get_document("token").ok().cloned()))
+    }
+
+    pub async fn save(&self, consumer: &str, token: &ResumeToken) -> DbResult<()> {
+        let set = doc! {"$set": {"token": token.clone(), "updated_at": DateTime::now()}};
+        let updated = self
+            .storage
+            .update_one(RESUME_TOKENS_COLL, doc! {"_id": consumer}, set, None)
+            .await?;
+        if updated.matched_count == 0 {
+            // a consumer runs alone, so nobody inserts its token in between.
+            self.storage
+                .insert_one(
+                    RESUME_TOKENS_COLL,
+                    doc! {"_id": consumer, "token": token.clone(), "updated_at": DateTime::now()},
+                    None,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn keeps_a_token_per_consumer() {
+        let tokens = ResumeTokens::new(MemoryStorage::new());
+        assert_eq!(tokens.load("feed").await.unwrap(), None);
+
+        tokens.save("feed", &doc! {"_data": "01"}).await.unwrap();
+        tokens.save("stats", &doc! {"_data": "02"}).await.unwrap();
+        tokens.save("feed", &doc! {"_data": "03"}).await.unwrap();
+
+        assert_eq!(
+            tokens.load("feed").await.unwrap(),
+            Some(doc! {"_data": "03"})
+        );
+        assert_eq!(
+            tokens.load("stats").await.unwrap(),
+            Some(doc! {"_data": "02"})
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! Workloads: operations recorded from a client, and replayed against a database.
+//!
+//! A workload is a JSONL file with an [`Entry`] per line, e.g.
+//!
+//! ```text
+//! {"at_ms":0,"op":"find_one","coll":"users","filter":{"id":"user_1"}}
+//! {"at_ms":3,"session":"s1","op":"start_transaction"}
+//! {"at_ms":3,"session":"s1","op":"update","coll":"books","filter":{"id":"book_1"},"update":{"$push":{"reviews":{"user_id":"user_1","text":"good"}}}}
+//! {"at_ms":5,"session":"s1","op":"commit_transaction"}
+//! ```
+//!
+//! Filters, updates and documents are Extended JSON, canonical or relaxed. The entries of a
+//! `session` run in order on a session of their own, and are the transactions of the workload.
+//! [`record::Recorder`] writes the commands a client sends, and [`replay::replay`] runs a
+//! workload on a [`Storage`](crate::storage::Storage).
+
+pub mod record;
+pub mod replay;
+
+use std::path::Path;
+
+use mongodb::bson::Document;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub use record::Recorder;
+pub use replay::{replay, FailedEntry, ReplayOptions, ReplayReport};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Insert {
+        coll: String,
+        #[serde(with = "ext_json_docs")]
+        documents: Vec<Document>,
+    },
+    Find {
+        coll: String,
+        #[serde(with = "ext_json", default)]
+        filter: Document,
+    },
+    FindOne {
+        coll: String,
+        #[serde(with = "ext_json", default)]
+        filter: Document,
+    },
+    /// Updates the first matching document.
+    Update {
+        coll: String,
+        #[serde(with = "ext_json")]
+        filter: Document,
+        #[serde(with = "ext_json")]
+        update: Document,
+    },
+    FindOneAndUpdate {
+        coll: String,
+        #[serde(with = "ext_json")]
+        filter: Document,
+        #[serde(with = "ext_json")]
+        update: Document,
+    },
+    StartTransaction,
+    CommitTransaction,
+    AbortTransaction,
+}
+
+impl Operation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Insert { .. } => "insert",
+            Operation::Find { .. } => "find",
+            Operation::FindOne { .. } => "find_one",
+            Operation::Update { .. } => "update",
+            Operation::FindOneAndUpdate { .. } => "find_one_and_update",
+            Operation::StartTransaction => "start_transaction",
+            Operation::CommitTransaction => "commit_transaction",
+            Operation::AbortTransaction => "abort_transaction",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Milliseconds since the recording started.
+    #[serde(default)]
+    pub at_ms: u64,
+    /// Name of the session running the entry, for the entries of transactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    #[serde(flatten)]
+    pub op: Operation,
+}
+
+#[derive(Error, Debug)]
+pub enum WorkloadError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{path}: line {line}: {source}")]
+    Parse {
+        path: String,
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+/// Parses the entries of a workload. Blank lines are skipped, and `path` only names the
+/// workload in the errors.
+pub fn parse(text: &str, path: &str) -> Result<Vec<Entry>, WorkloadError> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|source| WorkloadError::Parse {
+                path: path.to_string(),
+                line: i + 1,
+                source,
+            })
+        })
+        .collect()
+}
+
+pub fn read_file(path: &Path) -> Result<Vec<Entry>, WorkloadError> {
+    let path_str = path.display().to_string();
+    let text = std::fs::read_to_string(path).map_err(|source| WorkloadError::Read {
+        path: path_str.clone(),
+        source,
+    })?;
+    parse(&text, &path_str)
+}
+
+/// The line of `entry`, without the line break.
+pub fn to_line(entry: &Entry) -> String {
+    // the documents are written as JSON values, which always serialize.
+    serde_json::to_string(entry).unwrap()
+}
+
+/// A document as relaxed Extended JSON.
+mod ext_json {
+    use mongodb::bson::{Bson, Document};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(doc: &Document, serializer: S) -> Result<S::Ok, S::Error> {
+        Bson::Document(doc.clone())
+            .into_relaxed_extjson()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Document, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        crate::fixtures::to_document(value).map_err(D::Error::custom)
+    }
+}
+
+mod ext_json_docs {
+    use mongodb::bson::{Bson, Document};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(docs: &[Document], serializer: S) -> Result<S::Ok, S::Error> {
+        let values: Vec<Value> = docs
+            .iter()
+            .map(|doc| Bson::Document(doc.clone()).into_relaxed_extjson())
+            .collect();
+        values.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Document>, D::Error> {
+        Vec::<Value>::deserialize(deserializer)?
+            .into_iter()
+            .map(|value| crate::fixtures::to_document(value).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::{doc, oid::ObjectId};
+
+    #[test]
+    fn entry_round_trip() {
+        let entries = vec![
+            Entry {
+                at_ms: 0,
+                session: None,
+                op: Operation::Insert {
+                    coll: "users".to_string(),
+                    documents: vec![doc! {"_id": ObjectId::new(), "id": "user_1"}],
+                },
+            },
+            Entry {
+                at_ms: 12,
+                session: Some("s1".to_string()),
+                op: Operation::StartTransaction,
+            },
+            Entry {
+                at_ms: 12,
+                session: Some("s1".to_string()),
+                op: Operation::FindOneAndUpdate {
+                    coll: "books".to_string(),
+                    filter: doc! {"id": "book_1"},
+                    update: doc! {"$inc": {"version": 1}},
+                },
+            },
+        ];
+        let text: String = entries.iter().map(|e| to_line(e) + "\n").collect();
+        assert_eq!(parse(&text, "workload.jsonl").unwrap(), entries);
+    }
+
+    #[test]
+    fn reads_canonical_documents_and_defaults() {
+        let line = r#"{"op":"find","coll":"books","filter":{"version":{"$numberLong":"2"}}}"#;
+        let entries = parse(line, "workload.jsonl").unwrap();
+        assert_eq!(
+            entries[0],
+            Entry {
+                at_ms: 0,
+                session: None,
+                op: Operation::Find {
+                    coll: "books".to_string(),
+                    filter: doc! {"version": 2i64},
+                },
+            }
+        );
+
+        let err = parse("\n{\"op\":\"drop\"}", "workload.jsonl").unwrap_err();
+        assert!(
+            err.to_string().starts_with("workload.jsonl: line 2: "),
+            "{}",
+            err
+        );
+    }
+}
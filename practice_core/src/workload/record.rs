@@ -0,0 +1,365 @@
+//! Recording the commands a client sends, with the driver's command monitoring.
+//!
+//! ```ignore
+//! let recorder = Arc::new(Recorder::to_file(Path::new("workload.jsonl"))?);
+//! let client = connect_with_handler(&config, recorder.clone()).await?;
+//! // ... run the commands to record
+//! println!("{:?}", recorder.summary());
+//! ```
+//!
+//! The commands on the `admin`, `config` and `local` databases are left out, except the ends
+//! of the transactions. Commands a workload can't express, like deletes or aggregations, are
+//! counted in [`RecordSummary::skipped`] instead.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+use mongodb::{
+    bson::{Bson, Document},
+    event::command::{CommandEventHandler, CommandStartedEvent},
+};
+
+use super::{to_line, Entry, Operation};
+
+const INTERNAL_DBS: &[&str] = &["admin", "config", "local"];
+/// Commands that don't read or write documents, or only continue another one.
+const IGNORED_COMMANDS: &[&str] = &[
+    "getMore",
+    "killCursors",
+    "endSessions",
+    "hello",
+    "isMaster",
+    "ismaster",
+    "ping",
+    "buildInfo",
+    "saslStart",
+    "saslContinue",
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordSummary {
+    pub recorded: usize,
+    /// Commands that can't be replayed, by name.
+    pub skipped: BTreeMap<String, usize>,
+    /// The first failure to write the workload. Nothing is written after it.
+    pub write_error: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Session ids of the transactions, and their names in the workload.
+    sessions: Vec<(Document, String)>,
+    summary: RecordSummary,
+}
+
+pub struct Recorder {
+    started: Instant,
+    out: Mutex<Box<dyn Write + Send>>,
+    state: Mutex<State>,
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("started", &self.started)
+            .finish()
+    }
+}
+
+impl Recorder {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Recorder {
+            started: Instant::now(),
+            out: Mutex::new(Box::new(out)),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Records into `path`, truncating it.
+    pub fn to_file(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+
+    pub fn summary(&self) -> RecordSummary {
+        self.lock_state().summary.clone()
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        // a panic while recording leaves the state usable.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records a command sent to `db`.
+    fn record(&self, db: &str, command_name: &str, command: &Document) {
+        let ends_transaction = matches!(command_name, "commitTransaction" | "abortTransaction");
+        if INTERNAL_DBS.contains(&db) && !ends_transaction {
+            return;
+        }
+        let mut state = self.lock_state();
+        let mut ops = match operations(command_name, command) {
+            Some(ops) => ops,
+            None => {
+                *state
+                    .summary
+                    .skipped
+                    .entry(command_name.to_string())
+                    .or_default() += 1;
+                return;
+            }
+        };
+        let session = in_transaction(command).map(|lsid| session_name(&mut state.sessions, lsid));
+        if command.get_bool("startTransaction") == Ok(true) {
+            ops.insert(0, Operation::StartTransaction);
+        }
+
+        let at_ms = self.started.elapsed().as_millis() as u64;
+        for op in ops {
+            if state.summary.write_error.is_some() {
+                return;
+            }
+            let entry = Entry {
+                at_ms,
+                session: session.clone(),
+                op,
+            };
+            let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+            match writeln!(out, "{}", to_line(&entry)).and_then(|()| out.flush()) {
+                Ok(()) => state.summary.recorded += 1,
+                Err(e) => state.summary.write_error = Some(e.to_string()),
+            }
+        }
+    }
+}
+
+impl CommandEventHandler for Recorder {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        self.record(&event.db, &event.command_name, &event.command);
+    }
+}
+
+/// The session id of a command run in a transaction.
+fn in_transaction(command: &Document) -> Option<&Document> {
+    if command.get_bool("autocommit") != Ok(false) {
+        return None;
+    }
+    command.get_document("lsid").ok()
+}
+
+fn session_name(sessions: &mut Vec<(Document, String)>, lsid: &Document) -> String {
+    if let Some((_, name)) = sessions.iter().find(|(id, _)| id == lsid) {
+        return name.clone();
+    }
+    let name = format!("s{}", sessions.len() + 1);
+    sessions.push((lsid.clone(), name.clone()));
+    name
+}
+
+/// The operations `command` is replayed as: none for a command that doesn't touch documents,
+/// and `None` for a command that can't be replayed.
+pub fn operations(command_name: &str, command: &Document) -> Option<Vec<Operation>> {
+    if IGNORED_COMMANDS.contains(&command_name) {
+        return Some(vec![]);
+    }
+    let coll = || command.get_str(command_name).ok().map(str::to_string);
+    let doc_or_empty = |key| command.get_document(key).cloned().unwrap_or_default();
+    let op = match command_name {
+        "insert" => Operation::Insert {
+            coll: coll()?,
+            documents: documents(command.get_array("documents").ok()?)?,
+        },
+        "find" => {
+            let filter = doc_or_empty("filter");
+            let coll = coll()?;
+            if command.get_i64("limit").ok() == Some(1) || command.get_i32("limit").ok() == Some(1)
+            {
+                Operation::FindOne { coll, filter }
+            } else {
+                Operation::Find { coll, filter }
+            }
+        }
+        "update" => {
+            let coll = coll()?;
+            let statements = documents(command.get_array("updates").ok()?)?;
+            return statements
+                .into_iter()
+                .map(|statement| {
+                    if statement.get_bool("multi") == Ok(true)
+                        || statement.get_bool("upsert") == Ok(true)
+                    {
+                        return None;
+                    }
+                    Some(Operation::Update {
+                        coll: coll.clone(),
+                        filter: statement.get_document("q").ok()?.clone(),
+                        update: operator_update(statement.get("u")?)?,
+                    })
+                })
+                .collect();
+        }
+        "findAndModify" => {
+            if command.get_bool("remove") == Ok(true) || command.get_bool("upsert") == Ok(true) {
+                return None;
+            }
+            Operation::FindOneAndUpdate {
+                coll: coll()?,
+                filter: doc_or_empty("query"),
+                update: operator_update(command.get("update")?)?,
+            }
+        }
+        "commitTransaction" => Operation::CommitTransaction,
+        "abortTransaction" => Operation::AbortTransaction,
+        _ => return None,
+    };
+    Some(vec![op])
+}
+
+fn documents(values: &[Bson]) -> Option<Vec<Document>> {
+    values
+        .iter()
+        .map(|value| value.as_document().cloned())
+        .collect()
+}
+
+/// An update with operators. Replacements and pipelines can't be replayed.
+fn operator_update(update: &Bson) -> Option<Document> {
+    let update = update.as_document()?;
+    if update.keys().all(|key| key.starts_with('$')) && !update.is_empty() {
+        Some(update.clone())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workload::parse;
+    use mongodb::bson::doc;
+    use std::sync::Arc;
+
+    fn lsid(id: i32) -> Document {
+        doc! {"id": id}
+    }
+
+    /// A buffer the test keeps reading while the recorder writes it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn converts_commands() {
+        let insert = doc! {"insert": "users", "documents": [{"id": "user_1"}, {"id": "user_2"}], "$db": "test_db"};
+        assert_eq!(
+            operations("insert", &insert),
+            Some(vec![Operation::Insert {
+                coll: "users".to_string(),
+                documents: vec![doc! {"id": "user_1"}, doc! {"id": "user_2"}],
+            }])
+        );
+
+        let find_one = doc! {"find": "users", "filter": {"id": "user_1"}, "limit": 1i64};
+        assert!(matches!(
+            operations("find", &find_one).unwrap()[0],
+            Operation::FindOne { .. }
+        ));
+
+        let mut update = doc! {"update": "books", "updates": [
+            {"q": {"id": "book_1"}, "u": {"$set": {"name": "a"}}},
+        ]};
+        assert_eq!(
+            operations("update", &update),
+            Some(vec![Operation::Update {
+                coll: "books".to_string(),
+                filter: doc! {"id": "book_1"},
+                update: doc! {"$set": {"name": "a"}},
+            }])
+        );
+        update
+            .get_array_mut("updates")
+            .unwrap()
+            .push(doc! {"q": {}, "u": {"$set": {"name": "b"}}, "multi": true}.into());
+        assert_eq!(operations("update", &update), None);
+
+        let find_and_modify = doc! {"findAndModify": "books", "query": {"id": "book_1"}, "update": {"$inc": {"version": 1}}, "new": true};
+        assert_eq!(
+            operations("findAndModify", &find_and_modify),
+            Some(vec![Operation::FindOneAndUpdate {
+                coll: "books".to_string(),
+                filter: doc! {"id": "book_1"},
+                update: doc! {"$inc": {"version": 1}},
+            }])
+        );
+
+        let replace = doc! {"findAndModify": "books", "query": {}, "update": {"name": "a"}};
+        assert_eq!(operations("findAndModify", &replace), None);
+        assert_eq!(operations("getMore", &doc! {"getMore": 1i64}), Some(vec![]));
+        assert_eq!(operations("delete", &doc! {"delete": "books"}), None);
+    }
+
+    #[test]
+    fn records_transactions_by_session() {
+        let out = Shared::default();
+        let recorder = Recorder::new(out.clone());
+        let tx = |id: i32, mut command: Document| {
+            command.insert("lsid", lsid(id));
+            command.insert("txnNumber", 1i64);
+            command.insert("autocommit", false);
+            command
+        };
+
+        recorder.record(
+            "test_db",
+            "update",
+            &tx(
+                7,
+                doc! {"update": "users", "startTransaction": true, "updates": [{"q": {"id": "user_1"}, "u": {"$set": {"name": "a"}}}]},
+            ),
+        );
+        recorder.record(
+            "test_db",
+            "find",
+            &doc! {"find": "users", "filter": {}, "lsid": lsid(8)},
+        );
+        recorder.record(
+            "admin",
+            "commitTransaction",
+            &tx(7, doc! {"commitTransaction": 1}),
+        );
+        recorder.record("admin", "listDatabases", &doc! {"listDatabases": 1});
+        recorder.record("test_db", "aggregate", &doc! {"aggregate": "users"});
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let entries = parse(&text, "recorded").unwrap();
+        let ops: Vec<_> = entries
+            .iter()
+            .map(|e| (e.session.as_deref(), e.op.name()))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (Some("s1"), "start_transaction"),
+                (Some("s1"), "update"),
+                (None, "find"),
+                (Some("s1"), "commit_transaction"),
+            ]
+        );
+        let summary = recorder.summary();
+        assert_eq!(summary.recorded, 4);
+        assert_eq!(summary.skipped.get("aggregate"), Some(&1));
+    }
+}
@@ -0,0 +1,365 @@
+//! Running a workload against a [`Storage`].
+//!
+//! The entries are split into lanes: one per session, and `concurrency` lanes sharing the
+//! entries without a session. A lane runs its entries in order, and up to `concurrency` lanes
+//! run at once, so the order of the entries of different lanes isn't kept. With a `speed`, each
+//! entry waits for its recorded time divided by the speed, which can only be kept up with while
+//! no more than `concurrency` lanes are busy at the same time.
+//!
+//! A failed operation of a transaction aborts it, and the entries of the lane up to its commit
+//! or abort are skipped. A failed operation outside a transaction is only counted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use mongodb::options::TransactionOptions;
+use tokio::time::Instant;
+
+use super::{Entry, Operation};
+use crate::{
+    error::DbResult,
+    majority_tx_options,
+    storage::{SessionSource, Storage},
+    tx::TxSession,
+    DbError,
+};
+
+/// Errors kept in [`ReplayReport::errors`]. The others are only counted.
+const MAX_ERRORS: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// How many times faster than recorded the entries run, or `None` to run them as fast as
+    /// possible.
+    pub speed: Option<f64>,
+    /// Lanes running at once, at least 1.
+    pub concurrency: usize,
+    pub transaction_options: TransactionOptions,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            speed: None,
+            concurrency: 4,
+            transaction_options: majority_tx_options(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayReport {
+    pub executed: usize,
+    pub failed: usize,
+    /// Entries of aborted transactions after the failure, and transaction entries without a
+    /// session.
+    pub skipped: usize,
+    pub elapsed: Duration,
+    /// Executed entries by operation name, failed ones included.
+    pub by_op: BTreeMap<&'static str, usize>,
+    /// The first failures, in the order of the workload.
+    pub errors: Vec<FailedEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedEntry {
+    /// Index of the entry in the workload, from 0.
+    pub index: usize,
+    pub op: &'static str,
+    pub error: String,
+}
+
+impl fmt::Display for FailedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "entry {}: {}: {}", self.index, self.op, self.error)
+    }
+}
+
+impl ReplayReport {
+    fn merge(&mut self, other: ReplayReport) {
+        self.executed += other.executed;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+        for (op, count) in other.by_op {
+            *self.by_op.entry(op).or_default() += count;
+        }
+        self.errors.extend(other.errors);
+    }
+
+    fn fail(&mut self, index: usize, op: &Operation, e: &DbError) {
+        self.failed += 1;
+        self.errors.push(FailedEntry {
+            index,
+            op: op.name(),
+            error: e.to_string(),
+        });
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} executed, {} failed, {} skipped in {:.2?}",
+            self.executed, self.failed, self.skipped, self.elapsed
+        )?;
+        for (op, count) in &self.by_op {
+            writeln!(f, "  {:<20} {}", op, count)?;
+        }
+        for error in &self.errors {
+            writeln!(f, "  error: {}", error)?;
+        }
+        if self.failed > self.errors.len() {
+            writeln!(f, "  ... and {} more", self.failed - self.errors.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Entries run in order, with their index in the workload.
+struct Lane {
+    session: Option<String>,
+    entries: Vec<(usize, Entry)>,
+}
+
+/// Replays `entries`. Only failing to start a session fails the replay.
+pub async fn replay<S: Storage, C: SessionSource<S>>(
+    storage: &S,
+    sessions: &C,
+    entries: Vec<Entry>,
+    options: &ReplayOptions,
+) -> DbResult<ReplayReport> {
+    let concurrency = options.concurrency.max(1);
+    let lanes = split(entries, concurrency);
+
+    let started = Instant::now();
+    let results: Vec<DbResult<ReplayReport>> = futures::stream::iter(lanes)
+        .map(|lane| run_lane(storage, sessions, lane, options, started))
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    let mut report = ReplayReport::default();
+    for result in results {
+        report.merge(result?);
+    }
+    report.elapsed = started.elapsed();
+    // the lanes finish in any order.
+    report.errors.sort_by_key(|e| e.index);
+    report.errors.truncate(MAX_ERRORS);
+    Ok(report)
+}
+
+fn split(entries: Vec<Entry>, concurrency: usize) -> Vec<Lane> {
+    let mut lanes: Vec<Lane> = (0..concurrency)
+        .map(|_| Lane {
+            session: None,
+            entries: vec![],
+        })
+        .collect();
+    let mut by_session: HashMap<String, usize> = HashMap::new();
+    let mut sessionless = 0;
+    for (index, entry) in entries.into_iter().enumerate() {
+        let lane = match &entry.session {
+            Some(name) => *by_session.entry(name.clone()).or_insert_with(|| {
+                lanes.push(Lane {
+                    session: Some(name.clone()),
+                    entries: vec![],
+                });
+                lanes.len() - 1
+            }),
+            None => {
+                sessionless += 1;
+                (sessionless - 1) % concurrency
+            }
+        };
+        lanes[lane].entries.push((index, entry));
+    }
+    lanes.retain(|lane| !lane.entries.is_empty());
+    lanes
+}
+
+async fn run_lane<S: Storage, C: SessionSource<S>>(
+    storage: &S,
+    sessions: &C,
+    lane: Lane,
+    options: &ReplayOptions,
+    started: Instant,
+) -> DbResult<ReplayReport> {
+    let mut session = match lane.session {
+        Some(_) => Some(sessions.start_session().await?),
+        None => None,
+    };
+    let mut report = ReplayReport::default();
+    let mut in_transaction = false;
+    // set from a failure in a transaction up to the end of the transaction.
+    let mut skipping = false;
+
+    for (index, entry) in lane.entries {
+        if let Some(speed) = options.speed {
+            let at = Duration::from_secs_f64(entry.at_ms as f64 / 1000.0 / speed);
+            tokio::time::sleep_until(started + at).await;
+        }
+        let op = entry.op;
+        let ends_transaction = matches!(
+            op,
+            Operation::CommitTransaction | Operation::AbortTransaction
+        );
+        if skipping {
+            report.skipped += 1;
+            skipping = !ends_transaction;
+            continue;
+        }
+        if session.is_none() && (ends_transaction || op == Operation::StartTransaction) {
+            report.skipped += 1;
+            continue;
+        }
+
+        let result = match (&op, session.as_mut()) {
+            (Operation::StartTransaction, Some(session)) => {
+                in_transaction = true;
+                session
+                    .start_transaction(options.transaction_options.clone())
+                    .await
+                    .map_err(DbError::from)
+            }
+            (Operation::CommitTransaction, Some(session)) => {
+                in_transaction = false;
+                session.commit_transaction().await.map_err(DbError::from)
+            }
+            (Operation::AbortTransaction, Some(session)) => {
+                in_transaction = false;
+                session.abort_transaction().await.map_err(DbError::from)
+            }
+            (_, session) => execute(storage, op.clone(), session).await,
+        };
+
+        report.executed += 1;
+        *report.by_op.entry(op.name()).or_default() += 1;
+        if let Err(e) = result {
+            report.fail(index, &op, &e);
+            if in_transaction {
+                if let Some(session) = &mut session {
+                    // the transaction may be aborted on the server already.
+                    let _ = session.abort_transaction().await;
+                }
+                in_transaction = false;
+                skipping = true;
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn execute<S: Storage>(
+    storage: &S,
+    op: Operation,
+    session: Option<&mut S::Session>,
+) -> DbResult<()> {
+    match op {
+        Operation::Insert { coll, documents } => {
+            storage.insert_many(&coll, documents, session).await?;
+        }
+        Operation::Find { coll, filter } => {
+            storage.find(&coll, filter, session).await?;
+        }
+        Operation::FindOne { coll, filter } => {
+            storage.find_one(&coll, filter, session).await?;
+        }
+        Operation::Update {
+            coll,
+            filter,
+            update,
+        } => {
+            storage.update_one(&coll, filter, update, session).await?;
+        }
+        Operation::FindOneAndUpdate {
+            coll,
+            filter,
+            update,
+        } => {
+            storage
+                .find_one_and_update(&coll, filter, update, session)
+                .await?;
+        }
+        // skipped by the caller without a session.
+        Operation::StartTransaction
+        | Operation::CommitTransaction
+        | Operation::AbortTransaction => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::MemoryStorage, workload::parse};
+    use mongodb::bson::doc;
+
+    const WORKLOAD: &str = r#"
+{"at_ms":0,"op":"insert","coll":"books","documents":[{"id":"book_1","version":0},{"id":"book_2","version":0}]}
+{"at_ms":10,"session":"s1","op":"start_transaction"}
+{"at_ms":10,"session":"s1","op":"update","coll":"books","filter":{"id":"book_1"},"update":{"$inc":{"version":1}}}
+{"at_ms":20,"session":"s2","op":"start_transaction"}
+{"at_ms":20,"session":"s2","op":"update","coll":"books","filter":{"id":"book_2"},"update":{"$bogus":{"version":1}}}
+{"at_ms":20,"session":"s2","op":"update","coll":"books","filter":{"id":"book_2"},"update":{"$inc":{"version":1}}}
+{"at_ms":30,"session":"s2","op":"commit_transaction"}
+{"at_ms":30,"session":"s1","op":"commit_transaction"}
+{"at_ms":40,"op":"find_one_and_update","coll":"books","filter":{"id":"book_2"},"update":{"$inc":{"version":10}}}
+"#;
+
+    async fn versions(storage: &MemoryStorage) -> Vec<i32> {
+        storage
+            .find("books", doc! {}, None)
+            .await
+            .unwrap()
+            .iter()
+            .map(|book| book.get_i32("version").unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replays_transactions_by_session() {
+        let storage = MemoryStorage::new();
+        let entries = parse(WORKLOAD, "workload.jsonl").unwrap();
+        // with a lane at a time, the entries without a session run first.
+        let options = ReplayOptions {
+            concurrency: 1,
+            ..Default::default()
+        };
+
+        let report = replay(&storage, &storage, entries, &options).await.unwrap();
+
+        assert_eq!((report.executed, report.failed, report.skipped), (7, 1, 2));
+        assert_eq!(report.by_op.get("update"), Some(&2));
+        assert_eq!(report.errors.len(), 1);
+        assert!(
+            report.errors[0]
+                .to_string()
+                .starts_with("entry 4: update: "),
+            "{}",
+            report.errors[0]
+        );
+        // the transaction of s2 is aborted, so book_2 only gets the update outside of it.
+        assert_eq!(versions(&storage).await, vec![1, 10]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_the_recorded_timing() {
+        let storage = MemoryStorage::new();
+        let entries = parse(WORKLOAD, "workload.jsonl").unwrap();
+        let options = ReplayOptions {
+            speed: Some(0.01),
+            concurrency: 1,
+            ..Default::default()
+        };
+
+        let report = replay(&storage, &storage, entries, &options).await.unwrap();
+
+        // the last entry is at 40ms, slowed down 100 times.
+        assert!(report.elapsed >= Duration::from_secs(4), "{:?}", report);
+    }
+}
@@ -0,0 +1,59 @@
+//! Harness of the integration tests.
+//!
+//! The tests share a single node [`LocalReplicaSet`], which is stopped when the last test using
+//! it ends. Each test gets its own randomly named database, so the tests can run in parallel.
+//! A test fails when there is no `mongod` to start, instead of passing without running.
+
+use std::sync::{Arc, OnceLock, Weak};
+
+use mongodb::{bson::oid::ObjectId, Client};
+use practice_core::{
+    connect,
+    replset::{LaunchError, LocalReplicaSet, ReplicaSetOptions, ENV_MONGOD},
+    scenario::Target,
+};
+use tokio::sync::Mutex;
+
+static REPLICA_SET: OnceLock<Mutex<Weak<LocalReplicaSet>>> = OnceLock::new();
+
+/// The running replica set, or a new one if no test uses it now.
+async fn replica_set() -> Arc<LocalReplicaSet> {
+    let mut shared = REPLICA_SET.get_or_init(Default::default).lock().await;
+    if let Some(replica_set) = shared.upgrade() {
+        return replica_set;
+    }
+    let options = ReplicaSetOptions {
+        nodes: 1,
+        name: "rs0".to_string(),
+        ..Default::default()
+    };
+    let replica_set = match LocalReplicaSet::start(&options).await {
+        Ok(replica_set) => Arc::new(replica_set),
+        Err(e @ LaunchError::Spawn { .. }) => panic!(
+            "{}\nthe integration tests need mongod on PATH or at ${}",
+            e, ENV_MONGOD
+        ),
+        Err(e) => panic!("failed to start the replica set: {}", e),
+    };
+    *shared = Arc::downgrade(&replica_set);
+    replica_set
+}
+
+/// A database of its own for a test, on the shared replica set.
+pub struct TestDb {
+    pub client: Client,
+    pub target: Target,
+    _replica_set: Arc<LocalReplicaSet>,
+}
+
+pub async fn test_db() -> TestDb {
+    let replica_set = replica_set().await;
+    let client = connect(&replica_set.config())
+        .await
+        .expect("failed to connect to the replica set");
+    TestDb {
+        client,
+        target: Target::new(&format!("it_{}", ObjectId::new())),
+        _replica_set: replica_set,
+    }
+}
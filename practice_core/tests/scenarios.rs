@@ -0,0 +1,203 @@
+//! The scenarios of the practice binaries, run against a spawned replica set.
+//!
+//! Ignored by default since they need `mongod`: `cargo test -- --ignored` runs them.
+
+mod common;
+
+use std::path::Path;
+
+use mongodb::bson::{doc, Document};
+use practice_core::{
+    scenario::{
+        basic,
+        contention::{self, LoadOptions, Strategy},
+        isolation::{self, Anomaly, Outcome},
+        optimistic_lock, pessimistic_lock, write_conflict,
+    },
+    storage::MongoStorage,
+    watch::{self, ChangeEvent, OperationType, ResumeTokens, WatchOptions},
+    workload::{self, ReplayOptions},
+    Book,
+};
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn misc() {
+    let test = common::test_db().await;
+    basic::misc(&test.client, &test.target).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn indexes() {
+    let test = common::test_db().await;
+    basic::indexes(&test.client, &test.target).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn users_and_books() {
+    let test = common::test_db().await;
+    let (client, target) = (&test.client, &test.target);
+    basic::create_users(client, target).await.unwrap();
+    basic::create_books(client, target).await.unwrap();
+    basic::update_books(client, target).await.unwrap();
+    basic::find_users(client, target).await.unwrap();
+    basic::add_reviews_in_session(client, target).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn abort_tx() {
+    let test = common::test_db().await;
+    let (client, target) = (&test.client, &test.target);
+    basic::create_books(client, target).await.unwrap();
+    basic::abort_tx(client, target).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn write_conflict() {
+    let test = common::test_db().await;
+    let (client, target) = (&test.client, &test.target);
+    write_conflict::create_users(client, target).await.unwrap();
+    write_conflict::conflict_updating(client, target)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn optimistic_lock() {
+    let test = common::test_db().await;
+    let (client, target) = (&test.client, &test.target);
+    optimistic_lock::create_books(client, target).await.unwrap();
+    optimistic_lock::conflict_updating(client, target)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn pessimistic_lock() {
+    let test = common::test_db().await;
+    let (client, target) = (&test.client, &test.target);
+    write_conflict::create_users(client, target).await.unwrap();
+    pessimistic_lock::locked_updating(client, target)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn isolation_matrix() {
+    let test = common::test_db().await;
+    let matrix = isolation::matrix(&test.client, &test.target).await.unwrap();
+    for row in &matrix.rows {
+        for (anomaly, outcome) in &row.outcomes {
+            assert!(!matches!(outcome, Outcome::Error(_)), "{}", matrix);
+            // a transaction reads from a snapshot, whatever the read concern.
+            assert_eq!(
+                outcome.is_allowed(),
+                *anomaly == Anomaly::WriteSkew,
+                "{}",
+                matrix
+            );
+        }
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn contention() {
+    let test = common::test_db().await;
+    for strategy in &Strategy::ALL {
+        let options = LoadOptions {
+            strategy: *strategy,
+            workers: 4,
+            books: 2,
+            ops_per_worker: 10,
+            ..Default::default()
+        };
+        let report = contention::run(&test.client, &test.target, &options)
+            .await
+            .unwrap();
+        assert_eq!(report.lost_updates(), 0, "{}", report);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn replay_workload() {
+    let test = common::test_db().await;
+    basic::create_users(&test.client, &test.target)
+        .await
+        .unwrap();
+    basic::create_books(&test.client, &test.target)
+        .await
+        .unwrap();
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../workloads/reviews.jsonl");
+    let entries = workload::read_file(&path).unwrap();
+
+    let storage = MongoStorage::new(&test.client.database(&test.target.db_name));
+    let report = workload::replay(&storage, &test.client, entries, &ReplayOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!((report.executed, report.failed), (12, 0), "{}", report);
+}
+
+#[tokio::test]
+#[ignore = "needs mongod"]
+async fn watch_resumes_after_the_saved_token() {
+    let test = common::test_db().await;
+    let db = test.client.database(&test.target.db_name);
+    let books = db.collection::<Document>(&test.target.books);
+    let tokens = ResumeTokens::new(MongoStorage::new(&db));
+    let options = WatchOptions {
+        full_document_lookup: true,
+        ..Default::default()
+    };
+
+    let mut stream = watch::watch::<Book>(&books, &options, None).await.unwrap();
+    books
+        .insert_one(doc! {"id": "book_1", "name": "name"}, None)
+        .await
+        .unwrap();
+    books
+        .update_one(
+            doc! {"id": "book_1"},
+            doc! {"$set": {"name": "renamed"}},
+            None,
+        )
+        .await
+        .unwrap();
+    let inserted = stream.next().await.unwrap().unwrap();
+    assert_eq!(inserted.operation_type, OperationType::Insert);
+    assert_eq!(inserted.full_document.unwrap().name, "name");
+    tokens.save("test", &inserted.token).await.unwrap();
+    books.drop(None).await.unwrap();
+
+    // starts after the insert, and ends with the invalidate of the drop.
+    let mut seen = vec![];
+    watch::consume(
+        &books,
+        &tokens,
+        "test",
+        &options,
+        |event: ChangeEvent<Book>| {
+            seen.push(event.operation_type);
+            async { Ok(()) }
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        seen,
+        vec![
+            OperationType::Update,
+            OperationType::Drop,
+            OperationType::Invalidate
+        ]
+    );
+}